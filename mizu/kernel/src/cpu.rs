@@ -2,6 +2,9 @@ use core::sync::atomic::{self, AtomicUsize, Ordering::*};
 
 pub struct IpiComm {
     cmd: AtomicUsize,
+    /// The mask of harts that have yet to acknowledge `cmd`, so that wakeup
+    /// IPIs sent by [`Wfi`] are not counted as acknowledgements.
+    pending: AtomicUsize,
     result: AtomicUsize,
 }
 
@@ -15,6 +18,11 @@ impl IpiComm {
             core::arch::asm!("csrc sip, {}", in(reg) SIE);
         }
 
+        let bit = 1 << hart_id::hart_id();
+        if self.pending.fetch_and(!bit, SeqCst) & bit == 0 {
+            return;
+        }
+
         let cmd = self.cmd.load(Acquire);
         if let IPI_CMD_FENCE = cmd {
             atomic::fence(SeqCst)
@@ -25,6 +33,7 @@ impl IpiComm {
     fn send(&self, mask: usize, cmd: usize) {
         let count = mask.count_ones() as usize;
         self.cmd.store(cmd, Release);
+        self.pending.fetch_or(mask, SeqCst);
 
         let ret = sbi_rt::send_ipi(mask, 0).into_result();
         if ret.is_ok() {
@@ -48,5 +57,54 @@ impl IpiComm {
 
 pub static IPI: IpiComm = IpiComm {
     cmd: AtomicUsize::new(0),
+    pending: AtomicUsize::new(0),
     result: AtomicUsize::new(0),
 };
+
+/// The mask of harts sleeping in [`Wfi::park`].
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Puts idle harts to sleep with `wfi` until the next interrupt, which may be
+/// a wakeup IPI sent when a task is queued.
+pub struct Wfi;
+
+impl Wfi {
+    fn wake(mask: usize) {
+        if mask != 0 {
+            let _ = sbi_rt::send_ipi(mask, 0);
+        }
+    }
+}
+
+impl art::Idle for Wfi {
+    fn park(&self, ready: &dyn Fn() -> bool) {
+        let bit = 1 << hart_id::hart_id();
+        // With interrupts disabled, any interrupt arriving after the check
+        // stays pending and makes `wfi` return immediately, and is then
+        // handled at the end of the critical section.
+        ksync::critical(|| {
+            PARKED.fetch_or(bit, SeqCst);
+            atomic::fence(SeqCst);
+            if !ready() {
+                #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+                unsafe {
+                    core::arch::asm!("wfi")
+                }
+            }
+            PARKED.fetch_and(!bit, SeqCst);
+        })
+    }
+
+    fn unpark_one(&self) {
+        // Pairs with the fence in `park`, so that either the parking hart sees
+        // the queued task or this sees it parked.
+        atomic::fence(SeqCst);
+        let parked = PARKED.load(SeqCst);
+        Self::wake(parked & parked.wrapping_neg())
+    }
+
+    fn unpark_all(&self) {
+        atomic::fence(SeqCst);
+        Self::wake(PARKED.load(SeqCst))
+    }
+}
@@ -19,6 +19,14 @@ static DEV_INIT: Lazy<Handlers<&str, &FdtNode, bool>> = Lazy::new(|| {
         .map("virtio,mmio", virtio::virtio_mmio_init)
 });
 
+static FDT: Once<Fdt> = Once::new();
+
+/// The boot arguments in the `chosen` node of the FDT, if any.
+pub fn bootargs() -> Option<&'static str> {
+    let chosen = FDT.get()?.find_node("/chosen")?;
+    chosen.property("bootargs")?.as_str()
+}
+
 /// Initialize all the possible devices in this crate using FDT.
 ///
 /// # Errors
@@ -30,7 +38,6 @@ static DEV_INIT: Lazy<Handlers<&str, &FdtNode, bool>> = Lazy::new(|| {
 ///
 /// `fdt_base` must have `'static` read access to a valid FDT struct.
 pub unsafe fn init(fdt_base: *const ()) -> Result<(), FdtError> {
-    static FDT: Once<Fdt> = Once::new();
     let fdt = FDT.try_call_once(|| unsafe { fdt::Fdt::from_ptr(fdt_base.cast()) })?;
 
     // Some devices may depend on other devices (like interrupts), so we should keep
@@ -4,20 +4,21 @@ mod pipe;
 mod serial;
 mod tmp;
 
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
 use core::{fmt, time::Duration};
 
 use afat32::NullTimeProvider;
 use arsc_rs::Arsc;
+use async_trait::async_trait;
 use crossbeam_queue::ArrayQueue;
-use ksc::Error::{self, EACCES, ENOENT};
+use ksc::Error::{self, ENOENT};
 use ksync::{Sender, TryRecvError};
 use ktime::sleep;
 use spin::RwLock;
 use umifs::{
     path::{Path, PathBuf},
-    traits::{Entry, FileSystem},
-    types::{OpenOptions, Permissions},
+    traits::{Entry, FileSystem, ToIo},
+    types::{Metadata, OpenOptions, Permissions},
 };
 
 pub use self::pipe::pipe;
@@ -103,20 +104,36 @@ pub async fn open_dir(
     Ok(entry)
 }
 
-pub async fn unlink(path: &Path) -> Result<(), Error> {
-    let (entry, _) = open(
-        path.parent().ok_or(ENOENT)?,
-        OpenOptions::DIRECTORY | OpenOptions::RDWR,
-        Permissions::all_same(true, true, false),
-    )
-    .await?;
-    let Some(dir) = entry.to_dir_mut() else {
-        return Err(EACCES)
-    };
-    dir.unlink(path, None).await
+/// The root of the mount table, from which absolute paths are resolved.
+struct MountRoot;
+
+impl ToIo for MountRoot {}
+
+#[async_trait]
+impl Entry for MountRoot {
+    async fn open(
+        self: Arc<Self>,
+        path: &Path,
+        options: OpenOptions,
+        perm: Permissions,
+    ) -> Result<(Arc<dyn Entry>, bool), Error> {
+        open(path, options, perm).await
+    }
+
+    async fn metadata(&self) -> Metadata {
+        // Opening it with an empty path yields the root directory of the file
+        // system mounted there instead, so it is never handed out.
+        unreachable!("the mount root is never opened itself")
+    }
+}
+
+/// The entry [`umifs::traits::resolve_at`] resolves absolute paths from.
+pub fn root() -> Arc<dyn Entry> {
+    Arc::new(MountRoot)
 }
 
 pub async fn fs_init() {
+    umifs::traits::set_root(root());
     mount("dev/shm".into(), Arsc::new(tmp::TmpFs::new()));
     mount("dev".into(), Arsc::new(dev::DevFs));
     mount("tmp".into(), Arsc::new(tmp::TmpFs::new()));
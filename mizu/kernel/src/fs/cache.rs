@@ -115,6 +115,10 @@ impl Entry for CachedDir {
         self.entry.metadata()
     }
 
+    fn fs_id(&self) -> Option<usize> {
+        self.entry.fs_id()
+    }
+
     fn to_dir(self: Arc<Self>) -> Option<Arc<dyn Directory>> {
         Some(self)
     }
@@ -139,9 +143,22 @@ impl DirectoryMut for CachedDir {
         src_path: &Path,
         dst_parent: Arc<dyn DirectoryMut>,
         dst_path: &Path,
+        flags: RenameFlags,
     ) -> Result<(), Error> {
         let dir = self.entry.clone().to_dir_mut().ok_or(EPERM)?;
-        dir.rename(src_path, dst_parent, dst_path).await
+        // Pass the inner directory, which the file system can recognize.
+        let (dst_parent, dst_cached) = match dst_parent.clone().into_any().downcast::<Self>() {
+            Ok(dst) => (dst.entry.clone().to_dir_mut().ok_or(EPERM)?, Some(dst)),
+            Err(_) => (dst_parent, None),
+        };
+        dir.rename(src_path, dst_parent, dst_path, flags).await?;
+
+        // Drop the entries cached under the old names.
+        ksync::critical(|| self.cache.write().remove(src_path));
+        if let Some(dst) = dst_cached {
+            ksync::critical(|| dst.cache.write().remove(dst_path));
+        }
+        Ok(())
     }
 
     async fn link(
@@ -158,6 +175,11 @@ impl DirectoryMut for CachedDir {
         let dir = self.entry.clone().to_dir_mut().ok_or(EPERM)?;
         dir.unlink(path, expect_dir).await
     }
+
+    async fn reserve_dir_entries(&self, count: usize) -> Result<(), Error> {
+        let dir = self.entry.clone().to_dir_mut().ok_or(EPERM)?;
+        dir.reserve_dir_entries(count).await
+    }
 }
 
 impl ToIo for CachedFile {
@@ -181,4 +203,8 @@ impl Entry for CachedFile {
     fn metadata<'a: 'b, 'b>(&'a self) -> Boxed<'b, Metadata> {
         self.entry.metadata()
     }
+
+    fn fs_id(&self) -> Option<usize> {
+        self.entry.fs_id()
+    }
 }
@@ -1,5 +1,8 @@
 use alloc::{boxed::Box, sync::Arc};
-use core::sync::atomic::{AtomicUsize, Ordering::*};
+use core::{
+    sync::atomic::{AtomicUsize, Ordering::*},
+    task::{Context, Poll},
+};
 
 use arsc_rs::Arsc;
 use async_trait::async_trait;
@@ -8,15 +11,17 @@ use ksc::{
     Error,
     Error::{EEXIST, ENOTDIR, EPERM, ESPIPE},
 };
-use ksync::event::Event;
 use umifs::{
     path::Path,
-    traits::{Entry, Io},
+    traits::{Entry, Io, Pollable},
     types::{
-        ioslice_len, FileType, IoSlice, IoSliceMut, Metadata, OpenOptions, Permissions, SeekFrom,
+        ioslice_len, FileType, Interest, IoSlice, IoSliceMut, Metadata, OpenOptions, Permissions,
+        Readiness, SeekFrom,
     },
 };
 
+use crate::task::WaitQueue;
+
 struct PipeBackend(AtomicUsize);
 
 #[async_trait]
@@ -56,10 +61,17 @@ impl Io for PipeBackend {
 
 struct Pipe {
     phys: Phys,
-    readable: Event,
+    /// The readers and `poll`s waiting for the pipe to become readable.
+    readable: WaitQueue<()>,
     end_pos: AtomicUsize,
 }
 
+impl Pipe {
+    fn notify_readable(&self) {
+        self.readable.wake_all(&());
+    }
+}
+
 struct Receiver {
     pipe: Arsc<Pipe>,
     pos: AtomicUsize,
@@ -69,7 +81,7 @@ struct Receiver {
 impl Io for Receiver {
     async fn read(&self, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
         let mut pos = self.pos.load(Acquire);
-        let mut listener = None;
+        let mut wait = None;
         loop {
             let read_len = self.pipe.phys.read_at(pos, buffer).await?;
             log::trace!("Pipe::read: Attempt to read at {pos}, read len = {read_len}");
@@ -78,10 +90,10 @@ impl Io for Receiver {
                 if Arsc::count(&self.pipe) == 1 {
                     return Ok(0);
                 }
-                log::trace!("Pipe::read: Listen for event");
-                match listener.take() {
-                    Some(listener) => listener.await,
-                    None => listener = Some(self.pipe.readable.listen()),
+                log::trace!("Pipe::read: Wait for writers");
+                match wait.take() {
+                    Some(wait) => wait.await,
+                    None => wait = Some(self.pipe.readable.wait(())),
                 }
             } else {
                 match self
@@ -149,6 +161,43 @@ impl Entry for Receiver {
             last_created: None,
         }
     }
+
+    fn to_pollable(self: Arc<Self>) -> Option<Arc<dyn Pollable>> {
+        Some(self)
+    }
+}
+
+impl Receiver {
+    fn readiness(&self, interest: Interest) -> Readiness {
+        if Arsc::count(&self.pipe) == 1 {
+            return Readiness::HUP | (Readiness::READ & Readiness::always(interest));
+        }
+        let readable = self.pipe.end_pos.load(SeqCst) > self.pos.load(SeqCst);
+        if readable && interest.contains(Interest::READ) {
+            Readiness::READ
+        } else {
+            Readiness::empty()
+        }
+    }
+}
+
+impl Pollable for Receiver {
+    fn poll_ready(
+        &self,
+        cx: &mut Context<'_>,
+        interest: Interest,
+    ) -> Poll<Result<Readiness, Error>> {
+        let ready = self.readiness(interest);
+        if !ready.is_empty() {
+            return Poll::Ready(Ok(ready));
+        }
+        self.pipe.readable.register((), cx.waker());
+        // Check again in case the pipe was written before the registration.
+        match self.readiness(interest) {
+            ready if ready.is_empty() => Poll::Pending,
+            ready => Poll::Ready(Ok(ready)),
+        }
+    }
 }
 
 struct Sender {
@@ -168,7 +217,7 @@ impl Io for Sender {
         log::trace!("Pipe::write: Attempt to write, written len = {written_len}");
         if written_len > 0 {
             self.pipe.end_pos.fetch_add(written_len, SeqCst);
-            self.pipe.readable.notify(usize::MAX);
+            self.pipe.notify_readable();
         }
         Ok(written_len)
     }
@@ -223,11 +272,31 @@ impl Entry for Sender {
             last_created: None,
         }
     }
+
+    fn to_pollable(self: Arc<Self>) -> Option<Arc<dyn Pollable>> {
+        Some(self)
+    }
+}
+
+impl Pollable for Sender {
+    fn poll_ready(
+        &self,
+        _: &mut Context<'_>,
+        interest: Interest,
+    ) -> Poll<Result<Readiness, Error>> {
+        // The pipe is unbounded, so writes never block.
+        let ready = Readiness::WRITE & Readiness::always(interest);
+        Poll::Ready(Ok(if Arsc::count(&self.pipe) == 1 {
+            ready | Readiness::ERROR
+        } else {
+            ready
+        }))
+    }
 }
 
 impl Drop for Sender {
     fn drop(&mut self) {
-        self.pipe.readable.notify(usize::MAX);
+        self.pipe.notify_readable();
     }
 }
 
@@ -235,7 +304,7 @@ pub fn pipe() -> (Arc<dyn Entry>, Arc<dyn Entry>) {
     let phys = Phys::new_anon(true);
     let pipe = Arsc::new(Pipe {
         phys,
-        readable: Event::new(),
+        readable: WaitQueue::new(),
         end_pos: Default::default(),
     });
     let tx = Arc::new(Sender { pipe: pipe.clone() });
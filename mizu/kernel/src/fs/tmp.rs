@@ -12,7 +12,7 @@ use spin::Mutex;
 use umifs::{
     path::{Path, PathBuf},
     traits::{Directory, DirectoryMut, Entry, FileSystem, Io, ToIo},
-    types::{DirEntry, FileType, FsStat, Metadata, OpenOptions, Permissions},
+    types::{DirEntry, FileType, FsStat, Metadata, OpenOptions, Permissions, RenameFlags},
 };
 
 pub struct TmpFs(Arc<TmpRoot>);
@@ -119,6 +119,7 @@ impl DirectoryMut for TmpRoot {
         _: &Path,
         _: Arc<dyn DirectoryMut>,
         _: &Path,
+        _: RenameFlags,
     ) -> Result<(), Error> {
         Err(ENOSYS)
     }
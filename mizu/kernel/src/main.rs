@@ -43,6 +43,9 @@ async fn main(fdt: usize) {
 
     // Init devices.
     unsafe { crate::dev::init(fdt as _).expect("failed to initialize devices") };
+    if let Some(bootargs) = dev::bootargs() {
+        syscall::init_quiet_syscalls(bootargs);
+    }
     // Init FS.
     fs::fs_init().await;
 
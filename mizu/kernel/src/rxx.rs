@@ -52,11 +52,12 @@ fn run_art(payload: usize) {
     type Payload = *mut Box<dyn FnOnce() + Send>;
     if hart_id::is_bsp() {
         log::debug!("Starting ART");
-        let mut runners = Executor::start(config::MAX_HARTS, move |e| async move {
+        let init = move |e: Arsc<Executor>| async move {
             EXECUTOR.call_once(|| e);
             crate::main(payload).await;
             EXECUTOR.get().unwrap().shutdown()
-        });
+        };
+        let mut runners = Executor::start_with_idle(config::MAX_HARTS, crate::cpu::Wfi, init);
 
         let me = runners.next().unwrap();
         for (id, runner) in config::HART_RANGE
@@ -1,21 +1,24 @@
 use alloc::boxed::Box;
-use core::{ops::ControlFlow, pin::Pin, time::Duration};
+use core::{
+    ops::ControlFlow,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
 
 use co_trap::{TrapFrame, UserCx};
 use kmem::Virt;
 use ksc::{
     async_handler, AHandlers,
-    Error::{self, EPERM},
+    Error::{self, EINVAL, EPERM},
     Scn::{self, *},
 };
 use ktime::{Instant, InstantExt};
-use riscv::register::time;
 use spin::Lazy;
 use sygnal::SigInfo;
 
 use crate::{
     mem::{In, Out, UserPtr, USER_RANGE},
-    task::{self, fd, signal, TaskState},
+    task::{self, fd, signal, Rlimit, TaskState},
 };
 
 pub type ScParams<'a> = (&'a mut TaskState, &'a mut TrapFrame);
@@ -44,6 +47,7 @@ pub static SYSCALL: Lazy<AHandlers<Scn, ScParams, ScRet>> = Lazy::new(|| {
         .map(EXIT, task::exit)
         .map(EXIT_GROUP, task::exit_group)
         .map(EXECVE, task::execve)
+        .map(RSEQ, task::rseq)
         // Signals
         .map(SIGALTSTACK, signal::sigaltstack)
         .map(RT_SIGPROCMASK, signal::sigprocmask)
@@ -75,16 +79,23 @@ pub static SYSCALL: Lazy<AHandlers<Scn, ScParams, ScRet>> = Lazy::new(|| {
         .map(UTIMENSAT, fd::utimensat)
         .map(GETDENTS64, fd::getdents64)
         .map(UNLINKAT, fd::unlinkat)
+        .map(RENAMEAT2, fd::renameat2)
         .map(CLOSE, fd::close)
         .map(PIPE2, fd::pipe)
         .map(MOUNT, fd::mount)
         .map(UMOUNT2, fd::umount)
         .map(STATFS, fd::statfs)
         .map(IOCTL, fd::ioctl)
+        .map(FSYNC, fd::fsync)
+        .map(FDATASYNC, fd::fdatasync)
         // Time
         .map(GETTIMEOFDAY, gettimeofday)
         .map(CLOCK_GETTIME, clock_gettime)
-        .map(NANOSLEEP, sleep)
+        .map(NANOSLEEP, task::nanosleep)
+        .map(CLOCK_NANOSLEEP, task::clock_nanosleep)
+        .map(RESTART_SYSCALL, task::restart_syscall)
+        .map(GETITIMER, task::getitimer)
+        .map(SETITIMER, task::setitimer)
         // Miscellaneous
         .map(UNAME, uname)
         .map(PRLIMIT64, prlimit)
@@ -93,6 +104,81 @@ pub static SYSCALL: Lazy<AHandlers<Scn, ScParams, ScRet>> = Lazy::new(|| {
         .map(GETUID, getuid)
 });
 
+/// A set of syscall numbers, updated atomically.
+pub struct ScnSet([AtomicU64; Self::WORDS]);
+
+impl ScnSet {
+    const WORDS: usize = 8;
+
+    pub const fn new(scns: &[Scn]) -> Self {
+        let mut words = [0; Self::WORDS];
+        let mut index = 0;
+        while index < scns.len() {
+            let scn = scns[index] as usize;
+            words[scn / 64] |= 1 << (scn % 64);
+            index += 1;
+        }
+        let [w0, w1, w2, w3, w4, w5, w6, w7] = words;
+        ScnSet([
+            AtomicU64::new(w0),
+            AtomicU64::new(w1),
+            AtomicU64::new(w2),
+            AtomicU64::new(w3),
+            AtomicU64::new(w4),
+            AtomicU64::new(w5),
+            AtomicU64::new(w6),
+            AtomicU64::new(w7),
+        ])
+    }
+
+    pub fn contains(&self, scn: Scn) -> bool {
+        let scn = scn as usize;
+        self.0[scn / 64].load(Relaxed) & (1 << (scn % 64)) != 0
+    }
+
+    /// Add or remove the syscall numbered `scn`, returning `false` if the
+    /// number is out of range.
+    pub fn set(&self, scn: usize, value: bool) -> bool {
+        let Some(word) = self.0.get(scn / 64) else {
+            return false
+        };
+        let bit = 1 << (scn % 64);
+        if value {
+            word.fetch_or(bit, Relaxed);
+        } else {
+            word.fetch_and(!bit, Relaxed);
+        }
+        true
+    }
+
+    pub fn clear(&self) {
+        self.0.iter().for_each(|word| word.store(0, Relaxed));
+    }
+}
+
+/// The syscalls not logged on entry, so that noisy ones like `write` to the
+/// console don't flood the log. Set with the `quiet_syscalls` boot argument,
+/// see [`init_quiet_syscalls`].
+pub static QUIET_SYSCALLS: ScnSet = ScnSet::new(&[WRITE]);
+
+/// Replace [`QUIET_SYSCALLS`] with the syscall numbers listed by the
+/// `quiet_syscalls=` boot argument, separated by commas, e.g.
+/// `quiet_syscalls=63,64` for `read` and `write`, or `quiet_syscalls=` to log
+/// all the syscalls.
+pub fn init_quiet_syscalls(bootargs: &str) {
+    let mut args = bootargs.split_whitespace();
+    let Some(list) = args.find_map(|arg| arg.strip_prefix("quiet_syscalls=")) else {
+        return
+    };
+    QUIET_SYSCALLS.clear();
+    for scn in list.split(',').filter(|scn| !scn.is_empty()) {
+        let res = scn.parse().map(|scn| QUIET_SYSCALLS.set(scn, true));
+        if res != Ok(true) {
+            log::warn!("invalid syscall number {scn:?} in quiet_syscalls");
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 #[repr(C, packed)]
 pub struct Tv {
@@ -141,37 +227,6 @@ async fn clock_gettime(
     ScRet::Continue(None)
 }
 
-#[async_handler]
-async fn sleep(
-    ts: &mut TaskState,
-    cx: UserCx<'_, fn(UserPtr<Ts, In>, UserPtr<Ts, Out>) -> Result<(), Error>>,
-) -> ScRet {
-    async fn sleep_inner(
-        virt: Pin<&Virt>,
-        input: UserPtr<Ts, In>,
-        mut output: UserPtr<Ts, Out>,
-    ) -> Result<(), Error> {
-        let tv = input.read(virt).await?;
-
-        let dur = Duration::from_secs(tv.sec) + Duration::from_nanos(tv.nsec);
-        if dur.is_zero() {
-            crate::task::yield_now().await
-        } else {
-            ktime::sleep(dur).await;
-        }
-
-        if !output.is_null() {
-            output.write(virt, Default::default()).await?;
-        }
-
-        Ok(())
-    }
-    let (input, output) = cx.args();
-    cx.ret(sleep_inner(ts.virt.as_ref(), input, output).await);
-
-    ScRet::Continue(None)
-}
-
 #[async_handler]
 async fn uname(
     ts: &mut TaskState,
@@ -191,19 +246,13 @@ async fn uname(
 }
 
 const RLIMIT_CPU: u32 = 0; // CPU time in sec
+const RLIMIT_FSIZE: u32 = 1; // max file size
 const RLIMIT_DATA: u32 = 2; // max data size
 const RLIMIT_STACK: u32 = 3; // max stack size
 const RLIMIT_NPROC: u32 = 6; // max number of processes
 const RLIMIT_NOFILE: u32 = 7; // max number of open files
 const RLIMIT_AS: u32 = 9; // address space limit
 
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-struct Rlimit {
-    cur: usize,
-    max: usize,
-}
-
 #[async_handler]
 async fn prlimit(
     ts: &mut TaskState,
@@ -214,19 +263,34 @@ async fn prlimit(
         if pid != 0 {
             return Err(EPERM);
         }
+        let new = if new.is_null() {
+            None
+        } else {
+            Some(new.read(ts.virt.as_ref()).await?)
+        };
         let (cur, max) = match ty {
             RLIMIT_AS => (USER_RANGE.len(), USER_RANGE.len()),
             RLIMIT_NPROC => (65536, 65536),
-            RLIMIT_CPU => {
-                let s = time::read() / config::TIME_FREQ as usize;
-                (s, usize::MAX)
+            RLIMIT_CPU | RLIMIT_FSIZE | RLIMIT_STACK => {
+                let limit = match ty {
+                    RLIMIT_CPU => &mut ts.cpu_limit,
+                    RLIMIT_FSIZE => &mut ts.fsize_limit,
+                    _ => &mut ts.stack_limit,
+                };
+                let old = *limit;
+                if let Some(new) = new {
+                    if new.cur > new.max {
+                        return Err(EINVAL);
+                    }
+                    *limit = new;
+                }
+                (old.cur, old.max)
             }
-            RLIMIT_DATA | RLIMIT_STACK => (8 * 1024 * 1024, usize::MAX),
+            RLIMIT_DATA => (8 * 1024 * 1024, usize::MAX),
             RLIMIT_NOFILE => {
-                let limit = if new.is_null() {
-                    ts.files.get_limit()
-                } else {
-                    ts.files.set_limit(new.read(ts.virt.as_ref()).await?.cur)
+                let limit = match new {
+                    Some(new) => ts.files.set_limit(new.cur),
+                    None => ts.files.get_limit(),
                 };
                 (limit, limit)
             }
@@ -2,8 +2,12 @@ mod elf;
 pub mod fd;
 mod future;
 mod init;
+mod restart;
+mod rseq;
 pub mod signal;
 mod syscall;
+mod timer;
+mod wait;
 
 use alloc::{
     sync::{Arc, Weak},
@@ -12,25 +16,58 @@ use alloc::{
 use core::pin::Pin;
 
 use arsc_rs::Arsc;
+use co_trap::TrapFrame;
 use crossbeam_queue::SegQueue;
 use futures_util::future::{select, select_all, Either};
 use hashbrown::HashMap;
 use kmem::Virt;
-use ksc::Error::{self, ECHILD};
-use ksync::{unbounded, AtomicArsc, Broadcast, Receiver};
+use ksc::Error::{self, ECHILD, ESRCH};
+use ksync::{unbounded, AtomicArsc, Broadcast, Receiver, Sender};
 use rand_riscv::RandomState;
 use rv39_paging::{Attr, PAGE_SIZE};
 use spin::{Lazy, Mutex};
-use sygnal::{ActionSet, Sig, SigInfo, SigSet, Signals};
+use sygnal::{ActionSet, ChldCode, DefaultAction, Sig, SigInfo, SigSet, Signals};
 
-use self::{fd::Files, signal::SigStack};
-pub use self::{future::yield_now, init::InitTask, syscall::*};
+use self::{
+    fd::Files, future::VirtSlot, restart::Restart, rseq::Rseq, signal::SigStack, timer::ITimers,
+};
+pub use self::{
+    future::yield_now,
+    init::InitTask,
+    restart::{clock_nanosleep, nanosleep, restart_syscall},
+    rseq::rseq,
+    syscall::*,
+    timer::{getitimer, setitimer},
+    wait::WaitQueue,
+};
 use crate::mem::{Futexes, Out, UserPtr};
 
 const DEFAULT_STACK_SIZE: usize = PAGE_SIZE * 8;
 const DEFAULT_STACK_ATTR: Attr = Attr::USER_ACCESS
     .union(Attr::READABLE)
     .union(Attr::WRITABLE);
+/// The default soft limit of the size the user stack can grow to, as
+/// `RLIMIT_STACK` defaults to on Linux.
+const STACK_LIMIT: usize = 8 * 1024 * 1024;
+/// The distance below the user stack within which a page fault grows the
+/// stack instead of raising `SIGSEGV`. Setting it to 0 disables stack growth
+/// and leaves a fixed guard page below the stack.
+const STACK_GROWTH_GAP: usize = PAGE_SIZE * 16;
+
+/// A resource limit as used by `prlimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Rlimit {
+    pub cur: usize,
+    pub max: usize,
+}
+
+impl Rlimit {
+    pub const INFINITY: Rlimit = Rlimit {
+        cur: usize::MAX,
+        max: usize::MAX,
+    };
+}
 
 #[derive(Clone, Copy, Debug)]
 pub enum TaskEvent {
@@ -53,6 +90,11 @@ pub struct Task {
 
     sig: Signals,
     shared_sig: AtomicArsc<Signals>,
+    /// Shared by the tasks cloned with `CLONE_SIGHAND`, and read by the
+    /// children for the disposition of `SIGCHLD`.
+    sig_actions: Arsc<ActionSet>,
+    /// The user registers, only present while the task is stopped.
+    stopped_regs: spin::Mutex<Option<TrapFrame>>,
     event: Broadcast<SegQueue<TaskEvent>>,
 }
 
@@ -76,6 +118,26 @@ impl Task {
             }
         }
     }
+
+    /// Read the user registers of the task, which must be stopped.
+    ///
+    /// These accessors live here instead of in `TaskState`, which is owned by
+    /// the task's own future and unreachable from a tracer.
+    pub fn get_regs(&self) -> Result<TrapFrame, Error> {
+        ksync::critical(|| *self.stopped_regs.lock()).ok_or(ESRCH)
+    }
+
+    /// Write the general-purpose registers and `sepc` of the task, which must
+    /// be stopped. The supervisor CSRs in `regs` are ignored.
+    pub fn set_regs(&self, regs: &TrapFrame) -> Result<(), Error> {
+        ksync::critical(|| {
+            let mut stopped = self.stopped_regs.lock();
+            let tf = stopped.as_mut().ok_or(ESRCH)?;
+            tf.gpr = regs.gpr;
+            tf.sepc = regs.sepc;
+            Ok(())
+        })
+    }
 }
 
 pub struct TaskState {
@@ -88,13 +150,37 @@ pub struct TaskState {
 
     system_times: u64,
     user_times: u64,
+    nr_voluntary: usize,
+    nr_involuntary: usize,
+    pub(crate) cpu_limit: Rlimit,
+    /// The CPU time in seconds before which no more `SIGXCPU` is sent.
+    xcpu_next: usize,
+    pub(crate) fsize_limit: Rlimit,
+    pub(crate) stack_limit: Rlimit,
 
     pub(crate) virt: Pin<Arsc<Virt>>,
+    virt_slot: VirtSlot,
     pub(crate) futex: Arsc<Futexes>,
-    sig_actions: Arsc<ActionSet>,
     pub(crate) files: Files,
-    tid_clear: Option<UserPtr<usize, Out>>,
+    /// The `pid_t` zeroed and woken on exit, for thread joins.
+    tid_clear: Option<UserPtr<u32, Out>>,
     exit_signal: Option<Sig>,
+    /// Dropped on exec or exit to resume the parent suspended by `vfork`.
+    vfork_done: Option<Sender<SegQueue<()>>>,
+    rseq: Option<Rseq>,
+    itimers: ITimers,
+    restart: Option<Restart>,
+}
+
+/// Scheduling statistics of a task, in the spirit of `/proc/<pid>/sched`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SchedStat {
+    /// The total run time in timer ticks, both in user and kernel mode.
+    pub runtime: u64,
+    /// The number of times the task gave up the CPU waiting for events.
+    pub nr_voluntary: usize,
+    /// The number of times the task was preempted at quantum expiry.
+    pub nr_involuntary: usize,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -115,6 +201,36 @@ impl From<isize> for PidSelection {
 }
 
 impl TaskState {
+    pub fn sched_stat(&self) -> SchedStat {
+        SchedStat {
+            runtime: self.user_times + self.system_times,
+            nr_voluntary: self.nr_voluntary,
+            nr_involuntary: self.nr_involuntary,
+        }
+    }
+
+    /// Check the CPU time against `RLIMIT_CPU`, returning the signal to be
+    /// delivered if the limit is exceeded.
+    fn check_cpu_limit(&mut self) -> Option<SigInfo> {
+        if self.cpu_limit.cur == usize::MAX {
+            return None;
+        }
+        let times = (self.user_times + self.system_times) / config::TIME_FREQ as u64;
+        let secs = times as usize;
+
+        let sig = if secs >= self.cpu_limit.max {
+            Sig::SIGKILL
+        } else if secs >= self.cpu_limit.cur && secs >= self.xcpu_next {
+            // Like Linux, deliver `SIGXCPU` once per second until the hard limit
+            // is reached.
+            self.xcpu_next = secs + 1;
+            Sig::SIGXCPU
+        } else {
+            return None;
+        };
+        Some(SigInfo::kernel(sig))
+    }
+
     async fn wait(&self, pid: PidSelection) -> Result<(TaskEvent, usize), Error> {
         let (res, tid) = match pid {
             PidSelection::Task(None) => {
@@ -156,6 +272,8 @@ impl TaskState {
     }
 
     async fn cleanup(mut self, code: i32, sig: Option<Sig>) {
+        log::debug!("task {} exiting, {:?}", self.task.tid, self.sched_stat());
+
         if let Some(mut tid_clear) = self.tid_clear.take() {
             let _ = tid_clear.write(self.virt.as_ref(), 0).await;
             self.futex.notify(tid_clear.to_futex_key(), 1);
@@ -168,17 +286,24 @@ impl TaskState {
             tgroup.is_empty()
         });
         if last_thread {
-            let exit_signal = self.exit_signal.take();
-            if let (Some(sig), Some(parent)) = (exit_signal, self.task.parent.upgrade()) {
-                parent.sig.push(SigInfo {
-                    sig,
-                    code: sygnal::SigCode::USER as _,
-                    fields: sygnal::SigFields::SigChld {
-                        pid: self.task.tid,
-                        uid: 0,
-                        status: code,
+            if let Some(parent) = self.task.parent.upgrade() {
+                let (code, status) = match sig {
+                    None => (ChldCode::EXITED, code),
+                    Some(sig) => match sig.default_action() {
+                        DefaultAction::Core => (ChldCode::DUMPED, sig.raw()),
+                        _ => (ChldCode::KILLED, sig.raw()),
                     },
-                })
+                };
+                if let Some(exit_signal) = self.exit_signal.take() {
+                    parent.sig.push(self.chld_info(exit_signal, code, status));
+                }
+
+                // Like Linux, a parent that doesn't care about its children
+                // reaps them on exit, so they never become zombies.
+                if parent.sig_actions.get(Sig::SIGCHLD).no_cld_wait {
+                    let tid = self.task.tid;
+                    ksync::critical(|| parent.children.lock().retain(|c| c.task.tid != tid));
+                }
             }
         }
 
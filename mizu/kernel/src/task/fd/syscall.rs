@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use core::{
     alloc::Layout,
     mem::{self, MaybeUninit},
@@ -16,7 +16,12 @@ use ksc::{
 };
 use ktime::{Instant, InstantExt};
 use rand_riscv::RandomState;
-use umifs::types::{FileType, Metadata, OpenOptions, Permissions, SeekFrom};
+use sygnal::{Sig, SigInfo};
+use umifs::{
+    path::Path,
+    traits::{resolve_at, DirectoryMut, Entry, Io},
+    types::{FileType, Metadata, OpenOptions, Permissions, RenameFlags, SeekFrom},
+};
 
 use super::Files;
 use crate::{
@@ -61,10 +66,13 @@ pub async fn write(
         let entry = ts.files.get(fd).await?;
         let io = entry.to_io().ok_or(EBADF)?;
 
+        limit_fsize(&*io, None, &mut bufs, ts.fsize_limit.cur).await?;
         io.write(&mut bufs).await
     };
-    cx.ret(fut.await);
-    ScRet::Continue(None)
+    let ret = fut.await;
+    let sc = fsize_exceeded(&ret);
+    cx.ret(ret);
+    sc
 }
 
 #[async_handler]
@@ -103,10 +111,13 @@ pub async fn pwrite(
         let entry = ts.files.get(fd).await?;
         let io = entry.to_io().ok_or(EBADF)?;
 
+        limit_fsize(&*io, Some(offset), &mut bufs, ts.fsize_limit.cur).await?;
         io.write_at(offset, &mut bufs).await
     };
-    cx.ret(fut.await);
-    ScRet::Continue(None)
+    let ret = fut.await;
+    let sc = fsize_exceeded(&ret);
+    cx.ret(ret);
+    sc
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -175,10 +186,13 @@ pub async fn writev(
             })
             .await?;
 
+        limit_fsize(&*io, None, &mut bufs, ts.fsize_limit.cur).await?;
         io.write(&mut bufs).await
     };
-    cx.ret(fut.await);
-    ScRet::Continue(None)
+    let ret = fut.await;
+    let sc = fsize_exceeded(&ret);
+    cx.ret(ret);
+    sc
 }
 
 #[async_handler]
@@ -239,10 +253,50 @@ pub async fn pwritev(
             })
             .await?;
 
+        limit_fsize(&*io, Some(offset), &mut bufs, ts.fsize_limit.cur).await?;
         io.write_at(offset, &mut bufs).await
     };
-    cx.ret(fut.await);
-    ScRet::Continue(None)
+    let ret = fut.await;
+    let sc = fsize_exceeded(&ret);
+    cx.ret(ret);
+    sc
+}
+
+/// Clamp the buffers of a write at `offset` (or the current position if
+/// `None`) so that the file won't grow beyond `limit` (`RLIMIT_FSIZE`).
+///
+/// Returns `EFBIG` if the write starts at or beyond the limit.
+async fn limit_fsize(
+    io: &dyn Io,
+    offset: Option<usize>,
+    bufs: &mut Vec<&[u8]>,
+    limit: usize,
+) -> Result<(), Error> {
+    if limit == usize::MAX {
+        return Ok(());
+    }
+    let offset = match offset {
+        Some(offset) => offset,
+        None => match io.seek(SeekFrom::Current(0)).await {
+            Ok(offset) => offset,
+            // Non-seekable files are not subject to the limit.
+            Err(ESPIPE) => return Ok(()),
+            Err(err) => return Err(err),
+        },
+    };
+    let mut rest = limit.checked_sub(offset).filter(|&r| r > 0).ok_or(EFBIG)?;
+    bufs.retain_mut(|buf| {
+        let len = buf.len().min(rest);
+        *buf = &buf[..len];
+        rest -= len;
+        len > 0
+    });
+    Ok(())
+}
+
+fn fsize_exceeded<T>(ret: &Result<T, Error>) -> ScRet {
+    let sigxfsz = SigInfo::kernel(Sig::SIGXFSZ);
+    ScRet::Continue(matches!(ret, Err(EFBIG)).then_some(sigxfsz))
 }
 
 #[async_handler]
@@ -356,6 +410,57 @@ macro_rules! fssc {
 
 pub const MAX_PATH_LEN: usize = 256;
 
+const AT_SYMLINK_NOFOLLOW: i32 = 0x100;
+
+/// Resolve a path of the `*at` syscalls with [`resolve_at`], relative to the
+/// directory of `fd`, or from the root if `root`, as read by
+/// [`UserPtr::read_path`].
+async fn resolve(
+    files: &Files,
+    fd: i32,
+    path: &Path,
+    root: bool,
+    follow: bool,
+) -> Result<Arc<dyn Entry>, Error> {
+    if root {
+        let path = Path::new("/").join(path);
+        resolve_at(crate::fs::root(), &path, follow).await
+    } else {
+        resolve_at(files.get(fd).await?, path, follow).await
+    }
+}
+
+/// Resolve the parent directory of `path` like [`resolve`], returning it along
+/// with the last component of `path`, or the entry of `path` itself if it
+/// ends with no name, like `.`, `..` or the root.
+async fn parent_at<'a>(
+    files: &Files,
+    fd: i32,
+    path: &'a Path,
+    root: bool,
+) -> Result<(Arc<dyn Entry>, Option<&'a Path>), Error> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = resolve(files, fd, parent, root, true).await?;
+            Ok((parent, Some(Path::new(name))))
+        }
+        _ => Ok((resolve(files, fd, path, root, true).await?, None)),
+    }
+}
+
+/// Like [`parent_at`], but the parent must be a writable directory, and the
+/// path must end with a name.
+async fn dir_at<'a>(
+    files: &Files,
+    fd: i32,
+    path: &'a Path,
+    root: bool,
+) -> Result<(Arc<dyn DirectoryMut>, &'a Path), Error> {
+    let (parent, name) = parent_at(files, fd, path, root).await?;
+    let name = name.ok_or(EINVAL)?;
+    Ok((parent.to_dir_mut().ok_or(ENOTDIR)?, name))
+}
+
 fssc!(
     pub async fn chdir(
         virt: Pin<&Virt>,
@@ -455,15 +560,9 @@ fssc!(
             "user openat fd = {fd}, path = {path:?}, options = {options:?}, perm = {perm:?}"
         );
 
-        let entry = if root {
-            crate::fs::open(path, options, perm).await?.0
-        } else {
-            let base = files.get(fd).await?;
-            match base.open(path, options, perm).await {
-                Ok((entry, _)) => entry,
-                Err(ENOENT) if files.cwd() == "" => crate::fs::open(path, options, perm).await?.0,
-                Err(err) => return Err(err),
-            }
+        let entry = match parent_at(files, fd, path, root).await? {
+            (parent, Some(name)) => parent.open(name, options, perm).await?.0,
+            (entry, None) => entry,
         };
         let close_on_exec = options.contains(OpenOptions::CLOEXEC);
         files.open(entry, close_on_exec).await
@@ -482,13 +581,10 @@ fssc!(
 
         log::trace!("user mkdir fd = {fd}, path = {path:?}, perm = {perm:?}");
 
-        let (entry, created) = if root {
-            crate::fs::open(path, OpenOptions::DIRECTORY | OpenOptions::CREAT, perm).await?
-        } else {
-            let base = files.get(fd).await?;
-            base.open(path, OpenOptions::DIRECTORY | OpenOptions::CREAT, perm)
-                .await?
-        };
+        let (parent, name) = parent_at(files, fd, path, root).await?;
+        let name = name.ok_or(EEXIST)?;
+        let options = OpenOptions::DIRECTORY | OpenOptions::CREAT;
+        let (entry, created) = parent.open(name, options, perm).await?;
         if !created {
             return Err(EEXIST);
         }
@@ -512,32 +608,13 @@ fssc!(
         fd: i32,
         path: UserPtr<u8, In>,
         out: UserPtr<Kstat, Out>,
+        flags: i32,
     ) -> Result<(), Error> {
         let mut buf = [0; MAX_PATH_LEN];
         let (path, root) = path.read_path(virt, &mut buf).await?;
 
-        let file = if root {
-            crate::fs::open(
-                path,
-                OpenOptions::RDONLY,
-                Permissions::all_same(true, false, false),
-            )
-            .await?
-            .0
-        } else {
-            let base = files.get(fd).await?;
-            if path == "" {
-                base
-            } else {
-                base.open(
-                    path,
-                    OpenOptions::RDONLY,
-                    Permissions::all_same(true, false, false),
-                )
-                .await?
-                .0
-            }
-        };
+        let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+        let file = resolve(files, fd, path, root, follow).await?;
         let metadata = file.metadata().await;
         out.write(virt, metadata.into()).await
     }
@@ -548,6 +625,7 @@ fssc!(
         fd: i32,
         path: UserPtr<u8, In>,
         times: UserPtr<Ts, In>,
+        flags: i32,
     ) -> Result<(), Error> {
         const UTIME_NOW: u64 = 0x3fffffff;
         const UTIME_OMIT: u64 = 0x3ffffffe;
@@ -555,28 +633,8 @@ fssc!(
         let mut buf = [0; MAX_PATH_LEN];
         let (path, root) = path.read_path(virt, &mut buf).await?;
 
-        let file = if root {
-            crate::fs::open(
-                path,
-                OpenOptions::WRONLY,
-                Permissions::all_same(true, false, false),
-            )
-            .await?
-            .0
-        } else {
-            let base = files.get(fd).await?;
-            if path == "" {
-                base
-            } else {
-                base.open(
-                    path,
-                    OpenOptions::WRONLY,
-                    Permissions::all_same(true, false, false),
-                )
-                .await?
-                .0
-            }
-        };
+        let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+        let file = resolve(files, fd, path, root, follow).await?;
 
         let now = Instant::now();
         let (a, m) = if times.is_null() {
@@ -668,17 +726,32 @@ fssc!(
 
         log::trace!("user mkdir fd = {fd}, path = {path:?}, flags = {flags}");
 
-        if root {
-            crate::fs::unlink(path).await
-        } else {
-            let base = files.get(fd).await?;
-            let base = base.to_dir_mut().ok_or(ENOTDIR)?;
-            match base.unlink(path, (flags != 0).then_some(true)).await {
-                Ok(()) => Ok(()),
-                Err(ENOENT) if files.cwd() == "" => crate::fs::unlink(path).await,
-                Err(err) => Err(err),
-            }
-        }
+        let (dir, path) = dir_at(files, fd, path, root).await?;
+        dir.unlink(path, (flags != 0).then_some(true)).await
+    }
+
+    pub async fn renameat2(
+        virt: Pin<&Virt>,
+        files: &Files,
+        src_fd: i32,
+        src: UserPtr<u8, In>,
+        dst_fd: i32,
+        dst: UserPtr<u8, In>,
+        flags: u32,
+    ) -> Result<(), Error> {
+        let mut src_buf = [0; MAX_PATH_LEN];
+        let mut dst_buf = [0; MAX_PATH_LEN];
+        let (src, root_src) = src.read_path(virt, &mut src_buf).await?;
+        let (dst, root_dst) = dst.read_path(virt, &mut dst_buf).await?;
+        let flags = RenameFlags::from_bits(flags).ok_or(EINVAL)?;
+
+        log::trace!(
+            "user renameat2 src = {src_fd}:{src:?}, dst = {dst_fd}:{dst:?}, flags = {flags:?}"
+        );
+
+        let (src_dir, src) = dir_at(files, src_fd, src, root_src).await?;
+        let (dst_dir, dst) = dir_at(files, dst_fd, dst, root_dst).await?;
+        src_dir.rename(src, dst_dir, dst, flags).await
     }
 
     pub async fn close(_v: Pin<&Virt>, files: &Files, fd: i32) -> Result<(), Error> {
@@ -811,6 +884,16 @@ fssc!(
         .await
     }
 
+    pub async fn fsync(_v: Pin<&Virt>, files: &Files, fd: i32) -> Result<(), Error> {
+        let io = files.get(fd).await?.to_io().ok_or(EINVAL)?;
+        io.fsync(false).await
+    }
+
+    pub async fn fdatasync(_v: Pin<&Virt>, files: &Files, fd: i32) -> Result<(), Error> {
+        let io = files.get(fd).await?.to_io().ok_or(EINVAL)?;
+        io.fsync(true).await
+    }
+
     pub async fn ioctl(_v: Pin<&Virt>, files: &Files, fd: i32) -> Result<(), Error> {
         files.get(fd).await?;
         Ok(())
@@ -8,26 +8,33 @@ use core::{
 use arsc_rs::Arsc;
 use co_trap::{FastResult, TrapFrame};
 use kmem::Virt;
-use ksc::{Scn, ENOSYS};
+use ksc::{EFAULT, ENOSYS};
 use pin_project::pin_project;
 use riscv::register::{
     scause::{Exception, Scause, Trap},
     time,
 };
-use sygnal::{Sig, SigCode, SigInfo};
+use sygnal::{BusCode, IllCode, SegvCode, Sig, SigInfo};
 
-use super::TaskState;
-use crate::{syscall::ScRet, task::signal::SIGRETURN_GUARD};
+use super::{TaskState, STACK_GROWTH_GAP};
+use crate::{
+    syscall::{ScRet, QUIET_SYSCALLS},
+    task::signal::SIGRETURN_GUARD,
+};
+
+/// The address space a task runs in, shared between the task state and its
+/// [`TaskFut`] so that the latter follows the switch made by `execve`.
+pub(crate) type VirtSlot = Arsc<spin::Mutex<Pin<Arsc<Virt>>>>;
 
 #[pin_project]
 pub struct TaskFut<F> {
-    virt: Pin<Arsc<Virt>>,
+    virt: VirtSlot,
     #[pin]
     fut: F,
 }
 
 impl<F> TaskFut<F> {
-    pub fn new(virt: Pin<Arsc<Virt>>, fut: F) -> Self {
+    pub fn new(virt: VirtSlot, fut: F) -> Self {
         TaskFut { virt, fut }
     }
 }
@@ -36,15 +43,58 @@ impl<F: Future> Future for TaskFut<F> {
     type Output = F::Output;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let clear = unsafe { self.virt.clone().load() };
-        if let Some(clear) = clear {
-            crate::executor().spawn(clear).detach();
+        let virt = ksync::critical(|| self.virt.lock().clone());
+        // Threads sharing the address space don't need to reload it.
+        if !virt.is_loaded() {
+            let clear = unsafe { virt.load() };
+            if let Some(clear) = clear {
+                crate::executor().spawn(clear).detach();
+            }
         }
         self.project().fut.poll(cx)
     }
 }
 
+/// Counts the times the inner future gives up the CPU.
+#[pin_project]
+struct CountPending<'a, F> {
+    #[pin]
+    fut: F,
+    count: &'a mut usize,
+}
+
+impl<'a, F> CountPending<'a, F> {
+    fn new(fut: F, count: &'a mut usize) -> Self {
+        CountPending { fut, count }
+    }
+}
+
+impl<F: Future> Future for CountPending<'_, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let ret = this.fut.poll(cx);
+        if ret.is_pending() {
+            **this.count += 1;
+        }
+        ret
+    }
+}
+
 const TASK_GRAN: u64 = 20000;
+/// The longest interval accounted at once, beyond which the timer must have
+/// been reset or jumped.
+const MAX_TIME_DELTA: u64 = config::TIME_FREQ as u64 * 3600;
+
+/// The time elapsed from `last` to `now`.
+///
+/// The timer of each hart is assumed to be monotonic, but a task may migrate
+/// between harts whose timers are not exactly in sync, so a backward step
+/// counts as no time and a pathologically large one is clamped.
+fn elapsed(now: u64, last: u64) -> u64 {
+    now.saturating_sub(last).min(MAX_TIME_DELTA)
+}
 
 pub async fn user_loop(mut ts: TaskState, mut tf: TrapFrame) {
     log::debug!("task {} startup, tf.a0 = {}", ts.task.tid, tf.gpr.tx.a[0]);
@@ -58,7 +108,7 @@ pub async fn user_loop(mut ts: TaskState, mut tf: TrapFrame) {
         }
 
         let sys = time::read64();
-        ts.system_times += sys - stat_time;
+        ts.system_times += elapsed(sys, stat_time);
         stat_time = sys;
 
         log::trace!(
@@ -69,9 +119,16 @@ pub async fn user_loop(mut ts: TaskState, mut tf: TrapFrame) {
         let (scause, fr) = co_trap::yield_to_user(&mut tf);
 
         let usr = time::read64();
-        ts.user_times += usr - stat_time;
+        ts.user_times += elapsed(usr, stat_time);
         stat_time = usr;
 
+        if let Some(si) = ts.check_cpu_limit() {
+            ts.task.sig.push(si);
+        }
+        for si in ts.check_itimers() {
+            ts.task.sig.push(si);
+        }
+
         match fr {
             FastResult::Continue => {}
             FastResult::Pending => continue,
@@ -79,15 +136,28 @@ pub async fn user_loop(mut ts: TaskState, mut tf: TrapFrame) {
             FastResult::Yield => unreachable!(),
         }
 
-        match handle_scause(scause, &mut ts, &mut tf).await {
-            Continue(Some(sig)) => ts.task.sig.push(sig),
+        let mut pending = 0;
+        let res = CountPending::new(handle_scause(scause, &mut ts, &mut tf), &mut pending).await;
+        ts.nr_voluntary += pending;
+        match res {
+            Continue(Some(sig)) => {
+                ts.task.sig.push(sig);
+            }
             Continue(None) => {}
             Break(code) => break 'life (code, None),
         }
 
         let now = time::read64();
-        if now - sched_time >= TASK_GRAN {
+        if now < sched_time {
+            log::debug!("task {} timer went backwards", ts.task.tid);
+            sched_time = now;
+        }
+        if elapsed(now, sched_time) >= TASK_GRAN {
             sched_time = now;
+            ts.nr_involuntary += 1;
+            if let Some(si) = ts.rseq_abort(&mut tf).await {
+                ts.task.sig.push(si);
+            }
             log::trace!("task {} yield", ts.task.tid);
             yield_now().await;
             log::trace!("task {} yielded", ts.task.tid);
@@ -103,7 +173,7 @@ async fn handle_scause(scause: Scause, ts: &mut TaskState, tf: &mut TrapFrame) -
             Exception::UserEnvCall => {
                 let res = async {
                     let scn = tf.scn().ok_or(None)?;
-                    if scn != Scn::WRITE {
+                    if !QUIET_SYSCALLS.contains(scn) {
                         log::info!(
                             "task {} syscall {scn:?}, sepc = {:#x}",
                             ts.task.tid,
@@ -137,19 +207,55 @@ async fn handle_scause(scause: Scause, ts: &mut TaskState, tf: &mut TrapFrame) -
                     return TaskState::resume_from_signal(ts, tf).await;
                 }
 
+                if matches!(excep, Exception::StorePageFault) {
+                    let addr = tf.stval.into();
+                    let virt = ts.virt.as_ref();
+                    let candidate = virt.stack_growth_candidate(addr, STACK_GROWTH_GAP).await;
+                    if candidate.is_some() {
+                        let limit = ts.stack_limit.cur;
+                        let res = virt.grow_down(addr, STACK_GROWTH_GAP, limit).await;
+                        if let Err(err) = res {
+                            log::warn!("failing to grow the stack to {:#x}: {err}", tf.stval);
+                        }
+                    }
+                }
+
                 let res = ts.virt.commit(tf.stval.into()).await;
                 if let Err(err) = res {
                     log::error!("failing to commit pages at address {:#x}: {err}", tf.stval);
-                    return Continue(Some(SigInfo {
-                        sig: Sig::SIGSEGV,
-                        code: SigCode::KERNEL as _,
-                        fields: sygnal::SigFields::SigSys {
-                            addr: tf.stval.into(),
-                            num: 0,
-                        },
-                    }));
+                    let code = match err {
+                        EFAULT => SegvCode::MAPERR,
+                        _ => SegvCode::ACCERR,
+                    };
+                    return Continue(Some(SigInfo::segv(code, tf.stval.into())));
                 }
             }
+            Exception::InstructionFault | Exception::LoadFault | Exception::StoreFault => {
+                log::info!(
+                    "task {} {excep:?} at {:#x}, address = {:#x}",
+                    ts.task.tid,
+                    tf.sepc,
+                    tf.stval
+                );
+                return Continue(Some(SigInfo::segv(SegvCode::ACCERR, tf.stval.into())));
+            }
+            Exception::InstructionMisaligned
+            | Exception::LoadMisaligned
+            | Exception::StoreMisaligned => {
+                log::info!(
+                    "task {} {excep:?} at {:#x}, address = {:#x}",
+                    ts.task.tid,
+                    tf.sepc,
+                    tf.stval
+                );
+                let code = BusCode::ADRALN as _;
+                return Continue(Some(SigInfo::fault(Sig::SIGBUS, code, tf.stval.into())));
+            }
+            Exception::IllegalInstruction => {
+                log::info!("task {} {excep:?} at {:#x}", ts.task.tid, tf.sepc);
+                let code = IllCode::ILLOPC as _;
+                return Continue(Some(SigInfo::fault(Sig::SIGILL, code, tf.sepc.into())));
+            }
             _ => panic!(
                 "task {} unhandled excep {excep:?} at {:#x}, stval = {:#x}",
                 ts.task.tid, tf.sepc, tf.stval
@@ -29,7 +29,8 @@ use crate::{
         elf, fd,
         fd::Files,
         future::{user_loop, TaskFut},
-        Task, TaskState, DEFAULT_STACK_ATTR, DEFAULT_STACK_SIZE, TASKS,
+        Rlimit, Task, TaskState, DEFAULT_STACK_ATTR, DEFAULT_STACK_SIZE, STACK_GROWTH_GAP,
+        STACK_LIMIT, TASKS,
     },
 };
 
@@ -131,19 +132,26 @@ impl InitTask {
             .unwrap_or((DEFAULT_STACK_SIZE, DEFAULT_STACK_ATTR));
         let stack_size = (stack_size + PAGE_MASK) & !PAGE_MASK;
 
+        // If the stack can grow, the unmapped space below it serves as its guard
+        // instead of a fixed guard page.
+        let guard_size = if STACK_GROWTH_GAP > 0 { 0 } else { PAGE_SIZE };
         let addr = virt
             .map(
                 None,
                 Arc::new(Phys::new_anon(true)),
                 0,
-                (stack_size >> PAGE_SHIFT) + 1,
+                (stack_size + guard_size) >> PAGE_SHIFT,
                 stack_attr,
             )
             .await?;
-        virt.reprotect(addr..(addr + PAGE_SIZE), stack_attr - Attr::WRITABLE)
-            .await?;
+        if guard_size > 0 {
+            virt.reprotect(addr..(addr + guard_size), stack_attr - Attr::WRITABLE)
+                .await?;
+        } else {
+            virt.set_grows_down(addr).await?;
+        }
 
-        let end = addr + PAGE_SIZE + stack_size;
+        let end = addr + guard_size + stack_size;
         let sp = unsafe { Self::populate_args(end, virt, args, envs, auxv) }.await?;
 
         log::trace!("InitTask::load_stack finish {sp:?}");
@@ -249,6 +257,8 @@ impl InitTask {
 
             sig: Signals::new(),
             shared_sig: Default::default(),
+            sig_actions: Arsc::new(ActionSet::new()),
+            stopped_regs: Default::default(),
             event: Broadcast::new(),
         });
 
@@ -260,16 +270,29 @@ impl InitTask {
             brk: 0,
             system_times: 0,
             user_times: 0,
+            nr_voluntary: 0,
+            nr_involuntary: 0,
+            cpu_limit: Rlimit::INFINITY,
+            xcpu_next: 0,
+            fsize_limit: Rlimit::INFINITY,
+            stack_limit: Rlimit {
+                cur: STACK_LIMIT,
+                max: usize::MAX,
+            },
+            virt_slot: Arsc::new(spin::Mutex::new(self.virt.clone())),
             virt: self.virt,
             futex: Arsc::new(Futexes::new()),
             files: self.files,
-            sig_actions: Arsc::new(ActionSet::new()),
             tid_clear: None,
             exit_signal: Some(Sig::SIGCHLD),
+            vfork_done: None,
+            rseq: None,
+            itimers: Default::default(),
+            restart: None,
         };
 
         ksync::critical(|| TASKS.lock().insert(tid, task.clone()));
-        let fut = TaskFut::new(ts.virt.clone(), user_loop(ts, self.tf));
+        let fut = TaskFut::new(ts.virt_slot.clone(), user_loop(ts, self.tf));
         executor().spawn(fut).detach();
 
         Ok(task)
@@ -277,6 +300,12 @@ impl InitTask {
 
     pub async fn reset(self, ts: &mut TaskState, tf: &mut TrapFrame) {
         ts.virt = self.virt;
+        ksync::critical(|| *ts.virt_slot.lock() = ts.virt.clone());
+        // The task returns to the user in the same poll, before its `TaskFut`
+        // gets to reload the address space.
+        if let Some(clear) = unsafe { ts.virt.clone().load() } {
+            executor().spawn(clear).detach();
+        }
         ts.files.append_afterlife(&self.files).await;
         *tf = self.tf;
     }
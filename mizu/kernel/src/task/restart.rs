@@ -0,0 +1,173 @@
+//! Restarting syscalls interrupted by signals, in the spirit of Linux's
+//! `ERESTART_RESTARTBLOCK`.
+//!
+//! A syscall waiting with a timeout is interrupted as soon as an unblocked
+//! signal is pending, recording a [`RestartBlock`] and returning `EINTR`. If no
+//! user handler runs for the signal, e.g. because it is ignored or only stops
+//! the task, the syscall is then rewound to `restart_syscall(2)`, which resumes
+//! the wait with the timeout adjusted instead of failing.
+
+use alloc::boxed::Box;
+use core::{future::Future, pin::pin, sync::atomic::Ordering::SeqCst, time::Duration};
+
+use co_trap::{TrapFrame, UserCx};
+use futures_util::future::{select, Either};
+use ksc::{
+    async_handler,
+    Error::{self, EINTR, EINVAL},
+    Scn::RESTART_SYSCALL,
+};
+use ktime::{Instant, InstantExt, Timer};
+use rv39_paging::LAddr;
+
+use super::{yield_now, TaskState};
+use crate::{
+    mem::{In, Out, UserPtr},
+    syscall::{ScRet, Ts},
+};
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const TIMER_ABSTIME: i32 = 1;
+
+/// The information to resume an interrupted syscall, like Linux's `struct
+/// restart_block`. Other waits with timeouts, like `ppoll`, go here as well.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum RestartBlock {
+    /// A sleep until `deadline`, writing the remaining time to `rem` on
+    /// interruption if not null.
+    Sleep { deadline: Instant, rem: LAddr },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Restart {
+    /// The syscall has just returned `EINTR`, and the signal delivery is yet
+    /// to decide whether to restart it.
+    Interrupted(RestartBlock),
+    /// The syscall has been rewound to `restart_syscall`.
+    Rewound(RestartBlock),
+}
+
+fn to_duration(ts: Ts) -> Result<Duration, Error> {
+    let (sec, nsec) = (ts.sec, ts.nsec);
+    if nsec >= 1_000_000_000 {
+        return Err(EINVAL);
+    }
+    Ok(Duration::new(sec, nsec as u32))
+}
+
+impl TaskState {
+    /// Wait for `fut`, or return `None` as soon as an unblocked signal is
+    /// pending.
+    pub(super) async fn interruptible<F: Future>(&self, fut: F) -> Option<F::Output> {
+        let shared_sig = self.task.shared_sig.load(SeqCst);
+        let local = pin!(self.task.sig.wait_pending(self.sig_mask));
+        let shared = pin!(shared_sig.wait_pending(self.sig_mask));
+        match select(pin!(fut), select(local, shared)).await {
+            Either::Left((out, _)) => Some(out),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// Settle the syscall interrupted right before the signal delivery,
+    /// rewinding it to `restart_syscall` unless a user handler has been set
+    /// up to run.
+    pub(super) fn settle_restart(&mut self, tf: &mut TrapFrame, handled: bool) {
+        if let Some(Restart::Interrupted(block)) = self.restart {
+            self.restart = (!handled).then(|| {
+                tf.sepc -= 4;
+                tf.gpr.tx.a[7] = RESTART_SYSCALL as usize;
+                Restart::Rewound(block)
+            });
+        }
+    }
+
+    async fn resume(&mut self, block: RestartBlock) -> Result<(), Error> {
+        let RestartBlock::Sleep { deadline, rem } = block;
+        if deadline <= Instant::now() {
+            yield_now().await;
+            return Ok(());
+        }
+        let timer = Timer::deadline(deadline);
+        if self.interruptible(timer).await.is_some() {
+            return Ok(());
+        }
+
+        let mut rem = UserPtr::<Ts, Out>::new(rem);
+        if !rem.is_null() {
+            let left = deadline - Instant::now();
+            let ts = Ts {
+                sec: left.as_secs(),
+                nsec: left.subsec_nanos() as u64,
+            };
+            rem.write(self.virt.as_ref(), ts).await?;
+        }
+        self.restart = Some(Restart::Interrupted(block));
+        Err(EINTR)
+    }
+}
+
+#[async_handler]
+pub async fn nanosleep(
+    ts: &mut TaskState,
+    cx: UserCx<'_, fn(UserPtr<Ts, In>, UserPtr<Ts, Out>) -> Result<(), Error>>,
+) -> ScRet {
+    let (req, rem) = cx.args();
+    let fut = async move {
+        let dur = to_duration(req.read(ts.virt.as_ref()).await?)?;
+        let deadline = Instant::now() + dur;
+        ts.resume(RestartBlock::Sleep {
+            deadline,
+            rem: rem.addr(),
+        })
+        .await
+    };
+    cx.ret(fut.await);
+    ScRet::Continue(None)
+}
+
+#[async_handler]
+pub async fn clock_nanosleep(
+    ts: &mut TaskState,
+    cx: UserCx<'_, fn(usize, i32, UserPtr<Ts, In>, UserPtr<Ts, Out>) -> Result<(), Error>>,
+) -> ScRet {
+    let (clock, flags, req, rem) = cx.args();
+    let fut = async move {
+        // Both clocks count from the boot, as `clock_gettime` reports.
+        if !matches!(clock, CLOCK_REALTIME | CLOCK_MONOTONIC) {
+            return Err(EINVAL);
+        }
+        let dur = to_duration(req.read(ts.virt.as_ref()).await?)?;
+        let block = if flags & TIMER_ABSTIME != 0 {
+            // The remaining time of an absolute sleep is never reported.
+            RestartBlock::Sleep {
+                deadline: Instant::from_su(0, 0) + dur,
+                rem: 0usize.into(),
+            }
+        } else {
+            RestartBlock::Sleep {
+                deadline: Instant::now() + dur,
+                rem: rem.addr(),
+            }
+        };
+        ts.resume(block).await
+    };
+    cx.ret(fut.await);
+    ScRet::Continue(None)
+}
+
+#[async_handler]
+pub async fn restart_syscall(
+    ts: &mut TaskState,
+    cx: UserCx<'_, fn() -> Result<(), Error>>,
+) -> ScRet {
+    let fut = async move {
+        match ts.restart.take() {
+            Some(Restart::Rewound(block)) => ts.resume(block).await,
+            // Nothing to restart, as Linux does.
+            _ => Err(EINTR),
+        }
+    };
+    cx.ret(fut.await);
+    ScRet::Continue(None)
+}
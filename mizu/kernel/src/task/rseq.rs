@@ -0,0 +1,121 @@
+//! Restartable sequences, following the ABI of Linux's `rseq(2)`, except that
+//! CPU ids are only written on registration.
+
+use alloc::boxed::Box;
+
+use co_trap::{TrapFrame, UserCx};
+use ksc::{
+    async_handler,
+    Error::{self, EBUSY, EINVAL, EPERM},
+};
+use rv39_paging::LAddr;
+use sygnal::{Sig, SigInfo};
+
+use super::TaskState;
+use crate::{
+    mem::{In, InOut, Out, UserPtr},
+    syscall::ScRet,
+};
+
+const RSEQ_LEN: u32 = 32;
+const RSEQ_FLAG_UNREGISTER: i32 = 1;
+/// The offset of `rseq_cs` in `struct rseq`.
+const RSEQ_CS_OFFSET: usize = 8;
+
+/// `struct rseq_cs`, the descriptor of a critical section.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct RseqCs {
+    version: u32,
+    flags: u32,
+    start_ip: usize,
+    post_commit_offset: usize,
+    abort_ip: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Rseq {
+    area: LAddr,
+    sig: u32,
+}
+
+impl TaskState {
+    /// Move `sepc` to the abort handler if the task is being preempted inside
+    /// the critical section currently described in its `struct rseq`,
+    /// returning `SIGSEGV` if the descriptor is invalid.
+    pub(super) async fn rseq_abort(&mut self, tf: &mut TrapFrame) -> Option<SigInfo> {
+        let rseq = self.rseq?;
+        let res = async {
+            let virt = self.virt.as_ref();
+            let mut cs_ptr = UserPtr::<usize, InOut>::new(rseq.area + RSEQ_CS_OFFSET);
+            let cs = cs_ptr.read(virt).await?;
+            if cs == 0 {
+                return Ok(());
+            }
+            let cs = UserPtr::<RseqCs, In>::new(cs.into()).read(virt).await?;
+            let range = cs.start_ip..cs.start_ip.saturating_add(cs.post_commit_offset);
+            if !range.contains(&tf.sepc) {
+                return Ok(());
+            }
+
+            // The abort handler must be outside of the critical section and
+            // preceded by the registered signature.
+            let sig_ptr = UserPtr::<u32, In>::new(cs.abort_ip.wrapping_sub(4).into());
+            if range.contains(&cs.abort_ip) || sig_ptr.read(virt).await? != rseq.sig {
+                return Err(EINVAL);
+            }
+
+            log::trace!("task {} rseq abort to {:#x}", self.task.tid, cs.abort_ip);
+            cs_ptr.write(virt, 0).await?;
+            tf.sepc = cs.abort_ip;
+            Ok::<_, Error>(())
+        };
+        match res.await {
+            Ok(()) => None,
+            Err(err) => {
+                log::warn!("task {} invalid rseq descriptor: {err}", self.task.tid);
+                Some(SigInfo::kernel(Sig::SIGSEGV))
+            }
+        }
+    }
+}
+
+#[async_handler]
+pub async fn rseq(
+    ts: &mut TaskState,
+    cx: UserCx<'_, fn(UserPtr<u32, Out>, u32, i32, u32) -> Result<(), Error>>,
+) -> ScRet {
+    let (mut area, len, flags, sig) = cx.args();
+    let fut = async move {
+        if flags & RSEQ_FLAG_UNREGISTER != 0 {
+            let rseq = ts.rseq.ok_or(EINVAL)?;
+            if rseq.area != area.addr() || len < RSEQ_LEN {
+                return Err(EINVAL);
+            }
+            if rseq.sig != sig {
+                return Err(EPERM);
+            }
+            ts.rseq = None;
+            return Ok(());
+        }
+
+        if flags != 0 || len < RSEQ_LEN || area.addr().val() % RSEQ_LEN as usize != 0 {
+            return Err(EINVAL);
+        }
+        if let Some(rseq) = ts.rseq {
+            let same = rseq.area == area.addr() && rseq.sig == sig;
+            return Err(if same { EBUSY } else { EINVAL });
+        }
+
+        let cpu = hart_id::hart_id() as u32;
+        area.write_slice(ts.virt.as_ref(), &[cpu, cpu], false)
+            .await?;
+        ts.rseq = Some(Rseq {
+            area: area.addr(),
+            sig,
+        });
+        Ok(())
+    };
+    cx.ret(fut.await);
+    ScRet::Continue(None)
+}
@@ -1,6 +1,6 @@
 mod syscall;
 
-use alloc::{boxed::Box, vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
 use core::{alloc::Layout, mem, sync::atomic::Ordering::SeqCst};
 
 use arsc_rs::Arsc;
@@ -8,10 +8,10 @@ use co_trap::TrapFrame;
 use ksc::async_handler;
 use rv39_paging::LAddr;
 use static_assertions::const_assert;
-use sygnal::{ActionType, Sig, SigCode, SigFields, SigInfo, SigSet};
+use sygnal::{ActionType, ChldCode, Sig, SigFields, SigInfo, SigSet};
 
 pub use self::syscall::*;
-use super::{TaskEvent, TaskState};
+use super::{Task, TaskEvent, TaskState, TASKS};
 use crate::{
     mem::{In, Out, UserPtr},
     syscall::ScRet,
@@ -24,8 +24,9 @@ impl TaskState {
     ) -> Result<(), (i32, Sig)> {
         let si = self.task.sig.pop(self.sig_mask);
         let si = si.or_else(|| self.task.shared_sig.load(SeqCst).pop(self.sig_mask));
+        let mut handled = false;
         if let Some(si) = si {
-            let action = self.sig_actions.get(si.sig);
+            let action = self.task.sig_actions.get(si.sig);
             log::trace!("received signal {:?}, code = {}", si.sig, si.code);
             match action.ty {
                 ActionType::Ignore => {}
@@ -37,29 +38,58 @@ impl TaskState {
                     return Err((0, si.sig));
                 }
                 ActionType::Suspend => {
+                    ksync::critical(|| *self.task.stopped_regs.lock() = Some(*tf));
+                    self.notify_parent_stop(ChldCode::STOPPED, si.sig);
                     let _ = self.task.event.send(&TaskEvent::Suspended(si.sig)).await;
                     self.task.sig.wait_one(Sig::SIGCONT).await;
+                    if let Some(regs) = ksync::critical(|| self.task.stopped_regs.lock().take()) {
+                        *tf = regs;
+                    }
+                    self.notify_parent_stop(ChldCode::CONTINUED, Sig::SIGCONT);
                 }
                 ActionType::User { entry, exit, .. } => {
-                    if let Err(sig) = self.yield_to_signal(tf, si, entry, exit).await {
-                        let sigsegv = SigInfo {
-                            sig: Sig::SIGSEGV,
-                            code: SigCode::KERNEL as _,
-                            fields: SigFields::None,
-                        };
+                    if let Some(sigsegv) = self.rseq_abort(tf).await {
+                        self.task.sig.push(sigsegv);
+                    }
+                    let mask = action.handler_mask(si.sig, self.sig_mask);
+                    if let Err(sig) = self.yield_to_signal(tf, si, entry, exit, mask).await {
+                        let sigsegv = SigInfo::kernel(Sig::SIGSEGV);
                         if sig != Sig::SIGSEGV {
-                            self.task.sig.push(sigsegv)
+                            self.task.sig.push(sigsegv);
                         } else {
                             self.sig_fatal(sigsegv, false);
                             return Err((0, Sig::SIGSEGV));
                         }
+                    } else {
+                        handled = true;
                     }
                 }
             }
         }
+        self.settle_restart(tf, handled);
         Ok(())
     }
 
+    /// Send `SIGCHLD` to the parent when this task stops or continues, unless
+    /// the parent has set `SA_NOCLDSTOP`.
+    fn notify_parent_stop(&self, code: ChldCode, sig: Sig) {
+        let Some(parent) = self.task.parent.upgrade() else {
+            return;
+        };
+        if !parent.sig_actions.get(Sig::SIGCHLD).no_cld_stop {
+            let si = self.chld_info(Sig::SIGCHLD, code, sig.raw());
+            parent.sig.push(si);
+        }
+    }
+
+    /// The `SigInfo` of a state change of this task, delivered to its parent.
+    pub(in crate::task) fn chld_info(&self, sig: Sig, code: ChldCode, status: i32) -> SigInfo {
+        SigInfo {
+            sig,
+            ..SigInfo::child(self.task.tid, status, code)
+        }
+    }
+
     pub(in crate::task) fn sig_fatal(&mut self, si: SigInfo, clear: bool) {
         let tgroup = if clear {
             mem::replace(
@@ -84,6 +114,7 @@ impl TaskState {
         si: SigInfo,
         entry: LAddr,
         exit: LAddr,
+        mask: SigSet,
     ) -> Result<(), Sig> {
         let sig_stack = self.sig_stack.take();
         let cur = match sig_stack {
@@ -96,12 +127,7 @@ impl TaskState {
 
         let virt = self.virt.as_ref();
 
-        let usi = UsigInfo {
-            sig: si.sig,
-            errno: 0,
-            code: si.code,
-        };
-        usi_ptr.write(virt, usi).await.map_err(|_| si.sig)?;
+        usi_ptr.write(virt, si.into()).await.map_err(|_| si.sig)?;
 
         let mut uc = Ucontext {
             flags: 0,
@@ -127,7 +153,8 @@ impl TaskState {
         tf.gpr.tx.ra = exit.val();
         tf.gpr.tx.sp = usi_ptr.addr().val();
 
-        self.sig_mask |= si.sig;
+        // The original mask is saved in `uc` and restored by `resume_from_signal`.
+        self.sig_mask = mask;
         Ok(())
     }
 
@@ -136,14 +163,10 @@ impl TaskState {
         let uc_ptr = UserPtr::<Ucontext, In>::new((tf.gpr.tx.sp + MAX_SI_LEN).into());
         let Ok(uc) = uc_ptr.read(ts.virt.as_ref()).await else {
             tf.sepc += 4;
-            return ScRet::Continue(Some(SigInfo {
-                sig: Sig::SIGSEGV,
-                code: SigCode::KERNEL as _,
-                fields: SigFields::None,
-            }));
+            return ScRet::Continue(Some(SigInfo::kernel(Sig::SIGSEGV)));
         };
 
-        ts.sig_mask = uc.sig_mask.into();
+        ts.sig_mask = SigSet::from(uc.sig_mask).sanitize_mask();
         ts.sig_stack = (uc.stack.len != 0).then_some(uc.stack);
         tf.sepc = uc.mc.pc;
         tf.gpr.copy_from_x(&uc.mc.x);
@@ -151,12 +174,86 @@ impl TaskState {
     }
 }
 
+/// Deliver `si` to each of `targets`, returning the number of tasks that
+/// accepted it.
+///
+/// Blocked signals stay pending as usual, while ignored ones are discarded
+/// (but still accepted) unless they can't be caught. Tasks that have already
+/// exited are skipped.
+pub(in crate::task) fn send_signal(
+    targets: impl IntoIterator<Item = Arc<Task>>,
+    si: SigInfo,
+) -> usize {
+    let accept = |task: &Arc<Task>| {
+        // Holding the task table keeps the task from exiting concurrently.
+        ksync::critical(|| {
+            if !TASKS.lock().contains_key(&task.tid) {
+                return false;
+            }
+            let action = task.sig_actions.get(si.sig);
+            if si.sig.should_never_capture() || action.ty != ActionType::Ignore {
+                task.sig.push(si);
+            }
+            true
+        })
+    };
+    targets.into_iter().filter(accept).count()
+}
+
+/// All the live processes, each represented by its thread with the smallest
+/// TID.
+pub(in crate::task) fn processes() -> Vec<Arc<Task>> {
+    let mut tasks = ksync::critical(|| TASKS.lock().values().cloned().collect::<Vec<_>>());
+    tasks.sort_by_key(|task| task.tid);
+
+    // Threads of the same process share their process-wide signal queue.
+    let mut seen = Vec::new();
+    tasks.retain(|task| {
+        let shared = Arsc::as_ptr(&task.shared_sig.load(SeqCst));
+        let new = !seen.contains(&shared);
+        seen.push(shared);
+        new
+    });
+    tasks
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct UsigInfo {
     sig: Sig,
     errno: i32,
     code: i32,
+    fields: UsigFields,
+}
+
+impl From<SigInfo> for UsigInfo {
+    fn from(si: SigInfo) -> Self {
+        UsigInfo {
+            sig: si.sig,
+            errno: 0,
+            code: si.code,
+            fields: si.fields.into(),
+        }
+    }
+}
+
+/// The union of `siginfo_t` after `si_code`, at offset 16.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct UsigFields([usize; 2]);
+
+impl From<SigFields> for UsigFields {
+    fn from(fields: SigFields) -> Self {
+        let ids = |pid: usize, uid: usize| (pid as u32 as usize) | ((uid as u32 as usize) << 32);
+        UsigFields(match fields {
+            SigFields::SigKill { pid, uid } => [ids(pid, uid), 0],
+            SigFields::SigChld { pid, uid, status } => [ids(pid, uid), status as u32 as usize],
+            SigFields::SigSys { addr, num } => [addr.val(), num as usize],
+            SigFields::SigFault { addr } => [addr.val(), 0],
+            SigFields::Timer { id, overrun } => [ids(id as usize, overrun as usize), 0],
+            _ => [0; 2],
+        })
+    }
 }
 const MAX_SI_LEN: usize = 128;
 const_assert!(mem::size_of::<UsigInfo>() <= MAX_SI_LEN);
@@ -1,17 +1,18 @@
 use alloc::boxed::Box;
 use core::{mem, num::NonZeroI32, pin::pin, sync::atomic::Ordering::SeqCst, time::Duration};
 
+use arsc_rs::Arsc;
 use co_trap::UserCx;
 use futures_util::future::{select, Either};
 use ksc::{
     async_handler,
-    Error::{self, EINVAL, EPERM, ESRCH, ETIMEDOUT},
+    Error::{self, EAGAIN, EINVAL, EPERM, ESRCH, ETIMEDOUT},
 };
 use ktime::{TimeOutExt, Timer};
 use rv39_paging::{LAddr, PAGE_SIZE};
-use sygnal::{Action, ActionType, Sig, SigCode, SigFields, SigInfo, SigSet};
+use sygnal::{Action, ActionType, Sig, SigInfo, SigSet};
 
-use super::UsigInfo;
+use super::{processes, send_signal, UsigInfo};
 use crate::{
     mem::{In, Out, UserPtr},
     syscall::{ScRet, Tv},
@@ -42,7 +43,7 @@ impl Default for SigAction {
 
 impl From<Action> for SigAction {
     fn from(action: Action) -> Self {
-        match action.ty {
+        let mut sa = match action.ty {
             ActionType::Ignore => SigAction {
                 handler: SIG_IGN,
                 ..Default::default()
@@ -56,6 +57,7 @@ impl From<Action> for SigAction {
                 exit,
                 use_extra_cx,
                 use_alt_stack,
+                no_defer,
             } => {
                 let default_exit = exit.val() != SIGRETURN_GUARD;
                 SigAction {
@@ -69,6 +71,9 @@ impl From<Action> for SigAction {
                         if use_alt_stack {
                             flags |= SigFlags::ONSTACK
                         }
+                        if no_defer {
+                            flags |= SigFlags::NODEFER
+                        }
                         if !default_exit {
                             flags |= SigFlags::RESTORER
                         }
@@ -77,15 +82,26 @@ impl From<Action> for SigAction {
                     restorer: if default_exit { exit } else { 0usize.into() },
                 }
             }
+        };
+        if action.no_cld_stop {
+            sa.flags |= SigFlags::NOCLDSTOP
+        }
+        // An explicit `SIG_IGN` for `SIGCHLD` implies the flag by itself.
+        if action.no_cld_wait && action.ty != ActionType::Ignore {
+            sa.flags |= SigFlags::NOCLDWAIT
         }
+        sa
     }
 }
 
 bitflags::bitflags! {
     #[derive(Default, Clone, Copy, Debug)]
     struct SigFlags: isize {
+        const NOCLDSTOP = 1;
+        const NOCLDWAIT = 2;
         const SIGINFO = 4;
         const ONSTACK = 0x08000000;
+        const NODEFER = 0x40000000;
         const RESTORER = 0x04000000;
     }
 }
@@ -122,11 +138,15 @@ pub async fn sigaction(
                     },
                     use_extra_cx: action.flags.contains(SigFlags::SIGINFO),
                     use_alt_stack: action.flags.contains(SigFlags::ONSTACK),
+                    no_defer: action.flags.contains(SigFlags::NODEFER),
                 },
             },
             mask: action.mask,
+            no_cld_stop: action.flags.contains(SigFlags::NOCLDSTOP),
+            no_cld_wait: sig == Sig::SIGCHLD
+                && (action.handler == SIG_IGN || action.flags.contains(SigFlags::NOCLDWAIT)),
         };
-        let action = ts.sig_actions.replace(sig, action);
+        let action = ts.task.sig_actions.replace(sig, action);
         if !old.is_null() {
             old.write(ts.virt.as_ref(), action.into()).await?;
         }
@@ -212,7 +232,8 @@ pub async fn sigprocmask(
                 SIG_UNBLOCK => current & !set,
                 SIG_SETMASK => set,
                 _ => return Err(EINVAL),
-            };
+            }
+            .sanitize_mask();
         }
         Ok(())
     };
@@ -239,7 +260,8 @@ pub async fn sigtimedwait(
         if size != mem::size_of::<SigSet>() {
             return Err(EINVAL);
         }
-        let set = set.read(ts.virt.as_ref()).await?;
+        // Waiting for `SIGKILL` or `SIGSTOP` is silently ignored, as Linux does.
+        let set = set.read(ts.virt.as_ref()).await?.sanitize_mask();
         let tv = tv.read(ts.virt.as_ref()).await?;
         let dur = Duration::from_secs(tv.sec) + Duration::from_micros(tv.usec);
 
@@ -255,12 +277,7 @@ pub async fn sigtimedwait(
             Either::Right((si, _)) => si,
         };
         if !usi_ptr.is_null() {
-            let usi = UsigInfo {
-                sig: si.sig,
-                errno: 0,
-                code: si.code,
-            };
-            usi_ptr.write(ts.virt.as_ref(), usi).await?;
+            usi_ptr.write(ts.virt.as_ref(), si.into()).await?;
         }
 
         Ok(si.sig.raw())
@@ -281,14 +298,7 @@ pub async fn kill(
             .and_then(|s| Sig::new(s.get()))
             .ok_or(EINVAL)?;
 
-        let si = SigInfo {
-            sig,
-            code: SigCode::USER as _,
-            fields: SigFields::SigKill {
-                pid: ts.task.tid,
-                uid: 0,
-            },
-        };
+        let si = SigInfo::kill(sig, ts.task.tid, 0);
         match pid {
             PidSelection::Task(Some(tid)) => {
                 let child = ksync::critical(|| {
@@ -296,9 +306,33 @@ pub async fn kill(
                     let mut iter = children.iter();
                     iter.find(|c| c.task.tid == tid).map(|c| c.task.clone())
                 });
-                child.ok_or(ESRCH)?.sig.push(si);
+                if !child.ok_or(ESRCH)?.sig.push(si) {
+                    return Err(EAGAIN);
+                }
+            }
+            // Process groups are not tracked, so every process is the only
+            // member of its own group.
+            PidSelection::Group(None) => {
+                send_signal([ts.task.clone()], si);
+            }
+            PidSelection::Group(Some(pgid)) => {
+                let target = processes().into_iter().find(|task| task.tid == pgid);
+                if send_signal(target, si) == 0 {
+                    return Err(ESRCH);
+                }
+            }
+            PidSelection::Task(None) => {
+                // Skip the caller and the root tasks spawned by the kernel,
+                // which play the role of `init`.
+                let this = ts.task.shared_sig.load(SeqCst);
+                let targets = processes().into_iter().filter(|task| {
+                    let shared = task.shared_sig.load(SeqCst);
+                    task.parent.strong_count() > 0 && !Arsc::ptr_eq(&shared, &this)
+                });
+                if send_signal(targets, si) == 0 {
+                    return Err(ESRCH);
+                }
             }
-            x => todo!("kill {x:?}"),
         }
         Ok(())
     };
@@ -317,17 +351,12 @@ pub async fn tkill(
             .and_then(|s| Sig::new(s.get()))
             .ok_or(EINVAL)?;
 
-        let si = SigInfo {
-            sig,
-            code: SigCode::USER as _,
-            fields: SigFields::SigKill {
-                pid: ts.task.tid,
-                uid: 0,
-            },
-        };
+        let si = SigInfo::kill(sig, ts.task.tid, 0);
 
         let task = ksync::critical(|| ts.tgroup.1.read().iter().find(|t| t.tid == tid).cloned());
-        task.ok_or(ESRCH)?.sig.push(si);
+        if !task.ok_or(ESRCH)?.sig.push(si) {
+            return Err(EAGAIN);
+        }
         Ok(())
     };
     cx.ret(fut.await);
@@ -349,17 +378,12 @@ pub async fn tgkill(
             return Err(EPERM);
         }
 
-        let si = SigInfo {
-            sig,
-            code: SigCode::USER as _,
-            fields: SigFields::SigKill {
-                pid: ts.task.tid,
-                uid: 0,
-            },
-        };
+        let si = SigInfo::kill(sig, ts.task.tid, 0);
 
         let task = ksync::critical(|| ts.tgroup.1.read().iter().find(|t| t.tid == tid).cloned());
-        task.ok_or(ESRCH)?.sig.push(si);
+        if !task.ok_or(ESRCH)?.sig.push(si) {
+            return Err(EAGAIN);
+        }
         Ok(())
     };
     cx.ret(fut.await);
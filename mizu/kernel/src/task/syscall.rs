@@ -12,8 +12,8 @@ use ksc::{
     Error::{self, EINVAL, ENOTDIR},
     RawReg,
 };
-use ksync::{AtomicArsc, Broadcast};
-use sygnal::{Sig, SigCode, SigFields, SigInfo, SigSet, Signals};
+use ksync::{unbounded, AtomicArsc, Broadcast};
+use sygnal::{termination_status, Sig, SigCode, SigFields, SigInfo, SigSet, Signals};
 use umifs::types::Permissions;
 
 use crate::{
@@ -67,7 +67,7 @@ pub async fn times(
 #[async_handler]
 pub async fn set_tid_addr(
     ts: &mut TaskState,
-    cx: UserCx<'_, fn(UserPtr<usize, Out>) -> usize>,
+    cx: UserCx<'_, fn(UserPtr<u32, Out>) -> usize>,
 ) -> ScRet {
     ts.tid_clear = Some(cx.args());
     cx.ret(ts.task.tid);
@@ -97,9 +97,9 @@ async fn clone_task(
     tf: &TrapFrame,
     flags: u64,
     stack: Option<NonZeroUsize>,
-    mut ptid: UserPtr<usize, Out>,
+    mut ptid: UserPtr<u32, Out>,
     tls: usize,
-    mut ctid: UserPtr<usize, Out>,
+    mut ctid: UserPtr<u32, Out>,
 ) -> Result<usize, Error> {
     bitflags::bitflags! {
         #[derive(Debug, Copy, Clone)]
@@ -113,6 +113,8 @@ async fn clone_task(
             const FILES          = 0x00000400;
             /// Share sigaction.
             const SIGHAND        = 0x00000800;
+            /// Suspend the parent until the child execs or exits.
+            const VFORK          = 0x00004000;
             /// Share parent.
             const PARENT         = 0x00008000;
             /// Share thread group.
@@ -164,17 +166,26 @@ async fn clone_task(
         } else {
             Default::default()
         }),
+        sig_actions: if flags.contains(Flags::SIGHAND) {
+            ts.task.sig_actions.clone()
+        } else {
+            Arsc::new(ts.task.sig_actions.deep_fork())
+        },
+        stopped_regs: Default::default(),
         event: Broadcast::new(),
     });
     if flags.contains(Flags::PARENT_SETTID) {
-        ptid.write(ts.virt.as_ref(), new_tid).await?;
+        ptid.write(ts.virt.as_ref(), new_tid as u32).await?;
     }
     if flags.contains(Flags::CHILD_SETTID) {
-        ctid.write(ts.virt.as_ref(), new_tid).await?;
+        ctid.write(ts.virt.as_ref(), new_tid as u32).await?;
     }
 
     log::trace!("clone_task: cloning virt");
 
+    // `vfork` children share the address space too: the parent stays suspended
+    // until they exec or exit, and may read what they wrote before, e.g. the
+    // exec error of `posix_spawn`.
     let virt = if flags.contains(Flags::VM) {
         ts.virt.clone()
     } else {
@@ -194,6 +205,13 @@ async fn clone_task(
     }
     log::trace!("clone_task: setting up TaskState");
 
+    let (vfork_done, vfork_wait) = if flags.contains(Flags::VFORK) {
+        let (tx, rx) = unbounded();
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+
     let new_ts = TaskState {
         task: task.clone(),
         tgroup: if flags.contains(Flags::THREAD) {
@@ -208,6 +226,13 @@ async fn clone_task(
         brk: ts.brk,
         system_times: 0,
         user_times: 0,
+        nr_voluntary: 0,
+        nr_involuntary: 0,
+        cpu_limit: ts.cpu_limit,
+        xcpu_next: 0,
+        fsize_limit: ts.fsize_limit,
+        stack_limit: ts.stack_limit,
+        virt_slot: Arsc::new(spin::Mutex::new(virt.clone())),
         virt,
         futex: if flags.contains(Flags::THREAD) {
             ts.futex.clone()
@@ -218,13 +243,18 @@ async fn clone_task(
             .files
             .deep_fork(flags.contains(Flags::FS), flags.contains(Flags::FILES))
             .await,
-        sig_actions: if flags.contains(Flags::SIGHAND) {
-            ts.sig_actions.clone()
-        } else {
-            Arsc::new(ts.sig_actions.deep_fork())
-        },
         tid_clear: flags.contains(Flags::CHILD_CLEARTID).then_some(ctid),
         exit_signal,
+        vfork_done,
+        // Threads need to register their own areas.
+        rseq: if flags.contains(Flags::VM) {
+            None
+        } else {
+            ts.rseq
+        },
+        // Interval timers are not inherited by children.
+        itimers: Default::default(),
+        restart: None,
     };
 
     if !flags.contains(Flags::THREAD) {
@@ -246,9 +276,16 @@ async fn clone_task(
     }
 
     ksync::critical(|| TASKS.lock().insert(new_tid, task.clone()));
-    let fut = TaskFut::new(new_ts.virt.clone(), user_loop(new_ts, new_tf));
+    let fut = TaskFut::new(new_ts.virt_slot.clone(), user_loop(new_ts, new_tf));
     executor().spawn(fut).detach();
 
+    if let Some(vfork_wait) = vfork_wait {
+        log::trace!("clone_task: waiting for the vfork child {new_tid}");
+        // Nothing is ever sent; the channel closes when the child drops the
+        // sender on exec or exit.
+        let _ = vfork_wait.recv().await;
+    }
+
     Ok(new_tid)
 }
 
@@ -257,7 +294,7 @@ pub async fn clone(
     ts: &mut TaskState,
     cx: UserCx<
         '_,
-        fn(u64, usize, UserPtr<usize, Out>, usize, UserPtr<usize, Out>) -> Result<usize, Error>,
+        fn(u64, usize, UserPtr<u32, Out>, usize, UserPtr<u32, Out>) -> Result<usize, Error>,
     >,
 ) -> ScRet {
     let (flags, stack, parent_tidptr, tls, child_tidptr) = cx.args();
@@ -285,7 +322,8 @@ pub async fn waitpid(
         let (event, tid) = ts.wait(pid.into()).await?;
         if !wstatus.is_null() {
             let ws = match event {
-                TaskEvent::Exited(code, sig) => ((code & 0xff) << 8) | sig.map_or(0, Sig::raw),
+                TaskEvent::Exited(code, None) => (code & 0xff) << 8,
+                TaskEvent::Exited(_, Some(sig)) => termination_status(sig, sig.default_action()),
                 TaskEvent::Suspended(sig) => (sig.raw() << 8) | 0x7f,
                 TaskEvent::Continued => 0xffff,
             };
@@ -355,7 +393,6 @@ pub async fn execve(
             },
             true,
         );
-        ts.virt.clear().await;
         ts.futex = Arsc::new(Default::default());
         ts.task.shared_sig.swap(Default::default(), SeqCst);
 
@@ -366,12 +403,17 @@ pub async fn execve(
         let init = InitTask::from_elf(
             ts.task.parent.clone(),
             &Arc::new(phys),
-            ts.virt.clone(),
+            // The old address space may still be shared with a `vfork` parent,
+            // so switch to a new one instead of clearing it. The old one is
+            // torn down once no other task loads it.
+            crate::mem::new_virt(),
             args,
             envs,
         )
         .await?;
         init.reset(ts, tf).await;
+        ts.vfork_done = None;
+        ts.rseq = None;
 
         Ok(())
     }
@@ -0,0 +1,236 @@
+//! Interval timers of `setitimer(2)`.
+//!
+//! All the timers count in raw timer ticks: the real timer against the
+//! wall-clock, the virtual one against the user time and the profiling one
+//! against both the user and the system time of the task.
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    sync::atomic::{AtomicU64, Ordering::SeqCst},
+    time::Duration,
+};
+
+use co_trap::UserCx;
+use ksc::{
+    async_handler,
+    Error::{self, EINVAL},
+};
+use ktime::{Instant, Timer};
+use sygnal::{Sig, SigInfo};
+
+use super::{Task, TaskState};
+use crate::{
+    executor,
+    mem::{In, Out, UserPtr},
+    syscall::{ScRet, Tv},
+};
+
+const ITIMER_REAL: usize = 0;
+const ITIMER_VIRTUAL: usize = 1;
+const ITIMER_PROF: usize = 2;
+
+const TIME_FREQ: u128 = config::TIME_FREQ;
+
+/// `struct itimerval`.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct ITimerVal {
+    pub interval: Tv,
+    pub value: Tv,
+}
+
+fn to_ticks(tv: Tv) -> Result<u64, Error> {
+    let (sec, usec) = (tv.sec, tv.usec);
+    if usec >= 1_000_000 {
+        return Err(EINVAL);
+    }
+    let ticks = sec as u128 * TIME_FREQ + (usec as u128 * TIME_FREQ).div_ceil(1_000_000);
+    Ok(ticks.try_into().unwrap_or(u64::MAX))
+}
+
+fn to_tv(ticks: u64) -> Tv {
+    let ticks = ticks as u128;
+    Tv {
+        sec: (ticks / TIME_FREQ) as u64,
+        usec: ((ticks % TIME_FREQ) * 1_000_000 / TIME_FREQ) as u64,
+    }
+}
+
+fn to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * 1_000_000_000 / TIME_FREQ;
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ITimer {
+    /// The clock value at which the timer next expires, or `None` if disarmed.
+    expire: Option<u64>,
+    /// The reload value of a periodic timer, or 0 for a one-shot timer.
+    interval: u64,
+}
+
+impl ITimer {
+    /// Returns the remaining time and the interval of the timer at `now`.
+    fn get(&self, now: u64) -> (u64, u64) {
+        let value = match self.expire {
+            None => 0,
+            Some(expire) if now < expire => expire - now,
+            Some(_) if self.interval == 0 => 0,
+            Some(expire) => self.interval - (now - expire) % self.interval,
+        };
+        (value, self.interval)
+    }
+
+    /// Checks the timer against the clock, rearming it if periodic. Returns
+    /// whether it has expired.
+    fn check(&mut self, now: u64) -> bool {
+        let Some(expire) = self.expire.filter(|&expire| now >= expire) else {
+            return false;
+        };
+        self.expire = match self.interval {
+            0 => None,
+            // Signals don't queue up, so skip the missed periods.
+            i => expire.checked_add(((now - expire) / i + 1).saturating_mul(i)),
+        };
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct ITimers {
+    real: ITimer,
+    /// Bumped whenever the real timer is rearmed or the task goes away, to
+    /// stop the previous timer future.
+    real_gen: Arc<AtomicU64>,
+    virt: ITimer,
+    prof: ITimer,
+}
+
+impl Drop for ITimers {
+    fn drop(&mut self) {
+        self.real_gen.fetch_add(1, SeqCst);
+    }
+}
+
+fn spawn_real(task: Arc<Task>, gen: Arc<AtomicU64>, timer: ITimer) {
+    let cur = gen.load(SeqCst);
+    let Some(expire) = timer.expire else { return };
+    let fut = async move {
+        let now = Instant::now_raw();
+        Timer::after(to_duration(expire.saturating_sub(now))).await;
+        loop {
+            if gen.load(SeqCst) != cur {
+                break;
+            }
+            task.sig.push(SigInfo::timer(Sig::SIGALRM, 0));
+            if timer.interval == 0 {
+                break;
+            }
+            Timer::after(to_duration(timer.interval)).await;
+        }
+    };
+    executor().spawn(fut).detach();
+}
+
+impl TaskState {
+    /// Arms or disarms (if `value` is 0) the interval timer `which`, returning
+    /// the old remaining time and interval.
+    pub(super) fn set_itimer(
+        &mut self,
+        which: usize,
+        value: u64,
+        interval: u64,
+    ) -> Result<(u64, u64), Error> {
+        let now = match which {
+            ITIMER_REAL => Instant::now_raw(),
+            ITIMER_VIRTUAL => self.user_times,
+            ITIMER_PROF => self.user_times + self.system_times,
+            _ => return Err(EINVAL),
+        };
+        let new = ITimer {
+            expire: (value != 0).then(|| now.saturating_add(value)),
+            interval,
+        };
+        let timer = match which {
+            ITIMER_REAL => &mut self.itimers.real,
+            ITIMER_VIRTUAL => &mut self.itimers.virt,
+            _ => &mut self.itimers.prof,
+        };
+        let old = core::mem::replace(timer, new).get(now);
+
+        if which == ITIMER_REAL {
+            let gen = &self.itimers.real_gen;
+            gen.fetch_add(1, SeqCst);
+            spawn_real(self.task.clone(), gen.clone(), new);
+        }
+        Ok(old)
+    }
+
+    pub(super) fn get_itimer(&self, which: usize) -> Result<(u64, u64), Error> {
+        Ok(match which {
+            ITIMER_REAL => self.itimers.real.get(Instant::now_raw()),
+            ITIMER_VIRTUAL => self.itimers.virt.get(self.user_times),
+            ITIMER_PROF => {
+                let now = self.user_times + self.system_times;
+                self.itimers.prof.get(now)
+            }
+            _ => return Err(EINVAL),
+        })
+    }
+
+    /// Check the virtual and profiling timers against the CPU times, returning
+    /// the signals to be delivered.
+    ///
+    /// The real timer is driven by its own timer future instead.
+    pub(super) fn check_itimers(&mut self) -> impl Iterator<Item = SigInfo> {
+        let virt = self.itimers.virt.check(self.user_times);
+        let prof = self.itimers.prof.check(self.user_times + self.system_times);
+        let virt = virt.then(|| SigInfo::timer(Sig::SIGVTALRM, 0));
+        virt.into_iter()
+            .chain(prof.then(|| SigInfo::timer(Sig::SIGPROF, 0)))
+    }
+}
+
+#[async_handler]
+pub async fn setitimer(
+    ts: &mut TaskState,
+    cx: UserCx<'_, fn(usize, UserPtr<ITimerVal, In>, UserPtr<ITimerVal, Out>) -> Result<(), Error>>,
+) -> ScRet {
+    let (which, new, mut old) = cx.args();
+    let fut = async move {
+        let new = new.read(ts.virt.as_ref()).await?;
+        let (value, interval) = (to_ticks(new.value)?, to_ticks(new.interval)?);
+        let (value, interval) = ts.set_itimer(which, value, interval)?;
+        if !old.is_null() {
+            let old_val = ITimerVal {
+                interval: to_tv(interval),
+                value: to_tv(value),
+            };
+            old.write(ts.virt.as_ref(), old_val).await?;
+        }
+        Ok(())
+    };
+    cx.ret(fut.await);
+    ScRet::Continue(None)
+}
+
+#[async_handler]
+pub async fn getitimer(
+    ts: &mut TaskState,
+    cx: UserCx<'_, fn(usize, UserPtr<ITimerVal, Out>) -> Result<(), Error>>,
+) -> ScRet {
+    let (which, mut out) = cx.args();
+    let fut = async move {
+        let (value, interval) = ts.get_itimer(which)?;
+        let val = ITimerVal {
+            interval: to_tv(interval),
+            value: to_tv(value),
+        };
+        out.write(ts.virt.as_ref(), val).await
+    };
+    cx.ret(fut.await);
+    ScRet::Continue(None)
+}
@@ -0,0 +1,169 @@
+//! A generic queue for parking tasks until events on tokens.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering::SeqCst},
+    task::{Context, Poll, Waker},
+};
+
+use hashbrown::HashMap;
+use rand_riscv::RandomState;
+use spin::Mutex;
+
+/// The ID of poll-based registrations, which are not tracked by any future.
+const POLLER: usize = 0;
+static ID_ALLOC: AtomicUsize = AtomicUsize::new(POLLER + 1);
+
+/// A queue of tasks waiting on tokens of type `K`, e.g. the addresses of
+/// futexes or the PIDs of children.
+///
+/// Wakeups are never lost between checking a condition and parking: a
+/// [`Wait`] completes if any token has been woken since its creation, so the
+/// waiter should create it before checking its condition, as with event
+/// listeners. In turn, waits may complete spuriously and should be retried.
+#[derive(Debug)]
+pub struct WaitQueue<K> {
+    waiters: Mutex<HashMap<K, VecDeque<(usize, Waker)>, RandomState>>,
+    /// Bumped on every wakeup.
+    gen: AtomicU64,
+}
+
+impl<K: Eq + Hash + Clone> WaitQueue<K> {
+    pub fn new() -> Self {
+        WaitQueue {
+            waiters: Default::default(),
+            gen: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for a wakeup on `token`.
+    pub fn wait(&self, token: K) -> Wait<'_, K> {
+        Wait {
+            queue: self,
+            token,
+            gen: self.gen.load(SeqCst),
+            id: None,
+        }
+    }
+
+    /// Park `waker` on `token` for a poll-based waiter, which should check its
+    /// condition again afterwards.
+    pub fn register(&self, token: K, waker: &Waker) {
+        ksync::critical(|| {
+            let mut waiters = self.waiters.lock();
+            let list = waiters.entry(token).or_default();
+            if !list.iter().any(|(_, w)| w.will_wake(waker)) {
+                list.push_back((POLLER, waker.clone()));
+            }
+        })
+    }
+
+    /// Wake up to `n` waiters on `token` in the order they parked, returning
+    /// the number of them woken.
+    pub fn wake(&self, token: &K, n: usize) -> usize {
+        let woken = ksync::critical(|| {
+            self.gen.fetch_add(1, SeqCst);
+            let mut waiters = self.waiters.lock();
+            let Some(list) = waiters.get_mut(token) else {
+                return Vec::new();
+            };
+            let woken = list.drain(..n.min(list.len())).collect::<Vec<_>>();
+            if list.is_empty() {
+                waiters.remove(token);
+            }
+            woken
+        });
+        let count = woken.len();
+        woken.into_iter().for_each(|(_, waker)| waker.wake());
+        count
+    }
+
+    /// Wake all the waiters on `token`, returning the number of them.
+    pub fn wake_all(&self, token: &K) -> usize {
+        self.wake(token, usize::MAX)
+    }
+
+    /// Remove the waiter `id` from `token` if still parked.
+    fn remove(&self, token: &K, id: usize) {
+        ksync::critical(|| {
+            let mut waiters = self.waiters.lock();
+            if let Some(list) = waiters.get_mut(token) {
+                list.retain(|&(i, _)| i != id);
+                if list.is_empty() {
+                    waiters.remove(token);
+                }
+            }
+        })
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for WaitQueue<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future for [`WaitQueue::wait`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Wait<'a, K: Eq + Hash + Clone> {
+    queue: &'a WaitQueue<K>,
+    token: K,
+    /// The generation of the queue when the wait was created.
+    gen: u64,
+    id: Option<usize>,
+}
+
+// The token is never pinned.
+impl<K: Eq + Hash + Clone> Unpin for Wait<'_, K> {}
+
+impl<K: Eq + Hash + Clone> Future for Wait<'_, K> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let queue = this.queue;
+        let woken = ksync::critical(|| {
+            let mut waiters = queue.waiters.lock();
+            let parked = waiters.get_mut(&this.token).and_then(|list| {
+                let id = this.id?;
+                list.iter_mut().find(|(i, _)| *i == id)
+            });
+            match parked {
+                Some((_, waker)) => {
+                    if !waker.will_wake(cx.waker()) {
+                        *waker = cx.waker().clone();
+                    }
+                    false
+                }
+                // Taken out by a wakeup.
+                None if this.id.is_some() => true,
+                // Woken before the first poll.
+                None if queue.gen.load(SeqCst) != this.gen => true,
+                None => {
+                    let id = ID_ALLOC.fetch_add(1, SeqCst);
+                    let list = waiters.entry(this.token.clone()).or_default();
+                    list.push_back((id, cx.waker().clone()));
+                    this.id = Some(id);
+                    false
+                }
+            }
+        });
+        if woken {
+            this.id = None;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for Wait<'_, K> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.queue.remove(&self.token, id);
+        }
+    }
+}
@@ -3,11 +3,15 @@ use core::{cmp, iter, num, pin::pin, slice, str};
 
 use async_trait::async_trait;
 use futures_util::{stream, Stream, StreamExt};
-use ksc_core::Error::{self, EEXIST, EINVAL, EISDIR, ENOENT, ENOSYS, ENOTDIR, ENOTEMPTY};
+use ksc_core::Error::{
+    self, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOSYS, ENOTDIR, ENOTEMPTY, EXDEV,
+};
 use umifs::{
     path::Path,
     traits::{Directory, DirectoryMut, Entry, Io, IoExt},
-    types::{FileType, IoSlice, IoSliceMut, Metadata, OpenOptions, Permissions, SeekFrom},
+    types::{
+        FileType, IoSlice, IoSliceMut, Metadata, OpenOptions, Permissions, RenameFlags, SeekFrom,
+    },
 };
 
 use crate::{
@@ -326,12 +330,24 @@ impl<T: TimeProvider> FatDir<T> {
         Err(EINVAL)
     }
 
+    /// Rename an entry. Replacing an existing destination is not supported, so
+    /// it fails with `EEXIST` unless `flags` contains
+    /// [`RenameFlags::EXCHANGE`].
+    ///
+    /// The exchange swaps the contents of the two short entries in place, so
+    /// both names are present throughout, though an I/O failure in the middle
+    /// of it may leave both pointing to the same data.
     pub async fn rename(
         &self,
         src_path: &Path,
         dst_dir: &FatDir<T>,
         dst_path: &Path,
+        flags: RenameFlags,
     ) -> Result<(), Error> {
+        if flags.contains(RenameFlags::NOREPLACE | RenameFlags::EXCHANGE) {
+            return Err(EINVAL);
+        }
+
         let mut src_storage: Option<Self> = None;
         let mut dst_storage: Option<Self> = None;
         let mut src_node = self;
@@ -357,7 +373,9 @@ impl<T: TimeProvider> FatDir<T> {
             dst_node = dst_storage.insert(e.to_dir().await?);
         };
 
-        self.rename_internal(src_name, dst_dir, dst_name).await
+        src_node
+            .rename_internal(src_name, dst_node, dst_name, flags)
+            .await
     }
 
     async fn rename_internal(
@@ -365,6 +383,7 @@ impl<T: TimeProvider> FatDir<T> {
         src_name: &str,
         dst_dir: &FatDir<T>,
         dst_name: &str,
+        flags: RenameFlags,
     ) -> Result<(), Error> {
         // find existing file
         let e = self.find_entry(src_name, None, None).await?;
@@ -378,9 +397,16 @@ impl<T: TimeProvider> FatDir<T> {
                     // nothing to do
                     return Ok(());
                 }
+                if flags.contains(RenameFlags::EXCHANGE) {
+                    return self.exchange(&e, dst_dir, dst_e).await;
+                }
                 // destination file exists and it is not the same as source file - fail
                 return Err(EEXIST);
             }
+            // nothing to exchange with
+            DirEntryOrShortName::ShortName(_) if flags.contains(RenameFlags::EXCHANGE) => {
+                return Err(ENOENT)
+            }
             // destionation file does not exist, short name has been generated
             DirEntryOrShortName::ShortName(short_name) => short_name,
         };
@@ -389,8 +415,64 @@ impl<T: TimeProvider> FatDir<T> {
         // save new directory entry
         let sfn_entry = e.data.renamed(short_name);
         dst_dir.write_entry(dst_name, sfn_entry).await?;
+        if e.is_dir() {
+            let parent = dst_dir.file.first_cluster().await;
+            self.reparent(&e, parent).await?;
+        }
         Ok(())
     }
+
+    async fn exchange(
+        &self,
+        src_e: &DirEntry<T>,
+        dst_dir: &FatDir<T>,
+        dst_e: &DirEntry<T>,
+    ) -> Result<(), Error> {
+        // swap the contents of the short entries in place, each name keeping
+        // its own short name and long name entries
+        let device = &**self.file.fs.fat.device();
+        let src_data = src_e.data.exchanged(&dst_e.data);
+        let dst_data = dst_e.data.exchanged(&src_e.data);
+        device
+            .write_all_at(src_e.entry_pos as usize, &src_data.to_bytes())
+            .await?;
+        device
+            .write_all_at(dst_e.entry_pos as usize, &dst_data.to_bytes())
+            .await?;
+
+        // the directories swapped across parents point back to their new ones
+        if src_e.is_dir() {
+            let parent = dst_dir.file.first_cluster().await;
+            self.reparent(src_e, parent).await?;
+        }
+        if dst_e.is_dir() {
+            let parent = self.file.first_cluster().await;
+            dst_dir.reparent(dst_e, parent).await?;
+        }
+        Ok(())
+    }
+
+    /// Point the `..` entry of the directory `e`, moved out of `self`, to
+    /// `parent`, the first cluster of its new parent directory.
+    async fn reparent(&self, e: &DirEntry<T>, parent: Option<u32>) -> Result<(), Error> {
+        if self.file.first_cluster().await == parent {
+            return Ok(());
+        }
+        let dir = e.to_dir().await?;
+        let pos = DIR_ENTRY_SIZE as usize;
+        let mut buf = [0; DIR_ENTRY_SIZE as usize];
+        dir.file.read_exact_at(pos, &mut buf).await?;
+        match DirEntryData::parse(&buf)? {
+            (_, DirEntryData::File(mut data))
+                if *data.name() == ShortNameGenerator::generate_dotdot() =>
+            {
+                data.set_first_cluster(parent);
+                dir.file.write_all_at(pos, &data.to_bytes()).await
+            }
+            // not a directory made by us, nothing to fix
+            _ => Ok(()),
+        }
+    }
 }
 
 #[async_trait]
@@ -469,6 +551,10 @@ impl<T: TimeProvider> Entry for FatDir<T> {
         }
     }
 
+    fn fs_id(&self) -> Option<usize> {
+        self.file.fs_id()
+    }
+
     fn to_dir(self: Arc<Self>) -> Option<Arc<dyn Directory>> {
         Some(self as _)
     }
@@ -516,11 +602,15 @@ impl<T: TimeProvider> DirectoryMut for FatDir<T> {
         src_path: &Path,
         dst_parent: Arc<dyn DirectoryMut>,
         dst_path: &Path,
+        flags: RenameFlags,
     ) -> Result<(), Error> {
+        if dst_parent.fs_id() != self.fs_id() {
+            return Err(EXDEV);
+        }
         let Ok(dst_parent) = dst_parent.into_any().downcast::<Self>() else {
             return Err(ENOSYS)
         };
-        (*self).rename(src_path, &dst_parent, dst_path).await
+        (*self).rename(src_path, &dst_parent, dst_path, flags).await
     }
 
     async fn link(
@@ -535,6 +625,17 @@ impl<T: TimeProvider> DirectoryMut for FatDir<T> {
     async fn unlink(&self, path: &Path, expect_dir: Option<bool>) -> Result<(), Error> {
         self.remove(path, expect_dir).await
     }
+
+    async fn reserve_dir_entries(&self, count: usize) -> Result<(), Error> {
+        // Reserve from the end of the used entries, ignoring the deleted holes
+        // before it.
+        let end = self.find_free_entries(u32::MAX).await? as usize;
+        let len = count
+            .checked_mul(DIR_ENTRY_SIZE as usize)
+            .and_then(|len| len.checked_add(end))
+            .ok_or(EINVAL)?;
+        self.file.reserve(len).await
+    }
 }
 
 impl<T: TimeProvider> FatDir<T> {
@@ -544,12 +645,19 @@ impl<T: TimeProvider> FatDir<T> {
         let mut i: u32 = 0;
         loop {
             let mut buf = [0; DIR_ENTRY_SIZE as usize];
-            self.file
-                .read_exact_at((i * DIR_ENTRY_SIZE) as usize, &mut buf)
+            let len = self
+                .file
+                .preadv_exact_at((i * DIR_ENTRY_SIZE) as usize, &mut [&mut buf[..]])
                 .await?;
+            if len != 0 && len != buf.len() {
+                log::error!("unexpected EOF");
+                return Err(EIO);
+            }
 
             let (_, raw_entry) = DirEntryData::parse(&buf)?;
-            if raw_entry.is_end() {
+            // A full directory has no end marker, so the space past its chain
+            // is used, extending the chain.
+            if len == 0 || raw_entry.is_end() {
                 // first unused entry - all remaining space can be used
                 if num_free == 0 {
                     first_free = i;
@@ -178,6 +178,15 @@ impl DirFileEntryData {
         &self.name
     }
 
+    /// The entry of `other` under the name of `self`, along with the case
+    /// flags of the name.
+    pub(crate) fn exchanged(&self, other: &Self) -> Self {
+        const CASE_FLAGS: u8 = (1 << 3) | (1 << 4);
+        let mut sfn_entry = other.renamed(self.name);
+        sfn_entry.reserved_0 = (other.reserved_0 & !CASE_FLAGS) | (self.reserved_0 & CASE_FLAGS);
+        sfn_entry
+    }
+
     fn lowercase_name(&self) -> ShortName {
         let mut name_copy: [u8; SFN_SIZE] = self.name;
         if self.lowercase_basename() {
@@ -465,6 +474,9 @@ pub(crate) struct DirEntryEditor {
     data: DirFileEntryData,
     pos: u64,
     dirty: bool,
+    /// Whether the fields needed for retrieving the file data (size and first
+    /// cluster) have been changed.
+    layout_dirty: bool,
 }
 
 impl DirEntryEditor {
@@ -473,6 +485,7 @@ impl DirEntryEditor {
             data,
             pos,
             dirty: false,
+            layout_dirty: false,
         }
     }
 
@@ -484,6 +497,7 @@ impl DirEntryEditor {
         if first_cluster != self.data.first_cluster() {
             self.data.set_first_cluster(first_cluster);
             self.dirty = true;
+            self.layout_dirty = true;
         }
     }
 
@@ -492,6 +506,7 @@ impl DirEntryEditor {
             Some(n) if size != n => {
                 self.data.set_size(size);
                 self.dirty = true;
+                self.layout_dirty = true;
             }
             _ => {}
         }
@@ -523,6 +538,16 @@ impl DirEntryEditor {
         if self.dirty {
             self.write(device).await?;
             self.dirty = false;
+            self.layout_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Flush the entry only if the fields needed for retrieving the file data
+    /// have been changed.
+    pub(crate) async fn flush_layout(&mut self, device: &dyn Io) -> Result<(), Error> {
+        if self.layout_dirty {
+            self.flush(device).await?;
         }
         Ok(())
     }
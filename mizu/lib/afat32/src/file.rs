@@ -121,6 +121,89 @@ impl<T: TimeProvider> FatFile<T> {
         Ok(())
     }
 
+    /// Move the data of the file to a contiguous run of clusters, if its chain
+    /// is fragmented.
+    ///
+    /// The directory entry is written back pointing to the new chain before
+    /// the old one is freed, so a crash in between only leaks clusters.
+    pub async fn defragment(&self) -> Result<(), Error> {
+        let Some(ref entry) = self.entry else {
+            return Err(ENOSYS)
+        };
+
+        // The `.` and `..` entries of the children would be left behind.
+        if entry.lock().await.inner().is_dir() {
+            return Err(EISDIR);
+        }
+
+        let mut clusters = self.clusters.write().await;
+        let Some(&(start, _)) = clusters.first() else {
+            return Ok(());
+        };
+        let count = u32::try_from(clusters.len())?;
+        let new_start = self.fs.defragment_cluster_chain(start, count).await?;
+        if new_start == start {
+            return Ok(());
+        }
+
+        let mut entry = entry.lock().await;
+        entry.set_first_cluster(Some(new_start));
+        if let Err(err) = entry.flush(&**self.fs.fat.device()).await {
+            entry.set_first_cluster(Some(start));
+            let _ = self.fs.free_cluster_chain(new_start).await;
+            return Err(err);
+        }
+
+        let end = new_start + count - 1;
+        *clusters = (new_start..=end).map(|c| (c, end)).collect();
+        self.fs.free_cluster_chain(start).await
+    }
+
+    /// Grow the cluster chain up front to hold at least `len` bytes, allocating
+    /// the missing clusters contiguously and zeroing them.
+    pub(crate) async fn reserve(&self, len: usize) -> Result<(), Error> {
+        let count = (len + (1 << self.cluster_shift) - 1) >> self.cluster_shift;
+
+        let mut clusters = self.clusters.write().await;
+        let extra = match count.checked_sub(clusters.len()) {
+            Some(extra) if extra > 0 => extra,
+            _ => return Ok(()),
+        };
+        let prev = clusters.last().map(|&(c, _)| c);
+        let extra = u32::try_from(extra)?;
+        let start = self.fs.alloc_contiguous(prev, extra, true).await?;
+        let end = start + extra - 1;
+
+        match prev {
+            Some(prev) if prev + 1 == start => {
+                for (_, e) in clusters.iter_mut().rev() {
+                    if *e != prev {
+                        break;
+                    }
+                    *e = end;
+                }
+            }
+            Some(_) => {}
+            None => {
+                if let Some(ref entry) = self.entry {
+                    entry.lock().await.set_first_cluster(Some(start));
+                }
+            }
+        }
+        clusters.extend((start..=end).map(|c| (c, end)));
+
+        // Directories have no recorded size and span their whole chains.
+        let is_dir = match self.entry {
+            Some(ref entry) => entry.lock().await.inner().size().is_none(),
+            None => true,
+        };
+        if is_dir {
+            let len = clusters.len() << self.cluster_shift;
+            self.len.fetch_max(len, SeqCst);
+        }
+        Ok(())
+    }
+
     fn decomp(&self, offset: usize) -> (usize, usize) {
         let cluster_index = offset >> self.cluster_shift;
         let offset_in_cluster = offset & ((1 << self.cluster_shift) - 1);
@@ -141,9 +224,20 @@ impl<T: TimeProvider> FatFile<T> {
 
     async fn update_read(&self) {
         if let Some(ref entry) = self.entry {
-            let now = self.fs.time_provider.get_current_date();
+            let provider = &self.fs.time_provider;
+            let now = provider.get_current_date_time();
             let mut e = entry.lock().await;
-            e.set_accessed(now);
+            let data = e.inner();
+            // FAT keeps no change time, so the creation time stands in for it.
+            let update = provider.time_policy().should_update_atime(
+                data.accessed().to_instant(),
+                data.modified().to_instant(),
+                data.created().to_instant(),
+                now.to_instant(),
+            );
+            if update {
+                e.set_accessed(now.date);
+            }
         }
     }
 
@@ -166,6 +260,19 @@ impl<T: TimeProvider> FatFile<T> {
         }
         Ok(())
     }
+
+    async fn fsync(&self, datasync: bool) -> Result<(), Error> {
+        let device = self.fs.fat.device();
+        if let Some(ref entry) = self.entry {
+            let mut entry = entry.lock().await;
+            if datasync {
+                entry.flush_layout(&**device).await?;
+            } else {
+                entry.flush(&**device).await?;
+            }
+        }
+        device.fsync(datasync).await
+    }
 }
 
 #[async_trait]
@@ -265,6 +372,8 @@ impl<T: TimeProvider> Io for FatFile<T> {
         //     ioslice_len(&buffer)
         // );
 
+        self.fs.fat.mark_dirty().await?;
+
         let cluster_shift = self.cluster_shift;
         let (cluster_index, offset_in_cluster) = self.decomp(offset);
 
@@ -319,9 +428,10 @@ impl<T: TimeProvider> Io for FatFile<T> {
                 break Ok(written_len);
             }
             let len = rest.min(buffer[0].len());
-            let len = device
+            let res = device
                 .write_at(cluster_offset, &mut [&buffer[0][..len]])
-                .await?;
+                .await;
+            let len = self.fs.record_write(res).await?;
 
             cluster_offset += len;
             offset += len;
@@ -334,6 +444,10 @@ impl<T: TimeProvider> Io for FatFile<T> {
     async fn flush(&self) -> Result<(), Error> {
         self.flush().await
     }
+
+    async fn fsync(&self, datasync: bool) -> Result<(), Error> {
+        self.fsync(datasync).await
+    }
 }
 
 #[async_trait]
@@ -367,4 +481,8 @@ impl<T: TimeProvider> Entry for FatFile<T> {
             last_created: None,
         }
     }
+
+    fn fs_id(&self) -> Option<usize> {
+        Some(Arsc::as_ptr(&self.fs) as *const () as usize)
+    }
 }
@@ -6,7 +6,7 @@ use core::{
 
 use arsc_rs::Arsc;
 use async_trait::async_trait;
-use ksc_core::Error::{self, ENOSYS};
+use ksc_core::Error::{self, EINVAL};
 use spin::RwLock;
 use umifs::{
     traits::{Entry, FileSystem, Io, IoExt},
@@ -15,7 +15,7 @@ use umifs::{
 
 use crate::{
     raw::{BiosParameterBlock, BootSector, FsInfoSector},
-    table::{Fat, RESERVED_FAT_ENTRIES},
+    table::{ClusterBitmap, Fat, FatEntry, FatType, RESERVED_FAT_ENTRIES},
     FatDir, FatFile, TimeProvider,
 };
 
@@ -45,6 +45,18 @@ impl FatStats {
     pub fn free_clusters(&self) -> u32 {
         self.free_clusters
     }
+
+    /// Total size of the clusters usable for file allocation in bytes
+    #[must_use]
+    pub fn total_bytes(&self) -> u64 {
+        u64::from(self.total_clusters) * u64::from(self.cluster_size)
+    }
+
+    /// Size of the free clusters in bytes
+    #[must_use]
+    pub fn free_bytes(&self) -> u64 {
+        u64::from(self.free_clusters) * u64::from(self.cluster_size)
+    }
 }
 
 #[derive(Debug)]
@@ -62,6 +74,26 @@ impl<T: TimeProvider> FatFileSystem<T> {
         device: Arc<dyn Io>,
         block_shift: u32,
         time_provider: T,
+    ) -> Result<Arsc<Self>, Error> {
+        Self::new_impl(device, block_shift, None, time_provider).await
+    }
+
+    /// Mount the volume reading its allocation table from the FAT copy
+    /// `active_fat`, e.g. from the backup FAT when the first one is corrupt.
+    pub async fn new_with_active_fat(
+        device: Arc<dyn Io>,
+        block_shift: u32,
+        active_fat: u8,
+        time_provider: T,
+    ) -> Result<Arsc<Self>, Error> {
+        Self::new_impl(device, block_shift, Some(active_fat), time_provider).await
+    }
+
+    async fn new_impl(
+        device: Arc<dyn Io>,
+        block_shift: u32,
+        active_fat: Option<u8>,
+        time_provider: T,
     ) -> Result<Arsc<Self>, Error> {
         let mut b0 = vec![0; 1 << block_shift];
         device.read_exact_at(0, &mut b0).await?;
@@ -71,9 +103,9 @@ impl<T: TimeProvider> FatFileSystem<T> {
 
         log::trace!("BPB: {bpb:#?}");
 
-        if !bpb.is_fat32() {
-            log::error!("Unsupported FAT file system; only FAT32 is supported");
-            return Err(ENOSYS);
+        if let Err(reason) = bpb.validate_fat32() {
+            log::error!("Unsupported file system, only FAT32 is supported: {reason}");
+            return Err(EINVAL);
         }
 
         let fis = bpb.bytes_from_sectors(bpb.fs_info_sector());
@@ -84,8 +116,15 @@ impl<T: TimeProvider> FatFileSystem<T> {
 
         fis.fix(bpb.total_clusters());
 
+        let fat = match active_fat {
+            Some(active) => Fat::new_with_active(device, &bpb, Some(&fis), active)?,
+            None => Fat::new(device, &bpb, Some(&fis)),
+        };
+        if fat.load_volume_flags().await?.dirty {
+            log::warn!("The volume was not cleanly unmounted");
+        }
         Ok(Arsc::new(FatFileSystem {
-            fat: Fat::new(device, &bpb),
+            fat,
             bpb,
             fs_info: RwLock::new(fis),
             current_status_flags: AtomicU8::new(bpb.status_flags().encode()),
@@ -100,8 +139,8 @@ impl<T: TimeProvider> FatFileSystem<T> {
         prev_cluster: Option<u32>,
         zero: bool,
     ) -> Result<u32, Error> {
-        let hint = ksync::critical(|| self.fs_info.read().next_free_cluster);
-        let cluster = self.fat.allocate(prev_cluster, hint).await?;
+        self.fat.mark_dirty().await?;
+        let cluster = self.fat.allocate(prev_cluster, None).await?;
         if zero {
             write_zeros(
                 &**self.fat.device(),
@@ -112,12 +151,58 @@ impl<T: TimeProvider> FatFileSystem<T> {
         }
         ksync::critical(|| {
             let mut fs_info = self.fs_info.write();
-            fs_info.set_next_free_cluster(cluster + 1);
             fs_info.map_free_clusters(|n| n - 1);
         });
         Ok(cluster)
     }
 
+    pub(crate) async fn alloc_contiguous(
+        &self,
+        prev_cluster: Option<u32>,
+        count: u32,
+        zero: bool,
+    ) -> Result<u32, Error> {
+        self.fat.mark_dirty().await?;
+        let start = self.fat.allocate_contiguous(count).await?;
+        if let Some(prev) = prev_cluster {
+            self.fat.set(prev, FatEntry::Next(start)).await?;
+        }
+        if zero {
+            write_zeros(
+                &**self.fat.device(),
+                self.offset_from_cluster(start) as usize,
+                self.bpb.cluster_size() as usize * count as usize,
+            )
+            .await?;
+        }
+        ksync::critical(|| {
+            let mut fs_info = self.fs_info.write();
+            fs_info.map_free_clusters(|n| n - count);
+        });
+        Ok(start)
+    }
+
+    /// Copy the chain of `count` clusters at `start` to a contiguous run with
+    /// [`Fat::defragment_chain`], accounting the new clusters as allocated.
+    ///
+    /// The original chain is kept for the caller to free once the directory
+    /// entry points to the copy.
+    pub(crate) async fn defragment_cluster_chain(
+        &self,
+        start: u32,
+        count: u32,
+    ) -> Result<u32, Error> {
+        self.fat.mark_dirty().await?;
+        let new_start = self.fat.defragment_chain(start).await?;
+        if new_start != start {
+            ksync::critical(|| {
+                let mut fs_info = self.fs_info.write();
+                fs_info.map_free_clusters(|n| n - count);
+            });
+        }
+        Ok(new_start)
+    }
+
     fn sector_from_cluster(&self, cluster: u32) -> u32 {
         self.bpb.first_data_sector()
             + self
@@ -131,6 +216,7 @@ impl<T: TimeProvider> FatFileSystem<T> {
     }
 
     pub(crate) async fn truncate_cluster_chain(&self, cluster: u32) -> Result<(), Error> {
+        self.fat.mark_dirty().await?;
         let num_free = self.fat.truncate(cluster).await?;
         ksync::critical(|| {
             let mut fs_info = self.fs_info.write();
@@ -140,17 +226,31 @@ impl<T: TimeProvider> FatFileSystem<T> {
     }
 
     pub(crate) async fn free_cluster_chain(&self, cluster: u32) -> Result<(), Error> {
-        let num_free = self.fat.free(cluster).await?;
+        self.fat.mark_dirty().await?;
+        // Account for the clusters freed before an error as well.
+        let progress = self.fat.free_resumable(cluster).await;
         ksync::critical(|| {
             let mut fs_info = self.fs_info.write();
-            fs_info.map_free_clusters(|n| n + num_free);
+            fs_info.map_free_clusters(|n| n + progress.freed);
         });
-        Ok(())
+        match progress.error {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
     }
 
     async fn flush_fs_info(&self) -> Result<(), Error> {
+        let free = self.fat.cached_free_count();
+        let next_free = self.fat.next_free();
         let bytes = ksync::critical(|| {
             let mut fs_info = self.fs_info.write();
+            // The free map of the table is exact, unlike the running count.
+            if let Some(free) = free.filter(|&n| fs_info.free_cluster_count != Some(n)) {
+                fs_info.set_free_cluster_count(free);
+            }
+            if fs_info.next_free_cluster != Some(next_free) {
+                fs_info.set_next_free_cluster(next_free);
+            }
             let dirty = mem::replace(&mut fs_info.dirty, false);
             dirty.then(|| fs_info.to_bytes())
         });
@@ -160,7 +260,8 @@ impl<T: TimeProvider> FatFileSystem<T> {
                 .bpb
                 .bytes_from_sectors(u32::from(self.bpb.fs_info_sector));
             for b in [&prefix, &[0; 480], &suffix] as [&[u8]; 3] {
-                self.fat.device().write_all_at(offset as usize, b).await?;
+                let res = self.fat.device().write_all_at(offset as usize, b).await;
+                self.record_write(res).await?;
             }
         }
         Ok(())
@@ -195,12 +296,41 @@ impl<T: TimeProvider> FatFileSystem<T> {
         free_cluster_count
     }
 
+    /// Set the I/O error bit of the volume if `res` of a write to the device
+    /// is an error, passing it through.
+    pub(crate) async fn record_write<R>(&self, res: Result<R, Error>) -> Result<R, Error> {
+        if let Err(err) = &res {
+            log::error!("Failed to write to the device: {err}");
+            if let Err(err) = self.fat.mark_io_error().await {
+                log::error!("Failed to set the I/O error bit: {err}");
+            }
+        }
+        res
+    }
+
     pub async fn flush(&self) -> Result<(), Error> {
         self.flush_fs_info().await?;
         self.set_dirty_flag(false).await?;
+        self.fat.mark_clean().await?;
         Ok(())
     }
 
+    /// Whether the volume was left dirty by the previous mount, e.g. by a
+    /// crash, in which case it may need a check.
+    pub fn was_dirty(&self) -> bool {
+        self.fat.was_dirty()
+    }
+
+    /// Rewrite the FAT entries left diverged between the mirrors, e.g. by a
+    /// crash between their writes, returning the number of the clusters
+    /// repaired.
+    ///
+    /// This reads the whole table from every mirror, so it is left to an
+    /// explicit check rather than done at mount.
+    pub async fn repair_mirrors(&self) -> Result<usize, Error> {
+        self.fat.repair_mirrors().await
+    }
+
     pub async fn root_dir(self: Arsc<Self>) -> Result<FatDir<T>, Error> {
         FatFile::new(self.clone(), Some(self.bpb.root_dir_first_cluster), None)
             .await
@@ -215,15 +345,56 @@ impl<T: TimeProvider> FatFileSystem<T> {
             self.recalc_free_clusters().await
         };
         FatStats {
-            cluster_size: self.bpb.cluster_size(),
+            cluster_size: self.fat.cluster_size_bytes() as u32,
             total_clusters: self.fat.cluster_count(),
             free_clusters,
         }
     }
 
+    /// The type of the allocation table, decided by the cluster count.
+    pub fn fat_type(&self) -> FatType {
+        self.fat.fat_type()
+    }
+
     pub fn status(&self) -> FsStatusFlags {
         FsStatusFlags::load(&self.current_status_flags)
     }
+
+    /// Check whether there is any free cluster left.
+    ///
+    /// The free cluster count of FSInfo is used if known. Otherwise the first
+    /// free cluster from the next free cluster hint is looked up in the free
+    /// map of the table, and becomes the new hint.
+    pub async fn has_free(&self) -> Result<bool, Error> {
+        let count = ksync::critical(|| self.fs_info.read().free_cluster_count);
+        if let Some(count) = count {
+            return Ok(count > 0);
+        }
+
+        let free = self.fat.first_free(None).await?;
+        if free.is_none() {
+            ksync::critical(|| self.fs_info.write().set_free_cluster_count(0));
+        }
+        Ok(free.is_some())
+    }
+
+    /// Get a bitmap of the allocated clusters of the volume.
+    pub async fn allocation_bitmap(&self) -> Result<ClusterBitmap, Error> {
+        self.fat.allocation_bitmap().await
+    }
+
+    /// Read the volume flags kept in the allocation table, which, unlike
+    /// [`FatFileSystem::status`], are not cached on mount.
+    pub async fn volume_flags(&self) -> Result<FsStatusFlags, Error> {
+        self.fat.volume_flags().await
+    }
+
+    /// Write the volume flags into the allocation table, e.g. marking the
+    /// volume dirty on mount and clean on unmount so that an unclean shutdown
+    /// can be detected on the next mount.
+    pub async fn set_volume_flags(&self, flags: FsStatusFlags) -> Result<(), Error> {
+        self.fat.set_volume_flags(flags).await
+    }
 }
 
 #[async_trait]
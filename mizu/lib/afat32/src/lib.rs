@@ -20,5 +20,6 @@ pub use self::{
     dirent::{DirEntry, FileAttributes},
     file::FatFile,
     fs::{FatFileSystem, FatStats, FsStatusFlags},
+    table::{ClusterBitmap, FatType},
     time::{Date, DateTime, DefaultTimeProvider, NullTimeProvider, Time, TimeProvider},
 };
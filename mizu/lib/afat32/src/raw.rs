@@ -1,7 +1,11 @@
 use concat_arrays::concat_arrays;
 use nom::{bytes, number, IResult};
 
-use crate::{dirent::DIR_ENTRY_SIZE, fs::FsStatusFlags, table::RESERVED_FAT_ENTRIES};
+use crate::{
+    dirent::DIR_ENTRY_SIZE,
+    fs::FsStatusFlags,
+    table::{FatType, RESERVED_FAT_ENTRIES},
+};
 
 fn take_byte_array<const N: usize>(mut input: &[u8]) -> IResult<&[u8], [u8; N]> {
     let data;
@@ -43,6 +47,8 @@ pub struct BiosParameterBlock {
 impl BiosParameterBlock {
     const RESERVED_0: usize = 12;
     const RESERVED_1: usize = 1;
+    /// Cluster numbers from `0x0FFF_FFF7` on are reserved values.
+    const MAX_FAT32_CLUSTERS: u32 = 0x0FFF_FFF7 - RESERVED_FAT_ENTRIES;
 
     pub fn parse(mut input: &[u8]) -> IResult<&[u8], Self> {
         let mut bpb = Self::default();
@@ -110,6 +116,49 @@ impl BiosParameterBlock {
         self.sectors_per_fat_16 == 0
     }
 
+    /// Check that the BPB describes a FAT32 volume this crate can handle,
+    /// returning the reason otherwise.
+    pub fn validate_fat32(&self) -> Result<(), &'static str> {
+        if self.bytes_per_sector == 0 && self.sectors_per_cluster == 0 && self.fats == 0 {
+            // exFAT zeroes out the legacy BPB.
+            return Err("the BPB is empty; maybe an exFAT volume");
+        }
+        if !self.bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&self.bytes_per_sector)
+        {
+            return Err("invalid bytes per sector");
+        }
+        if !self.sectors_per_cluster.is_power_of_two() {
+            return Err("invalid sectors per cluster");
+        }
+        if self.reserved_sectors == 0 || self.fats == 0 {
+            return Err("no reserved sectors or FAT copies");
+        }
+        if !self.is_fat32() || self.root_entries != 0 {
+            return Err("FAT12/FAT16 layout");
+        }
+        if self.sectors_per_fat_32 == 0 {
+            return Err("empty FAT");
+        }
+        if self.total_sectors() <= self.first_data_sector() {
+            return Err("no data sectors");
+        }
+        let clusters = self.total_clusters();
+        // Volumes with fewer clusters are FAT12 or FAT16, regardless of the
+        // layout of their BPBs.
+        if self.fat_type() != FatType::Fat32 {
+            return Err("too few clusters for FAT32");
+        }
+        if clusters > Self::MAX_FAT32_CLUSTERS {
+            return Err("too many clusters for FAT32");
+        }
+        let fat_entries = self.bytes_from_sectors(self.sectors_per_fat_32) / 4;
+        if fat_entries < u64::from(clusters + RESERVED_FAT_ENTRIES) {
+            return Err("FAT too small for the clusters");
+        }
+        Ok(())
+    }
+
     pub fn sectors_per_fat(&self) -> u32 {
         if self.is_fat32() {
             self.sectors_per_fat_32
@@ -148,6 +197,10 @@ impl BiosParameterBlock {
         data_sectors / u32::from(self.sectors_per_cluster)
     }
 
+    pub fn fat_type(&self) -> FatType {
+        FatType::from_clusters(self.total_clusters())
+    }
+
     pub fn bytes_from_sectors(&self, sectors: u32) -> u64 {
         // Note: total number of sectors is a 32 bit number so offsets have to be 64 bit
         u64::from(sectors) * u64::from(self.bytes_per_sector)
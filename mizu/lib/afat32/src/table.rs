@@ -1,18 +1,128 @@
-use alloc::{sync::Arc, vec, vec::Vec};
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
 use core::{
-    fmt,
+    cmp::Reverse,
+    fmt, iter,
     mem::{self, MaybeUninit},
-    ops::{Bound, Range, RangeBounds},
+    ops::{Bound, Range, RangeBounds, RangeInclusive},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::SeqCst},
 };
 
-use futures_util::{future::try_join_all, stream, FutureExt, Stream, StreamExt, TryStreamExt};
-use ksc_core::Error::{self, EINVAL, ENOSPC};
+use futures_util::{
+    future::{self, try_join_all, Either},
+    stream, Stream, StreamExt, TryStreamExt,
+};
+use ksc_core::Error::{self, EINVAL, EIO, ENOSPC, EOPNOTSUPP};
+use ksync::Mutex;
 use umifs::traits::{Io, IoExt};
 
-use crate::raw::BiosParameterBlock;
+use crate::{
+    raw::{BiosParameterBlock, FsInfoSector},
+    FsStatusFlags,
+};
 
 pub const RESERVED_FAT_ENTRIES: u32 = 2;
 
+/// The reserved high nibble of FAT32 entries.
+const RESERVED_BITS: u32 = 0xf000_0000;
+
+/// The width of the entries of a FAT, decided by the cluster count of the
+/// volume alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    const MIN_FAT16_CLUSTERS: u32 = 4085;
+    const MIN_FAT32_CLUSTERS: u32 = 65525;
+
+    pub const fn from_clusters(total_clusters: u32) -> Self {
+        if total_clusters < Self::MIN_FAT16_CLUSTERS {
+            FatType::Fat12
+        } else if total_clusters < Self::MIN_FAT32_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    /// The size of an entry in bits.
+    const fn entry_bits(self) -> usize {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    /// The byte offset of the entry of `cluster` from the start of the table.
+    /// FAT12 entries at odd clusters start at the high nibble of this byte.
+    const fn entry_offset(self, cluster: u32) -> usize {
+        cluster as usize * self.entry_bits() / 8
+    }
+
+    /// The bits of an entry holding cluster numbers and special values.
+    const fn mask(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0fff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    const fn bad(self) -> u32 {
+        self.mask() - 8
+    }
+
+    /// The value written for end-of-chain; any value above [`FatType::bad`]
+    /// is read as one.
+    const fn end(self) -> u32 {
+        self.mask()
+    }
+
+    /// The special values, which are never valid cluster numbers.
+    const fn special(self) -> RangeInclusive<u32> {
+        self.bad()..=self.end()
+    }
+
+    /// The clean-shutdown and no-I/O-error bits in the entry of cluster 1, or
+    /// `None` for FAT12, which has no volume flags.
+    const fn volume_bits(self) -> Option<(u32, u32)> {
+        match self {
+            FatType::Fat12 => None,
+            FatType::Fat16 => Some((0x8000, 0x4000)),
+            FatType::Fat32 => Some((0x0800_0000, 0x0400_0000)),
+        }
+    }
+
+    /// Decode the FAT12 entry of `cluster` from the 16 bits starting at its
+    /// byte offset.
+    const fn fat12_decode(cluster: u32, bytes: [u8; 2]) -> u32 {
+        let raw = u16::from_le_bytes(bytes) as u32;
+        if cluster % 2 == 1 {
+            raw >> 4
+        } else {
+            raw & 0x0fff
+        }
+    }
+
+    /// Store the FAT12 entry `raw` of `cluster` into the 16 bits starting at
+    /// its byte offset, keeping the nibble shared with the neighbouring
+    /// entry.
+    const fn fat12_encode(cluster: u32, bytes: [u8; 2], raw: u32) -> [u8; 2] {
+        let old = u16::from_le_bytes(bytes);
+        let raw = (raw & 0x0fff) as u16;
+        let new = if cluster % 2 == 1 {
+            (old & 0x000f) | (raw << 4)
+        } else {
+            (old & 0xf000) | raw
+        };
+        new.to_le_bytes()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum FatEntry {
     Free,
@@ -22,10 +132,11 @@ pub enum FatEntry {
 }
 
 impl FatEntry {
-    pub fn from_raw(raw: u32, cluster: u32) -> Self {
-        match raw & 0x0fff_ffff {
-            0 if (0x0FFF_FFF7..=0x0FFF_FFFF).contains(&cluster) => {
-                let tmp = if cluster == 0x0FFF_FFF7 {
+    pub fn from_raw(raw: u32, cluster: u32, fat_type: FatType) -> Self {
+        let special = fat_type.special();
+        match raw & fat_type.mask() {
+            0 if special.contains(&cluster) => {
+                let tmp = if cluster == fat_type.bad() {
                     "BAD_CLUSTER"
                 } else {
                     "end-of-chain"
@@ -38,10 +149,10 @@ impl FatEntry {
                               // chain
             }
             0 => FatEntry::Free,
-            0x0FFF_FFF7 => FatEntry::Bad,
-            0x0FFF_FFF8..=0x0FFF_FFFF => FatEntry::End,
-            n if (0x0FFF_FFF7..=0x0FFF_FFFF).contains(&cluster) => {
-                let tmp = if cluster == 0x0FFF_FFF7 {
+            n if n == fat_type.bad() => FatEntry::Bad,
+            n if special.contains(&n) => FatEntry::End,
+            n if special.contains(&cluster) => {
+                let tmp = if cluster == fat_type.bad() {
                     "BAD_CLUSTER"
                 } else {
                     "end-of-chain"
@@ -54,12 +165,12 @@ impl FatEntry {
         }
     }
 
-    pub fn into_raw(self, cluster: u32, old_raw: u32) -> u32 {
-        if self == FatEntry::Free && (0x0FFF_FFF7..=0x0FFF_FFFF).contains(&cluster) {
+    pub fn into_raw(self, cluster: u32, old_raw: u32, fat_type: FatType) -> u32 {
+        if self == FatEntry::Free && fat_type.special().contains(&cluster) {
             // NOTE: it is technically allowed for them to store FAT chain loops,
             //       or even have them all store value '4' as their next cluster.
             //       Some believe only FatEntry::Bad should be allowed for this edge case.
-            let tmp = if cluster == 0x0FFF_FFF7 {
+            let tmp = if cluster == fat_type.bad() {
                 "BAD_CLUSTER"
             } else {
                 "end-of-chain"
@@ -71,8 +182,8 @@ impl FatEntry {
         };
         let raw = match self {
             FatEntry::Free => 0,
-            FatEntry::Bad => 0x0FFF_FFF7,
-            FatEntry::End => 0x0FFF_FFFF,
+            FatEntry::Bad => fat_type.bad(),
+            FatEntry::End => fat_type.end(),
             FatEntry::Next(n) => n,
         };
         old_raw | raw
@@ -81,25 +192,65 @@ impl FatEntry {
 
 pub struct Fat {
     device: Arc<dyn Io>,
+    fat_type: FatType,
     start_offset: usize,
     cluster_count: u32,
     mirrors: u8,
+    /// The mirror that entries are read from.
+    read_mirror: u8,
+    data_offset: usize,
+    cluster_size: usize,
+    /// Whether the volume was left dirty by the previous mount.
+    was_dirty: AtomicBool,
+    /// Whether the dirty bit has been set by this mount.
+    dirty: AtomicBool,
+    io_error: AtomicBool,
+    /// Serializes the read-modify-write of FAT12 entries.
+    fat12_lock: Mutex<()>,
+    /// Built by the first lookup of a free cluster, and updated by every write
+    /// of an entry afterwards.
+    free_map: spin::Mutex<Option<FreeMap>>,
+    /// The clusters being freed by [`Fat::free_resumable`], which are kept out
+    /// of the free map until unlinked from their chain.
+    held: spin::Mutex<Vec<Range<u32>>>,
+    /// The entries written while the free map is being built.
+    scan_log: spin::Mutex<ScanLog>,
+    /// Bumped by every write of an entry, so that scans racing with them are
+    /// not cached.
+    write_gen: AtomicU64,
+    /// The chains recently walked by [`Fat::all_clusters`].
+    chains: spin::Mutex<ChainCache>,
+    /// Where the search for a free cluster starts by default, as
+    /// `FSI_Nxt_Free` of FSInfo.
+    next_free: AtomicU32,
+    /// The free cluster count of FSInfo, or `u32::MAX` if unknown.
+    stored_free: u32,
+    /// Cleared once the device reports that it cannot discard.
+    discard: AtomicBool,
 }
 
 impl fmt::Debug for Fat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Fat")
+            .field("fat_type", &self.fat_type)
             .field("start_offset", &self.start_offset)
             .field("cluster_count", &self.cluster_count)
             .field("mirrors", &self.mirrors)
+            .field("read_mirror", &self.read_mirror)
+            .field("data_offset", &self.data_offset)
+            .field("cluster_size", &self.cluster_size)
             .finish()
     }
 }
 
 impl Fat {
-    const ENTRY_SIZE: usize = mem::size_of::<u32>();
-
-    pub fn new(device: Arc<dyn Io>, bpb: &BiosParameterBlock) -> Self {
+    /// Create the table described by `bpb`, seeding the free cluster hints
+    /// with `fs_info` if present. FAT12 and FAT16 volumes have no FSInfo.
+    pub fn new(
+        device: Arc<dyn Io>,
+        bpb: &BiosParameterBlock,
+        fs_info: Option<&FsInfoSector>,
+    ) -> Self {
         let sectors_per_fat = bpb.sectors_per_fat();
         let mirroring_enabled = bpb.mirroring_enabled();
         let (fat_first_sector, mirrors) = if mirroring_enabled {
@@ -109,32 +260,98 @@ impl Fat {
             let fat_first_sector = u32::from(bpb.reserved_sectors) + active_fat * sectors_per_fat;
             (fat_first_sector, 1)
         };
+        let cluster_count = bpb.total_clusters();
+        let allocable_range = RESERVED_FAT_ENTRIES..(cluster_count + RESERVED_FAT_ENTRIES);
+        let next_free = fs_info.and_then(|fs_info| fs_info.next_free_cluster);
+        let next_free = next_free.filter(|next| allocable_range.contains(next));
+        let stored_free = fs_info.and_then(|fs_info| fs_info.free_cluster_count);
+        let stored_free = stored_free.filter(|&free| free <= cluster_count);
         Fat {
             device,
+            fat_type: bpb.fat_type(),
             start_offset: bpb.bytes_from_sectors(fat_first_sector) as usize,
-            cluster_count: bpb.total_clusters(),
+            cluster_count,
             mirrors,
+            read_mirror: 0,
+            data_offset: bpb.bytes_from_sectors(bpb.first_data_sector()) as usize,
+            cluster_size: bpb.cluster_size() as usize,
+            was_dirty: AtomicBool::new(false),
+            dirty: AtomicBool::new(false),
+            io_error: AtomicBool::new(false),
+            fat12_lock: Mutex::new(()),
+            free_map: spin::Mutex::new(None),
+            held: Default::default(),
+            scan_log: Default::default(),
+            write_gen: AtomicU64::new(0),
+            chains: Default::default(),
+            next_free: AtomicU32::new(next_free.unwrap_or(allocable_range.start)),
+            stored_free: stored_free.unwrap_or(u32::MAX),
+            discard: AtomicBool::new(true),
         }
     }
 
+    /// Like [`Fat::new`], but read the entries from the FAT copy `active`
+    /// regardless of the active FAT recorded in the BPB, e.g. for recovering
+    /// a volume from its backup FAT.
+    ///
+    /// With mirroring enabled, writes still go to all the copies; otherwise
+    /// only `active` is used.
+    pub fn new_with_active(
+        device: Arc<dyn Io>,
+        bpb: &BiosParameterBlock,
+        fs_info: Option<&FsInfoSector>,
+        active: u8,
+    ) -> Result<Self, Error> {
+        if active >= bpb.fats {
+            return Err(EINVAL);
+        }
+        let mut fat = Fat::new(device, bpb, fs_info);
+        if bpb.mirroring_enabled() {
+            fat.read_mirror = active;
+        } else {
+            let fat_first_sector =
+                u32::from(bpb.reserved_sectors) + u32::from(active) * bpb.sectors_per_fat();
+            fat.start_offset = bpb.bytes_from_sectors(fat_first_sector) as usize;
+        }
+        Ok(fat)
+    }
+
     pub fn device(&self) -> &Arc<dyn Io> {
         &self.device
     }
 
+    pub const fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
     pub const fn size(&self) -> usize {
-        self.cluster_count as usize * Self::ENTRY_SIZE
+        (self.cluster_count as usize * self.fat_type.entry_bits()).div_ceil(8)
     }
 
     pub const fn cluster_count(&self) -> u32 {
         self.cluster_count
     }
 
+    /// The size of a cluster in bytes.
+    pub const fn cluster_size_bytes(&self) -> usize {
+        self.cluster_size
+    }
+
+    /// The total size of all the data clusters in bytes.
+    pub const fn total_bytes(&self) -> u64 {
+        self.cluster_count as u64 * self.cluster_size as u64
+    }
+
     pub const fn allocable_range(&self) -> Range<u32> {
         RESERVED_FAT_ENTRIES..(self.cluster_count + RESERVED_FAT_ENTRIES)
     }
 
     fn offset(&self, mirror: u8, cluster: u32) -> usize {
-        self.start_offset + self.size() * mirror as usize + cluster as usize * Self::ENTRY_SIZE
+        self.start_offset + self.size() * mirror as usize + self.fat_type.entry_offset(cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        self.data_offset + (cluster - RESERVED_FAT_ENTRIES) as usize * self.cluster_size
     }
 
     async fn get_raw(&self, cluster: u32) -> Result<u32, Error> {
@@ -142,11 +359,18 @@ impl Fat {
         if cluster >= self.allocable_range().end {
             return Err(EINVAL);
         }
+        let len = match self.fat_type {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
         self.device
-            .read_exact_at(self.offset(0, cluster), &mut buf)
+            .read_exact_at(self.offset(self.read_mirror, cluster), &mut buf[..len])
             .await?;
 
-        Ok(u32::from_le_bytes(buf))
+        Ok(match self.fat_type {
+            FatType::Fat12 => FatType::fat12_decode(cluster, [buf[0], buf[1]]),
+            FatType::Fat16 | FatType::Fat32 => u32::from_le_bytes(buf),
+        })
     }
 
     /// # Safety
@@ -154,6 +378,7 @@ impl Fat {
     /// The buf must be written zeros.
     async unsafe fn get_range_raw(
         &self,
+        mirror: u8,
         start: u32,
         buf: &mut [MaybeUninit<u32>],
     ) -> Result<usize, Error> {
@@ -165,10 +390,15 @@ impl Fat {
             return Ok(0);
         }
         let read_len = (end - start) as usize;
+        if self.fat_type != FatType::Fat32 {
+            return self
+                .get_range_packed(mirror, start, end, &mut buf[..read_len])
+                .await;
+        }
         let bytes = MaybeUninit::slice_as_bytes_mut(&mut buf[0..read_len]);
 
         self.device
-            .read_exact_at(self.offset(0, start), unsafe {
+            .read_exact_at(self.offset(mirror, start), unsafe {
                 MaybeUninit::slice_assume_init_mut(bytes)
             })
             .await?;
@@ -176,6 +406,35 @@ impl Fat {
         Ok(read_len)
     }
 
+    /// The FAT12/FAT16 path of [`Fat::get_range_raw`], whose entries are
+    /// narrower than the buffer elements and have to be unpacked one by one.
+    async fn get_range_packed(
+        &self,
+        mirror: u8,
+        start: u32,
+        end: u32,
+        buf: &mut [MaybeUninit<u32>],
+    ) -> Result<usize, Error> {
+        let fat_type = self.fat_type;
+        let first = fat_type.entry_offset(start);
+        // The last FAT12 entry may straddle into the next byte.
+        let last = fat_type.entry_offset(end - 1) + 2;
+        let mut bytes = vec![0; last - first];
+        self.device
+            .read_exact_at(self.offset(mirror, start), &mut bytes)
+            .await?;
+
+        for (slot, cluster) in buf.iter_mut().zip(start..end) {
+            let pos = fat_type.entry_offset(cluster) - first;
+            let pair = [bytes[pos], bytes[pos + 1]];
+            slot.write(match fat_type {
+                FatType::Fat12 => FatType::fat12_decode(cluster, pair),
+                _ => u16::from_le_bytes(pair).into(),
+            });
+        }
+        Ok(buf.len())
+    }
+
     pub async fn get_range<'a>(
         &self,
         start: u32,
@@ -183,13 +442,14 @@ impl Fat {
     ) -> Result<impl Iterator<Item = (u32, FatEntry)> + Send + Clone + 'a, Error> {
         buf.fill(0);
         // SAFETY: init to uninit is safe.
-        let len = unsafe { self.get_range_raw(start, mem::transmute(&mut *buf)) }.await?;
+        let len = unsafe { self.get_range_raw(self.read_mirror, start, mem::transmute(&mut *buf)) }
+            .await?;
 
+        let fat_type = self.fat_type;
         let zip = buf[..len].iter().zip(start..);
-        Ok(zip.map(|(&raw, cluster)| (cluster, FatEntry::from_raw(raw, cluster))))
+        Ok(zip.map(move |(&raw, cluster)| (cluster, FatEntry::from_raw(raw, cluster, fat_type))))
     }
 
-    #[allow(dead_code)]
     pub async fn set_range(
         &self,
         start: u32,
@@ -197,24 +457,36 @@ impl Fat {
         entry: FatEntry,
     ) -> Result<(), Error> {
         buf.fill(0);
-        let len = unsafe { self.get_range_raw(start, mem::transmute(&mut *buf)) }.await?;
+        let len = unsafe { self.get_range_raw(self.read_mirror, start, mem::transmute(&mut *buf)) }
+            .await?;
 
+        let fat_type = self.fat_type;
         for (raw, cluster) in buf[..len].iter_mut().zip(start..) {
-            let old = *raw & 0xf000_0000;
-            *raw = entry.into_raw(cluster, old)
+            let old = *raw & !fat_type.mask();
+            *raw = entry.into_raw(cluster, old, fat_type)
+        }
+        if fat_type != FatType::Fat32 {
+            for (&raw, cluster) in buf[..len].iter().zip(start..) {
+                self.set_raw(cluster, raw).await?;
+            }
+            return Ok(());
         }
 
         // SAFETY: init to uninit is safe.
-        let buf: &[MaybeUninit<u32>] = unsafe { mem::transmute(&buf[..len]) };
+        let uninit: &[MaybeUninit<u32>] = unsafe { mem::transmute(&buf[..len]) };
         // SAFETY: All bytes are valid.
         let bytes: &[u8] =
-            unsafe { MaybeUninit::slice_assume_init_ref(MaybeUninit::slice_as_bytes(buf)) };
+            unsafe { MaybeUninit::slice_assume_init_ref(MaybeUninit::slice_as_bytes(uninit)) };
 
-        try_join_all((0..self.mirrors).map(|mirror| async move {
+        let res = try_join_all((0..self.mirrors).map(|mirror| async move {
             let offset = self.offset(mirror, start);
             self.device.write_all_at(offset, bytes).await
         }))
-        .await?;
+        .await;
+        for (&raw, cluster) in buf[..len].iter().zip(start..) {
+            self.note_write(cluster, res.as_ref().ok().map(|_| raw));
+        }
+        res?;
 
         Ok(())
     }
@@ -222,24 +494,428 @@ impl Fat {
     pub async fn get(&self, cluster: u32) -> Result<FatEntry, Error> {
         self.get_raw(cluster)
             .await
-            .map(|raw| FatEntry::from_raw(raw, cluster))
+            .map(|raw| FatEntry::from_raw(raw, cluster, self.fat_type))
     }
 
     pub async fn set(&self, cluster: u32, entry: FatEntry) -> Result<(), Error> {
-        let old = self.get_raw(cluster).await? & 0xf000_0000;
-        let raw = entry.into_raw(cluster, old);
+        let fat_type = self.fat_type;
+        let old = self.get_raw(cluster).await? & !fat_type.mask();
+        let raw = entry.into_raw(cluster, old, fat_type);
+        self.set_raw(cluster, raw).await
+    }
 
-        let buffer = &raw.to_le_bytes();
-        try_join_all((0..self.mirrors).map(|mirror| async move {
-            let offset = self.offset(mirror, cluster);
-            self.device.write_all_at(offset, buffer).await
-        }))
-        .await?;
+    async fn set_raw(&self, cluster: u32, raw: u32) -> Result<(), Error> {
+        // FAT12 entries share a byte with their neighbours, so their
+        // read-modify-writes are serialized against each other.
+        let _guard = match self.fat_type {
+            FatType::Fat12 => Some(self.fat12_lock.lock().await),
+            _ => None,
+        };
+        let writes = (0..self.mirrors).map(|mirror| self.write_entry(mirror, cluster, raw));
+        let res = try_join_all(writes).await;
+        self.note_write(cluster, res.as_ref().ok().map(|_| raw));
+        res?;
 
         Ok(())
     }
 
-    async fn find_free<R>(&self, cluster_range: R) -> Result<u32, Error>
+    /// Write `raw` into the entry of `cluster` in `mirror` only.
+    async fn write_entry(&self, mirror: u8, cluster: u32, raw: u32) -> Result<(), Error> {
+        let offset = self.offset(mirror, cluster);
+        match self.fat_type {
+            FatType::Fat12 => {
+                let mut pair = [0; 2];
+                self.device.read_exact_at(offset, &mut pair).await?;
+                let pair = FatType::fat12_encode(cluster, pair, raw);
+                self.device.write_all_at(offset, &pair).await
+            }
+            FatType::Fat16 => {
+                let bytes = (raw as u16).to_le_bytes();
+                self.device.write_all_at(offset, &bytes).await
+            }
+            FatType::Fat32 => self.device.write_all_at(offset, &raw.to_le_bytes()).await,
+        }
+    }
+
+    /// Record the write of `raw` into the entry of `cluster` in the free map,
+    /// or drop the map if the write failed (`None`), since the entry on disk
+    /// is unknown then. The cached chains containing `cluster` are dropped
+    /// either way.
+    fn note_write(&self, cluster: u32, raw: Option<u32>) {
+        if !self.allocable_range().contains(&cluster) {
+            return;
+        }
+        let mask = self.fat_type.mask();
+        ksync::critical(|| {
+            let mut map = self.free_map.lock();
+            self.write_gen.fetch_add(1, SeqCst);
+            match raw {
+                Some(raw) => {
+                    let held = self.held.lock().iter().any(|run| run.contains(&cluster));
+                    let allocated = raw & mask != 0 || held;
+                    match &mut *map {
+                        Some(map) => map.mark(cluster, allocated),
+                        None => {
+                            let mut log = self.scan_log.lock();
+                            if log.scans > 0 {
+                                log.writes.push((cluster, allocated));
+                            }
+                        }
+                    }
+                }
+                None => {
+                    *map = None;
+                    self.scan_log.lock().failures += 1;
+                }
+            }
+            drop(map);
+            self.chains.lock().invalidate(cluster);
+        })
+    }
+
+    /// Drop the cached chains containing `start`, after the chain has been
+    /// modified bypassing this table.
+    pub fn invalidate_chain_cache(&self, start: u32) {
+        ksync::critical(|| self.chains.lock().invalidate(start))
+    }
+
+    /// Build the free map with a scan of the whole table if not built yet.
+    ///
+    /// The entries written during the scan are replayed on its result, so
+    /// only a failed write, whose entry on disk is unknown, makes it scan
+    /// again.
+    async fn load_free_map(&self) -> Result<(), Error> {
+        loop {
+            let failures = ksync::critical(|| {
+                let map = self.free_map.lock();
+                map.is_none().then(|| {
+                    let mut log = self.scan_log.lock();
+                    log.scans += 1;
+                    log.failures
+                })
+            });
+            let Some(failures) = failures else {
+                return Ok(());
+            };
+            let _scan = ScanGuard(self);
+            let bitmap = self.allocation_bitmap().await?;
+            let installed = ksync::critical(|| {
+                let mut map = self.free_map.lock();
+                if map.is_some() {
+                    return true;
+                }
+                let log = self.scan_log.lock();
+                if log.failures != failures {
+                    return false;
+                }
+                let map = map.insert(FreeMap::new(bitmap));
+                for &(cluster, allocated) in &log.writes {
+                    map.mark(cluster, allocated);
+                }
+                for run in self.held.lock().iter() {
+                    run.clone().for_each(|cluster| map.mark(cluster, true));
+                }
+                if let Some(stored) = self.stored_free_count().filter(|&n| n != map.free) {
+                    log::warn!(
+                        "free cluster count of FSInfo ({stored}) is stale, repaired to {}",
+                        map.free
+                    );
+                }
+                true
+            });
+            if installed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run `f` on the free map, building it first if needed.
+    async fn with_free_map<R>(&self, mut f: impl FnMut(&mut FreeMap) -> R) -> Result<R, Error> {
+        loop {
+            self.load_free_map().await?;
+            // The map may be dropped by a failed write right after loading.
+            let ret = ksync::critical(|| self.free_map.lock().as_mut().map(&mut f));
+            if let Some(ret) = ret {
+                break Ok(ret);
+            }
+        }
+    }
+
+    /// The number of free clusters, if the free map has been built.
+    pub fn cached_free_count(&self) -> Option<u32> {
+        ksync::critical(|| self.free_map.lock().as_ref().map(|map| map.free))
+    }
+
+    /// The free cluster count of FSInfo, trusted until the first write of an
+    /// entry since the table is created.
+    fn stored_free_count(&self) -> Option<u32> {
+        let untouched = self.write_gen.load(SeqCst) == 0;
+        (self.stored_free != u32::MAX && untouched).then_some(self.stored_free)
+    }
+
+    /// The cluster where the search for a free cluster starts by default,
+    /// which follows the last allocated one.
+    pub fn next_free(&self) -> u32 {
+        self.next_free.load(SeqCst)
+    }
+
+    fn advance_next_free(&self, last: u32) {
+        let next = last + 1;
+        let range = self.allocable_range();
+        let next = if range.contains(&next) {
+            next
+        } else {
+            range.start
+        };
+        self.next_free.store(next, SeqCst);
+    }
+
+    /// Give back a cluster taken from the free map whose entry was never
+    /// written.
+    fn release(&self, cluster: u32) {
+        ksync::critical(|| {
+            if let Some(map) = &mut *self.free_map.lock() {
+                map.mark(cluster, false);
+            }
+        })
+    }
+
+    /// Keep `clusters` out of the free map even once written free, until
+    /// [`Fat::release_held`].
+    fn hold(&self, clusters: Range<u32>) {
+        ksync::critical(|| self.held.lock().push(clusters))
+    }
+
+    /// Give back the held `clusters`, written free, to the free map.
+    fn release_held(&self, clusters: Range<u32>) {
+        ksync::critical(|| {
+            let mut map = self.free_map.lock();
+            let mut held = self.held.lock();
+            // Also drop what overlaps with the holds left by interrupted calls.
+            for run in mem::take(&mut *held) {
+                if run.end <= clusters.start || clusters.end <= run.start {
+                    held.push(run);
+                    continue;
+                }
+                if run.start < clusters.start {
+                    held.push(run.start..clusters.start);
+                }
+                if clusters.end < run.end {
+                    held.push(clusters.end..run.end);
+                }
+            }
+            if let Some(map) = &mut *map {
+                clusters.for_each(|cluster| map.mark(cluster, false));
+            }
+        })
+    }
+
+    /// Read the raw value of the entry of `cluster`, including the reserved
+    /// high nibble of FAT32 entries.
+    ///
+    /// This bypasses the interpretation of [`FatEntry::from_raw`], so special
+    /// values are returned as is.
+    pub async fn get_raw_entry(&self, cluster: u32) -> Result<u32, Error> {
+        self.get_raw(cluster).await
+    }
+
+    /// Write the raw value of the entry of `cluster` into all the mirrors. If
+    /// `preserve_reserved` is set, the reserved high nibble of a FAT32 entry on
+    /// disk is kept and only the low 28 bits of `raw` are written. FAT12 and
+    /// FAT16 entries have no reserved bits, and `raw` is truncated to their
+    /// width.
+    ///
+    /// # Footgun
+    ///
+    /// No validation is done at all: the value may corrupt cluster chains, or
+    /// mark the special bad/end-of-chain clusters as free. Only meant for
+    /// consistency checkers and repair tools.
+    pub async fn set_raw_entry(
+        &self,
+        cluster: u32,
+        raw: u32,
+        preserve_reserved: bool,
+    ) -> Result<(), Error> {
+        let mask = self.fat_type.mask();
+        let raw = if preserve_reserved {
+            let old = self.get_raw(cluster).await? & !mask;
+            old | (raw & mask)
+        } else {
+            raw
+        };
+        self.set_raw(cluster, raw).await
+    }
+
+    /// Replace the reserved high nibble of the entry of `cluster` with `bits`,
+    /// keeping the low 28 bits on disk.
+    ///
+    /// Returns `EINVAL` if `bits` has any of the low 28 bits set, or if the
+    /// table is not FAT32.
+    pub async fn set_reserved_bits(&self, cluster: u32, bits: u32) -> Result<(), Error> {
+        if self.fat_type != FatType::Fat32 || bits & !RESERVED_BITS != 0 {
+            return Err(EINVAL);
+        }
+        let old = self.get_raw(cluster).await? & !RESERVED_BITS;
+        self.set_raw(cluster, old | bits).await
+    }
+
+    /// Read the volume flags stored in the entry of cluster 1.
+    ///
+    /// FAT12 has no volume flags, so the volume is always reported clean.
+    pub async fn volume_flags(&self) -> Result<FsStatusFlags, Error> {
+        let Some((clean, no_io_error)) = self.fat_type.volume_bits() else {
+            return Ok(FsStatusFlags {
+                dirty: false,
+                io_error: false,
+            });
+        };
+        let raw = self.get_raw(1).await?;
+        Ok(FsStatusFlags {
+            dirty: raw & clean == 0,
+            io_error: raw & no_io_error == 0,
+        })
+    }
+
+    /// Write the volume flags into the entry of cluster 1, keeping its other
+    /// bits. Does nothing on FAT12.
+    pub async fn set_volume_flags(&self, flags: FsStatusFlags) -> Result<(), Error> {
+        let Some((clean, no_io_error)) = self.fat_type.volume_bits() else {
+            return Ok(());
+        };
+        let mut raw = self.get_raw(1).await? | clean | no_io_error;
+        if flags.dirty {
+            raw &= !clean;
+        }
+        if flags.io_error {
+            raw &= !no_io_error;
+        }
+        self.set_raw(1, raw).await
+    }
+
+    /// Load the volume flags on mount, remembering whether the volume was left
+    /// dirty, i.e. not cleanly unmounted.
+    pub async fn load_volume_flags(&self) -> Result<FsStatusFlags, Error> {
+        let flags = self.volume_flags().await?;
+        self.was_dirty.store(flags.dirty, SeqCst);
+        self.io_error.store(flags.io_error, SeqCst);
+        Ok(flags)
+    }
+
+    /// Whether the dirty bit is currently set on disk, either by this mount
+    /// or by a previous one that was not cleanly unmounted.
+    ///
+    /// Always `false` on FAT12, which has no volume flags.
+    pub async fn is_dirty(&self) -> Result<bool, Error> {
+        Ok(self.volume_flags().await?.dirty)
+    }
+
+    /// Whether the volume was left dirty by the previous mount, so that the
+    /// layer above can decide to run a check.
+    pub fn was_dirty(&self) -> bool {
+        self.was_dirty.load(SeqCst)
+    }
+
+    /// Set the dirty bit before the first write of this mount.
+    pub async fn mark_dirty(&self) -> Result<(), Error> {
+        if self.dirty.swap(true, SeqCst) {
+            return Ok(());
+        }
+        let res = self.store_volume_flags(true).await;
+        if res.is_err() {
+            self.dirty.store(false, SeqCst);
+        }
+        res
+    }
+
+    /// Clear the dirty bit set by this mount, once everything is written back.
+    ///
+    /// The bit left by a previous unclean mount is kept until this mount
+    /// writes anything.
+    pub async fn mark_clean(&self) -> Result<(), Error> {
+        if !self.dirty.swap(false, SeqCst) {
+            return Ok(());
+        }
+        let res = self.store_volume_flags(false).await;
+        if res.is_err() {
+            self.dirty.store(true, SeqCst);
+        }
+        res
+    }
+
+    /// Set the I/O error bit after a failed write to the device. The bit is
+    /// never cleared by this crate.
+    pub async fn mark_io_error(&self) -> Result<(), Error> {
+        if self.io_error.swap(true, SeqCst) {
+            return Ok(());
+        }
+        self.store_volume_flags(self.dirty.load(SeqCst)).await
+    }
+
+    async fn store_volume_flags(&self, dirty: bool) -> Result<(), Error> {
+        let io_error = self.io_error.load(SeqCst);
+        self.set_volume_flags(FsStatusFlags { dirty, io_error })
+            .await
+    }
+
+    /// Compare the entries of all the mirrors of the table, returning the
+    /// clusters whose entries differ, each with its raw entries in all the
+    /// mirrors in order.
+    ///
+    /// Nothing is returned if mirroring is disabled. Entries written during
+    /// the scan may be reported as well.
+    pub async fn verify_mirrors(&self) -> Result<Vec<(u32, Vec<u32>)>, Error> {
+        let mut ret = Vec::new();
+        if self.mirrors <= 1 {
+            return Ok(ret);
+        }
+        let end = self.allocable_range().end;
+        let mut bufs = vec![[0; BATCH_LEN]; self.mirrors as usize];
+        for start in (0..end).step_by(BATCH_LEN) {
+            let len = BATCH_LEN.min((end - start) as usize);
+            for (mirror, buf) in bufs.iter_mut().enumerate() {
+                let buf = &mut buf[..len];
+                buf.fill(0);
+                // SAFETY: init to uninit is safe.
+                let buf = unsafe { mem::transmute(buf) };
+                unsafe { self.get_range_raw(mirror as u8, start, buf) }.await?;
+            }
+            for (index, cluster) in (start..).take(len).enumerate() {
+                let first = bufs[0][index];
+                if bufs.iter().any(|buf| buf[index] != first) {
+                    ret.push((cluster, bufs.iter().map(|buf| buf[index]).collect()));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Rewrite the entries that differ between the mirrors from the mirror
+    /// the entries are read from, returning the number of the clusters
+    /// repaired.
+    ///
+    /// Like [`Fat::verify_mirrors`], this scans the whole table of every
+    /// mirror.
+    pub async fn repair_mirrors(&self) -> Result<usize, Error> {
+        let diverged = self.verify_mirrors().await?;
+        let _guard = match self.fat_type {
+            FatType::Fat12 => Some(self.fat12_lock.lock().await),
+            _ => None,
+        };
+        for (cluster, raws) in &diverged {
+            let good = raws[self.read_mirror as usize];
+            for (mirror, &raw) in raws.iter().enumerate() {
+                if raw != good {
+                    self.write_entry(mirror as u8, *cluster, good).await?;
+                }
+            }
+        }
+        Ok(diverged.len())
+    }
+
+    /// Find a free cluster in `cluster_range` with the free map, also taking
+    /// it out of the map if `take` is set, so that concurrent allocations
+    /// never return the same cluster.
+    async fn find_free<R>(&self, cluster_range: R, take: bool) -> Result<u32, Error>
     where
         R: RangeBounds<u32>,
     {
@@ -248,7 +924,7 @@ impl Fat {
         let start = match cluster_range.start_bound() {
             Bound::Included(&bound) => bound.max(allocable_range.start),
             Bound::Excluded(&bound) => bound.wrapping_add(1).max(allocable_range.start),
-            Bound::Unbounded => allocable_range.end,
+            Bound::Unbounded => allocable_range.start,
         };
         let end = match cluster_range.end_bound() {
             Bound::Included(&bound) => bound.wrapping_add(1).min(allocable_range.end),
@@ -256,41 +932,348 @@ impl Fat {
             Bound::Unbounded => allocable_range.end,
         };
 
-        let mut buf = [0; BATCH_LEN];
+        let found = self
+            .with_free_map(|map| {
+                let cluster = map.bitmap.find(start..end, false)?;
+                if take {
+                    map.mark(cluster, true);
+                }
+                Some(cluster)
+            })
+            .await?;
+        found.ok_or(ENOSPC)
+    }
 
-        // The range may be massive so that `try_join_all` will allocate huge amount of
-        // memory, reaulting in a potential memory exhaustion.
-        for start in (start..end).step_by(BATCH_LEN) {
+    /// Walk the whole table in windows of [`BATCH_LEN`] entries, yielding every
+    /// allocable cluster with its entry.
+    ///
+    /// The table is read lazily, so dropping the stream stops the scan.
+    pub fn scan(&self) -> impl Stream<Item = Result<(u32, FatEntry), Error>> + Send + '_ {
+        self.scan_from(self.allocable_range().start)
+    }
+
+    /// Same as [`Fat::scan`], but resume from `start`, e.g. the cluster after
+    /// the last one yielded by an interrupted scan.
+    pub fn scan_from(
+        &self,
+        start: u32,
+    ) -> impl Stream<Item = Result<(u32, FatEntry), Error>> + Send + '_ {
+        let Range { start: first, end } = self.allocable_range();
+        let windows = stream::iter((start.max(first)..end).step_by(BATCH_LEN));
+        let windows = windows.then(move |start| async move {
+            let mut buf = [0; BATCH_LEN];
             let len = BATCH_LEN.min((end - start) as usize);
-            for (cluster, entry) in self.get_range(start, &mut buf[..len]).await? {
-                if entry == FatEntry::Free {
-                    return Ok(cluster);
+            let entries: Vec<_> = self.get_range(start, &mut buf[..len]).await?.collect();
+            Ok::<_, Error>(stream::iter(entries).map(Ok))
+        });
+        windows.try_flatten()
+    }
+
+    /// Scan the whole table once and mark every allocated (non-free) cluster
+    /// in the returned bitmap.
+    pub async fn allocation_bitmap(&self) -> Result<ClusterBitmap, Error> {
+        let mut bitmap = ClusterBitmap::new(self.cluster_count);
+        self.scan()
+            .try_for_each(|(cluster, entry)| {
+                if entry != FatEntry::Free {
+                    bitmap.set(cluster);
                 }
+                future::ready(Ok(()))
+            })
+            .await?;
+        Ok(bitmap)
+    }
+
+    /// Count the free clusters with the free map, which is built with a scan
+    /// of the whole table on the first call, unless the count of FSInfo is
+    /// still valid.
+    pub async fn count_free(&self) -> usize {
+        if let Some(free) = self.cached_free_count().or(self.stored_free_count()) {
+            return free as usize;
+        }
+        self.with_free_map(|map| map.free).await.unwrap() as usize
+    }
+
+    /// Find the first free cluster from `hint`, or from [`Fat::next_free`] by
+    /// default, wrapping around, without taking it. The cluster found becomes
+    /// the new default.
+    pub async fn first_free(&self, hint: Option<u32>) -> Result<Option<u32>, Error> {
+        let hint = hint.unwrap_or_else(|| self.next_free());
+        match self.find_free_from(hint, false).await {
+            Ok(cluster) => {
+                self.next_free.store(cluster, SeqCst);
+                Ok(Some(cluster))
             }
+            Err(ENOSPC) => Ok(None),
+            Err(err) => Err(err),
         }
-        Err(ENOSPC)
     }
 
-    pub async fn count_free(&self) -> usize {
-        let stream = stream::iter(self.allocable_range())
-            .filter(|&cluster| self.get(cluster).map(|res| res.unwrap() == FatEntry::Free));
-        stream.count().await
+    async fn find_free_from(&self, hint: u32, take: bool) -> Result<u32, Error> {
+        match self.find_free(hint.., take).await {
+            Ok(cluster) => Ok(cluster),
+            Err(ENOSPC) => self.find_free(..hint, take).await,
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Link `ret`, taken from the free map, to the end of the chain at
+    /// `prev`.
+    async fn link_allocated(&self, prev: Option<u32>, ret: u32) -> Result<u32, Error> {
+        if let Err(err) = self.set(ret, FatEntry::End).await {
+            self.release(ret);
+            return Err(err);
+        }
+        self.advance_next_free(ret);
+        if let Some(prev) = prev {
+            self.set(prev, FatEntry::Next(ret)).await?;
+        }
+        Ok(ret)
     }
 
     pub async fn allocate(&self, prev: Option<u32>, hint: Option<u32>) -> Result<u32, Error> {
-        let hint = hint.unwrap_or(self.allocable_range().start);
+        let hint = hint.unwrap_or_else(|| self.next_free());
+
+        let ret = self.find_free_from(hint, true).await?;
+        self.link_allocated(prev, ret).await
+    }
 
-        let ret = match self.find_free(hint..).await {
+    /// Same as [`Fat::allocate`], but also reports whether the new cluster is
+    /// physically adjacent to `prev`, i.e. the chain stayed contiguous.
+    pub async fn allocate_reporting(
+        &self,
+        prev: Option<u32>,
+        hint: Option<u32>,
+    ) -> Result<(u32, bool), Error> {
+        let cluster = self.allocate(prev, hint).await?;
+        Ok((cluster, prev.map_or(true, |p| cluster == p + 1)))
+    }
+
+    /// Allocate a cluster, preferring the locality window `[anchor, anchor +
+    /// window)` before falling back to the wrap-around search starting from
+    /// `anchor`.
+    pub async fn allocate_near(
+        &self,
+        prev: Option<u32>,
+        anchor: u32,
+        window: u32,
+    ) -> Result<u32, Error> {
+        let ret = match self
+            .find_free(anchor..anchor.saturating_add(window), true)
+            .await
+        {
             Ok(cluster) => cluster,
-            Err(ENOSPC) => self.find_free(..hint).await?,
+            Err(ENOSPC) => self.find_free_from(anchor, true).await?,
             Err(err) => return Err(err),
         };
+        self.link_allocated(prev, ret).await
+    }
+
+    /// Find a run of `count` free clusters with the free map, taking all of
+    /// them out of the map.
+    async fn take_free_run(&self, count: u32) -> Result<u32, Error> {
+        if count == 0 {
+            return Err(EINVAL);
+        }
+        let found = self
+            .with_free_map(|map| {
+                let all = map.bitmap.range();
+                let start = map.bitmap.find_clear_run(all, count)?;
+                (start..start + count).for_each(|cluster| map.mark(cluster, true));
+                Some(start)
+            })
+            .await?;
+        found.ok_or(ENOSPC)
+    }
+
+    /// Allocate a new chain of `count` physically contiguous clusters,
+    /// returning its first cluster.
+    pub async fn allocate_contiguous(&self, count: u32) -> Result<u32, Error> {
+        let start = self.take_free_run(count).await?;
+        let end = start + count;
+        for cluster in start..end {
+            let entry = if cluster + 1 == end {
+                FatEntry::End
+            } else {
+                FatEntry::Next(cluster + 1)
+            };
+            if let Err(err) = self.set(cluster, entry).await {
+                for cluster in start..cluster {
+                    let _ = self.set(cluster, FatEntry::Free).await;
+                }
+                (cluster..end).for_each(|cluster| self.release(cluster));
+                return Err(err);
+            }
+        }
+        self.advance_next_free(end - 1);
+        Ok(start)
+    }
+
+    /// Take `count` clusters out of the free map, as a single run from `hint`
+    /// if possible, wrapping around, or otherwise as the fewest runs, longest
+    /// first.
+    async fn take_free_runs(&self, count: u32, hint: u32) -> Result<Vec<Range<u32>>, Error> {
+        let found = self
+            .with_free_map(|map| {
+                if map.free < count {
+                    return None;
+                }
+                let all = map.bitmap.range();
+                let run = map
+                    .bitmap
+                    .find_clear_run(hint.max(all.start)..all.end, count);
+                let run = run.or_else(|| map.bitmap.find_clear_run(all.clone(), count));
+                let runs = match run {
+                    Some(start) => vec![start..start + count],
+                    None => {
+                        let mut runs: Vec<_> = map.bitmap.free_runs(all).collect();
+                        runs.sort_by_key(|run| Reverse(run.end - run.start));
+                        let mut rest = count;
+                        let runs = runs.into_iter().map_while(|run| {
+                            let len = (run.end - run.start).min(rest);
+                            rest -= len;
+                            (len > 0).then_some(run.start..run.start + len)
+                        });
+                        runs.collect()
+                    }
+                };
+                for run in &runs {
+                    run.clone().for_each(|cluster| map.mark(cluster, true));
+                }
+                Some(runs)
+            })
+            .await?;
+        found.ok_or(ENOSPC)
+    }
+
+    /// Write `entries` into the entries from `start` on, with a single write
+    /// into each mirror, except on FAT12.
+    async fn set_run(&self, start: u32, entries: &[FatEntry]) -> Result<(), Error> {
+        let fat_type = self.fat_type;
+        let mut raws = vec![0; entries.len()];
+        // SAFETY: init to uninit is safe.
+        let len =
+            unsafe { self.get_range_raw(self.read_mirror, start, mem::transmute(&mut *raws)) }
+                .await?;
+        if len < entries.len() {
+            return Err(EINVAL);
+        }
+        let iter = raws.iter_mut().zip(start..).zip(entries);
+        for ((raw, cluster), entry) in iter {
+            *raw = entry.into_raw(cluster, *raw & !fat_type.mask(), fat_type);
+        }
+
+        let bytes: Vec<u8> = match fat_type {
+            FatType::Fat12 => {
+                for (&raw, cluster) in raws.iter().zip(start..) {
+                    self.set_raw(cluster, raw).await?;
+                }
+                return Ok(());
+            }
+            FatType::Fat16 => raws
+                .iter()
+                .flat_map(|&raw| (raw as u16).to_le_bytes())
+                .collect(),
+            FatType::Fat32 => raws.iter().flat_map(|&raw| raw.to_le_bytes()).collect(),
+        };
+        let bytes = &bytes;
+        let res = try_join_all((0..self.mirrors).map(|mirror| async move {
+            let offset = self.offset(mirror, start);
+            self.device.write_all_at(offset, bytes).await
+        }))
+        .await;
+        for (&raw, cluster) in raws.iter().zip(start..) {
+            self.note_write(cluster, res.as_ref().ok().map(|_| raw));
+        }
+        res?;
+
+        Ok(())
+    }
+
+    /// Allocate `count` clusters linked into a chain after `prev`, returning
+    /// them in the chain order.
+    ///
+    /// A contiguous run of free clusters from `hint` is preferred, and falls
+    /// back to the longest runs available. Each run is written with a single
+    /// write into each mirror of the table.
+    pub async fn allocate_run(
+        &self,
+        prev: Option<u32>,
+        count: u32,
+        hint: Option<u32>,
+    ) -> Result<Vec<u32>, Error> {
+        if count == 0 {
+            return Err(EINVAL);
+        }
+        let hint = hint.unwrap_or_else(|| self.next_free());
+        let runs = self.take_free_runs(count, hint).await?;
+
+        for (index, run) in runs.iter().enumerate() {
+            let next = runs.get(index + 1).map(|next| next.start);
+            let last = next.map_or(FatEntry::End, FatEntry::Next);
+            let entries: Vec<_> = (run.start + 1..run.end)
+                .map(FatEntry::Next)
+                .chain([last])
+                .collect();
+            if let Err(err) = self.set_run(run.start, &entries).await {
+                self.unwind_runs(&runs[..index]).await;
+                runs[index..]
+                    .iter()
+                    .flat_map(|run| run.clone())
+                    .for_each(|c| self.release(c));
+                return Err(err);
+            }
+        }
 
-        self.set(ret, FatEntry::End).await?;
         if let Some(prev) = prev {
-            self.set(prev, FatEntry::Next(ret)).await?;
+            if let Err(err) = self.set(prev, FatEntry::Next(runs[0].start)).await {
+                self.unwind_runs(&runs).await;
+                return Err(err);
+            }
         }
-        Ok(ret)
+        self.advance_next_free(runs.last().unwrap().end - 1);
+        Ok(runs.into_iter().flatten().collect())
+    }
+
+    /// Free the runs written by a failed [`Fat::allocate_run`].
+    async fn unwind_runs(&self, runs: &[Range<u32>]) {
+        for run in runs {
+            let entries = vec![FatEntry::Free; (run.end - run.start) as usize];
+            let _ = self.set_run(run.start, &entries).await;
+        }
+    }
+
+    async fn copy_clusters(&self, clusters: &[u32], dst_start: u32) -> Result<(), Error> {
+        let mut buf = vec![0; self.cluster_size];
+        for (&src, dst) in clusters.iter().zip(dst_start..) {
+            let (src, dst) = (self.cluster_offset(src), self.cluster_offset(dst));
+            self.device.read_exact_at(src, &mut buf).await?;
+            self.device.write_all_at(dst, &buf).await?;
+        }
+        Ok(())
+    }
+
+    /// Copy the fragmented chain starting at `start` to a new chain of
+    /// contiguous clusters, returning its first cluster. Contiguous chains are
+    /// left untouched, and `start` itself is returned.
+    ///
+    /// The original chain is kept: the caller must write the new first cluster
+    /// back to the directory entry before freeing it, so that the entry never
+    /// points to freed clusters. A failure here leaves the original chain
+    /// intact and releases the new clusters.
+    pub async fn defragment_chain(&self, start: u32) -> Result<u32, Error> {
+        let clusters: Vec<u32> = self.cluster_chain(start).try_collect().await?;
+        if clusters.windows(2).all(|w| w[1] == w[0] + 1) {
+            return Ok(start);
+        }
+
+        let new_start = self.allocate_contiguous(clusters.len().try_into()?).await?;
+        if let Err(err) = self.copy_clusters(&clusters, new_start).await {
+            // The new chain is not referenced by anyone yet.
+            let _ = self.free(new_start).await;
+            return Err(err);
+        }
+        Ok(new_start)
     }
 
     async fn iter_next(&self, cluster: u32) -> Result<Option<u32>, Error> {
@@ -326,22 +1309,86 @@ impl Fat {
     }
 
     pub fn cluster_chain(&self, start: u32) -> impl Stream<Item = Result<u32, Error>> + Send + '_ {
-        stream::unfold((self, Some(Ok(start))), |(this, cluster)| async move {
-            Some(match cluster? {
-                Ok(cluster) => {
-                    let next = this.iter_next(cluster).await;
-                    (Ok(cluster), (this, next.transpose()))
-                }
-                Err(err) => (Err(err), (this, None)),
-            })
-        })
+        if let Some(clusters) = ksync::critical(|| self.chains.lock().get(start)) {
+            return Either::Left(stream::iter(clusters).map(|(cluster, _)| Ok(cluster)));
+        }
+        Either::Right(stream::unfold(
+            (self, Some(Ok(start)), 0),
+            move |(this, cluster, steps)| async move {
+                Some(match cluster? {
+                    // A chain longer than the table must contain a loop.
+                    Ok(_) if steps >= this.cluster_count() => {
+                        log::warn!("loop detected in the cluster chain starting at {start}");
+                        (Err(EIO), (this, None, steps))
+                    }
+                    Ok(cluster) => {
+                        let next = this.iter_next(cluster).await;
+                        (Ok(cluster), (this, next.transpose(), steps + 1))
+                    }
+                    Err(err) => (Err(err), (this, None, steps)),
+                })
+            },
+        ))
+    }
+
+    /// Walk the chain from `start` to the cluster containing `byte_offset`,
+    /// returning the cluster and the offset within it.
+    ///
+    /// Returns `EINVAL` if the chain ends before `byte_offset`, and `EIO` if
+    /// the chain is longer than the whole FAT, which means it contains a loop.
+    pub async fn seek_cluster(
+        &self,
+        start: u32,
+        byte_offset: usize,
+        cluster_size: usize,
+    ) -> Result<(u32, usize), Error> {
+        if cluster_size == 0 {
+            return Err(EINVAL);
+        }
+        let mut rest = byte_offset / cluster_size;
+        let mut cluster = start;
+        let mut steps = 0;
+        while rest > 0 {
+            steps += 1;
+            if steps > self.cluster_count() {
+                log::warn!("loop detected in the cluster chain starting at {start}");
+                return Err(EIO);
+            }
+            cluster = self.iter_next(cluster).await?.ok_or(EINVAL)?;
+            rest -= 1;
+        }
+        Ok((cluster, byte_offset % cluster_size))
     }
 
+    /// Walk the chain from `start`, returning each cluster with the last
+    /// cluster of the contiguous run containing it.
+    ///
+    /// The result is cached until any cluster of the chain is written.
     pub async fn all_clusters(&self, start: u32) -> Result<Vec<(u32, u32)>, Error> {
+        if let Some(clusters) = ksync::critical(|| self.chains.lock().get(start)) {
+            return Ok(clusters);
+        }
+        let gen = self.write_gen.load(SeqCst);
+        let clusters = self.walk_clusters(start).await?;
+        ksync::critical(|| {
+            let mut chains = self.chains.lock();
+            // The chain may have been modified during the walk.
+            if self.write_gen.load(SeqCst) == gen {
+                chains.insert(start, &clusters);
+            }
+        });
+        Ok(clusters)
+    }
+
+    async fn walk_clusters(&self, start: u32) -> Result<Vec<(u32, u32)>, Error> {
         let mut buf = [0; BATCH_LEN];
         let mut ret = vec![(start, 0)];
         loop {
             let last_len = ret.len();
+            if last_len > self.cluster_count() as usize {
+                log::warn!("loop detected in the cluster chain starting at {start}");
+                return Err(EIO);
+            }
             let iter = self
                 .iter_ranged_next(ret.last().unwrap().0, &mut buf)
                 .await?;
@@ -369,13 +1416,124 @@ impl Fat {
         }
     }
 
+    /// Free the chain from `chain_start`, returning the number of freed
+    /// clusters.
+    ///
+    /// If this function fails or is cancelled partway, only a tail of the
+    /// chain has been freed, and the rest can be freed by calling it again
+    /// with the same `chain_start`. See [`Fat::free_resumable`] for the count
+    /// of the clusters freed before an error.
     pub async fn free(&self, chain_start: u32) -> Result<u32, Error> {
-        self.cluster_chain(chain_start)
-            .try_fold(0, |acc, cluster| async move {
-                self.set(cluster, FatEntry::Free).await?;
-                Ok(acc + 1)
-            })
-            .await
+        let progress = self.free_resumable(chain_start).await;
+        match progress.error {
+            None => Ok(progress.freed),
+            Some(err) => Err(err),
+        }
+    }
+
+    /// Free the chain from `chain_start`, from its last cluster backwards.
+    ///
+    /// The chain is freed by its contiguous runs, each with a single write
+    /// into every mirror of the table, unless longer than
+    /// [`FREE_BATCH_LEN`]. The cluster before each freed batch is then made
+    /// the end of the chain.
+    ///
+    /// The freed clusters only become allocatable again once unlinked, so the
+    /// chain from `chain_start` never runs into clusters reused by others. An
+    /// interrupted call can be completed by another one with the same
+    /// `chain_start`, which stops at the first free cluster and never counts
+    /// the clusters freed before twice. If the call is interrupted between
+    /// the two writes of a batch, its clusters are free on disk but stay out
+    /// of the free map until the next call frees the same batch, or until the
+    /// volume is mounted again.
+    pub async fn free_resumable(&self, chain_start: u32) -> FreeProgress {
+        let mut progress = FreeProgress {
+            freed: 0,
+            error: None,
+        };
+
+        let runs = match self.chain_runs(chain_start).await {
+            Ok(runs) => runs,
+            Err(err) => {
+                progress.error = Some(err);
+                return progress;
+            }
+        };
+
+        let mut buf = Vec::new();
+        let batches = runs.iter().enumerate().rev().flat_map(|(index, run)| {
+            let prev_run = index.checked_sub(1).map(|prev| runs[prev].end - 1);
+            let starts = (run.start..run.end).step_by(FREE_BATCH_LEN as usize);
+            let batches = starts.map(move |start| {
+                let prev = if start > run.start {
+                    Some(start - 1)
+                } else {
+                    prev_run
+                };
+                (prev, start..(start + FREE_BATCH_LEN).min(run.end))
+            });
+            batches.rev()
+        });
+        for (prev, batch) in batches {
+            buf.resize((batch.end - batch.start) as usize, 0);
+            self.hold(batch.clone());
+            if let Err(err) = self.set_range(batch.start, &mut buf, FatEntry::Free).await {
+                progress.error = Some(err);
+                break;
+            }
+            progress.freed += batch.end - batch.start;
+            if let Some(prev) = prev {
+                if let Err(err) = self.set(prev, FatEntry::End).await {
+                    progress.error = Some(err);
+                    break;
+                }
+            }
+            // Discard while the clusters cannot be reused yet, so that the
+            // data of their new owner is never dropped.
+            self.discard_clusters(batch.clone()).await;
+            self.release_held(batch);
+        }
+        progress
+    }
+
+    /// The contiguous runs of the chain from `chain_start` in the chain order,
+    /// up to the first free cluster if any.
+    async fn chain_runs(&self, chain_start: u32) -> Result<Vec<Range<u32>>, Error> {
+        let clusters = self.all_clusters(chain_start).await?;
+        let mut runs = Vec::<Range<u32>>::new();
+        for (cluster, _) in clusters {
+            match runs.last_mut() {
+                Some(run) if run.end == cluster => run.end += 1,
+                _ => runs.push(cluster..cluster + 1),
+            }
+        }
+        // The walk ends at a cluster not linked to another one, which is free
+        // if the chain has been partly freed before.
+        let last = runs.last_mut().unwrap();
+        if self.get(last.end - 1).await? == FatEntry::Free {
+            last.end -= 1;
+            if last.is_empty() {
+                runs.pop();
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Discard the data of the freed `clusters` on the device.
+    ///
+    /// Discarding is only a hint, so errors are logged and ignored, and
+    /// nothing is done at all once the device reports `EOPNOTSUPP`.
+    async fn discard_clusters(&self, clusters: Range<u32>) {
+        if clusters.is_empty() || !self.discard.load(SeqCst) {
+            return;
+        }
+        let offset = self.cluster_offset(clusters.start);
+        let len = (clusters.end - clusters.start) as usize * self.cluster_size;
+        match self.device.discard(offset, len).await {
+            Ok(()) => {}
+            Err(EOPNOTSUPP) => self.discard.store(false, SeqCst),
+            Err(err) => log::warn!("failed to discard clusters {clusters:?}: {err}"),
+        }
     }
 
     pub async fn truncate(&self, chain_start: u32) -> Result<u32, Error> {
@@ -387,4 +1545,558 @@ impl Fat {
     }
 }
 
+/// The result of [`Fat::free_resumable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeProgress {
+    /// The number of clusters freed by this call.
+    pub freed: u32,
+    /// The error that interrupted the call, which should be retried with the
+    /// same `chain_start` to finish the rest of the chain.
+    pub error: Option<Error>,
+}
+
+impl FreeProgress {
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// One bit per allocable cluster, set if the cluster is allocated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterBitmap {
+    words: Vec<u64>,
+    len: u32,
+}
+
+impl ClusterBitmap {
+    fn new(len: u32) -> Self {
+        ClusterBitmap {
+            words: vec![0; (len as usize).div_ceil(u64::BITS as usize)],
+            len,
+        }
+    }
+
+    fn set(&mut self, cluster: u32) {
+        let bit = (cluster - RESERVED_FAT_ENTRIES) as usize;
+        self.words[bit / u64::BITS as usize] |= 1 << (bit % u64::BITS as usize);
+    }
+
+    fn clear(&mut self, cluster: u32) {
+        let bit = (cluster - RESERVED_FAT_ENTRIES) as usize;
+        self.words[bit / u64::BITS as usize] &= !(1 << (bit % u64::BITS as usize));
+    }
+
+    /// Find the first cluster in `range` whose bit is `allocated`, skipping
+    /// whole words without one.
+    fn find(&self, range: Range<u32>, allocated: bool) -> Option<u32> {
+        let start = range.start.saturating_sub(RESERVED_FAT_ENTRIES);
+        let end = range.end.saturating_sub(RESERVED_FAT_ENTRIES).min(self.len);
+        let mut bit = start;
+        while bit < end {
+            let (index, shift) = (bit / u64::BITS, bit % u64::BITS);
+            let word = self.words[index as usize];
+            let bits = if allocated { word } else { !word } >> shift;
+            if bits == 0 {
+                bit = (index + 1) * u64::BITS;
+                continue;
+            }
+            let found = bit + bits.trailing_zeros();
+            return (found < end).then_some(found + RESERVED_FAT_ENTRIES);
+        }
+        None
+    }
+
+    /// The maximal runs of free clusters in `range`, in order.
+    fn free_runs(&self, range: Range<u32>) -> impl Iterator<Item = Range<u32>> + '_ {
+        let Range { mut start, end } = range;
+        iter::from_fn(move || {
+            let run = self.find(start..end, false)?;
+            let run_end = self.find(run..end, true).unwrap_or(end);
+            start = run_end;
+            Some(run..run_end)
+        })
+    }
+
+    /// Find the first run of `count` free clusters in `range`.
+    fn find_clear_run(&self, range: Range<u32>, count: u32) -> Option<u32> {
+        let mut runs = self.free_runs(range);
+        runs.find(|run| run.end - run.start >= count)
+            .map(|run| run.start)
+    }
+
+    fn range(&self) -> Range<u32> {
+        RESERVED_FAT_ENTRIES..(self.len + RESERVED_FAT_ENTRIES)
+    }
+
+    /// The number of clusters covered, i.e. the cluster count of the volume.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `cluster` is allocated. Clusters out of the allocable range
+    /// are reported as free.
+    pub fn get(&self, cluster: u32) -> bool {
+        let Some(bit) = cluster.checked_sub(RESERVED_FAT_ENTRIES) else {
+            return false;
+        };
+        if bit >= self.len {
+            return false;
+        }
+        let bit = bit as usize;
+        self.words[bit / u64::BITS as usize] & (1 << (bit % u64::BITS as usize)) != 0
+    }
+
+    /// The number of allocated clusters.
+    pub fn count_allocated(&self) -> u32 {
+        self.words.iter().map(|word| word.count_ones()).sum()
+    }
+
+    /// The raw bits, with cluster `RESERVED_FAT_ENTRIES + i` at bit `i % 64`
+    /// of word `i / 64`.
+    pub fn as_words(&self) -> &[u64] {
+        &self.words
+    }
+}
+
+/// The maximum number of entries freed by a single write of
+/// [`Fat::free_resumable`].
+const FREE_BATCH_LEN: u32 = 16384;
+
+/// The number of chains kept by the chain cache of [`Fat`].
+const CHAIN_CACHE_LEN: usize = 16;
+
+/// The chains recently walked by [`Fat::all_clusters`], least recently used
+/// first.
+#[derive(Default)]
+struct ChainCache(VecDeque<CachedChain>);
+
+struct CachedChain {
+    start: u32,
+    clusters: Vec<(u32, u32)>,
+    /// The contiguous runs of the chain, sorted.
+    runs: Vec<Range<u32>>,
+}
+
+impl CachedChain {
+    fn contains(&self, cluster: u32) -> bool {
+        let index = self.runs.partition_point(|run| run.start <= cluster);
+        index > 0 && self.runs[index - 1].contains(&cluster)
+    }
+}
+
+impl ChainCache {
+    fn get(&mut self, start: u32) -> Option<Vec<(u32, u32)>> {
+        let index = self.0.iter().position(|chain| chain.start == start)?;
+        let chain = self.0.remove(index)?;
+        let clusters = chain.clusters.clone();
+        self.0.push_back(chain);
+        Some(clusters)
+    }
+
+    fn insert(&mut self, start: u32, clusters: &[(u32, u32)]) {
+        self.0.retain(|chain| chain.start != start);
+        if self.0.len() >= CHAIN_CACHE_LEN {
+            self.0.pop_front();
+        }
+        let mut runs = Vec::<Range<u32>>::new();
+        for &(cluster, _) in clusters {
+            match runs.last_mut() {
+                Some(run) if run.end == cluster => run.end += 1,
+                _ => runs.push(cluster..cluster + 1),
+            }
+        }
+        runs.sort_unstable_by_key(|run| run.start);
+        self.0.push_back(CachedChain {
+            start,
+            clusters: clusters.to_vec(),
+            runs,
+        });
+    }
+
+    /// Drop the chains containing `cluster`.
+    fn invalidate(&mut self, cluster: u32) {
+        self.0.retain(|chain| !chain.contains(cluster));
+    }
+}
+
+/// The allocation state of the clusters kept by [`Fat`].
+struct FreeMap {
+    bitmap: ClusterBitmap,
+    free: u32,
+}
+
+impl FreeMap {
+    fn new(bitmap: ClusterBitmap) -> Self {
+        let free = bitmap.len() - bitmap.count_allocated();
+        FreeMap { bitmap, free }
+    }
+
+    fn mark(&mut self, cluster: u32, allocated: bool) {
+        match (self.bitmap.get(cluster), allocated) {
+            (false, true) => {
+                self.bitmap.set(cluster);
+                self.free -= 1;
+            }
+            (true, false) => {
+                self.bitmap.clear(cluster);
+                self.free += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The entries written while scans of [`Fat::load_free_map`] are running.
+#[derive(Default)]
+struct ScanLog {
+    scans: usize,
+    /// Whether each written cluster is allocated, in the order written.
+    writes: Vec<(u32, bool)>,
+    /// Bumped by every failed write.
+    failures: u64,
+}
+
+/// Unregisters a scan of [`Fat::load_free_map`] when it ends or is
+/// cancelled, dropping the log after the last one.
+struct ScanGuard<'a>(&'a Fat);
+
+impl Drop for ScanGuard<'_> {
+    fn drop(&mut self) {
+        ksync::critical(|| {
+            let mut log = self.0.scan_log.lock();
+            log.scans -= 1;
+            if log.scans == 0 {
+                log.writes = Vec::new();
+            }
+        })
+    }
+}
+
 const BATCH_LEN: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+
+    use futures_util::{FutureExt, StreamExt, TryStreamExt};
+    use ksc_core::Error::EIO;
+    use umifs::{
+        misc::{MemFault, MemIo, MemOp},
+        traits::IoExt,
+    };
+
+    use super::{Fat, FatEntry, FatType};
+    use crate::raw::BiosParameterBlock;
+
+    /// A FAT32 volume of one FAT and 66000 one-sector clusters, whose device
+    /// holds the reserved sector and the FAT, and has room for the first 16
+    /// clusters.
+    fn fat32() -> (Arc<MemIo>, Fat) {
+        let bpb = BiosParameterBlock {
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            reserved_sectors: 1,
+            fats: 1,
+            sectors_per_fat_32: 520,
+            total_sectors_32: 1 + 520 + 66000,
+            ..Default::default()
+        };
+        let limit = (1 + 520 + 16) * 512;
+        let device = Arc::new(MemIo::with_data(vec![0; (1 + 520) * 512], limit));
+        let fat = Fat::new(device.clone(), &bpb, None);
+        (device, fat)
+    }
+
+    /// A volume of one FAT and `clusters` one-sector clusters, small enough
+    /// for FAT12 or FAT16, whose device holds only the reserved sector and the
+    /// FAT.
+    fn small_fat(clusters: u16, sectors_per_fat: u16) -> (Arc<MemIo>, Fat) {
+        let bpb = BiosParameterBlock {
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            reserved_sectors: 1,
+            fats: 1,
+            sectors_per_fat_16: sectors_per_fat,
+            total_sectors_16: 1 + sectors_per_fat + clusters,
+            ..Default::default()
+        };
+        let data = vec![0; (1 + sectors_per_fat as usize) * 512];
+        let device = Arc::new(MemIo::with_data(data, 0));
+        let fat = Fat::new(device.clone(), &bpb, None);
+        (device, fat)
+    }
+
+    #[test]
+    fn fat12_codec() {
+        let bytes = [0xab, 0xcd];
+        assert_eq!(FatType::fat12_decode(2, bytes), 0xdab);
+        assert_eq!(FatType::fat12_decode(3, bytes), 0xcda);
+
+        // The nibble of the neighbouring entry is kept.
+        let even = FatType::fat12_encode(2, bytes, 0x123);
+        assert_eq!(even, [0x23, 0xc1]);
+        assert_eq!(FatType::fat12_decode(2, even), 0x123);
+        let odd = FatType::fat12_encode(3, bytes, 0x123);
+        assert_eq!(odd, [0x3b, 0x12]);
+        assert_eq!(FatType::fat12_decode(3, odd), 0x123);
+    }
+
+    #[test]
+    fn fat12_entries() {
+        let (device, fat) = small_fat(2000, 6);
+        assert_eq!(fat.fat_type(), FatType::Fat12);
+
+        let chain = [
+            (2, FatEntry::Next(0x123)),
+            (3, FatEntry::Next(0x456)),
+            (4, FatEntry::Next(0x789)),
+            (5, FatEntry::End),
+        ];
+        for (cluster, entry) in chain {
+            fat.set(cluster, entry).now_or_never().unwrap().unwrap();
+        }
+        // Entries 2 and 3 share the middle byte, and so do entries 4 and 5.
+        assert_eq!(
+            device.to_vec()[512 + 3..][..6],
+            [0x23, 0x61, 0x45, 0x89, 0xf7, 0xff]
+        );
+        for (cluster, entry) in chain {
+            assert_eq!(fat.get(cluster).now_or_never().unwrap(), Ok(entry));
+        }
+
+        // Starting at an odd cluster, every entry straddles a byte boundary
+        // of the packed range.
+        let mut buf = [0; 3];
+        let range = fat.get_range(3, &mut buf).now_or_never().unwrap().unwrap();
+        assert_eq!(range.collect::<Vec<_>>(), chain[1..]);
+    }
+
+    #[test]
+    fn fat16_entries() {
+        let (device, fat) = small_fat(10000, 40);
+        assert_eq!(fat.fat_type(), FatType::Fat16);
+
+        let chain = [
+            (2, FatEntry::Next(0x1234)),
+            (3, FatEntry::Next(0x5678)),
+            (4, FatEntry::End),
+        ];
+        for (cluster, entry) in chain {
+            fat.set(cluster, entry).now_or_never().unwrap().unwrap();
+        }
+        assert_eq!(
+            device.to_vec()[512 + 4..][..6],
+            [0x34, 0x12, 0x78, 0x56, 0xff, 0xff]
+        );
+
+        let mut buf = [0; 3];
+        let range = fat.get_range(2, &mut buf).now_or_never().unwrap().unwrap();
+        assert_eq!(range.collect::<Vec<_>>(), chain);
+    }
+
+    #[test]
+    fn diverged_mirrors() {
+        // A FAT16 volume of two mirrored FATs of 20 sectors each.
+        let bpb = BiosParameterBlock {
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            reserved_sectors: 1,
+            fats: 2,
+            sectors_per_fat_16: 20,
+            total_sectors_16: 1 + 2 * 20 + 5000,
+            ..Default::default()
+        };
+        let device = Arc::new(MemIo::with_data(vec![0; (1 + 2 * 20) * 512], 0));
+        let fat = Fat::new(device.clone(), &bpb, None);
+        let mirror = |index: usize, cluster: usize| (1 + index * 20) * 512 + cluster * 2;
+
+        let write = |offset, bytes: &[u8]| device.write_all_at(offset, bytes).now_or_never();
+        write(mirror(1, 3), &[0x34, 0x12]).unwrap().unwrap();
+        write(mirror(0, 4000), &[0x05, 0x00]).unwrap().unwrap();
+        let diverged = fat.verify_mirrors().now_or_never().unwrap().unwrap();
+        assert_eq!(diverged, [(3, vec![0, 0x1234]), (4000, vec![5, 0])]);
+
+        // The entries of the mirror read from win.
+        let repaired = fat.repair_mirrors().now_or_never().unwrap();
+        assert_eq!(repaired, Ok(2));
+        let diverged = fat.verify_mirrors().now_or_never().unwrap().unwrap();
+        assert!(diverged.is_empty());
+        let data = device.to_vec();
+        assert_eq!(data[mirror(1, 3)..][..2], [0, 0]);
+        assert_eq!(data[mirror(1, 4000)..][..2], [5, 0]);
+    }
+
+    #[test]
+    fn chain_cache() {
+        let (device, fat) = fat32();
+        let chain = [
+            (2, FatEntry::Next(3)),
+            (3, FatEntry::Next(7)),
+            (7, FatEntry::End),
+        ];
+        for (cluster, entry) in chain {
+            fat.set(cluster, entry).now_or_never().unwrap().unwrap();
+        }
+        let expected = vec![(2, 3), (3, 3), (7, 7)];
+
+        let before = device.reads();
+        let clusters = fat.all_clusters(2).now_or_never().unwrap().unwrap();
+        assert_eq!(clusters, expected);
+        assert!(device.reads() > before);
+
+        // The second walk is served from the cache.
+        let before = device.reads();
+        let clusters = fat.all_clusters(2).now_or_never().unwrap().unwrap();
+        assert_eq!(clusters, expected);
+        assert_eq!(device.reads(), before);
+
+        // Writing any cluster of the chain drops it.
+        fat.set(7, FatEntry::Next(8))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        fat.set(8, FatEntry::End).now_or_never().unwrap().unwrap();
+        let before = device.reads();
+        let clusters = fat.all_clusters(2).now_or_never().unwrap().unwrap();
+        assert_eq!(clusters, vec![(2, 3), (3, 3), (7, 8), (8, 8)]);
+        assert!(device.reads() > before);
+    }
+
+    #[test]
+    fn free_in_runs() {
+        let (device, fat) = fat32();
+        let alloc = fat.allocate_run(None, 6000, None);
+        let first = alloc.now_or_never().unwrap().unwrap();
+        let alloc = fat.allocate_run(Some(first[5999]), 4000, Some(20000));
+        let second = alloc.now_or_never().unwrap().unwrap();
+        assert_eq!((first[0], second[0]), (2, 20000));
+
+        let before = device.writes().len();
+        let freed = fat.free(2).now_or_never().unwrap().unwrap();
+        assert_eq!(freed, 10000);
+        // One write for each run, and one to unlink the tail run.
+        assert_eq!(device.writes().len() - before, 3);
+
+        for cluster in [2, 6001, 20000, 23999] {
+            let entry = fat.get(cluster).now_or_never().unwrap().unwrap();
+            assert_eq!(entry, FatEntry::Free);
+        }
+        assert_eq!(fat.free(2).now_or_never().unwrap(), Ok(0));
+    }
+
+    /// Free the chain from 2, made of the runs 2..12 and 100..110, letting
+    /// `writes` writes through before failing.
+    fn free_interrupted(device: &MemIo, fat: &Fat, writes: usize) {
+        let first = fat.allocate_run(None, 10, None).now_or_never().unwrap();
+        let second = fat.allocate_run(Some(11), 10, Some(100));
+        let second = second.now_or_never().unwrap().unwrap();
+        assert_eq!((first.unwrap()[0], second[0]), (2, 100));
+
+        let mut count = 0;
+        device.set_hook(move |op| {
+            count += matches!(op, MemOp::Write { .. }) as usize;
+            (count == writes + 1).then_some(MemFault::Fail(EIO))
+        });
+        let progress = fat.free_resumable(2).now_or_never().unwrap();
+        assert_eq!(progress.error, Some(EIO));
+        assert_eq!(progress.freed, 10);
+        device.clear_hook();
+    }
+
+    #[test]
+    fn free_resumed_after_realloc() {
+        let (device, fat) = fat32();
+        // The tail run is freed and unlinked, but not the head one.
+        free_interrupted(&device, &fat, 2);
+        assert_eq!(fat.get(11).now_or_never().unwrap(), Ok(FatEntry::End));
+
+        let other = fat.allocate_run(None, 10, Some(100));
+        assert_eq!(other.now_or_never().unwrap().unwrap()[0], 100);
+
+        assert_eq!(fat.free(2).now_or_never().unwrap(), Ok(10));
+        for cluster in [2, 11] {
+            let entry = fat.get(cluster).now_or_never().unwrap().unwrap();
+            assert_eq!(entry, FatEntry::Free);
+        }
+        // The chain reusing the tail is left alone.
+        let entry = fat.get(100).now_or_never().unwrap().unwrap();
+        assert_eq!(entry, FatEntry::Next(101));
+        let entry = fat.get(109).now_or_never().unwrap().unwrap();
+        assert_eq!(entry, FatEntry::End);
+    }
+
+    #[test]
+    fn free_interrupted_before_unlink() {
+        let (device, fat) = fat32();
+        // The tail run is freed, but still linked from the head one.
+        free_interrupted(&device, &fat, 1);
+        assert_eq!(fat.get(11).now_or_never().unwrap(), Ok(FatEntry::Next(100)));
+        assert_eq!(fat.get(100).now_or_never().unwrap(), Ok(FatEntry::Free));
+
+        // So it is not reused, even after the free map is rebuilt.
+        let other = fat.allocate_run(None, 10, Some(100));
+        assert_eq!(other.now_or_never().unwrap().unwrap()[0], 110);
+
+        assert_eq!(fat.free(2).now_or_never().unwrap(), Ok(10));
+        let entry = fat.get(110).now_or_never().unwrap().unwrap();
+        assert_eq!(entry, FatEntry::Next(111));
+    }
+
+    #[test]
+    fn defragment_keeps_original() {
+        let (device, fat) = fat32();
+        // The chain 2 -> 4, around the single cluster 3 of another chain.
+        let chains = [
+            (2, FatEntry::Next(4)),
+            (3, FatEntry::End),
+            (4, FatEntry::End),
+        ];
+        for (cluster, entry) in chains {
+            fat.set(cluster, entry).now_or_never().unwrap().unwrap();
+        }
+        for (cluster, byte) in [(2, 1), (4, 2)] {
+            let offset = fat.cluster_offset(cluster);
+            let write = device.write_all_at(offset, &[byte; 512]);
+            write.now_or_never().unwrap().unwrap();
+        }
+
+        let new_start = fat.defragment_chain(2).now_or_never().unwrap().unwrap();
+        let chain = |start| {
+            let chain = fat.cluster_chain(start).try_collect::<Vec<_>>();
+            chain.now_or_never().unwrap().unwrap()
+        };
+        assert_eq!(chain(new_start), [new_start, new_start + 1]);
+        // The original chain is left for the caller to free.
+        assert_eq!(chain(2), [2, 4]);
+
+        let mut data = [0; 512];
+        let read = device.read_exact_at(fat.cluster_offset(new_start + 1), &mut data);
+        read.now_or_never().unwrap().unwrap();
+        assert_eq!(data, [2; 512]);
+    }
+
+    #[test]
+    fn chain_loop() {
+        let (_, fat) = fat32();
+        let chain = [
+            (2, FatEntry::Next(3)),
+            (3, FatEntry::Next(4)),
+            (4, FatEntry::Next(2)),
+        ];
+        for (cluster, entry) in chain {
+            fat.set(cluster, entry).now_or_never().unwrap().unwrap();
+        }
+
+        let walk = fat.cluster_chain(2).collect::<Vec<_>>();
+        let walked = walk.now_or_never().unwrap();
+        assert_eq!(walked.len(), fat.cluster_count() as usize + 1);
+        assert_eq!(walked.last(), Some(&Err(EIO)));
+
+        assert_eq!(fat.all_clusters(2).now_or_never().unwrap(), Err(EIO));
+        assert_eq!(fat.free(2).now_or_never().unwrap(), Err(EIO));
+    }
+}
@@ -1,5 +1,8 @@
 use core::fmt::Debug;
 
+use ktime_core::{Instant, InstantExt};
+use umifs::types::TimeUpdatePolicy;
+
 const MIN_YEAR: u16 = 1980;
 const MAX_YEAR: u16 = 2107;
 const MIN_MONTH: u16 = 1;
@@ -54,6 +57,22 @@ impl Date {
     pub(crate) fn encode(self) -> u16 {
         ((self.year - MIN_YEAR) << 9) | (self.month << 5) | self.day
     }
+
+    /// The number of days since the Unix epoch, 1970-01-01.
+    pub(crate) fn days_since_epoch(self) -> u64 {
+        // Count the years from March, so that leap days end them.
+        let (month, day) = (u64::from(self.month), u64::from(self.day));
+        let year = u64::from(self.year) - u64::from(month <= 2);
+        let (era, year_of_era) = (year / 400, year % 400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    /// The instant of the start of the date, taken as UTC.
+    pub(crate) fn to_instant(self) -> Instant {
+        Instant::from_su(self.days_since_epoch() * 24 * 60 * 60, 0)
+    }
 }
 
 /// A DOS compatible time.
@@ -144,6 +163,19 @@ impl DateTime {
             Time::decode(dos_time, dos_time_hi_res),
         )
     }
+
+    /// The instant of the date and time, taken as UTC.
+    pub(crate) fn to_instant(self) -> Instant {
+        let Time {
+            hour,
+            min,
+            sec,
+            millis,
+        } = self.time;
+        let secs = u64::from(hour) * 60 * 60 + u64::from(min) * 60 + u64::from(sec);
+        let days = self.date.days_since_epoch();
+        Instant::from_su(days * 24 * 60 * 60 + secs, u64::from(millis) * 1000)
+    }
 }
 
 /// A current time and date provider.
@@ -154,6 +186,11 @@ impl DateTime {
 pub trait TimeProvider: Debug + Send + Sync + 'static {
     fn get_current_date(&self) -> Date;
     fn get_current_date_time(&self) -> DateTime;
+
+    /// The policy of updating the access dates of files on reads.
+    fn time_policy(&self) -> TimeUpdatePolicy {
+        TimeUpdatePolicy::default()
+    }
 }
 
 /// `TimeProvider` implementation that always returns DOS minimal date-time
@@ -208,6 +245,13 @@ mod tests {
         assert_eq!(d, Date::decode(x));
     }
 
+    #[test]
+    fn date_days_since_epoch() {
+        assert_eq!(Date::new(1980, 1, 1).days_since_epoch(), 3652);
+        assert_eq!(Date::new(2000, 3, 1).days_since_epoch(), 11017);
+        assert_eq!(Date::new(2055, 7, 23).days_since_epoch(), 31249);
+    }
+
     #[test]
     fn time_encode_decode() {
         let t1 = Time::new(15, 3, 29, 990);
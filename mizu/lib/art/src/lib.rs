@@ -6,4 +6,4 @@ extern crate alloc;
 pub mod queue;
 mod sched;
 
-pub use self::sched::Executor;
+pub use self::sched::{Executor, Idle, Spin};
@@ -38,6 +38,37 @@ pub struct Executor {
     injector: SegQueue<Runnable>,
     stealers: Box<[Stealer<Runnable, WORKER_CAP>]>,
     shutdown: AtomicBool,
+    idle: Box<dyn Idle>,
+}
+
+/// The way runners wait for tasks when they have nothing to run.
+pub trait Idle: Send + Sync + 'static {
+    /// Park the current runner until it is unparked or interrupted, unless
+    /// `ready` returns `true` when called after the runner is marked as
+    /// parked.
+    ///
+    /// Returning spuriously is allowed, since the runner polls its queues
+    /// again afterwards.
+    fn park(&self, ready: &dyn Fn() -> bool);
+
+    /// Wake up a parked runner, if any. Called after a task is queued.
+    fn unpark_one(&self);
+
+    /// Wake up all the parked runners.
+    fn unpark_all(&self);
+}
+
+/// Busy-polls the queues, never actually parking the runners.
+pub struct Spin;
+
+impl Idle for Spin {
+    fn park(&self, _: &dyn Fn() -> bool) {
+        hint::spin_loop()
+    }
+
+    fn unpark_one(&self) {}
+
+    fn unpark_all(&self) {}
 }
 
 scoped_thread_local!(pub(crate) static CX: Context);
@@ -48,6 +79,20 @@ impl Executor {
     /// The caller should iterate over the returned startup functions and run
     /// them concurrently.
     pub fn start<G, F>(num: usize, init: G) -> impl Iterator<Item = impl FnOnce() + Send>
+    where
+        G: FnOnce(Arsc<Executor>) -> F,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self::start_with_idle(num, Spin, init)
+    }
+
+    /// Like [`Executor::start`], but runners with nothing to run wait with
+    /// `idle` instead of spinning.
+    pub fn start_with_idle<G, F>(
+        num: usize,
+        idle: impl Idle,
+        init: G,
+    ) -> impl Iterator<Item = impl FnOnce() + Send>
     where
         G: FnOnce(Arsc<Executor>) -> F,
         F: Future<Output = ()> + Send + 'static,
@@ -64,6 +109,7 @@ impl Executor {
             injector: SegQueue::new(),
             stealers,
             shutdown: AtomicBool::new(false),
+            idle: Box::new(idle),
         });
 
         let e2 = executor.clone();
@@ -89,7 +135,14 @@ impl Executor {
     }
 
     pub fn shutdown(&self) {
-        self.shutdown.store(true, Release)
+        self.shutdown.store(true, Release);
+        self.idle.unpark_all();
+    }
+
+    fn has_task(&self) -> bool {
+        self.shutdown.load(Acquire)
+            || !self.injector.is_empty()
+            || self.stealers.iter().any(|stealer| !stealer.is_empty())
     }
 
     fn startup(rq: Local<Runnable, WORKER_CAP>, executor: Arsc<Executor>) {
@@ -163,7 +216,11 @@ impl Context {
                 continue;
             }
 
-            hint::spin_loop();
+            self.executor.idle.park(&|| {
+                let worker = self.worker.try_borrow();
+                let slot = worker.map_or(true, |worker| worker.preempt_slot.is_some());
+                slot || self.executor.has_task()
+            });
         }
     }
 
@@ -174,6 +231,7 @@ impl Context {
             } else {
                 cx.executor.injector.push(task)
             }
+            cx.executor.idle.unpark_one();
         });
         if ret.is_none() {
             log::warn!("executor exited while scheduling");
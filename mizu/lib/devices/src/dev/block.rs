@@ -25,6 +25,13 @@ pub trait Block: Io {
 
     async fn write(&self, block: usize, buf: &[u8]) -> Result<usize, Error>;
 
+    /// Discard `count` blocks from `block`. Devices without the support
+    /// return `EOPNOTSUPP`.
+    async fn discard_blocks(&self, block: usize, count: usize) -> Result<(), Error> {
+        let _ = (block, count);
+        Err(ksc::EOPNOTSUPP)
+    }
+
     async fn intr_dispatch(self: Arc<Self>, intr: Interrupt) {
         loop {
             let timer = Timer::after(Duration::from_millis(2));
@@ -105,6 +112,17 @@ macro_rules! impl_io_for_block {
             async fn flush(&self) -> Result<(), Error> {
                 Ok(())
             }
+
+            async fn discard(&self, offset: usize, len: usize) -> Result<(), Error> {
+                // Only the blocks fully covered can be discarded.
+                let shift = self.block_shift();
+                let start = offset.div_ceil(1 << shift);
+                let end = offset.saturating_add(len) >> shift;
+                if start >= end {
+                    return Ok(());
+                }
+                Block::discard_blocks(self, start, end - start).await
+            }
         }
     };
 }
@@ -12,6 +12,9 @@ mod virt;
 
 pub use self::{
     frame::{frames, init_frames, Arena},
-    phys::{Frame, Phys, ZERO},
+    phys::{
+        memory_report, set_anon_limit, CowBreakHook, FlushOrder, Frame, MemReport, Phys, PinGuard,
+        ZERO,
+    },
     virt::Virt,
 };
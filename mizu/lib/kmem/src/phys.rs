@@ -1,35 +1,107 @@
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
 use core::{
     borrow::Borrow,
     fmt, mem,
     num::NonZeroUsize,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Range, RangeInclusive},
     ptr::NonNull,
-    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst},
 };
 
 use async_trait::async_trait;
 use crossbeam_queue::SegQueue;
-use futures_util::Future;
+use futures_util::{future::join_all, stream, Future, Stream, StreamExt, TryFutureExt};
 use hashbrown::{
     hash_map::{Entry, OccupiedEntry},
     HashMap,
 };
-use ksc_core::{
-    handler::Boxed,
-    Error::{self, EINVAL, ENOENT, ENOMEM},
-};
+use ksc_core::Error::{self, EBUSY, EINVAL, EIO, ENOENT, ENOMEM, EOPNOTSUPP};
 use ksync::{unbounded, Receiver, Sender};
 use rand_riscv::RandomState;
-use rv39_paging::{PAddr, ID_OFFSET, PAGE_SHIFT, PAGE_SIZE};
-use spin::{Lazy, Mutex};
-use umio::{advance_slices, ioslice_len, Io, IoExt, IoSlice, IoSliceMut, SeekFrom};
+use rv39_paging::{PAddr, ID_OFFSET, PAGE_MASK, PAGE_SHIFT, PAGE_SIZE};
+use spin::{Lazy, Mutex, MutexGuard};
+use umio::{ioslice_len, FlushReport, Io, IoExt, IoSlice, IoSliceMut, SeekFrom, SliceCursor};
 
 pub static ZERO: Lazy<Arc<Frame>> = Lazy::new(|| Arc::new(Frame::new().unwrap()));
 
+static ANON_PAGES: AtomicUsize = AtomicUsize::new(0);
+static FILE_PAGES: AtomicUsize = AtomicUsize::new(0);
+static ANON_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// The logical clock of page accesses, ticking once per commit.
+static ACCESS_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// The numbers of pages committed by all the [`Phys`] objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemReport {
+    /// Pages of anonymous memory, including private copies of file pages.
+    pub anon_pages: usize,
+    /// Pages caching the contents of backends, which can be reclaimed.
+    pub file_pages: usize,
+    /// The hard cap of `anon_pages`.
+    pub anon_limit: usize,
+}
+
+pub fn memory_report() -> MemReport {
+    MemReport {
+        anon_pages: ANON_PAGES.load(SeqCst),
+        file_pages: FILE_PAGES.load(SeqCst),
+        anon_limit: ANON_LIMIT.load(SeqCst),
+    }
+}
+
+/// Set the hard cap of anonymous pages, beyond which committing new ones
+/// fails with `ENOMEM`. Pages already committed are not affected.
+pub fn set_anon_limit(pages: usize) {
+    ANON_LIMIT.store(pages, SeqCst)
+}
+
+/// What a frame is accounted as in the [`MemReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Charge {
+    None,
+    Anon,
+    File,
+}
+
+impl Charge {
+    fn charge(self) -> Result<(), Error> {
+        match self {
+            Charge::None => {}
+            Charge::Anon => {
+                let limit = ANON_LIMIT.load(SeqCst);
+                let res = ANON_PAGES
+                    .fetch_update(SeqCst, SeqCst, |pages| (pages < limit).then_some(pages + 1));
+                res.map_err(|_| ENOMEM)?;
+            }
+            Charge::File => {
+                FILE_PAGES.fetch_add(1, SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    fn uncharge(self) {
+        match self {
+            Charge::None => {}
+            Charge::Anon => {
+                ANON_PAGES.fetch_sub(1, SeqCst);
+            }
+            Charge::File => {
+                FILE_PAGES.fetch_sub(1, SeqCst);
+            }
+        }
+    }
+}
+
 pub struct Frame {
     base: PAddr,
     ptr: NonNull<u8>,
+    charge: Charge,
 }
 
 impl fmt::Debug for Frame {
@@ -43,13 +115,37 @@ unsafe impl Sync for Frame {}
 
 impl Frame {
     pub fn new() -> Result<Self, Error> {
-        let laddr = crate::frame::frames()
-            .allocate(NonZeroUsize::MIN)
-            .ok_or(ENOMEM)?;
-        unsafe { laddr.write_bytes(0, PAGE_SIZE) };
+        Self::new_charged(Charge::None)
+    }
+
+    /// Allocate a frame without zeroing it.
+    ///
+    /// # Safety
+    ///
+    /// The frame holds stale data of its previous owner, so the caller must
+    /// write every byte of it before exposing it to anyone else, e.g. by
+    /// mapping it or handing it out of the function.
+    pub unsafe fn new_uninit() -> Result<Self, Error> {
+        Self::new_charged_uninit(Charge::None)
+    }
+
+    fn new_charged(charge: Charge) -> Result<Self, Error> {
+        let mut frame = unsafe { Self::new_charged_uninit(charge) }?;
+        frame.fill(0);
+        Ok(frame)
+    }
+
+    /// See [`Frame::new_uninit`] for the safety contract.
+    unsafe fn new_charged_uninit(charge: Charge) -> Result<Self, Error> {
+        charge.charge()?;
+        let Some(laddr) = crate::frame::frames().allocate(NonZeroUsize::MIN) else {
+            charge.uncharge();
+            return Err(ENOMEM);
+        };
         Ok(Frame {
             base: laddr.to_paddr(ID_OFFSET),
             ptr: laddr.as_non_null().unwrap(),
+            charge,
         })
     }
 
@@ -69,11 +165,26 @@ impl Frame {
         unsafe { self.as_ptr().as_mut() }
     }
 
+    /// Make a private copy of the first `len` bytes, which is accounted as
+    /// anonymous memory.
     pub fn copy(&self, len: usize) -> Result<Frame, Error> {
-        let mut f = Self::new()?;
+        // SAFETY: Every byte is written right below.
+        let mut f = unsafe { Self::new_charged_uninit(Charge::Anon) }?;
         f[..len].copy_from_slice(&self[..len]);
+        f[len..].fill(0);
         Ok(f)
     }
+
+    /// The FNV-1a hash of the contents of this frame.
+    pub fn content_hash(&self) -> u64 {
+        umio::fnv1a(self.as_slice())
+    }
+
+    /// Whether the contents of the two frames are the same, unlike `==` which
+    /// compares the physical addresses.
+    pub fn content_eq(&self, other: &Frame) -> bool {
+        self.as_slice() == other.as_slice()
+    }
 }
 
 impl Deref for Frame {
@@ -93,6 +204,7 @@ impl Drop for Frame {
     fn drop(&mut self) {
         let laddr = self.base.to_laddr(ID_OFFSET);
         unsafe { crate::frame::frames().deallocate(laddr, NonZeroUsize::MIN) }
+        self.charge.uncharge();
     }
 }
 
@@ -131,7 +243,9 @@ impl FrameState {
 
 enum Commit {
     Shared(Arc<Frame>, usize),
-    Unique(FrameInfo),
+    /// The frame info moved out of a branch, and whether the frame has been
+    /// copied for it, i.e. a copy-on-write break.
+    Unique(FrameInfo, bool),
 }
 
 #[derive(Debug)]
@@ -139,6 +253,11 @@ struct FrameInfo {
     state: Option<FrameState>,
     dirty: bool,
     pin: usize,
+    /// Whether the frame is shared with other pages by
+    /// [`Phys::merge_identical`], and must be copied before written.
+    merged: bool,
+    /// The [`ACCESS_CLOCK`] tick of the last commit of the page.
+    accessed: u64,
 }
 
 impl FrameInfo {
@@ -147,7 +266,38 @@ impl FrameInfo {
             state: Some(FrameState::Shared(frame, len)),
             dirty: false,
             pin: 0,
+            merged: false,
+            accessed: ACCESS_CLOCK.load(SeqCst),
+        }
+    }
+
+    /// Whether the page would be read back the same from the backend if
+    /// dropped.
+    fn is_evictable(&self) -> bool {
+        match &self.state {
+            Some(FrameState::Shared(frame, _)) => {
+                !self.dirty && self.pin == 0 && !self.merged && Arc::strong_count(frame) == 1
+            }
+            _ => false,
+        }
+    }
+
+    fn touch(&mut self) {
+        self.accessed = ACCESS_CLOCK.fetch_add(1, SeqCst) + 1;
+    }
+
+    /// Give the page its own copy of the frame if it was merged with others,
+    /// or if it is the global [`ZERO`] frame, which must never be written.
+    fn unmerge(&mut self) -> Result<(), Error> {
+        let merged = mem::replace(&mut self.merged, false);
+        if let Some(FrameState::Shared(frame, _) | FrameState::Unique(frame, _)) = &mut self.state {
+            if Arc::ptr_eq(frame, &ZERO) {
+                *frame = Arc::new(Frame::new_charged(Charge::Anon)?);
+            } else if merged {
+                *frame = Arc::new(frame.copy(PAGE_SIZE)?);
+            }
         }
+        Ok(())
     }
 
     fn branch(
@@ -157,6 +307,10 @@ impl FrameInfo {
         cow: bool,
     ) -> Result<(Commit, bool), Error> {
         // log::trace!("branch write = {write:?} pin = {pin} cow = {cow}");
+        self.touch();
+        if write.is_some() && !cow {
+            self.unmerge()?;
+        }
         match mem::take(&mut self.state) {
             Some(FrameState::Shared(frame, len)) => match write {
                 None => {
@@ -175,16 +329,21 @@ impl FrameInfo {
                     let new_frame = frame.copy(new_len)?;
                     self.state = Some(FrameState::Unique(frame, new_len));
                     Ok((
-                        Commit::Unique(FrameInfo::new(Arc::new(new_frame), new_len)),
+                        Commit::Unique(FrameInfo::new(Arc::new(new_frame), new_len), true),
                         false,
                     ))
                 }
             },
             Some(FrameState::Unique(frame, len)) => Ok((
-                Commit::Unique(FrameInfo {
-                    pin: self.pin,
-                    ..FrameInfo::new(frame, len)
-                }),
+                Commit::Unique(
+                    FrameInfo {
+                        pin: self.pin,
+                        merged: self.merged,
+                        accessed: self.accessed,
+                        ..FrameInfo::new(frame, len)
+                    },
+                    false,
+                ),
                 true,
             )),
             None => Err(ENOENT),
@@ -193,13 +352,17 @@ impl FrameInfo {
 
     fn leaf(&mut self, write: Option<usize>, pin: bool) -> Result<(Arc<Frame>, usize), Error> {
         // log::trace!("leaf write = {write:?} pin = {pin}");
+        self.touch();
+        if write.is_some() {
+            self.unmerge()?;
+        }
         self.dirty |= write.is_some();
         self.pin += pin as usize;
         match &mut self.state {
             Some(s) => Ok(s.frame(write)),
             None => match write {
                 Some(new_len) => {
-                    let frame = Arc::new(Frame::new()?);
+                    let frame = Arc::new(Frame::new_charged(Charge::Anon)?);
                     self.state = Some(FrameState::Shared(frame.clone(), new_len));
                     Ok((frame, new_len))
                 }
@@ -208,6 +371,20 @@ impl FrameInfo {
         }
     }
 
+    /// Insert `fi` at `index` unless another commit got there first while the
+    /// list was unlocked, in which case that frame is kept, as it may have been
+    /// written since.
+    fn insert_vacant(
+        frames: &mut HashMap<usize, FrameInfo, RandomState>,
+        index: usize,
+        fi: FrameInfo,
+    ) -> OccupiedEntry<usize, FrameInfo, RandomState> {
+        match frames.entry(index) {
+            Entry::Occupied(ent) => ent,
+            Entry::Vacant(ent) => ent.insert_entry(fi),
+        }
+    }
+
     fn get(
         mut this: OccupiedEntry<usize, FrameInfo, RandomState>,
         branch: bool,
@@ -228,6 +405,14 @@ impl FrameInfo {
     }
 }
 
+/// Where [`Phys::peek_page`] finds a page.
+enum Peek {
+    /// Resident at some level of the parent chain, or a hole read as zeros.
+    Frame(Arc<Frame>, usize),
+    /// Only present in the backend, at the page index there.
+    Backend(Arc<dyn Io>, usize),
+}
+
 #[derive(Clone)]
 enum Parent {
     Phys {
@@ -272,19 +457,169 @@ struct FrameList {
     frames: HashMap<usize, FrameInfo, RandomState>,
 }
 
+impl FrameList {
+    /// Whether `pages` are all holes of an object without a parent, so that
+    /// they can be read as zeros without committing any of them.
+    ///
+    /// Only the pages in the range are looked up, however many are resident.
+    fn holes(&self, pages: RangeInclusive<usize>) -> bool {
+        self.parent.is_none()
+            && !pages
+                .into_iter()
+                .any(|index| self.frames.contains_key(&index))
+    }
+}
+
+/// The order in which [`Phys::flush_all_ordered`] writes back the levels of a
+/// parent chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushOrder {
+    /// Write back the pages of the object itself before those of its
+    /// ancestors.
+    #[default]
+    ChildFirst,
+    /// Write back the pages of the farthest ancestor first, and those of the
+    /// object itself last.
+    ParentFirst,
+}
+
 #[derive(Debug, Clone)]
 struct Flusher {
     sender: Sender<SegQueue<FlushData>>,
     offset: usize,
+    failures: Arc<FlushFailures>,
+}
+
+/// The write-back failures reported by the flusher to the objects feeding it.
+#[derive(Debug, Default)]
+struct FlushFailures {
+    last_error: Mutex<Option<Error>>,
+    /// The pages that failed to be written, by their backend indices, to be
+    /// marked dirty again.
+    pages: Mutex<Vec<(usize, Arc<Frame>)>>,
+}
+
+impl FlushFailures {
+    fn record(&self, page: Option<(usize, Arc<Frame>)>, err: Error) {
+        log::warn!("Phys::flusher: write-back failed: {err}");
+        ksync::critical(|| {
+            *self.last_error.lock() = Some(err);
+            self.pages.lock().extend(page);
+        })
+    }
+}
+
+impl Flusher {
+    async fn send_sync(&self, page: Option<(usize, Arc<Frame>, usize)>) -> Result<(), Error> {
+        let (tx, rx) = unbounded();
+        let _ = self.sender.send(FlushData::SingleSync(page, tx)).await;
+        match rx.recv().await {
+            Ok(res) => res,
+            Err(err) => err.data().ok_or(EIO)?,
+        }
+    }
+
+    async fn send_durable(
+        &self,
+        data: Vec<(usize, Arc<Frame>, usize)>,
+    ) -> Result<FlushReport, Error> {
+        let (tx, rx) = unbounded();
+        let _ = self.sender.send(FlushData::Durable(data, tx)).await;
+        match rx.recv().await {
+            Ok(res) => res,
+            // The flusher drops the sender right after replying, and is gone
+            // without replying only if the backend is no longer reachable.
+            Err(err) => err.data().ok_or(EIO)?,
+        }
+    }
+}
+
+/// Called by [`Phys`] with the count of its copy-on-write breaks when the
+/// threshold is reached.
+pub type CowBreakHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+#[derive(Default)]
+struct CowBreaks {
+    count: AtomicUsize,
+    hook: Mutex<Option<(usize, CowBreakHook)>>,
+}
+
+impl CowBreaks {
+    fn record(&self) {
+        let count = self.count.fetch_add(1, SeqCst) + 1;
+        let hook = ksync::critical(|| self.hook.lock().clone());
+        if let Some((threshold, hook)) = hook {
+            if count == threshold {
+                hook(count)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for CowBreaks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CowBreaks")
+            .field("count", &self.count)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug)]
 pub struct Phys {
     branch: bool,
+    /// The lock is only taken in short synchronous critical sections, and is
+    /// never held across an `.await` (notably not across backend reads in
+    /// `commit_impl`), so commits to different pages never wait on each
+    /// other's I/O. This keeps a single lock cheap enough that sharding it by
+    /// page index isn't worth the extra bookkeeping.
     list: Mutex<FrameList>,
     position: AtomicUsize,
+    /// The end of the furthest write, which is the only source of length for
+    /// anonymous objects.
+    len: AtomicUsize,
     cow: bool,
     flusher: Option<Flusher>,
+    cow_breaks: CowBreaks,
+    combining: AtomicBool,
+    combine: Mutex<Option<Combine>>,
+    /// The maximum number of pages a single read or write commits, or 0 if
+    /// unlimited.
+    fault_limit: AtomicUsize,
+    /// The number of pages read ahead of each read, or 0 if disabled.
+    readahead: AtomicUsize,
+    /// The end of the pages last read ahead.
+    readahead_next: AtomicUsize,
+    /// Where the reads queue the windows to read ahead.
+    prefetcher: Mutex<Option<Sender<SegQueue<(usize, usize)>>>>,
+    /// Whether reads and writes go to the backend directly, bypassing the
+    /// frames.
+    write_through: bool,
+}
+
+/// The number of pages committed concurrently by [`Phys::pages`].
+const PAGES_READAHEAD: usize = 8;
+
+/// Writes no larger than this are combined if enabled.
+const COMBINE_MAX: usize = 128;
+
+/// The page written directly by small writes with write combining, whose
+/// length and dirty state are not updated in the frame list yet.
+#[derive(Debug)]
+struct Combine {
+    index: usize,
+    frame: Arc<Frame>,
+    end: usize,
+}
+
+impl Combine {
+    fn settle(self, list: &mut FrameList) {
+        if let Some(fi) = list.frames.get_mut(&self.index) {
+            fi.dirty = true;
+            if let Some(state) = &mut fi.state {
+                state.frame(Some(self.end));
+            }
+        }
+    }
 }
 
 impl Phys {
@@ -294,6 +629,7 @@ impl Phys {
         cow: bool,
     ) -> (Self, impl Future<Output = ()> + Send) {
         let (sender, receiver) = unbounded();
+        let failures = Arc::<FlushFailures>::default();
         let phys = Phys {
             branch: false,
             list: Mutex::new(FrameList {
@@ -301,10 +637,52 @@ impl Phys {
                 frames: Default::default(),
             }),
             position: initial_pos.into(),
+            len: Default::default(),
             cow,
-            flusher: cow.then_some(Flusher { sender, offset: 0 }),
+            flusher: cow.then_some(Flusher {
+                sender,
+                offset: 0,
+                failures: failures.clone(),
+            }),
+            cow_breaks: Default::default(),
+            combining: Default::default(),
+            fault_limit: Default::default(),
+            readahead: Default::default(),
+            readahead_next: Default::default(),
+            prefetcher: Default::default(),
+            combine: Default::default(),
+            write_through: false,
         };
-        (phys, flusher(receiver, backend))
+        (phys, flusher(receiver, backend, failures))
+    }
+
+    /// Create an object that reads and writes `backend` directly, for backends
+    /// like device control files whose every access must reach the device.
+    ///
+    /// This disables the page cache for reads and writes of this object: each
+    /// write returns only after the backend write completes, and each read
+    /// fetches fresh data from the backend. Pages committed for mappings are
+    /// still cached, and are never written back.
+    pub fn new_write_through(backend: Arc<dyn Io>) -> Phys {
+        Phys {
+            branch: false,
+            list: Mutex::new(FrameList {
+                parent: Some(Parent::Backend(backend)),
+                frames: Default::default(),
+            }),
+            position: Default::default(),
+            len: Default::default(),
+            cow: false,
+            flusher: None,
+            cow_breaks: Default::default(),
+            combining: Default::default(),
+            fault_limit: Default::default(),
+            readahead: Default::default(),
+            readahead_next: Default::default(),
+            prefetcher: Default::default(),
+            combine: Default::default(),
+            write_through: true,
+        }
     }
 
     pub fn new_anon(cow: bool) -> Phys {
@@ -315,24 +693,44 @@ impl Phys {
                 frames: Default::default(),
             }),
             position: Default::default(),
+            len: Default::default(),
             cow,
             flusher: None,
+            cow_breaks: Default::default(),
+            combining: Default::default(),
+            fault_limit: Default::default(),
+            readahead: Default::default(),
+            readahead_next: Default::default(),
+            prefetcher: Default::default(),
+            combine: Default::default(),
+            write_through: false,
         }
     }
 
     pub fn clone_as(&self, cow: bool, index_offset: usize, fixed_count: Option<usize>) -> Self {
         let branch = ksync::critical(|| {
-            let mut list = self.list.lock();
+            // The combined page is settled here, as it is about to be shared
+            // with the other clone and must not be written directly anymore.
+            let mut list = self.list();
 
             let branch = Arc::new(Phys {
                 branch: true,
                 position: Default::default(),
+                len: self.len.load(SeqCst).into(),
                 list: Mutex::new(FrameList {
                     parent: list.parent.clone(),
                     frames: mem::take(&mut list.frames),
                 }),
                 cow: false,
                 flusher: None,
+                cow_breaks: Default::default(),
+                combining: Default::default(),
+                fault_limit: Default::default(),
+                readahead: Default::default(),
+                readahead_next: Default::default(),
+                prefetcher: Default::default(),
+                combine: Default::default(),
+                write_through: false,
             });
 
             list.parent = Some(Parent::Phys {
@@ -355,6 +753,7 @@ impl Phys {
                 frames: Default::default(),
             }),
             position: Default::default(),
+            len: Default::default(),
             cow,
             flusher: self.flusher.clone().and_then(|flusher| {
                 cow.then_some(Flusher {
@@ -362,101 +761,592 @@ impl Phys {
                     ..flusher
                 })
             }),
+            cow_breaks: Default::default(),
+            combining: Default::default(),
+            fault_limit: Default::default(),
+            readahead: Default::default(),
+            readahead_next: Default::default(),
+            prefetcher: Default::default(),
+            combine: Default::default(),
+            write_through: false,
+        }
+    }
+
+    /// Move the pages from `at_page` on into a new object, where they start
+    /// from index 0, and whose writeback goes to the same place in the backend
+    /// as before.
+    ///
+    /// Both objects keep sharing the parent, whose range seen by this object
+    /// ends at `at_page` from now on. A backend can't be cut like that though,
+    /// and neither can the writeback, so this object should no longer access
+    /// the pages moved out, or the two may write back over each other.
+    pub fn split_off(&self, at_page: usize) -> Phys {
+        assert!(!self.branch && !self.write_through);
+        let (parent, frames) = ksync::critical(|| {
+            let mut list = self.list();
+
+            let indices = list.frames.keys().filter(|&&index| index >= at_page);
+            let indices = indices.copied().collect::<Vec<_>>();
+            let frames = indices.into_iter().map(|index| {
+                let fi = list.frames.remove(&index).unwrap();
+                (index - at_page, fi)
+            });
+            let frames = frames.collect::<HashMap<_, _, RandomState>>();
+
+            let parent = match &mut list.parent {
+                Some(Parent::Phys { phys, start, end }) => {
+                    let split = (*start + at_page).min(end.unwrap_or(usize::MAX));
+                    let phys = phys.clone();
+                    let end = mem::replace(end, Some(split));
+                    Some(Parent::Phys {
+                        phys,
+                        start: split,
+                        end,
+                    })
+                }
+                // Backends can only be read from their starts, so offset them
+                // with an empty branch.
+                Some(Parent::Backend(backend)) => Some(Parent::Phys {
+                    phys: Arc::new(Phys {
+                        branch: true,
+                        list: Mutex::new(FrameList {
+                            parent: Some(Parent::Backend(backend.clone())),
+                            frames: Default::default(),
+                        }),
+                        position: Default::default(),
+                        len: Default::default(),
+                        cow: false,
+                        flusher: None,
+                        cow_breaks: Default::default(),
+                        combining: Default::default(),
+                        fault_limit: Default::default(),
+                        readahead: Default::default(),
+                        readahead_next: Default::default(),
+                        prefetcher: Default::default(),
+                        combine: Default::default(),
+                        write_through: false,
+                    }),
+                    start: at_page,
+                    end: None,
+                }),
+                None => None,
+            };
+            (parent, frames)
+        });
+
+        let split_len = at_page << PAGE_SHIFT;
+        let len = self.len.fetch_min(split_len, SeqCst);
+        Phys {
+            branch: false,
+            list: Mutex::new(FrameList { parent, frames }),
+            position: Default::default(),
+            len: len.saturating_sub(split_len).into(),
+            cow: self.cow,
+            flusher: self.flusher.clone().map(|flusher| Flusher {
+                offset: flusher.offset + at_page,
+                ..flusher
+            }),
+            cow_breaks: Default::default(),
+            combining: Default::default(),
+            fault_limit: Default::default(),
+            readahead: Default::default(),
+            readahead_next: Default::default(),
+            prefetcher: Default::default(),
+            combine: Default::default(),
+            write_through: false,
         }
     }
 
     pub fn is_cow(&self) -> bool {
         self.cow
     }
+
+    /// Enable or disable write combining, with which small writes to the same
+    /// page are copied directly into the last written frame, without
+    /// committing it again, until another page is written or the object is
+    /// read, flushed or cloned.
+    pub fn set_write_combining(&self, enabled: bool) {
+        self.combining.store(enabled, SeqCst);
+        if !enabled {
+            ksync::critical(|| drop(self.list()));
+        }
+    }
+
+    /// Limit the number of pages a single [`Io::read_at`] or [`Io::write_at`]
+    /// commits, or lift the limit if `None`.
+    ///
+    /// Requests spanning more pages return a short count, so that callers
+    /// looping over huge requests give the frame allocator a chance to reclaim
+    /// memory between chunks.
+    pub fn set_fault_limit(&self, pages: Option<NonZeroUsize>) {
+        self.fault_limit
+            .store(pages.map_or(0, NonZeroUsize::get), SeqCst);
+    }
+
+    /// Read `pages` pages ahead of each [`Io::read_at`], or disable read-ahead
+    /// if 0, returning the future committing them in the background, which
+    /// must be spawned like the flusher of [`Phys::new`].
+    ///
+    /// The reads only queue the pages to the future and never wait for them.
+    /// The pages are committed for reading only, and are never pinned or
+    /// marked dirty. The future ends once the object is dropped or read-ahead
+    /// is set again.
+    pub fn set_readahead(self: &Arc<Self>, pages: usize) -> impl Future<Output = ()> + Send {
+        let (tx, rx) = unbounded();
+        self.readahead.store(pages, SeqCst);
+        ksync::critical(|| *self.prefetcher.lock() = (pages > 0).then_some(tx));
+        prefetcher(rx, Arc::downgrade(self))
+    }
+
+    /// Shorten the range `start..end` to the pages allowed by the fault limit.
+    fn limit_faults(&self, start: usize, end: usize) -> usize {
+        match self.fault_limit.load(SeqCst) {
+            0 => end,
+            limit => {
+                let start_page = start >> PAGE_SHIFT;
+                let limit_end = start_page.saturating_add(limit).saturating_mul(PAGE_SIZE);
+                end.min(limit_end)
+            }
+        }
+    }
+
+    /// Collapse the resident clean pages owned by this object that have the
+    /// same contents into a single shared frame, returning the number of
+    /// frames reclaimed.
+    ///
+    /// Pages that are pinned, shared with other objects, or waiting to be
+    /// written back are skipped. The merged pages get their own copies again
+    /// once written. This never runs implicitly.
+    pub fn merge_identical(&self) -> Result<usize, Error> {
+        let writeback = self.flusher.is_some();
+        let mergeable = move |fi: &FrameInfo| match &fi.state {
+            Some(FrameState::Shared(frame, len)) if fi.pin == 0 && !(writeback && fi.dirty) => {
+                (fi.merged || Arc::strong_count(frame) == 1).then_some((frame.clone(), *len))
+            }
+            _ => None,
+        };
+
+        let candidates: Vec<_> = ksync::critical(|| {
+            let list = self.list();
+            let iter = list.frames.iter();
+            iter.filter_map(|(&index, fi)| mergeable(fi).map(|(frame, len)| (index, frame, len)))
+                .collect()
+        });
+
+        // Group the candidates outside of the lock, since hashing and comparing
+        // whole pages takes a while.
+        let mut buckets = HashMap::<_, Vec<Vec<_>>, RandomState>::default();
+        for (index, frame, len) in candidates {
+            let groups = buckets.entry((frame.content_hash(), len)).or_default();
+            match groups.iter_mut().find(|g| g[0].1.content_eq(&frame)) {
+                Some(group) => group.push((index, frame)),
+                None => groups.push(alloc::vec![(index, frame)]),
+            }
+        }
+
+        // Pages still referenced only by the list and the candidate haven't been
+        // handed out for writing since they were collected.
+        let unchanged = move |fi: &FrameInfo, frame: &Arc<Frame>| match &fi.state {
+            Some(FrameState::Shared(cur, _)) => {
+                fi.pin == 0
+                    && !(writeback && fi.dirty)
+                    && Arc::ptr_eq(cur, frame)
+                    && (fi.merged || Arc::strong_count(cur) == 2)
+            }
+            _ => false,
+        };
+
+        let mut reclaimed = 0;
+        ksync::critical(|| {
+            let mut list = self.list();
+            let groups = buckets.iter().flat_map(|(&(_, len), groups)| {
+                let groups = groups.iter().filter(|group| group.len() > 1);
+                groups.map(move |group| (len, group))
+            });
+            for (len, group) in groups {
+                let (target_index, target) = &group[0];
+                match list.frames.get_mut(target_index) {
+                    Some(fi) if unchanged(fi, target) => fi.merged = true,
+                    _ => continue,
+                }
+                for (index, frame) in &group[1..] {
+                    let Some(fi) = list.frames.get_mut(index) else {
+                        continue;
+                    };
+                    if !unchanged(fi, frame) || !frame.content_eq(target) {
+                        continue;
+                    }
+                    if !fi.merged {
+                        reclaimed += 1;
+                    }
+                    fi.merged = true;
+                    fi.state = Some(FrameState::Shared(target.clone(), len));
+                }
+            }
+        });
+        Ok(reclaimed)
+    }
+
+    /// Drop up to `max_pages` clean pages cached from the backend, the least
+    /// recently committed first, returning the number of frames freed.
+    ///
+    /// Only pages that would be read back the same from the backend are
+    /// dropped, so dirty, pinned and shared pages are skipped, and so are all
+    /// the pages of objects whose parents are other objects.
+    pub async fn evict_clean(&self, max_pages: usize) -> usize {
+        ksync::critical(|| {
+            let mut list = self.list();
+            if self.branch || !matches!(list.parent, Some(Parent::Backend(_))) {
+                return 0;
+            }
+            let iter = list.frames.iter().filter(|(_, fi)| fi.is_evictable());
+            let mut candidates: Vec<_> = iter.map(|(&index, fi)| (fi.accessed, index)).collect();
+            candidates.sort_unstable();
+
+            let victims = candidates.into_iter().take(max_pages);
+            victims
+                .filter(|(_, index)| list.frames.remove(index).is_some())
+                .count()
+        })
+    }
+
+    /// Lock the frame list, settling the writes combined so far in it first.
+    ///
+    /// Everything but the read-only peeks looks at the frame list through
+    /// here, so that the combined page is never seen with a stale length or
+    /// dirty state, and never written directly after it is shared or flushed.
+    fn list(&self) -> MutexGuard<'_, FrameList> {
+        let mut list = self.list.lock();
+        if let Some(combine) = self.combine.lock().take() {
+            combine.settle(&mut list);
+        }
+        list
+    }
+
+    /// Like [`Phys::list`], but with exclusive access.
+    fn list_mut(&mut self) -> &mut FrameList {
+        if let Some(combine) = self.combine.get_mut().take() {
+            combine.settle(self.list.get_mut());
+        }
+        self.list.get_mut()
+    }
+
+    /// The number of pages of this object committed within the last `window`
+    /// ticks of the logical clock counting commits of all the objects.
+    pub fn working_set(&self, window: u64) -> usize {
+        let now = ACCESS_CLOCK.load(SeqCst);
+        ksync::critical(|| {
+            let list = self.list();
+            let frames = list.frames.values();
+            frames
+                .filter(|fi| now.saturating_sub(fi.accessed) < window)
+                .count()
+        })
+    }
+
+    /// The number of pages committed to this object itself, not counting those
+    /// of its parents.
+    pub fn resident_pages(&self) -> usize {
+        ksync::critical(|| self.list().frames.len())
+    }
+
+    /// The number of pages of this object not yet written back.
+    pub fn dirty_pages(&self) -> usize {
+        self.redirty_failed();
+        ksync::critical(|| {
+            let list = self.list();
+            list.frames.values().filter(|fi| fi.dirty).count()
+        })
+    }
+
+    /// The total pin count of the pages of this object.
+    pub fn pinned_pages(&self) -> usize {
+        ksync::critical(|| self.list().frames.values().map(|fi| fi.pin).sum())
+    }
+
+    /// The number of pages copied for writes to this object because they were
+    /// shared with other objects.
+    pub fn cow_breaks(&self) -> usize {
+        self.cow_breaks.count.load(SeqCst)
+    }
+
+    /// Call `hook` with the current count once the copy-on-write breaks of
+    /// this object reach `threshold`.
+    ///
+    /// This is only a notification, e.g. for logging or memory control, and
+    /// the breaks themselves are never refused.
+    pub fn set_cow_break_hook(&self, threshold: usize, hook: CowBreakHook) {
+        ksync::critical(|| *self.cow_breaks.hook.lock() = Some((threshold, hook)))
+    }
+
+    pub fn clear_cow_break_hook(&self) {
+        ksync::critical(|| *self.cow_breaks.hook.lock() = None)
+    }
+
+    /// Drop the clean resident pages in `byte_range`, so that they will be
+    /// re-read from the backend on next access. Used when the backend has
+    /// been written to directly, bypassing this cache.
+    ///
+    /// Fails with `EBUSY` without dropping anything if any of the pages is
+    /// dirty or pinned; the caller must flush or unmap them first.
+    pub fn invalidate_range(&self, byte_range: Range<usize>) -> Result<(), Error> {
+        let start = byte_range.start >> PAGE_SHIFT;
+        let end = byte_range.end.saturating_add(PAGE_MASK) >> PAGE_SHIFT;
+        let range = start..end;
+
+        ksync::critical(|| {
+            let mut list = self.list();
+            let busy = list
+                .frames
+                .iter()
+                .any(|(index, fi)| range.contains(index) && (fi.dirty || fi.pin > 0));
+            if busy {
+                return Err(EBUSY);
+            }
+            list.frames.retain(|index, _| !range.contains(index));
+            Ok(())
+        })
+    }
+
+    /// Atomically exchange the pages in `a` with those in `b`, so that
+    /// readers see either all the old pages or all the new ones.
+    ///
+    /// The ranges must have the same length and must not overlap. Only the
+    /// pages committed to this object are exchanged; those not yet committed
+    /// keep being read from the parent at their own indices. Returns `EBUSY`
+    /// if any of the pages is pinned.
+    pub fn swap_pages(&self, a: Range<usize>, b: Range<usize>) -> Result<(), Error> {
+        if a.len() != b.len() || (a.start < b.end && b.start < a.end && !a.is_empty()) {
+            return Err(EINVAL);
+        }
+        ksync::critical(|| {
+            let mut list = self.list();
+            let mut indices = a.clone().chain(b.clone());
+            let pinned = indices.any(|index| list.frames.get(&index).is_some_and(|fi| fi.pin > 0));
+            if pinned {
+                return Err(EBUSY);
+            }
+            for (x, y) in a.zip(b) {
+                let fx = list.frames.remove(&x);
+                let fy = list.frames.remove(&y);
+                if let Some(fy) = fy {
+                    list.frames.insert(x, fy);
+                }
+                if let Some(fx) = fx {
+                    list.frames.insert(y, fx);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Discard the pages of this object beyond `new_len`, and zero the tail of
+    /// the last page, which is marked dirty so that the truncation reaches the
+    /// backend on flush.
+    ///
+    /// Fails with `EBUSY` without dropping anything if any of the discarded
+    /// pages is pinned. Pages not committed to this object are still read from
+    /// the parent, so the backend must be truncated by the caller as well.
+    pub async fn truncate(&self, new_len: usize) -> Result<(), Error> {
+        let (boundary, tail) = (new_len >> PAGE_SHIFT, new_len & PAGE_MASK);
+        let keep = new_len.div_ceil(PAGE_SIZE);
+
+        let frame = match tail {
+            0 => None,
+            _ => Some(self.commit(boundary, Some(tail), false).await?.0),
+        };
+
+        ksync::critical(|| {
+            let mut list = self.list();
+            let mut frames = list.frames.iter();
+            if frames.any(|(&index, fi)| index >= keep && fi.pin > 0) {
+                return Err(EBUSY);
+            }
+            list.frames.retain(|&index, _| index < keep);
+            if let Some(fi) = list.frames.get_mut(&boundary).filter(|_| tail != 0) {
+                if let Some(FrameState::Shared(_, len) | FrameState::Unique(_, len)) = &mut fi.state
+                {
+                    *len = (*len).min(tail);
+                }
+            }
+            // Zero the tail in the same section, so that no read or write sees
+            // the shortened page with its stale bytes.
+            if let Some(frame) = &frame {
+                let mut dst = frame.as_ptr();
+                unsafe { dst.as_mut()[tail..].fill(0) }
+            }
+            self.len.store(new_len, SeqCst);
+            Ok(())
+        })
+    }
+
+    /// Deallocate the bytes in `range`, so that they are read as zeros again.
+    ///
+    /// The pages fully covered are dropped if this object has no parent, or
+    /// otherwise shadowed with zero pages up to the end of the parent, which
+    /// is left intact and gets the zeros on flush. The bytes in the partially
+    /// covered pages are zeroed in place.
+    ///
+    /// Fails with `EBUSY` without dropping anything if any of the fully
+    /// covered pages is pinned.
+    pub async fn punch_hole(&self, range: Range<usize>) -> Result<(), Error> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let ((start_page, start_offset), (end_page, end_offset)) = offsets(range.start, range.end);
+        let full = (start_page + (start_offset != 0) as usize)
+            ..(end_page + (end_offset == PAGE_SIZE) as usize);
+        let mut partial = Vec::new();
+        if start_page == end_page {
+            if !full.contains(&start_page) {
+                partial.push((start_page, start_offset..end_offset));
+            }
+        } else {
+            if start_offset != 0 {
+                partial.push((start_page, start_offset..PAGE_SIZE));
+            }
+            if end_offset != PAGE_SIZE {
+                partial.push((end_page, 0..end_offset));
+            }
+        }
+
+        let parent = ksync::critical(|| self.list().parent.clone());
+        let parent_len = match parent {
+            Some(ref parent) => parent.stream_len().await?,
+            None => 0,
+        };
+        let mut zeroed = Vec::new();
+        for (index, bytes) in partial {
+            let resident = ksync::critical(|| self.list().frames.contains_key(&index));
+            if !resident && parent.is_none() {
+                continue;
+            }
+            let (frame, _) = self.commit(index, Some(0), false).await?;
+            zeroed.push((frame, bytes));
+        }
+
+        ksync::critical(|| {
+            let mut list = self.list();
+            let mut frames = list.frames.iter();
+            if frames.any(|(index, fi)| full.contains(index) && fi.pin > 0) {
+                return Err(EBUSY);
+            }
+            list.frames.retain(|index, _| !full.contains(index));
+            let shadowed = full.start..full.end.min(parent_len.div_ceil(PAGE_SIZE));
+            for index in shadowed {
+                let len = (parent_len - (index << PAGE_SHIFT)).min(PAGE_SIZE);
+                let fi = FrameInfo {
+                    dirty: true,
+                    ..FrameInfo::new(ZERO.clone(), len)
+                };
+                list.frames.insert(index, fi);
+            }
+            // The partial pages are zeroed in the same section as the full
+            // ones are dropped, so that no read sees the hole half punched.
+            for (frame, bytes) in zeroed {
+                let mut dst = frame.as_ptr();
+                unsafe { dst.as_mut()[bytes].fill(0) }
+            }
+            Ok(())
+        })
+    }
 }
 
 impl Phys {
-    fn commit_impl(
+    /// Commit the page at `index`, walking up the parent chain until it is
+    /// found, and then handing it back down level by level.
+    ///
+    /// The chain is walked with a loop instead of recursion, so the depth of
+    /// nested `clone_as` doesn't grow the size of the future.
+    async fn commit_impl(
         &self,
         index: usize,
         write: Option<usize>,
         pin: bool,
         cow: bool,
-    ) -> Boxed<Result<Commit, Error>> {
-        let cow = self.cow || cow;
-        Box::pin(async move {
-            let self_get = ksync::critical(|| {
-                // log::trace!("Phys::commit_impl: return from self");
-                let mut list = self.list.lock();
+        cache: bool,
+    ) -> Result<Commit, Error> {
+        let (self_index, self_cow) = (index, self.cow || cow);
+        let (mut index, mut cow) = (self_index, self_cow);
+        // The ancestors visited, with the page indices and the merged `cow`
+        // flags at their levels.
+        let mut ancestors: Vec<(Arc<Phys>, usize, bool)> = Vec::new();
+
+        let mut commit = loop {
+            let this = ancestors.last().map_or(self, |(phys, ..)| &**phys);
+
+            let this_get = ksync::critical(|| {
+                let mut list = this.list();
                 if let Entry::Occupied(ent) = list.frames.entry(index) {
-                    return FrameInfo::get(ent, self.branch, write, pin, cow).map(Some);
+                    return FrameInfo::get(ent, this.branch, write, pin, cow).map(Some);
                 }
                 Ok::<_, Error>(None)
             })?;
-            if let Some(commit) = self_get {
-                return Ok(commit);
+            if let Some(commit) = this_get {
+                break commit;
             }
 
-            if let Some(parent) = ksync::critical(|| self.list.lock().parent.clone()) {
-                match parent {
-                    Parent::Phys {
-                        phys: parent,
-                        start,
-                        end,
-                    } => {
-                        // log::trace!("Phys::commit_impl: return from parent");
-                        if end.map_or(true, |end| (0..(end - start)).contains(&index)) {
-                            let parent_index = start + index;
-                            return match parent.commit_impl(parent_index, write, pin, cow).await {
-                                Ok(s @ Commit::Shared(..)) => Ok(s),
-                                Ok(Commit::Unique(fi)) => ksync::critical(|| {
-                                    let mut list = self.list.lock();
-                                    let ent = list.frames.entry(index).insert(fi);
-                                    FrameInfo::get(ent, self.branch, write, pin, cow)
-                                }),
-                                Err(err) => Err(err),
-                            };
-                        }
-                    }
-                    Parent::Backend(backend) => {
-                        // log::trace!("Phys::commit_impl: copy from backend");
-                        let mut frame = Frame::new()?;
-
-                        let len = {
-                            let mut read_len = 0;
-                            let mut offset = index << PAGE_SHIFT;
-                            let mut buffer = &mut frame[..];
-                            loop {
-                                if buffer.is_empty() {
-                                    break read_len;
-                                }
-                                let len = backend.read_at(offset, &mut [buffer]).await?;
-                                if len == 0 {
-                                    break read_len;
-                                }
-                                offset += len;
-                                read_len += len;
-                                buffer = &mut buffer[len..];
-                            }
-                        };
-                        let fi = FrameInfo::new(Arc::new(frame), len);
-                        return ksync::critical(|| {
-                            let mut list = self.list.lock();
-                            let ent = list.frames.entry(index).insert(fi);
-                            FrameInfo::get(ent, self.branch, write, pin, cow)
-                        });
+            match ksync::critical(|| this.list().parent.clone()) {
+                Some(Parent::Phys { phys, start, end })
+                    if end.map_or(true, |end| (0..(end - start)).contains(&index)) =>
+                {
+                    index += start;
+                    cow |= phys.cow;
+                    ancestors.push((phys, index, cow));
+                    continue;
+                }
+                Some(Parent::Backend(backend)) => {
+                    // log::trace!("Phys::commit_impl: copy from backend");
+                    let mut frame = Frame::new_charged(Charge::File)?;
+                    let len = read_page(&*backend, index, &mut frame).await?;
+                    if !cache {
+                        break Commit::Shared(Arc::new(frame), len);
                     }
+                    let fi = FrameInfo::new(Arc::new(frame), len);
+                    break ksync::critical(|| {
+                        let mut list = this.list();
+                        let ent = FrameInfo::insert_vacant(&mut list.frames, index, fi);
+                        FrameInfo::get(ent, this.branch, write, pin, cow)
+                    })?;
                 }
+                _ => {}
             }
 
             // log::trace!("Phys::commit_impl: return new frame");
 
             let Some(new_len) = write else {
-                return Ok(Commit::Shared(ZERO.clone(), 0));
+                break Commit::Shared(ZERO.clone(), 0);
             };
 
-            let fi = FrameInfo::new(Arc::new(Frame::new()?), new_len);
-            ksync::critical(|| {
-                let mut list = self.list.lock();
+            let fi = FrameInfo::new(Arc::new(Frame::new_charged(Charge::Anon)?), new_len);
+            break ksync::critical(|| {
+                let mut list = this.list();
+                let ent = FrameInfo::insert_vacant(&mut list.frames, index, fi);
+                FrameInfo::get(ent, this.branch, write, pin, cow)
+            })?;
+        };
+
+        // Hand the result down to the levels below, each of which takes the
+        // frame info moved out of its parent.
+        while ancestors.pop().is_some() {
+            let (fi, copied) = match commit {
+                Commit::Shared(..) => break,
+                Commit::Unique(fi, copied) => (fi, copied),
+            };
+            let (this, index, cow) = match ancestors.last() {
+                Some((phys, index, cow)) => (&**phys, *index, *cow),
+                None => (self, self_index, self_cow),
+            };
+            if copied {
+                this.cow_breaks.record();
+            }
+            commit = ksync::critical(|| {
+                let mut list = this.list();
                 let ent = list.frames.entry(index).insert(fi);
-                FrameInfo::get(ent, self.branch, write, pin, cow)
-            })
-        })
+                FrameInfo::get(ent, this.branch, write, pin, cow)
+            })?;
+        }
+        Ok(commit)
     }
 
     pub async fn commit(
@@ -465,38 +1355,404 @@ impl Phys {
         writable: Option<usize>,
         pin: bool,
     ) -> Result<(Arc<Frame>, usize), Error> {
+        self.commit_with(index, writable, pin, true).await
+    }
+
+    /// Commit the pages in `indices`, returning them in order.
+    ///
+    /// The pages resident in this object are all committed under a single
+    /// lock, and only the others are fetched from the parent chain one by one.
+    /// If any of them fails, the pages pinned so far are unpinned.
+    pub async fn commit_range(
+        &self,
+        indices: Range<usize>,
+        writable: Option<usize>,
+        pin: bool,
+    ) -> Result<Vec<(Arc<Frame>, usize)>, Error> {
         log::trace!(
-            "Phys::commit index = {index} {writable:?}{}{}",
-            if pin { " pin" } else { "" },
-            if self.cow { " cow" } else { "" }
+            "Phys::commit_range indices = {indices:?} {writable:?}{}",
+            if pin { " pin" } else { "" }
         );
         assert!(!self.branch);
-        match self.commit_impl(index, writable, pin, self.cow).await {
-            Ok(Commit::Shared(frame, len)) => {
-                log::trace!("Phys::commit result = {frame:?}, len = {len:#x}");
-                Ok((frame, len))
+        let local = ksync::critical(|| {
+            let mut list = self.list();
+            let pages = indices.clone().map(|index| {
+                let Entry::Occupied(ent) = list.frames.entry(index) else {
+                    return Ok(None);
+                };
+                match FrameInfo::get(ent, false, writable, pin, self.cow)? {
+                    Commit::Shared(frame, len) => Ok(Some((frame, len))),
+                    Commit::Unique(..) => unreachable!(),
+                }
+            });
+            pages.collect::<Vec<Result<_, Error>>>()
+        });
+
+        let mut pages = Vec::with_capacity(local.len());
+        let mut local = indices.clone().zip(local);
+        while let Some((index, page)) = local.next() {
+            let page = match page {
+                Ok(Some(page)) => Ok(page),
+                Ok(None) => self.commit(index, writable, pin).await,
+                Err(err) => Err(err),
+            };
+            match page {
+                Ok(page) => pages.push(page),
+                Err(err) => {
+                    if pin {
+                        // Unpin the pages committed before, and the resident ones after.
+                        let rest = local.filter_map(|(index, page)| {
+                            matches!(page, Ok(Some(_))).then_some(index)
+                        });
+                        (indices.start..index)
+                            .chain(rest)
+                            .for_each(|index| self.unpin(index));
+                    }
+                    return Err(err);
+                }
             }
-            Ok(Commit::Unique(..)) => unreachable!(),
-            Err(err) => Err(err),
+        }
+        Ok(pages)
+    }
+
+    /// Commit the page at `index` for reading.
+    ///
+    /// If `cache` is `false`, a page read from the backend is handed out in a
+    /// throwaway frame instead of being cached, so that a scan reading each
+    /// page only once doesn't evict the pages actually reused. Pages already
+    /// cached are returned as usual.
+    pub async fn commit_read(
+        &self,
+        index: usize,
+        cache: bool,
+    ) -> Result<(Arc<Frame>, usize), Error> {
+        self.commit_with(index, None, false, cache).await
+    }
+
+    async fn commit_with(
+        &self,
+        index: usize,
+        writable: Option<usize>,
+        pin: bool,
+        cache: bool,
+    ) -> Result<(Arc<Frame>, usize), Error> {
+        log::trace!(
+            "Phys::commit index = {index} {writable:?}{}{}{}",
+            if pin { " pin" } else { "" },
+            if self.cow { " cow" } else { "" },
+            if cache { "" } else { " uncached" }
+        );
+        assert!(!self.branch);
+        match self
+            .commit_impl(index, writable, pin, self.cow, cache)
+            .await
+        {
+            Ok(Commit::Shared(frame, len)) => {
+                log::trace!("Phys::commit result = {frame:?}, len = {len:#x}");
+                debug_assert!(
+                    writable.is_none() || !Arc::ptr_eq(&frame, &ZERO),
+                    "the zero frame is committed for writing"
+                );
+                Ok((frame, len))
+            }
+            Ok(Commit::Unique(..)) => unreachable!(),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Look up the page at `index` along the parent chain without committing
+    /// it anywhere.
+    fn peek_page(&self, mut index: usize) -> Peek {
+        let mut storage = None;
+        let mut this = self;
+
+        loop {
+            let (frame, parent) = ksync::critical(|| {
+                let list = this.list.lock();
+                let frame = list.frames.get(&index).map(|fi| match &fi.state {
+                    Some(FrameState::Shared(frame, len) | FrameState::Unique(frame, len)) => {
+                        (frame.clone(), *len)
+                    }
+                    None => (ZERO.clone(), 0),
+                });
+                (frame, list.parent.clone())
+            });
+            if let Some((frame, len)) = frame {
+                break Peek::Frame(frame, len);
+            }
+            match parent {
+                Some(Parent::Phys { phys, start, end })
+                    if end.map_or(true, |end| (0..(end - start)).contains(&index)) =>
+                {
+                    index += start;
+                    this = &**storage.insert(phys);
+                }
+                Some(Parent::Backend(backend)) => break Peek::Backend(backend, index),
+                _ => break Peek::Frame(ZERO.clone(), 0),
+            }
+        }
+    }
+
+    /// Read the current contents at `offset` like [`Io::read_at`], but without
+    /// any side effect on the object.
+    ///
+    /// Pages resident anywhere in the parent chain are copied as they are,
+    /// while the others are read through from the backend every time instead
+    /// of being committed. No frames are allocated or inserted, and the access
+    /// times are left alone, so that debuggers and metrics collectors can watch
+    /// the object without perturbing its cache.
+    pub async fn readonly_peek(
+        &self,
+        offset: usize,
+        buffer: &mut [IoSliceMut<'_>],
+    ) -> Result<usize, Error> {
+        if let Some(backend) = self.write_through_backend() {
+            return backend.read_at(offset, buffer).await;
+        }
+
+        let end = offset.checked_add(ioslice_len(&buffer)).ok_or(EINVAL)?;
+        if offset == end {
+            return Ok(0);
+        }
+        let pages = (offset >> PAGE_SHIFT)..=((end - 1) >> PAGE_SHIFT);
+        let holes = ksync::critical(|| self.list.lock().holes(pages));
+        if holes {
+            let len = end.min(self.len.load(SeqCst)).saturating_sub(offset);
+            return Ok(zero_slices(buffer, len));
+        }
+        // Small writes combined are not settled in the frame list yet.
+        let combined = ksync::critical(|| {
+            let combine = self.combine.lock();
+            combine.as_ref().map(|combine| (combine.index, combine.end))
+        });
+
+        let mut buffer = SliceCursor::new(buffer);
+        let mut scratch = None;
+        let (mut pos, mut read_len) = (offset, 0);
+        while pos < end {
+            let (index, start) = (pos >> PAGE_SHIFT, pos & PAGE_MASK);
+            let page_end = (end - (index << PAGE_SHIFT)).min(PAGE_SIZE);
+
+            let frame;
+            let (page, len): (&[u8], usize) = match self.peek_page(index) {
+                Peek::Frame(f, len) => {
+                    let len = self.read_len(index, &f, len);
+                    let len = match combined {
+                        Some((combined, end)) if combined == index => len.max(end),
+                        _ => len,
+                    };
+                    frame = f;
+                    (&frame[..], len)
+                }
+                Peek::Backend(backend, backend_index) => {
+                    let page = scratch.get_or_insert_with(|| vec![0; PAGE_SIZE]);
+                    let len = read_page(&*backend, backend_index, page).await?;
+                    (&page[..], len)
+                }
+            };
+            read_len += buffer.copy_from(&page[start.min(len)..page_end.min(len)]);
+            if len < PAGE_SIZE || buffer.is_empty() {
+                break;
+            }
+            pos = (index + 1) << PAGE_SHIFT;
+        }
+        Ok(read_len)
+    }
+
+    /// Commit the pages in `range` for reading, yielding them in order along
+    /// with their indices and lengths.
+    ///
+    /// A few pages ahead are committed concurrently, so that their backend
+    /// reads overlap. Pages written in the meantime are yielded as written,
+    /// since a commit keeps a frame inserted while it awaits the backend.
+    pub fn pages(
+        &self,
+        range: Range<usize>,
+    ) -> impl Stream<Item = Result<(usize, Arc<Frame>, usize), Error>> + Send + '_ {
+        let commits = stream::iter(range).map(move |index| {
+            let commit = self.commit(index, None, false);
+            commit.map_ok(move |(frame, len)| (index, frame, len))
+        });
+        commits.buffered(PAGES_READAHEAD)
+    }
+
+    /// Copy the pages from `start` to `end`, both as page indices and offsets,
+    /// into `buffer`, stopping at the first short page.
+    /// The length to read from the page at `index`, committed or peeked as
+    /// `frame` of `len` bytes.
+    ///
+    /// Holes of an object without a parent are read as zeros up to the written
+    /// length, whether the read covers only holes or not.
+    fn read_len(&self, index: usize, frame: &Arc<Frame>, len: usize) -> usize {
+        let hole = len == 0
+            && Arc::ptr_eq(frame, &ZERO)
+            && ksync::critical(|| self.list.lock().parent.is_none());
+        if hole {
+            let written = self.len.load(SeqCst);
+            written.saturating_sub(index << PAGE_SHIFT).min(PAGE_SIZE)
+        } else {
+            len
+        }
+    }
+
+    async fn read_pages(
+        &self,
+        buffer: &mut SliceCursor<'_, IoSliceMut<'_>>,
+        (start_page, start_offset): (usize, usize),
+        (end_page, end_offset): (usize, usize),
+    ) -> Result<usize, Error> {
+        if start_page == end_page {
+            let (frame, end) = self.commit(start_page, None, false).await?;
+            let end = self.read_len(start_page, &frame, end);
+
+            Ok(copy_from_frame(
+                buffer,
+                &frame,
+                start_offset,
+                end_offset.min(end),
+            ))
+        } else {
+            let mut read_len = 0;
+            {
+                let (frame, end) = self.commit(start_page, None, false).await?;
+                let end = self.read_len(start_page, &frame, end);
+                read_len += copy_from_frame(buffer, &frame, start_offset, end);
+                if end < PAGE_SIZE || buffer.is_empty() {
+                    return Ok(read_len);
+                }
+            }
+            for index in (start_page + 1)..end_page {
+                let (frame, end) = self.commit(index, None, false).await?;
+                let end = self.read_len(index, &frame, end);
+                read_len += copy_from_frame(buffer, &frame, 0, end);
+                if end < PAGE_SIZE || buffer.is_empty() {
+                    return Ok(read_len);
+                }
+            }
+            {
+                let (frame, end) = self.commit(end_page, None, false).await?;
+                let end = self.read_len(end_page, &frame, end);
+                read_len += copy_from_frame(buffer, &frame, 0, end_offset.min(end));
+            }
+
+            Ok(read_len)
+        }
+    }
+
+    /// Commit the `pages` pages from `from` for reading, skipping those read
+    /// ahead by the last sequential read and those already resident, and
+    /// stopping at the end of the parent.
+    async fn prefetch(&self, from: usize, pages: usize) {
+        let to = from.saturating_add(pages);
+        let last = self.readahead_next.swap(to, SeqCst);
+        // Continue from the last window if the reads are sequential.
+        let from = if (from..=to).contains(&last) {
+            last
+        } else {
+            from
+        };
+        if from >= to {
+            return;
+        }
+        let Some(parent) = ksync::critical(|| self.list().parent.clone()) else {
+            return;
+        };
+        let Ok(len) = parent.stream_len().await else {
+            return;
+        };
+        let to = to.min(len.div_ceil(PAGE_SIZE));
+        let indices = ksync::critical(|| {
+            let list = self.list();
+            let indices = (from..to).filter(|index| !list.frames.contains_key(index));
+            indices.collect::<Vec<_>>()
+        });
+        let commits = indices
+            .into_iter()
+            .map(|index| self.commit(index, None, false));
+        // Failures are left to the reads actually reaching the pages.
+        join_all(commits).await;
+    }
+
+    /// Same as [`Phys::commit`] with `pin` set, but the page is unpinned
+    /// automatically when the returned guard is dropped.
+    pub async fn commit_pinned(
+        &self,
+        index: usize,
+        writable: Option<usize>,
+    ) -> Result<PinGuard<'_>, Error> {
+        let (frame, len) = self.commit(index, writable, true).await?;
+        Ok(PinGuard {
+            phys: self,
+            index,
+            frame,
+            len,
+        })
+    }
+
+    /// Decrease the pin count of the page at `index`, at the level of the
+    /// parent chain where it was pinned.
+    fn unpin(&self, mut index: usize) {
+        let mut storage = None;
+        let mut this = self;
+
+        loop {
+            let parent = ksync::critical(|| {
+                let mut list = this.list();
+                match list.frames.get_mut(&index) {
+                    Some(fi) if fi.pin > 0 => {
+                        fi.pin -= 1;
+                        None
+                    }
+                    _ => list.parent.clone(),
+                }
+            });
+            let Some(Parent::Phys { phys, start, .. }) = parent else {
+                break
+            };
+            index += start;
+            this = &**storage.insert(phys);
         }
     }
 
     pub async fn flush(
+        &self,
+        index: usize,
+        force_dirty: Option<bool>,
+        unpin: bool,
+    ) -> Result<(), Error> {
+        self.flush_page(index, force_dirty, unpin, false).await
+    }
+
+    /// Write back the page at `index` if dirty, and wait until it and all the
+    /// data queued before have been written to the backend and the backend has
+    /// been flushed, returning the error of either.
+    ///
+    /// A clean page is still waited for, since its last write-back may be
+    /// queued but not done yet. The page is marked clean as soon as it is
+    /// queued, so it isn't written back again after an error until it is
+    /// written to again.
+    pub async fn flush_and_wait(&self, index: usize) -> Result<(), Error> {
+        self.flush_page(index, None, false, true).await
+    }
+
+    async fn flush_page(
         &self,
         mut index: usize,
         force_dirty: Option<bool>,
         unpin: bool,
+        wait: bool,
     ) -> Result<(), Error> {
         let Some(mut flusher) = self.flusher.clone() else {
             return Ok(())
         };
+        self.redirty_failed();
 
         let mut storage = None;
         let mut this = self;
 
-        loop {
+        let page = loop {
             let data = ksync::critical(|| {
-                let mut list = this.list.lock();
+                let mut list = this.list();
                 list.frames.get_mut(&index).and_then(|fi| {
                     fi.pin = fi.pin.saturating_sub(unpin as usize);
                     let dirty = mem::replace(&mut fi.dirty, false);
@@ -509,77 +1765,355 @@ impl Phys {
             });
 
             if let Some((frame, len)) = data {
-                let _ = flusher
-                    .sender
-                    .send(FlushData::Single((index + flusher.offset, frame, len)))
-                    .await;
-
-                break Ok(());
+                break Some((index + flusher.offset, frame, len));
             }
 
-            let parent = ksync::critical(|| this.list.lock().parent.clone());
+            let parent = ksync::critical(|| this.list().parent.clone());
             let Some(Parent::Phys { phys, start, end }) = parent else {
-                break Ok(())
+                break None
             };
             if Arc::strong_count(&phys) > 1 {
-                break Ok(());
+                break None;
             }
 
             let Some(pi) = start.checked_add(index)
-                .filter(|&i| i <= end.unwrap_or(usize::MAX))
+                .filter(|&i| end.map_or(true, |end| i < end))
              else {
-                break Ok(())
+                break None
             };
 
             flusher.offset -= start;
             index = pi;
             this = &**storage.insert(phys);
+        };
+
+        match page {
+            _ if wait => flusher.send_sync(page).await,
+            Some(page) => {
+                let _ = flusher.sender.send(FlushData::Single(page)).await;
+                Ok(())
+            }
+            None => Ok(()),
         }
     }
 
     pub async fn flush_all(&self) -> Result<(), Error> {
-        let Some(mut flusher) = self.flusher.clone() else {
+        self.flush_all_ordered(FlushOrder::ChildFirst).await
+    }
+
+    /// Flush all the dirty pages of this object and its exclusively owned
+    /// ancestors, in the specified order of levels.
+    ///
+    /// Pages of the same level are always written in ascending order of their
+    /// indices.
+    pub async fn flush_all_ordered(&self, order: FlushOrder) -> Result<(), Error> {
+        let Some(flusher) = &self.flusher else {
+            return Ok(())
+        };
+        self.redirty_failed();
+
+        let mut levels = self.take_dirty(flusher.offset);
+        if order == FlushOrder::ParentFirst {
+            levels.reverse();
+        }
+        for data in levels {
+            let _ = flusher.sender.send(FlushData::Multiple(data)).await;
+        }
+        Ok(())
+    }
+
+    /// Like [`Phys::flush_all`], but wait for the pages to be written and the
+    /// backend to be flushed, and report the amount of work done.
+    pub async fn flush_all_durable(&self) -> Result<FlushReport, Error> {
+        let Some(flusher) = &self.flusher else {
+            return Ok(FlushReport {
+                backend_flushed: true,
+                ..Default::default()
+            });
+        };
+
+        self.redirty_failed();
+        let data = self.take_dirty(flusher.offset).concat();
+        flusher.send_durable(data).await
+    }
+
+    /// Write back the dirty pages at `indices`, as [`Phys::flush`] does for
+    /// each of them, in a single batch.
+    ///
+    /// Pages that are not resident or not dirty are skipped. If `wait` is set,
+    /// this returns after the flusher has written the pages and flushed the
+    /// backend.
+    pub async fn flush_indices(&self, indices: &[usize], wait: bool) -> Result<(), Error> {
+        let Some(flusher) = &self.flusher else {
             return Ok(())
         };
+        self.redirty_failed();
+
+        let mut data: Vec<_> = indices
+            .iter()
+            .filter_map(|&index| self.take_dirty_at(index, flusher.offset))
+            .collect();
+        data.sort_unstable_by_key(|&(index, ..)| index);
+
+        if wait {
+            let report = flusher.send_durable(data).await?;
+            if !report.backend_flushed {
+                return Err(EIO);
+            }
+        } else {
+            let _ = flusher.sender.send(FlushData::Multiple(data)).await;
+        }
+        Ok(())
+    }
 
+    /// Write back all the dirty pages like [`Phys::flush_all`], and wait for
+    /// the flusher to finish them before dropping this object.
+    ///
+    /// `Drop` only queues the dirty pages without waiting for them, and
+    /// ignores any error of the writes, so this should be preferred whenever
+    /// the caller is able to await.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        let report = self.flush_all_durable().await?;
+        if report.backend_flushed {
+            Ok(())
+        } else {
+            Err(EIO)
+        }
+    }
+
+    /// Take the dirty pages of this object and its exclusively owned
+    /// ancestors, grouped by levels from the object itself to its farthest
+    /// ancestor, with each level sorted by page indices.
+    fn take_dirty(&self, mut offset: usize) -> Vec<Vec<(usize, Arc<Frame>, usize)>> {
         let mut storage = None;
         let mut this = self;
+        let mut levels = Vec::new();
 
         loop {
-            let data = ksync::critical(|| {
-                let mut list = this.list.lock();
+            let mut data: Vec<_> = ksync::critical(|| {
+                let mut list = this.list();
                 let iter = list.frames.iter_mut().filter_map(|(&index, fi)| {
                     let dirty = mem::replace(&mut fi.dirty, false);
                     dirty
                         .then(|| fi.state.as_mut().map(|s| s.frame(None)))
                         .flatten()
-                        .map(|(frame, len)| (index + flusher.offset, frame, len))
+                        .map(|(frame, len)| (index + offset, frame, len))
                 });
                 iter.collect()
             });
+            data.sort_unstable_by_key(|&(index, ..)| index);
+            levels.push(data);
 
-            let _ = flusher.sender.send(FlushData::Multiple(data)).await;
+            let parent = ksync::critical(|| this.list().parent.clone());
+            let Some(Parent::Phys { phys, start, .. }) = parent else {
+                break levels
+            };
+            if Arc::strong_count(&phys) > 1 {
+                break levels;
+            }
+
+            offset -= start;
+            this = &**storage.insert(phys);
+        }
+    }
+
+    /// Copy `buffer` into the pages from `start` to `end`, both as page indices
+    /// and offsets.
+    async fn write_pages(
+        &self,
+        buffer: &mut SliceCursor<'_, IoSlice<'_>>,
+        (start_page, start_offset): (usize, usize),
+        (end_page, end_offset): (usize, usize),
+    ) -> Result<usize, Error> {
+        if start_page == end_page {
+            let (frame, _) = self.commit(start_page, Some(end_offset), false).await?;
+            return Ok(copy_to_frame(buffer, &frame, start_offset, end_offset));
+        }
+
+        let mut written_len = 0;
+        {
+            let (frame, _) = self.commit(start_page, Some(PAGE_SIZE), false).await?;
+            written_len += copy_to_frame(buffer, &frame, start_offset, PAGE_SIZE);
+            if buffer.is_empty() {
+                return Ok(written_len);
+            }
+        }
+        for index in (start_page + 1)..end_page {
+            let (frame, _) = self.commit(index, Some(PAGE_SIZE), false).await?;
+            written_len += copy_to_frame(buffer, &frame, 0, PAGE_SIZE);
+            if buffer.is_empty() {
+                return Ok(written_len);
+            }
+        }
+        {
+            let (frame, _) = self.commit(end_page, Some(end_offset), false).await?;
+            written_len += copy_to_frame(buffer, &frame, 0, end_offset);
+        }
+
+        Ok(written_len)
+    }
+
+    /// The error of the last failed write-back of the pages of this object or
+    /// its clones, if any.
+    pub fn last_flush_error(&self) -> Option<Error> {
+        let flusher = self.flusher.as_ref()?;
+        ksync::critical(|| *flusher.failures.last_error.lock())
+    }
+
+    /// Mark the pages that failed to be written back dirty again, so that
+    /// they are retried by the next flush instead of being lost.
+    fn redirty_failed(&self) {
+        let Some(flusher) = &self.flusher else {
+            return;
+        };
+        let failed = ksync::critical(|| mem::take(&mut *flusher.failures.pages.lock()));
+        if failed.is_empty() {
+            return;
+        }
+        // Failures of pages owned by clones are left to them, unless the pages
+        // are gone already.
+        let left = failed.into_iter().filter(|(index, frame)| {
+            !self.redirty(*index, frame, flusher.offset) && Arc::strong_count(frame) > 1
+        });
+        let left = left.collect::<Vec<_>>();
+        ksync::critical(|| flusher.failures.pages.lock().extend(left));
+    }
+
+    /// Mark the page at the backend index `index` dirty in this object or its
+    /// exclusively owned ancestors if it still holds `frame`.
+    fn redirty(&self, index: usize, frame: &Arc<Frame>, mut offset: usize) -> bool {
+        let mut storage = None;
+        let mut this = self;
+
+        loop {
+            let found = index.checked_sub(offset).is_some_and(|index| {
+                ksync::critical(|| {
+                    let mut list = this.list();
+                    let Some(fi) = list.frames.get_mut(&index) else {
+                        return false;
+                    };
+                    match &fi.state {
+                        Some(FrameState::Shared(cur, _) | FrameState::Unique(cur, _))
+                            if Arc::ptr_eq(cur, frame) =>
+                        {
+                            fi.dirty = true;
+                            true
+                        }
+                        _ => false,
+                    }
+                })
+            });
+            if found {
+                break true;
+            }
 
-            let parent = ksync::critical(|| this.list.lock().parent.clone());
+            let parent = ksync::critical(|| this.list().parent.clone());
             let Some(Parent::Phys { phys, start, .. }) = parent else {
-                    break Ok(())
-                };
+                break false;
+            };
             if Arc::strong_count(&phys) > 1 {
-                break Ok(());
+                break false;
             }
 
-            flusher.offset -= start;
+            offset -= start;
+            this = &**storage.insert(phys);
+        }
+    }
+
+    fn write_through_backend(&self) -> Option<Arc<dyn Io>> {
+        if !self.write_through {
+            return None;
+        }
+        match ksync::critical(|| self.list().parent.clone()) {
+            Some(Parent::Backend(backend)) => Some(backend),
+            _ => None,
+        }
+    }
+
+    /// Take the dirty page at `index` from this object or the first
+    /// exclusively owned ancestor that has it dirty, with its backend index.
+    fn take_dirty_at(
+        &self,
+        mut index: usize,
+        mut offset: usize,
+    ) -> Option<(usize, Arc<Frame>, usize)> {
+        let mut storage = None;
+        let mut this = self;
+
+        loop {
+            let data = ksync::critical(|| {
+                let mut list = this.list();
+                list.frames.get_mut(&index).and_then(|fi| {
+                    let dirty = mem::replace(&mut fi.dirty, false);
+                    dirty
+                        .then(|| fi.state.as_mut().map(|s| s.frame(None)))
+                        .flatten()
+                })
+            });
+            if let Some((frame, len)) = data {
+                break Some((index + offset, frame, len));
+            }
+
+            let parent = ksync::critical(|| this.list().parent.clone());
+            let Some(Parent::Phys { phys, start, end }) = parent else {
+                break None
+            };
+            if Arc::strong_count(&phys) > 1 {
+                break None;
+            }
+
+            index = start
+                .checked_add(index)
+                .filter(|&i| end.map_or(true, |end| i < end))?;
+            offset -= start;
             this = &**storage.insert(phys);
         }
     }
 }
 
+/// A committed page pinned in its [`Phys`], which is unpinned on drop.
+#[derive(Debug)]
+pub struct PinGuard<'a> {
+    phys: &'a Phys,
+    index: usize,
+    frame: Arc<Frame>,
+    len: usize,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl PinGuard<'_> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn frame(&self) -> &Arc<Frame> {
+        &self.frame
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Deref for PinGuard<'_> {
+    type Target = Frame;
+
+    fn deref(&self) -> &Self::Target {
+        &self.frame
+    }
+}
+
+impl Drop for PinGuard<'_> {
+    fn drop(&mut self) {
+        self.phys.unpin(self.index)
+    }
+}
+
 impl Drop for Phys {
     fn drop(&mut self) {
         let Some(mut flusher) = self.flusher.clone() else {
             return;
         };
-
         let mut storage = None;
         let mut this = self;
 
@@ -587,7 +2121,7 @@ impl Drop for Phys {
             if flusher.sender.is_closed() {
                 break;
             }
-            let list = this.list.get_mut();
+            let list = this.list_mut();
             let data = list.frames.iter_mut().filter_map(|(&index, fi)| {
                 let dirty = mem::replace(&mut fi.dirty, false);
                 dirty
@@ -618,8 +2152,8 @@ impl Io for Phys {
         let pos = match whence {
             SeekFrom::Start(pos) => pos,
             SeekFrom::End(pos) => {
-                let mut len = self.position.load(SeqCst);
-                if let Some(parent) = ksync::critical(|| self.list.lock().parent.clone()) {
+                let mut len = self.position.load(SeqCst).max(self.len.load(SeqCst));
+                if let Some(parent) = ksync::critical(|| self.list().parent.clone()) {
                     len = len.max(parent.stream_len().await?)
                 }
                 let pos = pos.checked_add(len.try_into()?);
@@ -635,105 +2169,155 @@ impl Io for Phys {
         Ok(pos)
     }
 
-    async fn read_at(&self, offset: usize, mut buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+    async fn read_at(&self, offset: usize, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
         log::trace!(
             "Phys::read_at {offset:#x}, buffer len = {} {}",
             ioslice_len(&buffer),
             if self.cow { "cow" } else { "" }
         );
+        if let Some(backend) = self.write_through_backend() {
+            return backend.read_at(offset, buffer).await;
+        }
 
         let ioslice_len = ioslice_len(&buffer);
         let (start, end) = (offset, offset.checked_add(ioslice_len).ok_or(EINVAL)?);
         if start == end {
             return Ok(0);
         }
+        let end = self.limit_faults(start, end);
 
         let ((start_page, start_offset), (end_page, end_offset)) = offsets(start, end);
 
-        if start_page == end_page {
-            let (frame, end) = self.commit(start_page, None, false).await?;
+        // Holes of anonymous objects are read as zeros up to the written length,
+        // without committing a page for each of them.
+        let holes = ksync::critical(|| self.list().holes(start_page..=end_page));
+        if holes {
+            let len = end.min(self.len.load(SeqCst)).saturating_sub(start);
+            return Ok(zero_slices(buffer, len));
+        }
 
-            Ok(copy_from_frame(
-                &mut buffer,
-                &frame,
-                start_offset,
-                end_offset.min(end),
-            ))
-        } else {
-            let mut read_len = 0;
-            {
-                let (frame, end) = self.commit(start_page, None, false).await?;
-                read_len += copy_from_frame(&mut buffer, &frame, start_offset, end);
-                if end < PAGE_SIZE || buffer.is_empty() {
-                    return Ok(read_len);
-                }
-            }
-            for index in (start_page + 1)..end_page {
-                let (frame, end) = self.commit(index, None, false).await?;
-                read_len += copy_from_frame(&mut buffer, &frame, 0, end);
-                if end < PAGE_SIZE || buffer.is_empty() {
-                    return Ok(read_len);
-                }
-            }
-            {
-                let (frame, end) = self.commit(end_page, None, false).await?;
-                read_len += copy_from_frame(&mut buffer, &frame, 0, end_offset.min(end));
+        let mut buffer = SliceCursor::new(buffer);
+        let read = self.read_pages(
+            &mut buffer,
+            (start_page, start_offset),
+            (end_page, end_offset),
+        );
+        let pages = self.readahead.load(SeqCst);
+        if pages > 0 {
+            if let Some(prefetcher) = ksync::critical(|| self.prefetcher.lock().clone()) {
+                let _ = prefetcher.try_send((end_page + 1, pages));
             }
-
-            Ok(read_len)
         }
+        read.await
     }
 
-    async fn write_at(&self, offset: usize, mut buffer: &mut [IoSlice]) -> Result<usize, Error> {
+    async fn write_at(&self, offset: usize, buffer: &mut [IoSlice]) -> Result<usize, Error> {
         log::trace!(
             "Phys::write_at {offset:#x}, buffer len = {} {}",
             ioslice_len(&buffer),
             if self.cow { "cow" } else { "" }
         );
+        if let Some(backend) = self.write_through_backend() {
+            let written_len = backend.write_at(offset, buffer).await?;
+            self.len.fetch_max(offset + written_len, SeqCst);
+            return Ok(written_len);
+        }
 
         let ioslice_len = ioslice_len(&buffer);
         let (start, end) = (offset, offset.checked_add(ioslice_len).ok_or(EINVAL)?);
         if start == end {
             return Ok(0);
         }
+        let end = self.limit_faults(start, end);
 
         let ((start_page, start_offset), (end_page, end_offset)) = offsets(start, end);
-
-        if start_page == end_page {
-            let (frame, _) = self.commit(start_page, Some(end_offset), false).await?;
-
-            Ok(copy_to_frame(&mut buffer, &frame, start_offset, end_offset))
-        } else {
-            let mut written_len = 0;
-            {
-                let (frame, _) = self.commit(start_page, Some(PAGE_SIZE), false).await?;
-                let len = copy_to_frame(&mut buffer, &frame, start_offset, PAGE_SIZE);
-                written_len += len;
-                if buffer.is_empty() {
-                    return Ok(written_len);
+        let mut buffer = SliceCursor::new(buffer);
+
+        if ioslice_len <= COMBINE_MAX && start_page == end_page && self.combining.load(SeqCst) {
+            // The bytes are copied under the combine lock, so that the page is
+            // never settled, and then written back or shared, halfway through.
+            let combined = ksync::critical(|| match &mut *self.combine.lock() {
+                Some(combine) if combine.index == start_page => {
+                    combine.end = combine.end.max(end_offset);
+                    let frame = &combine.frame;
+                    Some(copy_to_frame(&mut buffer, frame, start_offset, end_offset))
                 }
-            }
-            for index in (start_page + 1)..end_page {
-                let (frame, _) = self.commit(index, Some(PAGE_SIZE), false).await?;
-                let len = copy_to_frame(&mut buffer, &frame, 0, PAGE_SIZE);
-                written_len += len;
-                if buffer.is_empty() {
-                    return Ok(written_len);
+                _ => None,
+            });
+            let written_len = match combined {
+                Some(written_len) => Some(written_len),
+                None => {
+                    let (frame, _) = self.commit(start_page, Some(end_offset), false).await?;
+                    // Only pages still owned by this object with the committed
+                    // frame can be settled later, and the others are written
+                    // normally instead.
+                    ksync::critical(|| {
+                        let list = self.list();
+                        let state = list
+                            .frames
+                            .get(&start_page)
+                            .and_then(|fi| fi.state.as_ref());
+                        let owned = state.is_some_and(|state| match state {
+                            FrameState::Shared(f, _) | FrameState::Unique(f, _) => {
+                                Arc::ptr_eq(f, &frame)
+                            }
+                        });
+                        owned.then(|| {
+                            let written_len =
+                                copy_to_frame(&mut buffer, &frame, start_offset, end_offset);
+                            *self.combine.lock() = Some(Combine {
+                                index: start_page,
+                                frame,
+                                end: end_offset,
+                            });
+                            written_len
+                        })
+                    })
                 }
+            };
+            if let Some(written_len) = written_len {
+                self.len.fetch_max(start + written_len, SeqCst);
+                return Ok(written_len);
             }
-            {
-                let (frame, _) = self.commit(end_page, Some(end_offset), false).await?;
-                let len = copy_to_frame(&mut buffer, &frame, 0, end_offset);
-                written_len += len;
-            }
-
-            Ok(written_len)
         }
+
+        let write = self.write_pages(
+            &mut buffer,
+            (start_page, start_offset),
+            (end_page, end_offset),
+        );
+        let written_len = write.await?;
+        self.len.fetch_max(start + written_len, SeqCst);
+        Ok(written_len)
     }
 
     async fn flush(&self) -> Result<(), Error> {
         self.flush_all().await
     }
+
+    async fn flush_durable(&self) -> Result<FlushReport, Error> {
+        self.flush_all_durable().await
+    }
+
+    /// Forward the hint to the backend, dropping the clean pages fully covered
+    /// by the range. Copy-on-write objects never write into their parents, and
+    /// cannot discard.
+    async fn discard(&self, offset: usize, len: usize) -> Result<(), Error> {
+        let end = offset.checked_add(len).ok_or(EINVAL)?;
+        let backend = ksync::critical(|| match &self.list().parent {
+            Some(Parent::Backend(backend)) if !self.cow && !self.branch => Some(backend.clone()),
+            _ => None,
+        });
+        let backend = backend.ok_or(EOPNOTSUPP)?;
+
+        let full = offset.div_ceil(PAGE_SIZE)..(end >> PAGE_SHIFT);
+        ksync::critical(|| {
+            let mut list = self.list();
+            list.frames
+                .retain(|index, fi| !full.contains(index) || !fi.is_evictable());
+        });
+        backend.discard(offset, len).await
+    }
 }
 
 fn offsets(start: usize, end: usize) -> ((usize, usize), (usize, usize)) {
@@ -753,80 +2337,1022 @@ fn offsets(start: usize, end: usize) -> ((usize, usize), (usize, usize)) {
     ((start_page, start_offset), (end_page, end_offset))
 }
 
-fn copy_from_frame(
-    buffer: &mut &mut [IoSliceMut],
-    frame: &Frame,
-    mut start: usize,
-    end: usize,
-) -> usize {
-    let mut read_len = 0;
-    loop {
-        if buffer.is_empty() || end == start {
-            break read_len;
+fn zero_slices(buffer: &mut [IoSliceMut], mut len: usize) -> usize {
+    let mut zeroed = 0;
+    for buf in buffer {
+        if len == 0 {
+            break;
         }
-        let buf = &mut buffer[0];
-        if buf.is_empty() {
-            *buffer = &mut mem::take(buffer)[1..];
-            continue;
-        }
-        let len = buf.len().min(end - start);
-        buf[..len].copy_from_slice(&frame[start..][..len]);
+        let l = buf.len().min(len);
+        buf[..l].fill(0);
+        len -= l;
+        zeroed += l;
+    }
+    zeroed
+}
 
+/// Read the page at `index` of `backend` into `buffer`, returning the length
+/// read before the end of the backend.
+async fn read_page(backend: &dyn Io, index: usize, mut buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut read_len = 0;
+    let mut offset = index << PAGE_SHIFT;
+    while !buffer.is_empty() {
+        let len = backend.read_at(offset, &mut [&mut *buffer]).await?;
+        if len == 0 {
+            break;
+        }
+        offset += len;
         read_len += len;
-        start += len;
-        advance_slices(buffer, len);
+        buffer = &mut buffer[len..];
     }
+    Ok(read_len)
+}
+
+fn copy_from_frame(
+    buffer: &mut SliceCursor<IoSliceMut>,
+    frame: &Frame,
+    start: usize,
+    end: usize,
+) -> usize {
+    buffer.copy_from(&frame[start..end])
 }
 
 fn copy_to_frame(
-    buffer: &mut &mut [IoSlice],
+    buffer: &mut SliceCursor<IoSlice>,
     frame: &Frame,
-    mut start: usize,
+    start: usize,
     end: usize,
 ) -> usize {
-    let mut written_len = 0;
-    loop {
-        if buffer.is_empty() || end == start {
-            break written_len;
-        }
-        let buf = buffer[0];
-        if buf.is_empty() {
-            *buffer = &mut mem::take(buffer)[1..];
-            continue;
-        }
-        let len = buf.len().min(end - start);
-        unsafe {
-            let mut src = frame.as_ptr();
-            src.as_mut()[start..][..len].copy_from_slice(&buf[..len])
-        }
-        written_len += len;
-        start += len;
-        advance_slices(buffer, len);
+    unsafe {
+        let mut dst = frame.as_ptr();
+        buffer.copy_into(&mut dst.as_mut()[start..end])
     }
 }
 
 enum FlushData {
     Single((usize, Arc<Frame>, usize)),
+    /// A barrier replied to after the data queued before and the page if any
+    /// are written, and the backend is flushed.
+    SingleSync(
+        Option<(usize, Arc<Frame>, usize)>,
+        Sender<SegQueue<Result<(), Error>>>,
+    ),
     Multiple(Vec<(usize, Arc<Frame>, usize)>),
+    Durable(
+        Vec<(usize, Arc<Frame>, usize)>,
+        Sender<SegQueue<Result<FlushReport, Error>>>,
+    ),
 }
 
-async fn flusher(rx: Receiver<SegQueue<FlushData>>, backend: Arc<dyn Io>) {
+/// Read ahead the windows queued by the reads of `phys` while it's alive.
+async fn prefetcher(rx: Receiver<SegQueue<(usize, usize)>>, phys: Weak<Phys>) {
+    while let Ok((from, pages)) = rx.recv().await {
+        let Some(phys) = phys.upgrade() else {
+            break;
+        };
+        phys.prefetch(from, pages).await;
+    }
+}
+
+async fn flusher(
+    rx: Receiver<SegQueue<FlushData>>,
+    backend: Arc<dyn Io>,
+    failures: Arc<FlushFailures>,
+) {
+    let (backend, failures) = (&*backend, &*failures);
     loop {
-        let Ok(data) = rx.recv().await else { break };
+        // Pages queued right before the last sender is dropped, notably by
+        // `Phys::drop`, are still written back.
+        let data = match rx.recv().await {
+            Ok(data) => data,
+            Err(err) => match err.data() {
+                Some(data) => data,
+                None => break,
+            },
+        };
         match data {
-            FlushData::Single((index, frame, len)) => {
-                let _ = backend
-                    .write_all_at(index << PAGE_SHIFT, &frame[..len])
-                    .await;
+            FlushData::Single(page) => {
+                let _ = write_back(backend, failures, page).await;
+                let _ = flush_backend(backend, failures).await;
+            }
+            FlushData::SingleSync(page, reply) => {
+                let res = match page {
+                    Some(page) => write_back(backend, failures, page).await,
+                    None => Ok(()),
+                };
+                let res = match res {
+                    Ok(()) => flush_backend(backend, failures).await,
+                    Err(err) => Err(err),
+                };
+                let _ = reply.send(res).await;
             }
             FlushData::Multiple(data) => {
-                for (index, frame, len) in data {
-                    let _ = backend
-                        .write_all_at(index << PAGE_SHIFT, &frame[..len])
-                        .await;
+                for page in data {
+                    let _ = write_back(backend, failures, page).await;
                 }
+                let _ = flush_backend(backend, failures).await;
+            }
+            FlushData::Durable(data, reply) => {
+                let mut report = FlushReport::default();
+                let mut res = Ok(());
+                for page in data {
+                    let len = page.2;
+                    match write_back(backend, failures, page).await {
+                        Ok(()) => {
+                            report.pages += 1;
+                            report.bytes += len;
+                        }
+                        Err(err) => res = Err(err),
+                    }
+                }
+                report.backend_flushed = flush_backend(backend, failures).await.is_ok();
+                let _ = reply.send(res.map(|_| report)).await;
             }
         }
-        let _ = backend.flush().await;
+    }
+}
+
+/// Write the page at `index` of the backend, recording it in `failures` to
+/// be marked dirty again on failure.
+async fn write_back(
+    backend: &dyn Io,
+    failures: &FlushFailures,
+    (index, frame, len): (usize, Arc<Frame>, usize),
+) -> Result<(), Error> {
+    let res = backend
+        .write_all_at(index << PAGE_SHIFT, &frame[..len])
+        .await;
+    if let Err(err) = res {
+        failures.record(Some((index, frame)), err);
+    }
+    res
+}
+
+async fn flush_backend(backend: &dyn Io, failures: &FlushFailures) -> Result<(), Error> {
+    let res = backend.flush().await;
+    if let Err(err) = res {
+        failures.record(None, err);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::{
+        num::NonZeroUsize,
+        pin::pin,
+        sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    };
+
+    use futures_util::{FutureExt, TryStreamExt};
+    use ksc_core::Error::{EBUSY, EINVAL, EIO};
+    use rv39_paging::PAGE_SIZE;
+    use umifs::misc::{MemFault, MemIo, MemOp};
+    use umio::{Io, IoExt};
+
+    use super::{FlushOrder, FlushReport, Phys, ACCESS_CLOCK, ZERO};
+    use crate::frame::init_frames_for_test;
+
+    fn flush_with(order: FlushOrder) -> Vec<usize> {
+        init_frames_for_test();
+
+        let backend = Arc::new(MemIo::new(usize::MAX));
+        let (phys, flusher) = Phys::new(backend.clone(), 0, true);
+        let mut flusher = pin!(flusher);
+
+        // Page 0 is moved to the branch by `clone_as`, which becomes the only
+        // parent of `phys` after the clone is dropped.
+        phys.write_all_at(0, &[1; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        drop(phys.clone_as(true, 0, None));
+        phys.write_all_at(PAGE_SIZE, &[2; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        phys.flush_all_ordered(order)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(flusher.as_mut().now_or_never().is_none());
+
+        backend.writes()
+    }
+
+    #[test]
+    fn split_off() {
+        init_frames_for_test();
+
+        let backend = Arc::new(MemIo::new(usize::MAX));
+        let (phys, flusher) = Phys::new(backend.clone(), 0, true);
+        let mut flusher = pin!(flusher);
+        for index in 0..4 {
+            phys.write_all_at(index * PAGE_SIZE, &[index as u8; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+
+        let split = phys.split_off(2);
+        assert_eq!(phys.resident_pages(), 2);
+        assert_eq!(split.resident_pages(), 2);
+        let mut out = [0; 16];
+        split
+            .read_exact_at(PAGE_SIZE, &mut out)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(out, [3; 16]);
+
+        // The pages are written back to where they were before the split.
+        split.flush_all().now_or_never().unwrap().unwrap();
+        assert!(flusher.as_mut().now_or_never().is_none());
+        let mut writes = backend.writes();
+        writes.sort_unstable();
+        assert_eq!(writes, [PAGE_SIZE * 2, PAGE_SIZE * 3]);
+    }
+
+    #[test]
+    fn split_off_ranged() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(false);
+        for index in 0..4 {
+            phys.write_all_at(index * PAGE_SIZE, &[index as u8 + 1; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        let first = |phys: &Phys, index| {
+            let commit = phys.commit(index, None, false).now_or_never().unwrap();
+            commit.unwrap().0.as_slice()[0]
+        };
+
+        // The clone sees the pages 1 and 2 of the parent.
+        let clone = phys.clone_as(true, 1, Some(2));
+        let split = clone.split_off(1);
+        assert_eq!(first(&split, 0), 3);
+        assert_eq!(first(&split, 1), 0);
+        // The page moved out is no longer seen through the original.
+        assert_eq!(first(&clone, 0), 2);
+        assert_eq!(first(&clone, 1), 0);
+
+        // Splitting beyond the range leaves an empty one.
+        let empty = split.split_off(4);
+        assert_eq!(first(&empty, 0), 0);
+        assert_eq!(first(&split, 0), 3);
+    }
+
+    #[test]
+    fn flush_and_wait() {
+        init_frames_for_test();
+
+        let backend = Arc::new(MemIo::new(usize::MAX));
+        let (phys, flusher) = Phys::new(backend.clone(), 0, true);
+        let mut flusher = pin!(flusher);
+        phys.write_all_at(PAGE_SIZE, &[1; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        let mut flush = pin!(phys.flush_and_wait(1));
+        assert!(flush.as_mut().now_or_never().is_none());
+        assert!(flusher.as_mut().now_or_never().is_none());
+        assert_eq!(flush.now_or_never(), Some(Ok(())));
+        assert_eq!(backend.writes(), [PAGE_SIZE]);
+
+        // Clean pages still wait for the write-backs queued before.
+        phys.write_all_at(0, &[1; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        phys.flush_all().now_or_never().unwrap().unwrap();
+        let mut flush = pin!(phys.flush_and_wait(1));
+        assert!(flush.as_mut().now_or_never().is_none());
+        assert!(flusher.as_mut().now_or_never().is_none());
+        assert_eq!(flush.now_or_never(), Some(Ok(())));
+        assert_eq!(backend.writes(), [PAGE_SIZE, 0]);
+    }
+
+    #[test]
+    fn flush_error() {
+        init_frames_for_test();
+
+        let backend = MemIo::new(usize::MAX);
+        backend.set_hook(|op| matches!(op, MemOp::Write { .. }).then_some(MemFault::Fail(EIO)));
+        let (phys, flusher) = Phys::new(Arc::new(backend), 0, true);
+        let mut flusher = pin!(flusher);
+        phys.write_all_at(0, &[1; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(phys.last_flush_error(), None);
+
+        phys.flush_all().now_or_never().unwrap().unwrap();
+        assert_eq!(phys.dirty_pages(), 0);
+        assert!(flusher.as_mut().now_or_never().is_none());
+        assert_eq!(phys.last_flush_error(), Some(EIO));
+        assert_eq!(phys.dirty_pages(), 1);
+
+        let mut flush = pin!(phys.flush_and_wait(0));
+        assert!(flush.as_mut().now_or_never().is_none());
+        assert!(flusher.as_mut().now_or_never().is_none());
+        assert_eq!(flush.now_or_never(), Some(Err(EIO)));
+        assert_eq!(phys.dirty_pages(), 1);
+    }
+
+    #[test]
+    fn flush_child_first() {
+        assert_eq!(flush_with(FlushOrder::ChildFirst), [PAGE_SIZE, 0]);
+    }
+
+    #[test]
+    fn flush_parent_first() {
+        assert_eq!(flush_with(FlushOrder::ParentFirst), [0, PAGE_SIZE]);
+    }
+
+    #[test]
+    fn flush_durable_report() {
+        init_frames_for_test();
+
+        let backend = Arc::new(MemIo::new(usize::MAX));
+        let (phys, flusher) = Phys::new(backend.clone(), 0, true);
+        let mut flusher = pin!(flusher);
+
+        for index in 0..2 {
+            phys.write_all_at(index * PAGE_SIZE, &[1; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+
+        let mut durable = pin!(phys.flush_durable());
+        assert!(durable.as_mut().now_or_never().is_none());
+        assert!(flusher.as_mut().now_or_never().is_none());
+        let report = durable.now_or_never().unwrap().unwrap();
+        assert_eq!(report.pages, 2);
+        assert!(report.backend_flushed);
+        assert_eq!(backend.writes().len(), 2);
+
+        // Nothing is left dirty for the next call.
+        let mut durable = pin!(phys.flush_durable());
+        assert!(durable.as_mut().now_or_never().is_none());
+        assert!(flusher.as_mut().now_or_never().is_none());
+        let report = durable.now_or_never().unwrap().unwrap();
+        assert_eq!(
+            report,
+            FlushReport {
+                backend_flushed: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn flush_indices() {
+        init_frames_for_test();
+
+        let backend = Arc::new(MemIo::new(usize::MAX));
+        let (phys, flusher) = Phys::new(backend.clone(), 0, true);
+        let mut flusher = pin!(flusher);
+
+        for index in 0..4 {
+            phys.write_all_at(index * PAGE_SIZE, &[1; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+
+        // Page 2 is flushed twice and page 7 is not resident.
+        let mut fut = pin!(phys.flush_indices(&[2, 0, 7, 2], true));
+        assert!(fut.as_mut().now_or_never().is_none());
+        assert!(flusher.as_mut().now_or_never().is_none());
+        fut.now_or_never().unwrap().unwrap();
+        assert_eq!(backend.writes(), [0, 2 * PAGE_SIZE]);
+
+        let mut report = pin!(phys.flush_all_durable());
+        assert!(report.as_mut().now_or_never().is_none());
+        assert!(flusher.as_mut().now_or_never().is_none());
+        assert_eq!(report.now_or_never().unwrap().unwrap().pages, 2);
+    }
+
+    #[test]
+    fn pages_in_order() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        for index in 0..3 {
+            phys.write_all_at(index * PAGE_SIZE, &[index as u8 + 1; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+
+        let pages: Vec<_> = phys
+            .pages(0..3)
+            .try_collect()
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let heads: Vec<_> = pages
+            .iter()
+            .map(|(index, frame, _)| (*index, frame.as_slice()[0]))
+            .collect();
+        assert_eq!(heads, [(0, 1), (1, 2), (2, 3)]);
+    }
+
+    #[test]
+    fn commit_uncached() {
+        init_frames_for_test();
+
+        let backend = Phys::new_anon(true);
+        backend
+            .write_all_at(0, &[1; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let (phys, _flusher) = Phys::new(Arc::new(backend), 0, true);
+
+        let (frame, len) = phys.commit_read(0, false).now_or_never().unwrap().unwrap();
+        assert_eq!((frame.as_slice()[0], len), (1, 16));
+        assert!(ksync::critical(|| phys.list.lock().frames.is_empty()));
+
+        let (frame, _) = phys.commit_read(0, true).now_or_never().unwrap().unwrap();
+        assert_eq!(frame.as_slice()[0], 1);
+        assert!(ksync::critical(|| phys.list.lock().frames.contains_key(&0)));
+    }
+
+    #[test]
+    fn readonly_peek() {
+        init_frames_for_test();
+
+        let backend = Phys::new_anon(true);
+        backend
+            .write_all_at(PAGE_SIZE - 4, &[1; 8])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let (phys, _flusher) = Phys::new(Arc::new(backend), 0, true);
+
+        let mut buf = [0; 8];
+        let len = phys
+            .readonly_peek(PAGE_SIZE - 4, &mut [&mut buf])
+            .now_or_never();
+        assert_eq!(len.unwrap(), Ok(8));
+        assert_eq!(buf, [1; 8]);
+        assert!(ksync::critical(|| phys.list.lock().frames.is_empty()));
+
+        // Resident pages are read as they are, without being touched.
+        phys.write_all_at(PAGE_SIZE, &[2; 2])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let accessed = ksync::critical(|| phys.list.lock().frames[&1].accessed);
+        let len = phys
+            .readonly_peek(PAGE_SIZE - 4, &mut [&mut buf])
+            .now_or_never();
+        assert_eq!(len.unwrap(), Ok(8));
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2, 1, 1]);
+        let frames = ksync::critical(|| phys.list.lock().frames.len());
+        assert_eq!(frames, 1);
+        assert_eq!(
+            ksync::critical(|| phys.list.lock().frames[&1].accessed),
+            accessed
+        );
+    }
+
+    #[test]
+    fn write_untouched_page() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        let (zero, _) = phys.commit(0, None, false).now_or_never().unwrap().unwrap();
+        assert!(Arc::ptr_eq(&zero, &ZERO));
+
+        let child = phys.clone_as(true, 0, None);
+        for phys in [&phys, &child] {
+            phys.write_all_at(0, &[1; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            let (frame, _) = phys
+                .commit(1, Some(16), false)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            assert!(!Arc::ptr_eq(&frame, &ZERO));
+        }
+        assert!(ZERO.as_slice().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn working_set() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        for index in 0..4 {
+            phys.write_all_at(index * PAGE_SIZE, &[1; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(phys.working_set(u64::MAX), 4);
+
+        // The clock is shared with other tests, so only the pages accessed
+        // before `since` are known to be outside of the window.
+        let since = ACCESS_CLOCK.load(SeqCst);
+        phys.commit(3, None, false).now_or_never().unwrap().unwrap();
+        let window = ACCESS_CLOCK.load(SeqCst) - since;
+        assert!(phys.working_set(window) <= 1);
+        assert_eq!(phys.working_set(0), 0);
+    }
+
+    #[test]
+    fn swap_pages() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        for (index, byte) in [1, 2, 3, 4].into_iter().enumerate() {
+            phys.write_all_at(index * PAGE_SIZE, &[byte; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(phys.swap_pages(0..2, 1..3), Err(EINVAL));
+        phys.swap_pages(0..2, 2..4).unwrap();
+
+        let mut buf = [0; 16];
+        for (index, byte) in [3, 4, 1, 2].into_iter().enumerate() {
+            phys.read_exact_at(index * PAGE_SIZE, &mut buf)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            assert_eq!(buf, [byte; 16]);
+        }
+
+        let pinned = phys.commit_pinned(3, None).now_or_never().unwrap().unwrap();
+        assert_eq!(phys.swap_pages(0..1, 3..4), Err(EBUSY));
+        drop(pinned);
+        phys.swap_pages(0..1, 3..4).unwrap();
+    }
+
+    #[test]
+    fn merge_identical() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        for (index, byte) in [1, 1, 2, 1].into_iter().enumerate() {
+            phys.write_all_at(index * PAGE_SIZE, &[byte; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(phys.merge_identical().unwrap(), 2);
+        assert_eq!(phys.merge_identical().unwrap(), 0);
+
+        // Writing a merged page doesn't affect the others.
+        phys.write_all_at(PAGE_SIZE, &[3; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let mut buf = [0; 16];
+        for (index, byte) in [1, 3, 2, 1].into_iter().enumerate() {
+            phys.read_exact_at(index * PAGE_SIZE, &mut buf)
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            assert_eq!(buf, [byte; 16]);
+        }
+    }
+
+    #[test]
+    fn write_through() {
+        init_frames_for_test();
+
+        let backend = Arc::new(MemIo::new(usize::MAX));
+        let phys = Phys::new_write_through(backend.clone());
+
+        phys.write_all_at(PAGE_SIZE + 8, &[1; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(backend.to_vec()[PAGE_SIZE + 8..], [1; 16]);
+        assert_eq!(phys.len.load(SeqCst), PAGE_SIZE + 24);
+
+        let mut buf = [0; 16];
+        phys.read_exact_at(PAGE_SIZE + 8, &mut buf)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, [1; 16]);
+        assert!(ksync::critical(|| phys.list.lock().frames.is_empty()));
+    }
+
+    #[test]
+    fn shutdown_waits_for_flusher() {
+        init_frames_for_test();
+
+        let backend = Arc::new(MemIo::new(usize::MAX));
+        let (phys, flusher) = Phys::new(backend.clone(), 0, true);
+        let mut flusher = pin!(flusher);
+
+        phys.write_all_at(PAGE_SIZE, &[1; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        let mut shutdown = pin!(phys.shutdown());
+        assert!(shutdown.as_mut().now_or_never().is_none());
+        assert!(backend.writes().is_empty());
+
+        assert!(flusher.as_mut().now_or_never().is_none());
+        shutdown.now_or_never().unwrap().unwrap();
+        assert_eq!(backend.writes(), [PAGE_SIZE]);
+        // The sender is dropped with the object, which stops the flusher.
+        assert!(flusher.now_or_never().is_some());
+    }
+
+    #[test]
+    fn cow_break_hook() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        for index in 0..2 {
+            phys.write_all_at(index * PAGE_SIZE, &[1; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        let child = phys.clone_as(true, 0, None);
+
+        let reported = Arc::new(AtomicUsize::new(0));
+        let r = reported.clone();
+        child.set_cow_break_hook(2, Arc::new(move |count| r.store(count, SeqCst)));
+
+        for index in 0..2 {
+            assert_eq!(reported.load(SeqCst), 0);
+            child
+                .write_all_at(index * PAGE_SIZE, &[2; 16])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+            assert_eq!(child.cow_breaks(), index + 1);
+        }
+        assert_eq!(reported.load(SeqCst), 2);
+
+        // The last sharer takes the original frame without copying.
+        phys.write_all_at(0, &[3; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(phys.cow_breaks(), 0);
+    }
+
+    #[test]
+    fn write_combining() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        phys.set_write_combining(true);
+        for offset in 0..10 {
+            phys.write_all_at(offset, &[offset as u8])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        phys.write_all_at(PAGE_SIZE, &[10])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        let mut buf = [0xff; 16];
+        let len = phys
+            .read_at(0, &mut [&mut buf])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+        // The page being combined is visible to reads as well.
+        let len = phys
+            .read_at(PAGE_SIZE, &mut [&mut buf])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], &[10]);
+    }
+
+    #[test]
+    fn fault_limit() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        phys.set_fault_limit(NonZeroUsize::new(2));
+        let buf = vec![1; PAGE_SIZE * 4];
+        let len = phys.write_at(8, &mut [&buf[..]]).now_or_never();
+        assert_eq!(len.unwrap(), Ok(PAGE_SIZE * 2 - 8));
+        assert_eq!(ksync::critical(|| phys.list.lock().frames.len()), 2);
+
+        phys.set_fault_limit(None);
+        phys.write_all_at(8, &buf).now_or_never().unwrap().unwrap();
+
+        phys.set_fault_limit(NonZeroUsize::new(1));
+        let mut buf = vec![0; PAGE_SIZE * 4];
+        let len = phys.read_at(PAGE_SIZE, &mut [&mut buf[..]]).now_or_never();
+        assert_eq!(len.unwrap(), Ok(PAGE_SIZE));
+        assert!(buf[..PAGE_SIZE].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn truncate() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        let buf = vec![1; PAGE_SIZE * 3];
+        phys.write_all_at(0, &buf).now_or_never().unwrap().unwrap();
+
+        let pin = phys.commit(2, None, true).now_or_never().unwrap();
+        assert!(pin.is_ok());
+        let res = phys.truncate(PAGE_SIZE + 8).now_or_never().unwrap();
+        assert_eq!(res, Err(EBUSY));
+        assert_eq!(ksync::critical(|| phys.list.lock().frames.len()), 3);
+
+        phys.unpin(2);
+        let res = phys.truncate(PAGE_SIZE + 8).now_or_never().unwrap();
+        assert_eq!(res, Ok(()));
+        assert_eq!(ksync::critical(|| phys.list.lock().frames.len()), 2);
+        assert!(ksync::critical(|| phys.list.lock().frames[&1].dirty));
+
+        let mut out = [0xff; 16];
+        let len = phys.read_at(PAGE_SIZE, &mut [&mut out]).now_or_never();
+        assert_eq!(len.unwrap(), Ok(8));
+
+        // The truncated tail reads as zeros once the page is extended again.
+        phys.write_all_at(PAGE_SIZE + 15, &[2])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let len = phys.read_at(PAGE_SIZE, &mut [&mut out]).now_or_never();
+        assert_eq!(len.unwrap(), Ok(16));
+        assert_eq!(out, [1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn page_counts() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        phys.write_all_at(PAGE_SIZE, &[1; 8])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let pin = phys.commit(1, None, true).now_or_never().unwrap();
+        assert!(pin.is_ok());
+        // Reading a hole commits nothing.
+        let zero = phys.commit(0, None, false).now_or_never().unwrap();
+        assert!(zero.is_ok());
+        assert_eq!(phys.resident_pages(), 1);
+        assert_eq!(phys.dirty_pages(), 1);
+        assert_eq!(phys.pinned_pages(), 1);
+    }
+
+    #[test]
+    fn readahead() {
+        init_frames_for_test();
+
+        let backend = Phys::new_anon(true);
+        let buf = vec![1; PAGE_SIZE * 4];
+        backend
+            .write_all_at(0, &buf)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let (phys, _flusher) = Phys::new(Arc::new(backend), 0, false);
+        let phys = Arc::new(phys);
+        let mut prefetcher = pin!(phys.set_readahead(2));
+
+        // The read doesn't wait for the stalled read-ahead.
+        let mut out = [0; 8];
+        let len = phys.read_at(0, &mut [&mut out[..]]).now_or_never();
+        assert_eq!(len.unwrap(), Ok(8));
+        assert_eq!(phys.resident_pages(), 1);
+
+        assert!(prefetcher.as_mut().now_or_never().is_none());
+        assert_eq!(phys.resident_pages(), 3);
+        assert_eq!(phys.dirty_pages(), 0);
+        assert_eq!(phys.pinned_pages(), 0);
+
+        // Read-ahead stops at the end of the backend.
+        let prefetcher = phys.set_readahead(8);
+        let len = phys.read_at(PAGE_SIZE, &mut [&mut out]).now_or_never();
+        assert_eq!(len.unwrap(), Ok(8));
+        assert_eq!(prefetcher.now_or_never(), None);
+        assert_eq!(phys.resident_pages(), 4);
+    }
+
+    #[test]
+    fn commit_range() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(false);
+        phys.write_all_at(PAGE_SIZE, &[1; 8])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let pages = phys.commit_range(0..3, Some(PAGE_SIZE), true);
+        let pages = pages.now_or_never().unwrap().unwrap();
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|(_, len)| *len == PAGE_SIZE));
+        assert_eq!(pages[1].0.as_slice()[..8], [1; 8]);
+        assert_eq!(phys.resident_pages(), 3);
+        assert_eq!(phys.pinned_pages(), 3);
+
+        // A failed page unpins the pages committed before it and the resident
+        // pages after it.
+        let backend = MemIo::with_data(vec![1; PAGE_SIZE * 4], 0);
+        backend.set_hook(|op| match op {
+            MemOp::Read { offset, .. } if offset == PAGE_SIZE * 2 => Some(MemFault::Fail(EIO)),
+            _ => None,
+        });
+        let (phys, _flusher) = Phys::new(Arc::new(backend), 0, true);
+        for index in [0, 3] {
+            phys.write_all_at(index * PAGE_SIZE, &[2; 8])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        let pages = phys.commit_range(0..4, None, true).now_or_never().unwrap();
+        assert_eq!(pages.err(), Some(EIO));
+        assert_eq!(phys.resident_pages(), 3);
+        assert_eq!(phys.pinned_pages(), 0);
+    }
+
+    #[test]
+    fn read_across_hole() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        for index in [0, 2] {
+            phys.write_all_at(index * PAGE_SIZE, &[1; PAGE_SIZE])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+
+        // The hole in the middle is read as zeros, and doesn't end the read.
+        let mut out = vec![0xff; PAGE_SIZE * 3];
+        let len = phys.read_at(0, &mut [&mut out[..]]).now_or_never().unwrap();
+        assert_eq!(len, Ok(PAGE_SIZE * 3));
+        assert!(out[PAGE_SIZE..PAGE_SIZE * 2].iter().all(|&b| b == 0));
+        assert!(out[PAGE_SIZE * 2..].iter().all(|&b| b == 1));
+
+        let mut peeked = vec![0xff; PAGE_SIZE * 3];
+        let mut bufs = [&mut peeked[..]];
+        let len = phys.readonly_peek(0, &mut bufs).now_or_never().unwrap();
+        assert_eq!(len, Ok(PAGE_SIZE * 3));
+        assert_eq!(peeked, out);
+        assert_eq!(phys.resident_pages(), 2);
+    }
+
+    #[test]
+    fn punch_hole() {
+        init_frames_for_test();
+
+        let phys = Phys::new_anon(true);
+        let buf = vec![1; PAGE_SIZE * 3];
+        phys.write_all_at(0, &buf).now_or_never().unwrap().unwrap();
+        let res = phys.punch_hole(PAGE_SIZE - 4..PAGE_SIZE * 2 + 4);
+        assert_eq!(res.now_or_never().unwrap(), Ok(()));
+        assert_eq!(phys.resident_pages(), 2);
+
+        let mut out = vec![0xff; PAGE_SIZE];
+        phys.read_exact_at(0, &mut out)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(out[..PAGE_SIZE - 4].iter().all(|&b| b == 1));
+        assert_eq!(out[PAGE_SIZE - 4..], [0; 4]);
+        phys.read_exact_at(PAGE_SIZE * 2, &mut out)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(out[..4], [0; 4]);
+        assert!(out[4..].iter().all(|&b| b == 1));
+
+        // Pages of the parent are shadowed instead of being written over.
+        let backend = Arc::new(Phys::new_anon(true));
+        backend
+            .write_all_at(0, &buf)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let (phys, _flusher) = Phys::new(backend.clone(), 0, true);
+        let res = phys.punch_hole(0..PAGE_SIZE);
+        assert_eq!(res.now_or_never().unwrap(), Ok(()));
+
+        let mut out = [0xff; 8];
+        phys.read_exact_at(0, &mut out)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(out, [0; 8]);
+        backend
+            .read_exact_at(0, &mut out)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(out, [1; 8]);
+    }
+
+    #[test]
+    fn evict_clean() {
+        init_frames_for_test();
+
+        let backend = Phys::new_anon(true);
+        let buf = vec![1; PAGE_SIZE * 4];
+        backend
+            .write_all_at(0, &buf)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let (phys, _flusher) = Phys::new(Arc::new(backend), 0, false);
+
+        let pages = phys.commit_range(0..4, None, false).now_or_never().unwrap();
+        drop(pages.unwrap());
+        let pinned = phys.commit(1, None, true).now_or_never().unwrap();
+        assert!(pinned.is_ok());
+        let held = phys.commit(2, None, false).now_or_never().unwrap().unwrap();
+        phys.write_all_at(PAGE_SIZE * 3, &[2])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(phys.evict_clean(8).now_or_never(), Some(1));
+        assert_eq!(phys.resident_pages(), 3);
+        drop(held);
+        assert_eq!(phys.evict_clean(8).now_or_never(), Some(1));
+        assert_eq!(phys.resident_pages(), 2);
+
+        let mut out = [0; 8];
+        phys.read_exact_at(0, &mut out)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(out, [1; 8]);
+    }
+
+    #[test]
+    fn commit_during_backend_read() {
+        init_frames_for_test();
+
+        // Reads at offset 0 stall once before reaching EOF.
+        let backend = MemIo::new(usize::MAX);
+        backend.set_hook(|op| match op {
+            MemOp::Read { offset: 0, .. } => Some(MemFault::Stall),
+            _ => None,
+        });
+        let (phys, _flusher) = Phys::new(Arc::new(backend), 0, false);
+
+        let mut stalled = pin!(phys.commit(0, None, false));
+        assert!(stalled.as_mut().now_or_never().is_none());
+        assert!(phys.list.try_lock().is_some());
+
+        let other = phys.commit(1, None, false).now_or_never();
+        assert!(matches!(other, Some(Ok((_, 0)))));
+        let stalled = stalled.now_or_never();
+        assert!(matches!(stalled, Some(Ok((_, 0)))));
+    }
+
+    #[test]
+    fn pages_during_write() {
+        init_frames_for_test();
+
+        // Only the first read stalls, the one of the stream.
+        let backend = MemIo::with_data(vec![1; PAGE_SIZE], usize::MAX);
+        let mut stall = true;
+        backend.set_hook(move |op| match op {
+            MemOp::Read { .. } if stall => {
+                stall = false;
+                Some(MemFault::Stall)
+            }
+            _ => None,
+        });
+        let (phys, _flusher) = Phys::new(Arc::new(backend), 0, false);
+
+        let mut pages = pin!(phys.pages(0..1).try_collect::<Vec<_>>());
+        assert!(pages.as_mut().now_or_never().is_none());
+        phys.write_all_at(0, &[2; 16])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        let pages = pages.now_or_never().unwrap().unwrap();
+        let data = pages[0].1.as_slice();
+        assert_eq!(data[..16], [2; 16]);
+        assert_eq!(data[16], 1);
+        assert!(ksync::critical(|| phys.list.lock().frames[&0].dirty));
     }
 }
@@ -12,7 +12,7 @@ use core::{
 
 use arsc_rs::Arsc;
 use futures_util::Future;
-use ksc_core::Error::{self, EFAULT, EINVAL, ENOSPC};
+use ksc_core::Error::{self, EFAULT, EINVAL, ENOMEM, ENOSPC};
 use ksync::Mutex;
 use range_map::{AslrKey, RangeMap};
 use rv39_paging::{
@@ -27,6 +27,9 @@ struct Mapping {
     phys: Arc<Phys>,
     start_index: usize,
     attr: Attr,
+    /// The top of the stack if this mapping is a part of a grows-down stack
+    /// region.
+    stack_top: Option<LAddr>,
 }
 
 pub struct Virt {
@@ -125,6 +128,7 @@ impl Mapping {
             phys: Arc::new(self.phys.clone_as(self.phys.is_cow(), 0, None)),
             start_index: self.start_index,
             attr: self.attr,
+            stack_top: self.stack_top,
         }
     }
 }
@@ -148,6 +152,13 @@ impl Virt {
         tlb::set_virt(self)
     }
 
+    /// Whether this address space is currently loaded on the executing CPU,
+    /// e.g. by a sibling thread sharing it.
+    #[inline]
+    pub fn is_loaded(&self) -> bool {
+        tlb::is_current(self)
+    }
+
     pub async fn map(
         &self,
         addr: Option<LAddr>,
@@ -175,6 +186,7 @@ impl Virt {
                     phys,
                     start_index,
                     attr: attr | Attr::VALID,
+                    stack_top: None,
                 };
                 log::trace!("Virt::map result = {start:?}..{end:?}");
                 map.try_insert(start..end, mapping).map_err(|_| ENOSPC)?;
@@ -191,12 +203,73 @@ impl Virt {
                     phys,
                     start_index,
                     attr: attr | Attr::VALID,
+                    stack_top: None,
                 });
                 Ok(addr)
             }
         }
     }
 
+    /// Mark the mapping starting at `addr` as a grows-down stack region, so
+    /// that faults right below it can be resolved by [`Virt::grow_down`].
+    pub async fn set_grows_down(&self, addr: LAddr) -> Result<(), Error> {
+        let mut map = self.map.lock().await;
+        let (range, _) = map.get_key_value(&addr).ok_or(EFAULT)?;
+        let end = *range.end;
+        let mapping = map.get_mut(&addr).ok_or(EFAULT)?;
+        mapping.stack_top = Some(end);
+        Ok(())
+    }
+
+    fn stack_below(
+        map: &RangeMap<LAddr, Mapping>,
+        addr: LAddr,
+        guard: usize,
+    ) -> Option<(Range<LAddr>, &Mapping)> {
+        let window = addr..LAddr::from(addr.val().saturating_add(guard));
+        let (range, mapping) = map.intersection(window).last()?;
+        (*range.start > addr && mapping.stack_top.is_some())
+            .then_some((*range.start..*range.end, mapping))
+    }
+
+    /// Returns the start of the grows-down mapping right above `addr` if
+    /// `addr` lies within `guard` bytes below it, which means a fault at
+    /// `addr` is a stack-growth candidate rather than a wild access.
+    pub async fn stack_growth_candidate(&self, addr: LAddr, guard: usize) -> Option<LAddr> {
+        let map = self.map.lock().await;
+        Self::stack_below(&map, addr, guard).map(|(range, _)| range.start)
+    }
+
+    /// Extend the grows-down stack region right above `addr` downwards so that
+    /// it covers `addr`, as long as the whole region stays within `limit`
+    /// bytes.
+    pub async fn grow_down(&self, addr: LAddr, guard: usize, limit: usize) -> Result<(), Error> {
+        let mut map = self.map.lock().await;
+
+        let (range, mapping) = Self::stack_below(&map, addr, guard).ok_or(EFAULT)?;
+        let (attr, stack_top) = (mapping.attr, mapping.stack_top);
+
+        let start = LAddr::from(addr.val() & !PAGE_MASK);
+        if stack_top.map_or(true, |top| top.val() - start.val() > limit) {
+            return Err(ENOMEM);
+        }
+        // Keep at least one unmapped page below the stack as its guard.
+        let guard_start = LAddr::from(start.val().saturating_sub(PAGE_SIZE));
+        if map.intersects(guard_start..start) {
+            return Err(ENOSPC);
+        }
+
+        log::trace!("Virt::grow_down {range:?} => {start:?}..{:?}", range.end);
+        let mapping = Mapping {
+            phys: Arc::new(Phys::new_anon(true)),
+            start_index: 0,
+            attr,
+            stack_top,
+        };
+        map.try_insert(start..range.start, mapping)
+            .map_err(|_| ENOSPC)
+    }
+
     pub async fn find_free(
         &self,
         start: Option<LAddr>,
@@ -320,6 +393,7 @@ impl Virt {
                 phys: mapping.phys.clone(),
                 start_index: mapping.start_index + offset,
                 attr,
+                stack_top: mapping.stack_top,
             };
 
             entry.set_former(mapping);
@@ -340,6 +414,7 @@ impl Virt {
                 phys: mapping.phys.clone(),
                 start_index: mapping.start_index,
                 attr,
+                stack_top: mapping.stack_top,
             };
 
             mapping.start_index += count;
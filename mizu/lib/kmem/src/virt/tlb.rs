@@ -18,7 +18,17 @@ use crate::Virt;
 #[thread_local]
 static mut CUR_VIRT: *const Virt = ptr::null();
 
+/// Whether `virt` is the address space loaded on the current CPU.
+pub fn is_current(virt: &Virt) -> bool {
+    unsafe { ptr::eq(CUR_VIRT, virt) }
+}
+
 pub fn set_virt(virt: Pin<Arsc<Virt>>) -> Option<impl Future<Output = ()> + Send + 'static> {
+    if is_current(&virt) {
+        // The CPU already holds a reference to it, so there's nothing to load
+        // or tear down.
+        return None;
+    }
     let addr = unsafe { ptr::addr_of_mut!(*virt.root.as_ptr()) };
 
     virt.cpu_mask.fetch_or(1 << hart_id::hart_id(), SeqCst);
@@ -46,6 +46,7 @@ pub enum Scn {
     NEWFSTATAT = 79,
     FSTAT = 80,
     FSYNC = 82,
+    FDATASYNC = 83,
     UTIMENSAT = 88,
     EXIT = 93,
     EXIT_GROUP = 94,
@@ -54,10 +55,13 @@ pub enum Scn {
     SET_ROBUST_LIST = 99,
     GET_ROBUST_LIST = 100,
     NANOSLEEP = 101,
+    GETITIMER = 102,
     SETITIMER = 103,
     CLOCK_GETTIME = 113,
+    CLOCK_NANOSLEEP = 115,
     SYSLOG = 116,
     SCHED_YIELD = 124,
+    RESTART_SYSCALL = 128,
     KILL = 129,
     TKILL = 130,
     TGKILL = 131,
@@ -104,4 +108,5 @@ pub enum Scn {
     RENAMEAT2 = 276,
     GETRANDOM = 278,
     MEMBARRIER = 283,
+    RSEQ = 293,
 }
@@ -17,25 +17,59 @@ pub enum ActionType {
         exit: LAddr,
         use_extra_cx: bool,
         use_alt_stack: bool,
+        /// `SA_NODEFER`: don't block the signal itself while its handler runs.
+        no_defer: bool,
     },
 }
 
 impl ActionType {
     pub const fn default(sig: Sig) -> Self {
-        use ActionType::*;
-        match sig {
-            Sig::SIGCHLD | Sig::SIGURG => Ignore,
-            Sig::SIGSTOP => Suspend,
-            Sig::SIGCONT => Resume,
-            _ => ActionType::Kill,
+        match sig.default_action() {
+            DefaultAction::Term | DefaultAction::Core => ActionType::Kill,
+            DefaultAction::Ign => ActionType::Ignore,
+            DefaultAction::Stop => ActionType::Suspend,
+            DefaultAction::Cont => ActionType::Resume,
         }
     }
 }
 
+/// The default disposition of a signal, as listed in `signal(7)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    /// Terminate the process.
+    Term,
+    /// Terminate the process and dump core.
+    Core,
+    /// Ignore the signal.
+    Ign,
+    /// Stop the process.
+    Stop,
+    /// Continue the process if it is currently stopped.
+    Cont,
+}
+
+/// The `wstatus` reported by `wait4` for a task terminated by `sig`.
+///
+/// The lower 7 bits hold the signal number, and bit 7 is set if `action`
+/// dumps core.
+pub const fn termination_status(sig: Sig, action: DefaultAction) -> i32 {
+    let core = match action {
+        DefaultAction::Core => 0x80,
+        _ => 0,
+    };
+    (sig.raw() & 0x7f) | core
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Action {
     pub ty: ActionType,
     pub mask: SigSet,
+    /// `SA_NOCLDSTOP`, only meaningful for `SIGCHLD`: the parent is not
+    /// notified when its children stop or continue.
+    pub no_cld_stop: bool,
+    /// `SA_NOCLDWAIT` or an explicit `SIG_IGN`, only meaningful for
+    /// `SIGCHLD`: children are reaped on exit instead of becoming zombies.
+    pub no_cld_wait: bool,
 }
 
 impl Action {
@@ -43,8 +77,24 @@ impl Action {
         Action {
             ty: ActionType::default(sig),
             mask: SigSet::EMPTY,
+            no_cld_stop: false,
+            no_cld_wait: false,
         }
     }
+
+    /// The signal mask while the handler of `sig` runs, given the mask `cur`
+    /// when the signal is delivered.
+    ///
+    /// Besides the mask of the action, the signal itself is blocked unless
+    /// `SA_NODEFER` is set, so that the handler is not re-entered by it.
+    pub fn handler_mask(&self, sig: Sig, cur: SigSet) -> SigSet {
+        let mask = cur | self.mask;
+        let mask = match self.ty {
+            ActionType::User { no_defer: true, .. } => mask,
+            _ => mask | sig,
+        };
+        mask.sanitize_mask()
+    }
 }
 
 pub struct ActionSet {
@@ -54,13 +104,7 @@ pub struct ActionSet {
 impl ActionSet {
     pub const fn new() -> Self {
         ActionSet {
-            data: array![
-                index => match Sig::from_index(index) {
-                    Some(sig) => Mutex::new(Action::default(sig)),
-                    None => panic!("unsupported index for signal")
-                };
-                NR_SIGNALS
-            ],
+            data: array![index => Mutex::new(Action::default(Sig::ALL[index])); NR_SIGNALS],
         }
     }
 
@@ -94,3 +138,51 @@ impl const Default for ActionSet {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handler_mask() {
+        let action = |no_defer| Action {
+            ty: ActionType::User {
+                entry: LAddr::from(0x1000usize),
+                exit: LAddr::from(0x2000usize),
+                use_extra_cx: false,
+                use_alt_stack: false,
+                no_defer,
+            },
+            mask: Sig::SIGUSR2.into(),
+            ..Action::default(Sig::SIGUSR1)
+        };
+        let cur = SigSet::from(Sig::SIGINT);
+
+        // Without `SA_NODEFER`, a second `SIGUSR1` is deferred until return.
+        let mask = action(false).handler_mask(Sig::SIGUSR1, cur);
+        assert_eq!(mask, cur | Sig::SIGUSR1 | Sig::SIGUSR2);
+
+        // With it, the handler is re-entered.
+        let mask = action(true).handler_mask(Sig::SIGUSR1, cur);
+        assert_eq!(mask, cur | Sig::SIGUSR2);
+        assert!(!mask.contains(Sig::SIGUSR1));
+    }
+
+    #[test]
+    fn test_default_action() {
+        assert_eq!(Sig::SIGWINCH.default_action(), DefaultAction::Ign);
+        assert_eq!(Sig::SIGTTIN.default_action(), DefaultAction::Stop);
+        assert_eq!(Sig::SIGABRT.default_action(), DefaultAction::Core);
+        assert_eq!(Sig::SIGUSR1.default_action(), DefaultAction::Term);
+        assert_eq!(ActionType::default(Sig::SIGTSTP), ActionType::Suspend);
+        assert_eq!(ActionType::default(Sig::SIGCONT), ActionType::Resume);
+    }
+
+    #[test]
+    fn test_termination_status() {
+        let status = |sig| termination_status(sig, sig.default_action());
+        assert_eq!(status(Sig::SIGKILL), 9);
+        assert_eq!(status(Sig::SIGSEGV), 11 | 0x80);
+        assert_eq!(status(Sig::SIGTERM), 15);
+    }
+}
@@ -1,3 +1,4 @@
+use alloc::collections::VecDeque;
 use core::{
     array,
     future::Future,
@@ -6,26 +7,25 @@ use core::{
     task::{ready, Context, Poll},
 };
 
-use crossbeam_queue::ArrayQueue;
-use futures_util::{future, FutureExt};
+use futures_util::FutureExt;
 use ksc_core::handler::Param;
 use ksync::event::{Event, EventListener};
 use rv39_paging::LAddr;
+use spin::Mutex;
 
-use crate::{Sig, SigSet, NR_SIGNALS};
+use crate::{ChldCode, SegvCode, Sig, SigCode, SigSet, NR_SIGNALS};
 
-const CAP_PER_SIG: usize = 8;
-
-#[derive(Debug)]
-struct SigPending {
-    queue: ArrayQueue<SigInfo>,
-    event: Event,
-}
+/// The number of real-time signal instances a task can have pending, as
+/// `RLIMIT_SIGPENDING` does.
+const MAX_QUEUED: usize = 256;
 
 #[derive(Debug)]
 pub struct Signals {
+    /// A copy of the pending set of `queue`, for checking without locking.
     set: AtomicU64,
-    pending: [SigPending; NR_SIGNALS],
+    queue: Mutex<SigQueue>,
+    /// Notified on every signal pushed, for the waiting futures.
+    any: Event,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -39,6 +39,62 @@ impl Param for SigInfo {
     type Item<'a> = SigInfo;
 }
 
+impl SigInfo {
+    /// A signal sent by the kernel itself, without further information.
+    pub const fn kernel(sig: Sig) -> Self {
+        SigInfo {
+            sig,
+            code: SigCode::KERNEL as _,
+            fields: SigFields::None,
+        }
+    }
+
+    /// A signal sent by the task `pid` with `kill(2)` and its friends.
+    pub const fn kill(sig: Sig, pid: usize, uid: usize) -> Self {
+        SigInfo {
+            sig,
+            code: SigCode::USER as _,
+            fields: SigFields::SigKill { pid, uid },
+        }
+    }
+
+    /// A signal sent by the expiration of the timer `id`.
+    pub const fn timer(sig: Sig, id: i32) -> Self {
+        SigInfo {
+            sig,
+            code: SigCode::TIMER as _,
+            fields: SigFields::Timer { id, overrun: 0 },
+        }
+    }
+
+    /// A synchronous fault, where `addr` is the faulting address of the memory
+    /// access, or of the instruction for `SIGILL` and `SIGFPE`.
+    pub const fn fault(sig: Sig, code: i32, addr: LAddr) -> Self {
+        SigInfo {
+            sig,
+            code,
+            fields: SigFields::SigFault { addr },
+        }
+    }
+
+    pub const fn segv(code: SegvCode, addr: LAddr) -> Self {
+        Self::fault(Sig::SIGSEGV, code as _, addr)
+    }
+
+    /// A `SIGCHLD` reporting the state change `code` of the child `pid`.
+    pub const fn child(pid: usize, status: i32, code: ChldCode) -> Self {
+        SigInfo {
+            sig: Sig::SIGCHLD,
+            code: code as _,
+            fields: SigFields::SigChld {
+                pid,
+                uid: 0,
+                status,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[non_exhaustive]
 pub enum SigFields {
@@ -46,74 +102,121 @@ pub enum SigFields {
     SigKill { pid: usize, uid: usize },
     SigChld { pid: usize, uid: usize, status: i32 },
     SigSys { addr: LAddr, num: u32 },
+    SigFault { addr: LAddr },
+    Timer { id: i32, overrun: i32 },
 }
 
 impl Signals {
     pub fn new() -> Self {
         Signals {
             set: AtomicU64::new(0),
-            pending: array::from_fn(|index| SigPending {
-                queue: ArrayQueue::new(match Sig::from_index(index) {
-                    // Each legacy signal only needs 1 entry.
-                    Some(sig) if sig.is_legacy() => 1,
-                    _ => CAP_PER_SIG,
-                }),
-                event: Event::new(),
-            }),
+            queue: Mutex::new(SigQueue::new(MAX_QUEUED)),
+            any: Event::new(),
         }
     }
 
-    pub fn push(&self, info: SigInfo) {
-        let old: SigSet = self.set.fetch_or(info.sig.mask(), SeqCst).into();
+    /// Run `f` on the locked queue, keeping the copy of its pending set up to
+    /// date.
+    fn with_queue<T>(&self, f: impl FnOnce(&mut SigQueue) -> T) -> T {
+        ksync::critical(|| {
+            let mut queue = self.queue.lock();
+            let ret = f(&mut queue);
+            self.set.store(queue.pending().raw(), SeqCst);
+            ret
+        })
+    }
 
-        if !(info.sig.is_legacy() && old.contains(info.sig)) {
-            let sig_pending = &self.pending[info.sig.index()];
-            let res = sig_pending.queue.push(info);
-            if res.is_ok() {
-                sig_pending.event.notify_additional(1);
-            }
+    /// Queue `info`, returning `false` if it is a real-time signal and too
+    /// many of them are already pending. See [`SigQueue::push`].
+    pub fn push(&self, info: SigInfo) -> bool {
+        let ret = self.with_queue(|queue| queue.push(info));
+        if ret {
+            self.any.notify(usize::MAX);
         }
+        ret
     }
 
     pub fn is_empty(&self) -> bool {
         self.set.load(SeqCst) == 0
     }
 
-    pub fn pop(&self, masked: SigSet) -> Option<SigInfo> {
-        if self.is_empty() {
-            return None;
-        }
-        let iter = self.pending.iter().enumerate();
+    /// Whether any signal not in `masked` is pending.
+    pub fn has_pending(&self, masked: SigSet) -> bool {
+        self.set.load(SeqCst) & !masked.raw() != 0
+    }
 
-        let (info, is_empty) = iter
-            .filter(|&(index, _)| !masked.contains_index(index))
-            .find_map(|(_, pending)| pending.queue.pop().map(|s| (s, pending.queue.is_empty())))?;
+    /// Wait until any signal not in `masked` is pending, without dequeuing
+    /// it, e.g. to interrupt a blocking syscall.
+    pub async fn wait_pending(&self, masked: SigSet) {
+        while !self.has_pending(masked) {
+            let listener = self.any.listen();
+            if self.has_pending(masked) {
+                break;
+            }
+            listener.await;
+        }
+    }
 
-        if is_empty {
-            self.set.fetch_and(!info.sig.mask(), SeqCst);
+    pub fn pop(&self, masked: SigSet) -> Option<SigInfo> {
+        if !self.has_pending(masked) {
+            return None;
         }
-        Some(info)
+        self.with_queue(|queue| queue.pop(masked))
     }
 
+    /// Wait for `sig` and dequeue it, regardless of the other signals.
     pub fn wait_one(&self, sig: Sig) -> WaitOne {
         WaitOne {
-            pending: &self.pending[sig.index()],
-            set: &self.set,
+            signals: self,
+            sig,
             listener: None,
         }
     }
 
-    pub async fn wait(&self, sigset: SigSet) -> SigInfo {
-        let wait_one = move |sig| self.wait_one(sig);
-        let wait_any = future::select_all(sigset.map(wait_one));
-        wait_any.await.0
+    /// Wait for any signal in `sigset` and dequeue it in delivery order, as
+    /// `sigtimedwait(2)` does.
+    pub fn wait(&self, sigset: SigSet) -> SigWait {
+        SigWait {
+            signals: self,
+            set: sigset,
+            listener: None,
+        }
+    }
+}
+
+/// Future for [`Signals::wait`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct SigWait<'a> {
+    signals: &'a Signals,
+    set: SigSet,
+    listener: Option<EventListener>,
+}
+
+impl Future for SigWait<'_> {
+    type Output = SigInfo;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            // The listener is always set up before checking again, so no
+            // signal pushed in between is missed.
+            if let Some(info) = self.signals.pop(!self.set) {
+                break Poll::Ready(info);
+            }
+            match self.listener.as_mut() {
+                Some(listener) => {
+                    ready!(listener.poll_unpin(cx));
+                    self.listener = None;
+                }
+                None => self.listener = Some(self.signals.any.listen()),
+            }
+        }
     }
 }
 
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct WaitOne<'a> {
-    pending: &'a SigPending,
-    set: &'a AtomicU64,
+    signals: &'a Signals,
+    sig: Sig,
     listener: Option<EventListener>,
 }
 
@@ -122,20 +225,16 @@ impl Future for WaitOne<'_> {
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         loop {
-            match self.pending.queue.pop() {
-                Some(info) => {
-                    if self.pending.queue.is_empty() {
-                        self.set.fetch_and(!info.sig.mask(), SeqCst);
-                    }
-                    break Poll::Ready(info);
+            let sig = self.sig;
+            if let Some(info) = self.signals.with_queue(|queue| queue.pop_one(sig)) {
+                break Poll::Ready(info);
+            }
+            match self.listener.as_mut() {
+                Some(listener) => {
+                    ready!(listener.poll_unpin(cx));
+                    self.listener = None;
                 }
-                None => match self.listener.as_mut() {
-                    Some(listener) => {
-                        ready!(listener.poll_unpin(cx));
-                        self.listener = None;
-                    }
-                    None => self.listener = Some(self.pending.event.listen()),
-                },
+                None => self.listener = Some(self.signals.any.listen()),
             }
         }
     }
@@ -146,3 +245,261 @@ impl Default for Signals {
         Self::new()
     }
 }
+
+/// Reads pending signals of a set as data, for implementing `signalfd(2)`.
+///
+/// The signals are taken from the same queues as the normal delivery path, so
+/// each signal is consumed by exactly one of them, whichever comes first. The
+/// reader ignores the blocked mask: the caller should block the signals of
+/// interest, or they are likely to be delivered to handlers before being read.
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFdReader<'a> {
+    signals: &'a Signals,
+    set: SigSet,
+}
+
+impl<'a> SignalFdReader<'a> {
+    pub fn new(signals: &'a Signals, set: SigSet) -> Self {
+        SignalFdReader { signals, set }
+    }
+
+    pub fn set(&self) -> SigSet {
+        self.set
+    }
+
+    /// Dequeue the next pending signal of the set in delivery order, without
+    /// waiting.
+    pub fn try_read(&self) -> Option<SigInfo> {
+        self.signals.pop(!self.set)
+    }
+
+    /// Dequeue up to `max` pending signals of the set in delivery order,
+    /// without waiting.
+    pub fn read_many(&self, max: usize) -> impl Iterator<Item = SigInfo> + '_ {
+        (0..max).map_while(|_| self.try_read())
+    }
+
+    /// Dequeue the next pending signal of the set, waiting for one if none is
+    /// pending.
+    pub async fn read(&self) -> SigInfo {
+        match self.try_read() {
+            Some(info) => info,
+            None => self.signals.wait(self.set).await,
+        }
+    }
+}
+
+/// The number of legacy signals, which are never queued more than once.
+const NR_LEGACY: usize = Sig::SIG_LEGACY_MAX.raw() as usize;
+
+/// A plain queue of pending signals, queuing every instance of a real-time
+/// signal as `sigqueue(3)` requires.
+///
+/// A legacy signal pending when sent again is coalesced with the pending one,
+/// keeping the info of the first. Real-time signals are queued with their own
+/// infos up to a total limit, as `RLIMIT_SIGPENDING` does. Dequeuing takes the
+/// lowest-numbered unmasked signal first, and the instances of a real-time
+/// signal in the order they are sent.
+#[derive(Debug, Clone)]
+pub struct SigQueue {
+    /// Every pending signal, legacy or real-time.
+    set: SigSet,
+    legacy: [Option<SigInfo>; NR_LEGACY],
+    rt: [VecDeque<SigInfo>; NR_SIGNALS - NR_LEGACY],
+    /// The number of real-time instances queued.
+    queued: usize,
+    limit: usize,
+}
+
+impl SigQueue {
+    /// Create an empty queue holding at most `limit` real-time instances.
+    pub fn new(limit: usize) -> Self {
+        SigQueue {
+            set: SigSet::EMPTY,
+            legacy: [None; NR_LEGACY],
+            rt: array::from_fn(|_| VecDeque::new()),
+            queued: 0,
+            limit,
+        }
+    }
+
+    /// Queue `info`, returning `false` if it is a real-time signal and the
+    /// limit has been reached, in which case `sigqueue` fails with `EAGAIN`.
+    ///
+    /// A legacy signal is always accepted, even if coalesced.
+    pub fn push(&mut self, info: SigInfo) -> bool {
+        let index = info.sig.index();
+        if info.sig.is_legacy() {
+            self.legacy[index].get_or_insert(info);
+        } else {
+            if self.queued >= self.limit {
+                return false;
+            }
+            self.rt[index - NR_LEGACY].push_back(info);
+            self.queued += 1;
+        }
+        self.set |= info.sig;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    /// The set of pending signals.
+    pub fn pending(&self) -> SigSet {
+        self.set
+    }
+
+    /// The number of pending instances of `sig`.
+    pub fn count(&self, sig: Sig) -> usize {
+        if sig.is_legacy() {
+            self.legacy[sig.index()].is_some() as usize
+        } else {
+            self.rt[sig.index() - NR_LEGACY].len()
+        }
+    }
+
+    /// Dequeue the lowest-numbered pending signal not in `masked`.
+    pub fn pop(&mut self, masked: SigSet) -> Option<SigInfo> {
+        let sig = (self.set & !masked).delivery_order().next()?;
+        Some(self.pop_one(sig).expect("pending signal without info"))
+    }
+
+    /// Dequeue the earliest pending instance of `sig`.
+    pub fn pop_one(&mut self, sig: Sig) -> Option<SigInfo> {
+        let index = sig.index();
+        let (info, is_empty) = if sig.is_legacy() {
+            (self.legacy[index].take()?, true)
+        } else {
+            let queue = &mut self.rt[index - NR_LEGACY];
+            let info = queue.pop_front()?;
+            self.queued -= 1;
+            (info, queue.is_empty())
+        };
+        if is_empty {
+            self.set.remove(sig);
+        }
+        Some(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(sig: Sig) -> SigInfo {
+        SigInfo {
+            sig,
+            code: SigCode::USER as _,
+            fields: SigFields::None,
+        }
+    }
+
+    #[test]
+    fn test_signalfd_reader() {
+        let signals = Signals::new();
+        signals.push(info(Sig::SIGUSR2));
+        signals.push(info(Sig::SIGTERM));
+        signals.push(info(Sig::SIGUSR1));
+
+        let set = SigSet::from(Sig::SIGUSR1) | Sig::SIGUSR2;
+        let reader = SignalFdReader::new(&signals, set);
+        let read: alloc::vec::Vec<_> = reader.read_many(4).map(|info| info.sig).collect();
+        assert_eq!(read, [Sig::SIGUSR1, Sig::SIGUSR2]);
+
+        // The signals read are consumed, while the others are left for delivery.
+        let rest = signals.pop(SigSet::EMPTY).map(|info| info.sig);
+        assert_eq!(rest, Some(Sig::SIGTERM));
+        assert!(signals.is_empty());
+    }
+
+    #[test]
+    fn test_has_pending() {
+        let signals = Signals::new();
+        let masked = SigSet::from(Sig::SIGUSR1);
+        signals.push(info(Sig::SIGUSR1));
+        assert!(!signals.has_pending(masked));
+        assert!(signals.has_pending(SigSet::EMPTY));
+
+        // Checking doesn't consume the signal.
+        assert!(signals.wait_pending(SigSet::EMPTY).now_or_never().is_some());
+        let popped = signals.pop(SigSet::EMPTY).map(|info| info.sig);
+        assert_eq!(popped, Some(Sig::SIGUSR1));
+        assert!(signals.wait_pending(SigSet::EMPTY).now_or_never().is_none());
+    }
+
+    #[test]
+    fn test_sig_queue() {
+        let rt = Sig::new(Sig::SIG_LEGACY_MAX.raw() + 2).unwrap();
+        let rt_low = Sig::new(Sig::SIG_LEGACY_MAX.raw() + 1).unwrap();
+        let mut queue = SigQueue::new(3);
+        let with_code = |sig, code| SigInfo { code, ..info(sig) };
+
+        // Legacy signals are coalesced, keeping the first info.
+        assert!(queue.push(with_code(Sig::SIGUSR1, 1)));
+        assert!(queue.push(with_code(Sig::SIGUSR1, 2)));
+        assert_eq!(queue.count(Sig::SIGUSR1), 1);
+
+        // Real-time signals are queued in order up to the limit.
+        assert!(queue.push(with_code(rt, 1)));
+        assert!(queue.push(with_code(rt, 2)));
+        assert!(queue.push(with_code(rt_low, 3)));
+        assert!(!queue.push(with_code(rt, 4)));
+        assert_eq!(queue.count(rt), 2);
+
+        let popped = core::iter::from_fn(|| queue.pop(SigSet::EMPTY))
+            .map(|info| (info.sig, info.code))
+            .collect::<alloc::vec::Vec<_>>();
+        assert_eq!(popped, [(Sig::SIGUSR1, 1), (rt_low, 3), (rt, 1), (rt, 2)]);
+        assert!(queue.is_empty());
+        assert!(queue.push(with_code(rt, 5)));
+    }
+
+    #[test]
+    fn test_signals_queue_rt() {
+        let signals = Signals::new();
+        let rt = Sig::new(Sig::SIG_LEGACY_MAX.raw() + 1).unwrap();
+        assert!(signals.push(info(Sig::SIGUSR1)));
+        assert!(signals.push(info(Sig::SIGUSR1)));
+        assert!((0..MAX_QUEUED).all(|_| signals.push(info(rt))));
+        assert!(!signals.push(info(rt)));
+
+        let mut wait = signals.wait_one(rt);
+        assert!((0..MAX_QUEUED).all(|_| (&mut wait).now_or_never().is_some()));
+        assert!(wait.now_or_never().is_none());
+        assert!(signals.has_pending(SigSet::EMPTY));
+        assert_eq!(
+            signals.pop(SigSet::EMPTY).map(|info| info.sig),
+            Some(Sig::SIGUSR1)
+        );
+        assert!(signals.is_empty());
+    }
+
+    #[test]
+    fn test_sig_wait() {
+        let signals = Signals::new();
+        let rt = Sig::new(Sig::SIG_LEGACY_MAX.raw() + 1).unwrap();
+        let set = SigSet::from(Sig::SIGUSR1) | rt;
+        let mut wait = signals.wait(set);
+        assert!((&mut wait).now_or_never().is_none());
+
+        signals.push(info(Sig::SIGTERM));
+        assert!((&mut wait).now_or_never().is_none());
+
+        signals.push(SigInfo::kill(rt, 2, 0));
+        signals.push(SigInfo::kill(rt, 3, 0));
+        signals.push(SigInfo::kill(Sig::SIGUSR1, 1, 0));
+        let fields = |info: SigInfo| info.fields;
+        let pids = [1, 2, 3].map(|pid| SigFields::SigKill { pid, uid: 0 });
+        assert_eq!(wait.now_or_never().map(fields), Some(pids[0]));
+        assert_eq!(signals.wait(set).now_or_never().map(fields), Some(pids[1]));
+        assert_eq!(signals.wait(set).now_or_never().map(fields), Some(pids[2]));
+
+        // Signals outside the set are left for delivery.
+        assert_eq!(
+            signals.pop(SigSet::EMPTY).map(|info| info.sig),
+            Some(Sig::SIGTERM)
+        );
+    }
+}
@@ -5,6 +5,8 @@ use core::{
 
 use ksc_core::handler::Param;
 
+use crate::DefaultAction;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 #[repr(transparent)]
 pub struct Sig(i32);
@@ -93,6 +95,18 @@ impl Sig {
 
     pub const SIG_MAX: Sig = Sig(64);
 
+    /// Every valid signal, in the order of their indices, for building tables
+    /// indexed by [`Sig::index`] in const contexts.
+    pub const ALL: [Sig; NR_SIGNALS] = {
+        let mut all = [Sig(0); NR_SIGNALS];
+        let mut index = 0;
+        while index < NR_SIGNALS {
+            all[index] = Sig(index as i32 + 1);
+            index += 1;
+        }
+        all
+    };
+
     pub const fn from_index(index: usize) -> Option<Self> {
         if index < NR_SIGNALS {
             Some(Sig(index as i32 + 1))
@@ -121,8 +135,30 @@ impl Sig {
         (self.0 - 1) as usize
     }
 
+    /// What happens to a task receiving this signal without a handler.
+    pub const fn default_action(self) -> DefaultAction {
+        use DefaultAction::*;
+        match self {
+            Sig::SIGQUIT
+            | Sig::SIGILL
+            | Sig::SIGTRAP
+            | Sig::SIGABRT
+            | Sig::SIGBUS
+            | Sig::SIGFPE
+            | Sig::SIGSEGV
+            | Sig::SIGXCPU
+            | Sig::SIGXFSZ
+            | Sig::SIGSYS => Core,
+            Sig::SIGCHLD | Sig::SIGURG | Sig::SIGWINCH => Ign,
+            Sig::SIGSTOP | Sig::SIGTSTP | Sig::SIGTTIN | Sig::SIGTTOU => Stop,
+            Sig::SIGCONT => Cont,
+            _ => Term,
+        }
+    }
+
+    /// Whether the signal can never be caught, blocked or ignored.
     pub const fn should_never_capture(self) -> bool {
-        matches!(self, Sig::SIGKILL | Sig::SIGSTOP)
+        UNCATCHABLE.0 & self.mask() != 0
     }
 }
 
@@ -130,6 +166,9 @@ impl Sig {
 #[repr(transparent)]
 pub struct SigSet(u64);
 
+/// The signals that can never be caught, blocked or ignored.
+const UNCATCHABLE: SigSet = SigSet(Sig::SIGKILL.mask() | Sig::SIGSTOP.mask());
+
 impl const From<u64> for SigSet {
     fn from(value: u64) -> Self {
         SigSet(value)
@@ -151,6 +190,22 @@ impl const From<Sig> for SigSet {
 impl SigSet {
     pub const EMPTY: SigSet = SigSet(0);
 
+    /// The signals whose delivery is mandatory, i.e. `SIGKILL` and `SIGSTOP`.
+    pub const fn mandatory() -> SigSet {
+        UNCATCHABLE
+    }
+
+    /// Every signal but the mandatory ones.
+    pub const fn catchable() -> SigSet {
+        SigSet(!UNCATCHABLE.0)
+    }
+
+    /// The set without the mandatory signals, which is safe to be used as a
+    /// signal mask.
+    pub const fn sanitize_mask(&self) -> SigSet {
+        SigSet(self.0 & !UNCATCHABLE.0)
+    }
+
     pub const fn is_empty(&self) -> bool {
         self.0 == 0
     }
@@ -159,6 +214,25 @@ impl SigSet {
         self.0
     }
 
+    /// The number of signals in the set.
+    pub const fn len(&self) -> u32 {
+        self.0.count_ones()
+    }
+
+    /// The `n`th signal in the set in ascending order, starting from 0.
+    pub const fn nth_signal(&self, n: u32) -> Option<Sig> {
+        if n >= self.len() {
+            return None;
+        }
+        let mut bits = self.0;
+        let mut i = 0;
+        while i < n {
+            bits &= bits - 1;
+            i += 1;
+        }
+        Some(Sig((bits.trailing_zeros() + 1) as i32))
+    }
+
     pub const fn contains<S: ~const Into<Self>>(self, other: S) -> bool {
         let other: Self = other.into();
         (!self & other).0 == 0
@@ -172,6 +246,38 @@ impl SigSet {
         self.contains(other)
     }
 
+    /// The signals in the set in ascending order, without consuming it.
+    pub fn iter(&self) -> impl Iterator<Item = Sig> {
+        *self
+    }
+
+    /// The signals in the set numbered `sig` or higher, in ascending order.
+    pub fn iter_from(&self, sig: Sig) -> impl Iterator<Item = Sig> {
+        SigSet(self.0 & !(sig.mask() - 1))
+    }
+
+    pub const fn lowest(&self) -> Option<Sig> {
+        match self.0 {
+            0 => None,
+            bits => Some(Sig((bits.trailing_zeros() + 1) as i32)),
+        }
+    }
+
+    pub const fn highest(&self) -> Option<Sig> {
+        match self.0 {
+            0 => None,
+            bits => Some(Sig((u64::BITS - bits.leading_zeros()) as i32)),
+        }
+    }
+
+    /// The signals in the set in the order they are delivered: legacy signals
+    /// before real-time ones, and lower-numbered ones first within each.
+    pub fn delivery_order(&self) -> impl Iterator<Item = Sig> {
+        let rt_start = Sig(Sig::SIG_LEGACY_MAX.0 + 1);
+        let legacy = SigSet(self.0 & (rt_start.mask() - 1));
+        legacy.chain(self.iter_from(rt_start))
+    }
+
     pub fn insert(&mut self, sig: Sig) -> bool {
         if self.contains(sig) {
             false
@@ -288,6 +394,68 @@ pub enum SigCode {
     ASYNCNL = -60,
 }
 
+/// The `si_code` of `SIGCHLD`, describing the state change of the child.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(i32)]
+pub enum ChldCode {
+    /// child has exited
+    EXITED = 1,
+    /// child was killed
+    KILLED = 2,
+    /// child terminated abnormally
+    DUMPED = 3,
+    /// traced child has trapped
+    TRAPPED = 4,
+    /// child has stopped
+    STOPPED = 5,
+    /// stopped child has continued
+    CONTINUED = 6,
+}
+
+/// The `si_code` of `SIGSEGV`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(i32)]
+pub enum SegvCode {
+    /// address not mapped to object
+    MAPERR = 1,
+    /// invalid permissions for mapped object
+    ACCERR = 2,
+}
+
+/// The `si_code` of `SIGBUS`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(i32)]
+pub enum BusCode {
+    /// invalid address alignment
+    ADRALN = 1,
+    /// non-existent physical address
+    ADRERR = 2,
+    /// object specific hardware error
+    OBJERR = 3,
+}
+
+/// The `si_code` of `SIGILL`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(i32)]
+pub enum IllCode {
+    /// illegal opcode
+    ILLOPC = 1,
+    /// illegal operand
+    ILLOPN = 2,
+    /// illegal addressing mode
+    ILLADR = 3,
+    /// illegal trap
+    ILLTRP = 4,
+    /// privileged opcode
+    PRVOPC = 5,
+    /// privileged register
+    PRVREG = 6,
+    /// coprocessor error
+    COPROC = 7,
+    /// internal stack error
+    BADSTK = 8,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -297,8 +465,47 @@ mod tests {
         let mut ss = SigSet::from(Sig::SIGBUS) | Sig::SIGFPE | Sig::SIGPROF;
         assert_eq!(ss.remove(Sig::SIGABRT), false);
         assert_eq!(ss.remove(Sig::SIGFPE), true);
+        assert_eq!(ss.len(), 2);
+        assert_eq!(ss.nth_signal(1), Some(Sig::SIGPROF));
+        assert_eq!(ss.nth_signal(2), None);
         assert_eq!(ss.next(), Some(Sig::SIGBUS));
         assert_eq!(ss.next(), Some(Sig::SIGPROF));
         assert_eq!(ss.next(), None)
     }
+
+    #[test]
+    fn test_delivery_order() {
+        let rt = Sig::new(Sig::SIG_LEGACY_MAX.raw() + 1).unwrap();
+        let ss = SigSet::from(Sig::SIG_MAX) | rt | Sig::SIGTERM | Sig::SIGHUP;
+        assert_eq!(ss.lowest(), Some(Sig::SIGHUP));
+        assert_eq!(ss.highest(), Some(Sig::SIG_MAX));
+        assert_eq!(SigSet::EMPTY.highest(), None);
+
+        let order: alloc::vec::Vec<_> = ss.delivery_order().collect();
+        assert_eq!(order, [Sig::SIGHUP, Sig::SIGTERM, rt, Sig::SIG_MAX]);
+        assert!(ss
+            .iter_from(Sig::SIGINT)
+            .eq([Sig::SIGTERM, rt, Sig::SIG_MAX]));
+        // Iterating doesn't consume the set.
+        assert_eq!(ss.iter().count(), 4);
+        assert_eq!(ss.len(), 4);
+    }
+
+    #[test]
+    fn test_sanitize_mask() {
+        let mask = SigSet::from(Sig::SIGKILL) | Sig::SIGSTOP | Sig::SIGINT;
+        assert_eq!(mask.sanitize_mask(), SigSet::from(Sig::SIGINT));
+        assert!(!SigSet::catchable().contains(Sig::SIGKILL));
+        assert!(SigSet::mandatory().all(Sig::should_never_capture));
+        assert_eq!(SigSet::catchable().len() as usize, NR_SIGNALS - 2);
+    }
+
+    #[test]
+    fn test_all() {
+        for (index, sig) in Sig::ALL.into_iter().enumerate() {
+            assert_eq!(sig.index(), index);
+            assert_eq!(Sig::from_index(index), Some(sig));
+        }
+        assert_eq!(Sig::ALL[NR_SIGNALS - 1], Sig::SIG_MAX);
+    }
 }
@@ -0,0 +1,221 @@
+//! The per-open state of files.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicI32, AtomicUsize, Ordering::SeqCst};
+
+use ksc_core::Error::{self, EBADF, EINVAL};
+use ksync::Mutex;
+
+use crate::{
+    traits::File,
+    types::{IoSlice, IoSliceMut, OpenOptions, SeekFrom},
+};
+
+/// The status flags that can be changed after opening, as with `fcntl(2)`'s
+/// `F_SETFL`.
+const STATUS_FLAGS: OpenOptions = OpenOptions::APPEND
+    .union(OpenOptions::NONBLOCK)
+    .union(OpenOptions::ASYNC)
+    .union(OpenOptions::DIRECT)
+    .union(OpenOptions::NOATIME);
+
+/// An open file description, holding the offset and the status flags of an
+/// open file apart from the file itself.
+///
+/// Duplicated descriptors and forked tasks should share the same handle, so
+/// that reads, writes and seeks through either of them move the offset seen by
+/// both, while opening the file again gets a fresh handle.
+///
+/// Like `f_pos_lock` in Linux, the reads, writes and seeks of a handle are
+/// serialized, so that concurrent calls never use the same offset.
+pub struct FileHandle {
+    file: Arc<dyn File>,
+    offset: AtomicUsize,
+    pos_lock: Mutex<()>,
+    flags: AtomicI32,
+}
+
+impl FileHandle {
+    pub fn new(file: Arc<dyn File>, flags: OpenOptions) -> Self {
+        FileHandle {
+            file,
+            offset: AtomicUsize::new(0),
+            pos_lock: Mutex::new(()),
+            flags: AtomicI32::new(flags.bits()),
+        }
+    }
+
+    pub fn file(&self) -> &Arc<dyn File> {
+        &self.file
+    }
+
+    pub fn flags(&self) -> OpenOptions {
+        OpenOptions::from_bits_retain(self.flags.load(SeqCst))
+    }
+
+    /// Replace the status flags with those in `flags`, leaving the access mode
+    /// and the creation flags intact.
+    pub fn set_flags(&self, flags: OpenOptions) {
+        let flags = flags & STATUS_FLAGS;
+        let old = self.flags();
+        let new = old.difference(STATUS_FLAGS) | flags;
+        self.flags.store(new.bits(), SeqCst);
+    }
+
+    pub fn is_readable(&self) -> bool {
+        let access = self.flags() & OpenOptions::ACCMODE;
+        access == OpenOptions::RDONLY || access == OpenOptions::RDWR
+    }
+
+    pub fn is_writable(&self) -> bool {
+        let access = self.flags() & OpenOptions::ACCMODE;
+        access == OpenOptions::WRONLY || access == OpenOptions::RDWR
+    }
+
+    pub fn is_append(&self) -> bool {
+        self.flags().contains(OpenOptions::APPEND)
+    }
+
+    pub fn is_nonblock(&self) -> bool {
+        self.flags().contains(OpenOptions::NONBLOCK)
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset.load(SeqCst)
+    }
+
+    /// Read from the current offset, advancing it by the length read.
+    pub async fn read(&self, buffer: &mut [IoSliceMut<'_>]) -> Result<usize, Error> {
+        if !self.is_readable() {
+            return Err(EBADF);
+        }
+        let _pos = self.pos_lock.lock().await;
+        let offset = self.offset.load(SeqCst);
+        let len = self.file.read_at(offset, buffer).await?;
+        self.offset.store(offset + len, SeqCst);
+        Ok(len)
+    }
+
+    /// Write at the current offset, or at the end of the file if opened with
+    /// [`OpenOptions::APPEND`], advancing the offset past the written data.
+    ///
+    /// Concurrent appends through different handles are not serialized with
+    /// each other, and may overwrite each other's data.
+    pub async fn write(&self, buffer: &mut [IoSlice<'_>]) -> Result<usize, Error> {
+        if !self.is_writable() {
+            return Err(EBADF);
+        }
+        let _pos = self.pos_lock.lock().await;
+        let offset = if self.is_append() {
+            self.file.stream_len().await?
+        } else {
+            self.offset.load(SeqCst)
+        };
+        let len = self.file.write_at(offset, buffer).await?;
+        self.offset.store(offset + len, SeqCst);
+        Ok(len)
+    }
+
+    /// Move the offset as specified by `whence`, returning the new offset.
+    pub async fn seek(&self, whence: SeekFrom) -> Result<usize, Error> {
+        let _pos = self.pos_lock.lock().await;
+        let pos = match whence {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::End(pos) => {
+                let len = self.file.stream_len().await?;
+                let pos = pos.checked_add(len.try_into()?);
+                pos.ok_or(EINVAL)?.try_into()?
+            }
+            SeekFrom::Current(pos) => {
+                let pos = pos.checked_add(self.offset.load(SeqCst).try_into()?);
+                pos.ok_or(EINVAL)?.try_into()?
+            }
+        };
+        self.offset.store(pos, SeqCst);
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::misc::{MemFault, MemIo, MemOp};
+
+    fn open(data: &[u8], flags: OpenOptions) -> (Arc<MemIo>, FileHandle) {
+        let file = Arc::new(MemIo::with_data(data.to_vec(), 64));
+        (file.clone(), FileHandle::new(file, flags))
+    }
+
+    #[test]
+    fn read_seek() {
+        let (_, handle) = open(&[1, 2, 3, 4, 5, 6], OpenOptions::RDONLY);
+        let mut buf = [0; 4];
+        let len = handle.read(&mut [&mut buf]).now_or_never().unwrap();
+        assert_eq!((len, buf), (Ok(4), [1, 2, 3, 4]));
+        let len = handle.read(&mut [&mut buf]).now_or_never().unwrap();
+        assert_eq!((len, &buf[..2]), (Ok(2), &[5, 6][..]));
+        assert_eq!(handle.offset(), 6);
+
+        let pos = handle.seek(SeekFrom::End(-3)).now_or_never().unwrap();
+        assert_eq!(pos, Ok(3));
+        let pos = handle.seek(SeekFrom::Current(-1)).now_or_never().unwrap();
+        assert_eq!(pos, Ok(2));
+        let pos = handle.seek(SeekFrom::Current(-3)).now_or_never().unwrap();
+        assert_eq!(pos, Err(EINVAL));
+        let len = handle.read(&mut [&mut buf]).now_or_never().unwrap();
+        assert_eq!((len, buf), (Ok(4), [3, 4, 5, 6]));
+
+        let len = handle.write(&mut [&[0]]).now_or_never().unwrap();
+        assert_eq!(len, Err(EBADF));
+    }
+
+    #[test]
+    fn append() {
+        let (file, handle) = open(&[1, 2], OpenOptions::WRONLY | OpenOptions::APPEND);
+        handle
+            .seek(SeekFrom::Start(0))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let len = handle.write(&mut [&[3, 4]]).now_or_never().unwrap();
+        assert_eq!(len, Ok(2));
+        assert_eq!(handle.offset(), 4);
+        assert_eq!(file.to_vec(), [1, 2, 3, 4]);
+
+        handle.set_flags(OpenOptions::empty());
+        handle
+            .seek(SeekFrom::Start(1))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let len = handle.write(&mut [&[5]]).now_or_never().unwrap();
+        assert_eq!(len, Ok(1));
+        assert_eq!(file.to_vec(), [1, 5, 3, 4]);
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        let (file, handle) = open(&[1, 2, 3, 4], OpenOptions::RDONLY);
+        // The first read stalls while holding the offset.
+        file.set_hook(|op| match op {
+            MemOp::Read { offset: 0, .. } => Some(MemFault::Stall),
+            _ => None,
+        });
+
+        let (mut a, mut b) = ([0; 2], [0; 2]);
+        {
+            let (mut bufs_a, mut bufs_b) = ([&mut a[..]], [&mut b[..]]);
+            let mut read_a = pin!(handle.read(&mut bufs_a));
+            let mut read_b = pin!(handle.read(&mut bufs_b));
+            assert_eq!(read_a.as_mut().now_or_never(), None);
+            assert_eq!(read_b.as_mut().now_or_never(), None);
+            assert_eq!(read_a.now_or_never(), Some(Ok(2)));
+            assert_eq!(read_b.now_or_never(), Some(Ok(2)));
+        }
+        assert_eq!((a, b), ([1, 2], [3, 4]));
+    }
+}
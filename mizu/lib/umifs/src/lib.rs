@@ -1,5 +1,6 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod handle;
 pub mod misc;
 pub mod path;
 pub mod traits;
@@ -1,13 +1,21 @@
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+};
 
 use async_trait::async_trait;
-use ksc_core::Error::{self, EEXIST, ENOTDIR, EPERM};
-use umio::{ioslice_len, Io};
+use ksc_core::Error::{self, EEXIST, EFBIG, EINVAL, ENOTDIR, EPERM, EROFS};
+use spin::Mutex;
+use umio::{ioslice_len, FlushReport, Io, SliceCursor, ToIo};
 
 use crate::{
     path::Path,
-    traits::Entry,
-    types::{FileType, IoSlice, IoSliceMut, Metadata, OpenOptions, Permissions, SeekFrom},
+    traits::{Directory, DirectoryMut, Entry},
+    types::{
+        DirEntry, FileType, IoSlice, IoSliceMut, Metadata, OpenOptions, Permissions, RenameFlags,
+        SeekFrom,
+    },
 };
 
 pub struct Null;
@@ -132,3 +140,627 @@ impl Entry for Zero {
         }
     }
 }
+
+/// A seekable I/O object backed by a growable in-memory buffer.
+///
+/// Writes beyond the current length extend the buffer, zero-filling any gap,
+/// but never past `limit` bytes.
+///
+/// With the `test` feature, as a test backend, it counts the reads and records
+/// the offsets of the writes it receives, and a hook set by
+/// [`MemIo::set_hook`] can fail or stall any of them.
+pub struct MemIo {
+    data: Mutex<Vec<u8>>,
+    pos: AtomicUsize,
+    limit: usize,
+    #[cfg(any(test, feature = "test"))]
+    probe: MemProbe,
+}
+
+/// The instrumentation of a [`MemIo`] as a test backend.
+#[cfg(any(test, feature = "test"))]
+#[derive(Default)]
+struct MemProbe {
+    reads: AtomicUsize,
+    writes: Mutex<Vec<usize>>,
+    hook: Mutex<Option<MemHook>>,
+}
+
+#[cfg(any(test, feature = "test"))]
+type MemHook = Box<dyn FnMut(MemOp) -> Option<MemFault> + Send>;
+
+/// A read or a write received by a [`MemIo`], passed to its hook.
+#[cfg(any(test, feature = "test"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemOp {
+    Read { offset: usize, len: usize },
+    Write { offset: usize, len: usize },
+}
+
+/// What the hook of a [`MemIo`] does to an operation.
+#[cfg(any(test, feature = "test"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemFault {
+    /// Fail with the error, leaving the data untouched.
+    Fail(Error),
+    /// Return `Pending` once, waking the task right away, before carrying on.
+    Stall,
+}
+
+impl fmt::Debug for MemIo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemIo")
+            .field("pos", &self.pos)
+            .field("limit", &self.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemIo {
+    pub fn new(limit: usize) -> Self {
+        Self::with_data(Vec::new(), limit)
+    }
+
+    pub fn with_data(data: Vec<u8>, limit: usize) -> Self {
+        MemIo {
+            limit: limit.max(data.len()),
+            data: Mutex::new(data),
+            pos: AtomicUsize::new(0),
+            #[cfg(any(test, feature = "test"))]
+            probe: MemProbe::default(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        ksync_core::critical(|| self.data.lock().clone())
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data.into_inner()
+    }
+}
+
+#[cfg(any(test, feature = "test"))]
+impl MemIo {
+    /// The number of `read_at` calls received so far.
+    pub fn reads(&self) -> usize {
+        self.probe.reads.load(SeqCst)
+    }
+
+    /// The offsets of the non-empty writes that reached the buffer so far, in
+    /// order.
+    pub fn writes(&self) -> Vec<usize> {
+        ksync_core::critical(|| self.probe.writes.lock().clone())
+    }
+
+    /// Call `hook` on every following read and write before serving it, which
+    /// is failed or stalled as it returns.
+    pub fn set_hook(&self, hook: impl FnMut(MemOp) -> Option<MemFault> + Send + 'static) {
+        ksync_core::critical(|| *self.probe.hook.lock() = Some(Box::new(hook)));
+    }
+
+    pub fn clear_hook(&self) {
+        ksync_core::critical(|| *self.probe.hook.lock() = None);
+    }
+
+    async fn intercept(&self, op: MemOp) -> Result<(), Error> {
+        use core::{future::poll_fn, task::Poll};
+
+        let hook = &self.probe.hook;
+        let fault = ksync_core::critical(|| hook.lock().as_mut().and_then(|hook| hook(op)));
+        match fault {
+            Some(MemFault::Fail(err)) => Err(err),
+            Some(MemFault::Stall) => {
+                let mut stalled = false;
+                let stall = poll_fn(|cx| {
+                    if stalled {
+                        return Poll::Ready(());
+                    }
+                    stalled = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                });
+                stall.await;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Io for MemIo {
+    async fn seek(&self, whence: SeekFrom) -> Result<usize, Error> {
+        let (base, delta) = match whence {
+            SeekFrom::Start(pos) => {
+                self.pos.store(pos, SeqCst);
+                return Ok(pos);
+            }
+            SeekFrom::End(delta) => (ksync_core::critical(|| self.data.lock().len()), delta),
+            SeekFrom::Current(delta) => (self.pos.load(SeqCst), delta),
+        };
+        let pos = base.checked_add_signed(delta).ok_or(EINVAL)?;
+        self.pos.store(pos, SeqCst);
+        Ok(pos)
+    }
+
+    async fn stream_len(&self) -> Result<usize, Error> {
+        Ok(ksync_core::critical(|| self.data.lock().len()))
+    }
+
+    async fn read_at(&self, offset: usize, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+        #[cfg(any(test, feature = "test"))]
+        {
+            self.probe.reads.fetch_add(1, SeqCst);
+            let len = ioslice_len(&buffer);
+            self.intercept(MemOp::Read { offset, len }).await?;
+        }
+
+        ksync_core::critical(|| {
+            let data = self.data.lock();
+            let src = data.get(offset..).unwrap_or_default();
+            Ok(SliceCursor::new(buffer).copy_from(src))
+        })
+    }
+
+    async fn write_at(&self, offset: usize, buffer: &mut [IoSlice]) -> Result<usize, Error> {
+        let len = ioslice_len(&buffer);
+        if len == 0 {
+            return Ok(0);
+        }
+        let rest = self.limit.checked_sub(offset).filter(|&r| r > 0);
+        let len = len.min(rest.ok_or(EFBIG)?);
+        #[cfg(any(test, feature = "test"))]
+        self.intercept(MemOp::Write { offset, len }).await?;
+
+        ksync_core::critical(|| {
+            let mut data = self.data.lock();
+            if data.len() < offset + len {
+                data.resize(offset + len, 0);
+            }
+            SliceCursor::new(buffer).copy_into(&mut data[offset..][..len]);
+            #[cfg(any(test, feature = "test"))]
+            self.probe.writes.lock().push(offset);
+        });
+        Ok(len)
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Entry for MemIo {
+    async fn open(
+        self: Arc<Self>,
+        path: &Path,
+        options: OpenOptions,
+        perm: Permissions,
+    ) -> Result<(Arc<dyn Entry>, bool), Error> {
+        if !path.as_str().is_empty() || options.contains(OpenOptions::DIRECTORY) {
+            return Err(ENOTDIR);
+        }
+        if options.contains(OpenOptions::CREAT) {
+            return Err(EEXIST);
+        }
+        if !Permissions::all_same(true, true, false).contains(perm) {
+            return Err(EPERM);
+        }
+        Ok((self, false))
+    }
+
+    async fn metadata(&self) -> Metadata {
+        Metadata {
+            ty: FileType::REG,
+            len: ksync_core::critical(|| self.data.lock().len()),
+            offset: 0,
+            perm: Permissions::all_same(true, true, false),
+            block_size: 0,
+            block_count: 0,
+            last_access: None,
+            last_modified: None,
+            last_created: None,
+        }
+    }
+}
+
+/// A read-only view of an entry and everything opened through it, like a
+/// read-only bind mount.
+///
+/// Write flags are stripped from [`Entry::open`], writes to files fail with
+/// `EROFS`, and directories are never exposed as [`DirectoryMut`].
+pub struct ReadOnly<E: ?Sized = dyn Entry> {
+    inner: Arc<E>,
+}
+
+impl<E: Entry + ?Sized> ReadOnly<E> {
+    pub fn new(inner: Arc<E>) -> Self {
+        ReadOnly { inner }
+    }
+
+    pub fn inner(&self) -> &Arc<E> {
+        &self.inner
+    }
+}
+
+impl<E: Entry + ?Sized> ToIo for ReadOnly<E> {
+    fn to_io(self: Arc<Self>) -> Option<Arc<dyn Io>> {
+        let io = self.inner.clone().to_io()?;
+        Some(Arc::new(ReadOnlyIo(io)))
+    }
+}
+
+#[async_trait]
+impl<E: Entry + ?Sized> Entry for ReadOnly<E> {
+    async fn open(
+        self: Arc<Self>,
+        path: &Path,
+        options: OpenOptions,
+        perm: Permissions,
+    ) -> Result<(Arc<dyn Entry>, bool), Error> {
+        let mut options = options.difference(
+            OpenOptions::CREAT | OpenOptions::EXCL | OpenOptions::TRUNC | OpenOptions::APPEND,
+        );
+        let access = options & OpenOptions::ACCMODE;
+        if access == OpenOptions::WRONLY || access == OpenOptions::RDWR {
+            options = options.difference(OpenOptions::ACCMODE) | OpenOptions::RDONLY;
+        }
+        let perm = perm.difference(Permissions::all_same(false, true, false));
+
+        let (entry, created) = self.inner.clone().open(path, options, perm).await?;
+        Ok((Arc::new(ReadOnly::new(entry)), created))
+    }
+
+    async fn metadata(&self) -> Metadata {
+        self.inner.metadata().await
+    }
+
+    fn fs_id(&self) -> Option<usize> {
+        self.inner.fs_id()
+    }
+
+    fn to_dir(self: Arc<Self>) -> Option<Arc<dyn Directory>> {
+        self.inner.clone().to_dir()?;
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl<E: Entry + ?Sized> Directory for ReadOnly<E> {
+    async fn next_dirent(&self, last: Option<&DirEntry>) -> Result<Option<DirEntry>, Error> {
+        let dir = self.inner.clone().to_dir().ok_or(ENOTDIR)?;
+        dir.next_dirent(last).await
+    }
+}
+
+/// Unreachable through [`Entry::to_dir_mut`], but keeps the wrapper usable
+/// where a [`DirectoryMut`] is required.
+#[async_trait]
+impl<E: Entry + ?Sized> DirectoryMut for ReadOnly<E> {
+    async fn rename(
+        self: Arc<Self>,
+        _: &Path,
+        _: Arc<dyn DirectoryMut>,
+        _: &Path,
+        _: RenameFlags,
+    ) -> Result<(), Error> {
+        Err(EROFS)
+    }
+
+    async fn link(
+        self: Arc<Self>,
+        _: &Path,
+        _: Arc<dyn DirectoryMut>,
+        _: &Path,
+    ) -> Result<(), Error> {
+        Err(EROFS)
+    }
+
+    async fn unlink(&self, _: &Path, _: Option<bool>) -> Result<(), Error> {
+        Err(EROFS)
+    }
+
+    async fn reserve_dir_entries(&self, _: usize) -> Result<(), Error> {
+        Err(EROFS)
+    }
+
+    async fn create(
+        self: Arc<Self>,
+        _: &str,
+        _: FileType,
+        _: Permissions,
+    ) -> Result<Arc<dyn Entry>, Error> {
+        Err(EROFS)
+    }
+}
+
+struct ReadOnlyIo(Arc<dyn Io>);
+
+#[async_trait]
+impl Io for ReadOnlyIo {
+    async fn read(&self, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+        self.0.read(buffer).await
+    }
+
+    async fn seek(&self, whence: SeekFrom) -> Result<usize, Error> {
+        self.0.seek(whence).await
+    }
+
+    async fn stream_len(&self) -> Result<usize, Error> {
+        self.0.stream_len().await
+    }
+
+    async fn read_at(&self, offset: usize, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+        self.0.read_at(offset, buffer).await
+    }
+
+    async fn write(&self, _: &mut [IoSlice]) -> Result<usize, Error> {
+        Err(EROFS)
+    }
+
+    async fn write_at(&self, _: usize, _: &mut [IoSlice]) -> Result<usize, Error> {
+        Err(EROFS)
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// An I/O operation observed by [`Tap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoEvent {
+    Read {
+        /// The offset of a `read_at`, or `None` for a `read` at the cursor.
+        offset: Option<usize>,
+        /// The result of the inner call.
+        res: Result<usize, Error>,
+        /// The FNV-1a hash of the bytes read, if enabled.
+        hash: Option<u64>,
+    },
+    Write {
+        /// The offset of a `write_at`, or `None` for a `write` at the cursor.
+        offset: Option<usize>,
+        /// The result of the inner call.
+        res: Result<usize, Error>,
+        /// The FNV-1a hash of the bytes written, if enabled.
+        hash: Option<u64>,
+    },
+}
+
+/// A transparent wrapper reporting every read and write of the inner I/O object
+/// to a callback, e.g. as the backend of a `Phys` to trace what hits the
+/// device.
+///
+/// Every method is forwarded to the inner object, so that its own overrides
+/// still apply. The events are reported after the inner calls return, whose
+/// results are passed through untouched.
+pub struct Tap<I: ?Sized = dyn Io> {
+    inner: Arc<I>,
+    on_io: Box<dyn Fn(IoEvent) + Send + Sync>,
+    hash: bool,
+}
+
+impl<I: Io + ?Sized> Tap<I> {
+    pub fn new(inner: Arc<I>, hash: bool, on_io: impl Fn(IoEvent) + Send + Sync + 'static) -> Self {
+        Tap {
+            inner,
+            on_io: Box::new(on_io),
+            hash,
+        }
+    }
+
+    pub fn inner(&self) -> &Arc<I> {
+        &self.inner
+    }
+
+    fn hash<'a>(&self, bufs: impl Iterator<Item = &'a [u8]>, len: usize) -> Option<u64> {
+        self.hash
+            .then(|| umio::fnv1a(bufs.flat_map(|buf| buf.iter()).take(len)))
+    }
+
+    /// Read at `offset`, or at the cursor if `None`, and report it.
+    async fn tap_read(
+        &self,
+        offset: Option<usize>,
+        buffer: &mut [IoSliceMut<'_>],
+    ) -> Result<usize, Error> {
+        // The inner object may advance the slices, so pass reborrowed ones.
+        let mut bufs = buffer.iter_mut().map(|buf| &mut **buf).collect::<Vec<_>>();
+        let res = match offset {
+            Some(offset) => self.inner.read_at(offset, &mut bufs).await,
+            None => self.inner.read(&mut bufs).await,
+        };
+        drop(bufs);
+        let hash = match res {
+            Ok(len) => self.hash(buffer.iter().map(|b| &**b), len),
+            Err(_) => None,
+        };
+        (self.on_io)(IoEvent::Read { offset, res, hash });
+        res
+    }
+
+    /// Write at `offset`, or at the cursor if `None`, and report it.
+    async fn tap_write(
+        &self,
+        offset: Option<usize>,
+        buffer: &mut [IoSlice<'_>],
+    ) -> Result<usize, Error> {
+        // The inner object may advance the slices, so keep the original ones.
+        let bufs = self.hash.then(|| buffer.to_vec());
+        let res = match offset {
+            Some(offset) => self.inner.write_at(offset, buffer).await,
+            None => self.inner.write(buffer).await,
+        };
+        let hash = match (&bufs, res) {
+            (Some(bufs), Ok(len)) => self.hash(bufs.iter().copied(), len),
+            _ => None,
+        };
+        (self.on_io)(IoEvent::Write { offset, res, hash });
+        res
+    }
+}
+
+#[async_trait]
+impl<I: Io + ?Sized> Io for Tap<I> {
+    async fn read(&self, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+        self.tap_read(None, buffer).await
+    }
+
+    async fn write(&self, buffer: &mut [IoSlice]) -> Result<usize, Error> {
+        self.tap_write(None, buffer).await
+    }
+
+    async fn seek(&self, whence: SeekFrom) -> Result<usize, Error> {
+        self.inner.seek(whence).await
+    }
+
+    async fn stream_len(&self) -> Result<usize, Error> {
+        self.inner.stream_len().await
+    }
+
+    async fn read_at(&self, offset: usize, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+        self.tap_read(Some(offset), buffer).await
+    }
+
+    async fn write_at(&self, offset: usize, buffer: &mut [IoSlice]) -> Result<usize, Error> {
+        self.tap_write(Some(offset), buffer).await
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        self.inner.flush().await
+    }
+
+    async fn flush_durable(&self) -> Result<FlushReport, Error> {
+        self.inner.flush_durable().await
+    }
+
+    async fn fsync(&self, datasync: bool) -> Result<(), Error> {
+        self.inner.fsync(datasync).await
+    }
+
+    async fn discard(&self, offset: usize, len: usize) -> Result<(), Error> {
+        self.inner.discard(offset, len).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use futures_util::FutureExt;
+    use ksc_core::EIO;
+    use umio::IoExt;
+
+    use super::*;
+
+    #[test]
+    fn checksummed() {
+        let mem = Arc::new(MemIo::with_data(vec![0; 32], 32));
+        let io = umio::Checksummed::new(mem.clone(), 16).unwrap();
+        assert_eq!(io.stream_len().now_or_never().unwrap(), Ok(24));
+
+        // A partial write spanning two blocks keeps the rest of them.
+        let write = io.write_all_at(8, &[1; 8]);
+        write.now_or_never().unwrap().unwrap();
+        let mut buf = [0xff; 24];
+        let read = io.read_exact_at(0, &mut buf);
+        read.now_or_never().unwrap().unwrap();
+        assert_eq!(buf[..8], [0; 8]);
+        assert_eq!(buf[8..16], [1; 8]);
+        assert_eq!(buf[16..], [0; 8]);
+
+        // Corrupt the first block.
+        let write = mem.write_all_at(0, &[2]);
+        write.now_or_never().unwrap().unwrap();
+        let read = io.read_exact_at(0, &mut buf);
+        assert_eq!(read.now_or_never().unwrap(), Err(EIO));
+    }
+
+    #[test]
+    fn mem_io_hook() {
+        let mem = MemIo::new(64);
+        let mut writes = 0;
+        mem.set_hook(move |op| match op {
+            MemOp::Write { .. } => {
+                writes += 1;
+                (writes == 2).then_some(MemFault::Fail(EIO))
+            }
+            MemOp::Read { offset, .. } => (offset == 0).then_some(MemFault::Stall),
+        });
+
+        for (offset, res) in [(0, Ok(())), (8, Err(EIO)), (16, Ok(()))] {
+            let write = mem.write_all_at(offset, &[1; 8]);
+            assert_eq!(write.now_or_never().unwrap(), res);
+        }
+        assert_eq!(mem.writes(), [0, 16]);
+
+        let mut buf = [0; 8];
+        {
+            let mut bufs = [&mut buf[..]];
+            let mut read = mem.read_at(0, &mut bufs);
+            assert_eq!(read.as_mut().now_or_never(), None);
+            assert_eq!(read.now_or_never(), Some(Ok(8)));
+        }
+        assert_eq!(buf, [1; 8]);
+        assert_eq!(mem.reads(), 1);
+
+        mem.clear_hook();
+        let read = mem.read_at(0, &mut [&mut buf]).now_or_never();
+        assert_eq!(read, Some(Ok(8)));
+    }
+
+    #[test]
+    fn tap_read_hash() {
+        let data = (0..64).collect::<Vec<u8>>();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let tap = Tap::new(Arc::new(MemIo::with_data(data.clone(), 64)), true, {
+            let events = events.clone();
+            move |event| events.lock().push(event)
+        });
+
+        let (mut a, mut b) = (vec![0; 10], vec![0; 30]);
+        let mut bufs = [&mut a[..], &mut b[..]];
+        let res = tap.read_at(4, &mut bufs).now_or_never().unwrap();
+        assert_eq!(res, Ok(40));
+        assert_eq!((&a[..], &b[..]), (&data[4..14], &data[14..44]));
+
+        let hash = Some(umio::fnv1a(&data[4..44]));
+        let expected = IoEvent::Read {
+            offset: Some(4),
+            res,
+            hash,
+        };
+        assert_eq!(*events.lock(), [expected]);
+    }
+
+    #[test]
+    fn tap_write_at_cursor() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mem = Arc::new(MemIo::new(64));
+        let tap = Tap::new(mem.clone(), false, {
+            let events = events.clone();
+            move |event| events.lock().push(event)
+        });
+
+        tap.seek(SeekFrom::Start(8))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let res = tap.write(&mut [&[1; 4][..]]).now_or_never().unwrap();
+        assert_eq!(res, Ok(4));
+        assert_eq!(mem.to_vec()[8..], [1; 4]);
+
+        let expected = IoEvent::Write {
+            offset: None,
+            res,
+            hash: None,
+        };
+        assert_eq!(*events.lock(), [expected]);
+    }
+}
@@ -1,14 +1,21 @@
 use alloc::{boxed::Box, sync::Arc};
+use core::{
+    future::poll_fn,
+    task::{Context, Poll},
+};
 
 use arsc_rs::Arsc;
 use async_trait::async_trait;
-use ksc_core::Error;
+use ksc_core::Error::{self, EEXIST, EINVAL, ENOENT, EPERM};
 use ktime_core::Instant;
 pub use umio::{IntoAny, IntoAnyExt, Io, IoExt, ToIo};
 
 use crate::{
     path::Path,
-    types::{DirEntry, FsStat, Metadata, OpenOptions, Permissions},
+    types::{
+        DirEntry, FileType, FsStat, Interest, Metadata, OpenOptions, Permissions, Readiness,
+        RenameFlags,
+    },
 };
 
 #[async_trait]
@@ -42,6 +49,81 @@ pub trait Entry: IntoAny + Send + ToIo + Sync + 'static {
     fn to_dir_mut(self: Arc<Self>) -> Option<Arc<dyn DirectoryMut>> {
         None
     }
+
+    /// The readiness interface of the entry for `poll(2)` and the like.
+    ///
+    /// Entries without one, like regular files, are always ready.
+    fn to_pollable(self: Arc<Self>) -> Option<Arc<dyn Pollable>> {
+        None
+    }
+
+    /// The identity of the file system instance owning this entry, if any.
+    fn fs_id(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether this entry and `other` belong to the same file system instance,
+    /// e.g. for `rename` and `link` to reject cross-device operations with
+    /// `EXDEV`.
+    ///
+    /// Entries of unknown file systems are never considered the same.
+    fn same_filesystem(&self, other: &dyn Entry) -> bool {
+        self.fs_id().is_some_and(|id| other.fs_id() == Some(id))
+    }
+}
+
+static ROOT: spin::Once<Arc<dyn Entry>> = spin::Once::new();
+
+/// Set the entry [`resolve_at`] resolves absolute paths from, e.g. one crossing
+/// the mount points of the caller. Only the first call takes effect.
+pub fn set_root(root: Arc<dyn Entry>) {
+    ROOT.call_once(|| root);
+}
+
+/// Resolve `path` like the `*at` syscalls, returning the entry it names.
+///
+/// Absolute paths are resolved from the root set by [`set_root`], and relative
+/// ones from `base`, where an empty path or `.` stands for `base` itself. If
+/// `follow` is `false`, a symbolic link as the last component is not followed.
+pub async fn resolve_at(
+    base: Arc<dyn Entry>,
+    path: &Path,
+    follow: bool,
+) -> Result<Arc<dyn Entry>, Error> {
+    let options = if follow {
+        OpenOptions::RDONLY
+    } else {
+        OpenOptions::RDONLY | OpenOptions::NOFOLLOW
+    };
+    let perm = Permissions::empty();
+    let (entry, _) = match path.as_str().strip_prefix('/') {
+        Some(path) => {
+            let root = ROOT.get().ok_or(ENOENT)?.clone();
+            root.open(Path::new(path.trim_start_matches('/')), options, perm)
+                .await?
+        }
+        None if path == "" || path == "." => return Ok(base),
+        None => base.open(path, options, perm).await?,
+    };
+    Ok(entry)
+}
+
+pub trait Pollable: Send + Sync + 'static {
+    /// Poll for the events of `interest`, registering the waker of `cx` to be
+    /// woken when the readiness may have changed if none of them is ready.
+    fn poll_ready(
+        &self,
+        cx: &mut Context<'_>,
+        interest: Interest,
+    ) -> Poll<Result<Readiness, Error>> {
+        let _ = cx;
+        Poll::Ready(Ok(Readiness::always(interest)))
+    }
+}
+
+/// Wait until any of the events of `interest` is ready on `pollable`.
+pub async fn ready(pollable: &dyn Pollable, interest: Interest) -> Result<Readiness, Error> {
+    poll_fn(|cx| pollable.poll_ready(cx, interest)).await
 }
 
 pub trait File: Entry + Io {}
@@ -54,11 +136,17 @@ pub trait Directory: Entry {
 
 #[async_trait]
 pub trait DirectoryMut: Directory {
+    /// Move the entry at `src_path` to `dst_path` under `dst_parent`.
+    ///
+    /// With [`RenameFlags::EXCHANGE`], both entries must exist and are swapped,
+    /// so that both paths stay present throughout: lookups see either entry
+    /// under either path, but never a missing one.
     async fn rename(
         self: Arc<Self>,
         src_path: &Path,
         dst_parent: Arc<dyn DirectoryMut>,
         dst_path: &Path,
+        flags: RenameFlags,
     ) -> Result<(), Error>;
 
     async fn link(
@@ -69,4 +157,72 @@ pub trait DirectoryMut: Directory {
     ) -> Result<(), Error>;
 
     async fn unlink(&self, path: &Path, expect_dir: Option<bool>) -> Result<(), Error>;
+
+    /// Create exactly one new entry named `name` of type `ty` in this
+    /// directory, unlike [`Entry::open`] with [`OpenOptions::CREAT`] which
+    /// also opens existing ones.
+    ///
+    /// Returns `EEXIST` if the name is taken. The new entry is empty and has
+    /// all its timestamps set to now.
+    async fn create(
+        self: Arc<Self>,
+        name: &str,
+        ty: FileType,
+        perm: Permissions,
+    ) -> Result<Arc<dyn Entry>, Error> {
+        if matches!(name, "" | "." | "..") || name.contains('/') {
+            return Err(EINVAL);
+        }
+        let options = if ty == FileType::FILE {
+            OpenOptions::RDWR
+        } else if ty == FileType::DIR {
+            OpenOptions::RDONLY | OpenOptions::DIRECTORY
+        } else {
+            return Err(EPERM);
+        };
+        let options = options | OpenOptions::CREAT | OpenOptions::EXCL;
+
+        let (entry, created) = self.open(Path::new(name), options, perm).await?;
+        if !created {
+            return Err(EEXIST);
+        }
+        let now = Instant::now();
+        entry.set_times(Some(now), Some(now), Some(now)).await;
+        Ok(entry)
+    }
+
+    /// Hint that `count` more entries are about to be created in this
+    /// directory, so that it can grow its storage for them at once.
+    async fn reserve_dir_entries(&self, count: usize) -> Result<(), Error> {
+        let _ = count;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use ksc_core::Error::ENOTDIR;
+
+    use super::*;
+    use crate::misc::MemIo;
+
+    #[test]
+    fn resolve_rooted() {
+        let root: Arc<dyn Entry> = Arc::new(MemIo::new(0));
+        let base: Arc<dyn Entry> = Arc::new(MemIo::new(0));
+        set_root(root.clone());
+
+        let resolve = |path: &str| {
+            let resolved = resolve_at(base.clone(), Path::new(path), true);
+            resolved.now_or_never().unwrap()
+        };
+        for path in ["", "."] {
+            assert!(Arc::ptr_eq(&resolve(path).unwrap(), &base));
+        }
+        for path in ["/", "//"] {
+            assert!(Arc::ptr_eq(&resolve(path).unwrap(), &root));
+        }
+        assert_eq!(resolve("file").err(), Some(ENOTDIR));
+    }
 }
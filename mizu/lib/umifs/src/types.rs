@@ -1,4 +1,5 @@
 use alloc::string::String;
+use core::time::Duration;
 
 use bitflags::bitflags;
 use ktime_core::Instant;
@@ -65,6 +66,42 @@ bitflags! {
         const SOCK = 12;
         const WHT = 14;
     }
+
+    /// Flags of `renameat2(2)`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+    pub struct RenameFlags: u32 {
+        /// Fail with `EEXIST` if the destination already exists.
+        const NOREPLACE = 1;
+        /// Swap the source and the destination, both of which must exist.
+        const EXCHANGE = 1 << 1;
+    }
+
+    /// The events a poller is interested in, with the values of `poll(2)`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+    pub struct Interest: u16 {
+        const READ = 0x001;
+        const PRI = 0x002;
+        const WRITE = 0x004;
+    }
+
+    /// The events ready on a file, with the values of `poll(2)`.
+    ///
+    /// `ERROR` and `HUP` are reported regardless of the interest.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+    pub struct Readiness: u16 {
+        const READ = 0x001;
+        const PRI = 0x002;
+        const WRITE = 0x004;
+        const ERROR = 0x008;
+        const HUP = 0x010;
+    }
+}
+
+impl Readiness {
+    /// The readiness of a file always ready for the operations of `interest`.
+    pub const fn always(interest: Interest) -> Self {
+        Readiness::from_bits_truncate(interest.bits())
+    }
 }
 
 impl FileType {
@@ -116,6 +153,46 @@ pub struct Metadata {
     pub last_created: Option<Instant>,
 }
 
+/// When reads update the access time of a file, like the `strictatime`,
+/// `relatime` and `noatime` mount options of Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TimeUpdatePolicy {
+    /// Update the access time on every read.
+    Strict,
+    /// Update the access time only if it is not newer than the modification
+    /// or the change time, or if it is older than [`Self::RELATIME_INTERVAL`].
+    #[default]
+    Relatime,
+    /// Never update the access time on reads.
+    NoAtime,
+}
+
+impl TimeUpdatePolicy {
+    pub const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Whether a read at `now` should update the access time `atime` of a file
+    /// last modified at `mtime` and changed at `ctime`.
+    pub fn should_update_atime(
+        &self,
+        atime: Instant,
+        mtime: Instant,
+        ctime: Instant,
+        now: Instant,
+    ) -> bool {
+        match self {
+            TimeUpdatePolicy::Strict => true,
+            TimeUpdatePolicy::NoAtime => false,
+            TimeUpdatePolicy::Relatime => {
+                atime <= mtime
+                    || atime <= ctime
+                    || now
+                        .checked_duration_since(atime)
+                        .map_or(false, |d| d >= Self::RELATIME_INTERVAL)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DirEntry {
     pub name: String,
@@ -4,13 +4,19 @@ use alloc::{
     boxed::Box,
     string::{String, ToString},
     sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    mem, slice, str,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
 };
-use core::{any::Any, mem, slice, str};
 
 use arsc_rs::Arsc;
 use async_trait::async_trait;
 use futures_util::{stream, Stream};
-use ksc_core::{Error, EINTR, EIO};
+use ksc_core::{Error, EINTR, EINVAL, EIO, EOPNOTSUPP};
 
 extern crate alloc;
 
@@ -34,6 +40,17 @@ pub enum SeekFrom {
     Current(isize),
 }
 
+/// The amount of work done by [`Io::flush_durable`].
+#[derive(Copy, PartialEq, Eq, Clone, Debug, Default)]
+pub struct FlushReport {
+    /// The number of pages written to the backend.
+    pub pages: usize,
+    /// The number of bytes written to the backend.
+    pub bytes: usize,
+    /// Whether the backend's own `flush` succeeded after the writes.
+    pub backend_flushed: bool,
+}
+
 pub type IoSlice<'a> = &'a [u8];
 
 pub type IoSliceMut<'a> = &'a mut [u8];
@@ -77,6 +94,12 @@ pub fn ioslice_len(bufs: &&mut [impl IoSliceExt]) -> usize {
     bufs.iter().fold(0, |sum, buf| sum + buf.len())
 }
 
+/// The 64-bit FNV-1a hash of `bytes`.
+pub fn fnv1a<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u64 {
+    let fold = |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, fold)
+}
+
 pub fn ioslice_is_empty(bufs: &&mut [impl IoSliceExt]) -> bool {
     bufs.iter().all(|b| b.len() == 0)
 }
@@ -107,6 +130,72 @@ pub fn advance_slices(bufs: &mut &mut [impl IoSliceExt], n: usize) {
     }
 }
 
+/// A cursor over io slices, advancing through them as bytes are copied in or
+/// out.
+#[derive(Debug)]
+pub struct SliceCursor<'a, S> {
+    bufs: &'a mut [S],
+}
+
+impl<'a, S: IoSliceExt> SliceCursor<'a, S> {
+    pub fn new(bufs: &'a mut [S]) -> Self {
+        SliceCursor { bufs }
+    }
+
+    /// The number of bytes remaining in the slices.
+    pub fn len(&self) -> usize {
+        self.bufs.iter().fold(0, |sum, buf| sum + buf.len())
+    }
+
+    /// Returns `true` if all the slices have been consumed.
+    pub fn is_empty(&self) -> bool {
+        self.bufs.is_empty()
+    }
+
+    /// Returns the slices not yet consumed.
+    pub fn into_inner(self) -> &'a mut [S] {
+        self.bufs
+    }
+}
+
+impl SliceCursor<'_, IoSliceMut<'_>> {
+    /// Copies `src` into the slices, returning the number of bytes copied.
+    pub fn copy_from(&mut self, mut src: &[u8]) -> usize {
+        let mut copied = 0;
+        while !src.is_empty() {
+            let Some(buf) = self.bufs.first_mut() else {
+                break;
+            };
+            let len = buf.len().min(src.len());
+            buf[..len].copy_from_slice(&src[..len]);
+
+            src = &src[len..];
+            copied += len;
+            advance_slices(&mut self.bufs, len);
+        }
+        copied
+    }
+}
+
+impl SliceCursor<'_, IoSlice<'_>> {
+    /// Copies the slices into `dst`, returning the number of bytes copied.
+    pub fn copy_into(&mut self, mut dst: &mut [u8]) -> usize {
+        let mut copied = 0;
+        while !dst.is_empty() {
+            let Some(buf) = self.bufs.first() else {
+                break;
+            };
+            let len = buf.len().min(dst.len());
+            dst[..len].copy_from_slice(&buf[..len]);
+
+            dst = &mut mem::take(&mut dst)[len..];
+            copied += len;
+            advance_slices(&mut self.bufs, len);
+        }
+        copied
+    }
+}
+
 pub trait IntoAny: Any + Send + Sync {
     fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
 
@@ -165,6 +254,36 @@ pub trait Io: ToIo + IntoAny {
     async fn write_at(&self, offset: usize, buffer: &mut [IoSlice]) -> Result<usize, Error>;
 
     async fn flush(&self) -> Result<(), Error>;
+
+    /// Flush the object like [`Io::flush`], reporting how much data has been
+    /// made durable by this call.
+    ///
+    /// Objects without a write-back cache have nothing to write, and only
+    /// report the result of `flush`.
+    async fn flush_durable(&self) -> Result<FlushReport, Error> {
+        self.flush().await?;
+        Ok(FlushReport {
+            backend_flushed: true,
+            ..Default::default()
+        })
+    }
+
+    /// Make the written data durable. If `datasync` is set, metadata that is
+    /// not needed for retrieving the data (e.g. timestamps) may be skipped.
+    async fn fsync(&self, datasync: bool) -> Result<(), Error> {
+        let _ = datasync;
+        self.flush().await
+    }
+    /// Tell the object that the data in `offset..offset + len` is no longer
+    /// needed, e.g. for flash devices to reclaim the blocks. The data read
+    /// from the range afterwards is unspecified.
+    ///
+    /// This is only a hint. Objects that cannot discard return `EOPNOTSUPP`,
+    /// and their data is left intact.
+    async fn discard(&self, offset: usize, len: usize) -> Result<(), Error> {
+        let _ = (offset, len);
+        Err(EOPNOTSUPP)
+    }
 }
 
 #[async_trait]
@@ -193,6 +312,30 @@ pub trait IoExt: Io {
         }
     }
 
+    /// Read into all the slices of `buffer` starting at `offset`, retrying on
+    /// short reads until the buffer is filled or EOF is reached. Returns the
+    /// total length read.
+    async fn preadv_exact_at(
+        &self,
+        mut offset: usize,
+        mut buffer: &mut [IoSliceMut],
+    ) -> Result<usize, Error> {
+        let mut read_len = 0;
+        while !ioslice_is_empty(&buffer) {
+            match self.read_at(offset, buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    offset += n;
+                    read_len += n;
+                    advance_slices(&mut buffer, n);
+                }
+                Err(EINTR) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read_len)
+    }
+
     async fn read_exact(&self, mut buffer: &mut [u8]) -> Result<(), Error> {
         while !buffer.is_empty() {
             match self.read(&mut [buffer]).await {
@@ -230,6 +373,30 @@ pub trait IoExt: Io {
         }
     }
 
+    /// Write all the slices of `buffer` starting at `offset`, retrying on
+    /// short writes until the buffer is consumed or the object refuses to take
+    /// more. Returns the total length written.
+    async fn pwritev_all_at(
+        &self,
+        mut offset: usize,
+        mut buffer: &mut [IoSlice],
+    ) -> Result<usize, Error> {
+        let mut written_len = 0;
+        while !ioslice_is_empty(&buffer) {
+            match self.write_at(offset, buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    offset += n;
+                    written_len += n;
+                    advance_slices(&mut buffer, n);
+                }
+                Err(EINTR) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written_len)
+    }
+
     async fn write_all(&self, mut buffer: &[u8]) -> Result<(), Error> {
         while !buffer.is_empty() {
             match self.write(&mut [buffer]).await {
@@ -301,6 +468,248 @@ impl<T: Io> ToIo for T {
     }
 }
 
+/// Several objects presented as one address space, where each segment takes
+/// up its given length in order.
+///
+/// Requests spanning multiple segments are split at the boundaries, and those
+/// past the end of the last segment read or write nothing.
+pub struct Concat {
+    segments: Vec<(Arc<dyn Io>, usize)>,
+    position: AtomicUsize,
+}
+
+impl Concat {
+    pub fn new(segments: Vec<(Arc<dyn Io>, usize)>) -> Self {
+        Concat {
+            segments,
+            position: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|&(_, len)| len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Find the segment containing `offset`, with the offset inside it and
+    /// the length remaining in it.
+    fn locate(&self, mut offset: usize) -> Option<(&Arc<dyn Io>, usize, usize)> {
+        for (io, len) in &self.segments {
+            if offset < *len {
+                return Some((io, offset, len - offset));
+            }
+            offset -= len;
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl Io for Concat {
+    async fn seek(&self, whence: SeekFrom) -> Result<usize, Error> {
+        let pos = match whence {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(pos) => self.len().checked_add_signed(pos),
+            SeekFrom::Current(pos) => self.position.load(SeqCst).checked_add_signed(pos),
+        };
+        let pos = pos.ok_or(EINVAL)?;
+        self.position.store(pos, SeqCst);
+        Ok(pos)
+    }
+
+    async fn read_at(&self, mut offset: usize, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+        let mut read_len = 0;
+        for buf in buffer {
+            let mut buf = &mut **buf;
+            while !buf.is_empty() {
+                let Some((io, inner, remaining)) = self.locate(offset) else {
+                    return Ok(read_len);
+                };
+                let chunk = buf.len().min(remaining);
+                let len = io.read_at(inner, &mut [&mut buf[..chunk]]).await?;
+                offset += len;
+                read_len += len;
+                if len < chunk {
+                    return Ok(read_len);
+                }
+                buf = &mut buf[len..];
+            }
+        }
+        Ok(read_len)
+    }
+
+    async fn write_at(&self, mut offset: usize, buffer: &mut [IoSlice]) -> Result<usize, Error> {
+        let mut written_len = 0;
+        for buf in buffer {
+            let mut buf = &**buf;
+            while !buf.is_empty() {
+                let Some((io, inner, remaining)) = self.locate(offset) else {
+                    return Ok(written_len);
+                };
+                let chunk = buf.len().min(remaining);
+                let len = io.write_at(inner, &mut [&buf[..chunk]]).await?;
+                offset += len;
+                written_len += len;
+                if len < chunk {
+                    return Ok(written_len);
+                }
+                buf = &buf[len..];
+            }
+        }
+        Ok(written_len)
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        for (io, _) in &self.segments {
+            io.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    });
+    !crc
+}
+
+/// An adapter storing a CRC-32 at the end of every block of `inner`, which is
+/// verified on reads.
+///
+/// Each block of `block` bytes holds `block - 4` bytes of data, so the
+/// logical capacity is slightly smaller than that of `inner`. Blocks never
+/// written, i.e. all zeros, are read as zeros.
+///
+/// Partial blocks are written by reading, modifying and writing back the
+/// whole blocks, so concurrent writes to the same block must be serialized by
+/// the caller.
+pub struct Checksummed {
+    inner: Arc<dyn Io>,
+    block: usize,
+    position: AtomicUsize,
+}
+
+impl Checksummed {
+    const CRC_LEN: usize = mem::size_of::<u32>();
+
+    pub fn new(inner: Arc<dyn Io>, block: usize) -> Result<Self, Error> {
+        if block <= Self::CRC_LEN {
+            return Err(EINVAL);
+        }
+        Ok(Checksummed {
+            inner,
+            block,
+            position: AtomicUsize::new(0),
+        })
+    }
+
+    /// The number of data bytes in a block.
+    fn data_len(&self) -> usize {
+        self.block - Self::CRC_LEN
+    }
+
+    /// Read and verify the block at `index`, returning `false` if it is
+    /// beyond the end of `inner`.
+    async fn read_block(&self, index: usize, buf: &mut [u8]) -> Result<bool, Error> {
+        let mut offset = index * self.block;
+        let mut rest = &mut *buf;
+        while !rest.is_empty() {
+            match self.inner.read_at(offset, &mut [rest]).await? {
+                0 => return Ok(false),
+                len => {
+                    offset += len;
+                    rest = &mut mem::take(&mut rest)[len..];
+                }
+            }
+        }
+
+        let (data, crc) = buf.split_at(self.data_len());
+        let crc = u32::from_le_bytes(crc.try_into().unwrap());
+        if crc32(data) != crc && buf.iter().any(|&b| b != 0) {
+            log::error!("checksum mismatch in block {index}");
+            return Err(EIO);
+        }
+        Ok(true)
+    }
+
+    async fn write_block(&self, index: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let (data, crc) = buf.split_at_mut(self.data_len());
+        crc.copy_from_slice(&crc32(data).to_le_bytes());
+        self.inner.write_all_at(index * self.block, buf).await
+    }
+}
+
+#[async_trait]
+impl Io for Checksummed {
+    async fn seek(&self, whence: SeekFrom) -> Result<usize, Error> {
+        let pos = match whence {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(pos) => self.stream_len().await?.checked_add_signed(pos),
+            SeekFrom::Current(pos) => self.position.load(SeqCst).checked_add_signed(pos),
+        };
+        let pos = pos.ok_or(EINVAL)?;
+        self.position.store(pos, SeqCst);
+        Ok(pos)
+    }
+
+    async fn stream_len(&self) -> Result<usize, Error> {
+        let len = self.inner.stream_len().await?;
+        Ok(len / self.block * self.data_len())
+    }
+
+    async fn read_at(&self, offset: usize, buffer: &mut [IoSliceMut]) -> Result<usize, Error> {
+        let data_len = self.data_len();
+        let mut cursor = SliceCursor::new(buffer);
+        let mut block = vec![0; self.block];
+        let (mut index, mut start) = (offset / data_len, offset % data_len);
+        let mut read_len = 0;
+        while !cursor.is_empty() {
+            if !self.read_block(index, &mut block).await? {
+                break;
+            }
+            read_len += cursor.copy_from(&block[start..data_len]);
+            (index, start) = (index + 1, 0);
+        }
+        Ok(read_len)
+    }
+
+    async fn write_at(&self, offset: usize, buffer: &mut [IoSlice]) -> Result<usize, Error> {
+        let data_len = self.data_len();
+        let mut rest = ioslice_len(&buffer);
+        let mut cursor = SliceCursor::new(buffer);
+        let mut block = vec![0; self.block];
+        let (mut index, mut start) = (offset / data_len, offset % data_len);
+        let mut written_len = 0;
+        while rest > 0 {
+            let end = data_len.min(start + rest);
+            if start != 0 || end != data_len {
+                // Keep the rest of a partially written block.
+                if !self.read_block(index, &mut block).await? {
+                    block.fill(0);
+                }
+            }
+            let len = cursor.copy_into(&mut block[start..end]);
+            self.write_block(index, &mut block).await?;
+
+            written_len += len;
+            rest -= len;
+            (index, start) = (index + 1, 0);
+        }
+        Ok(written_len)
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        self.inner.flush().await
+    }
+}
+
 /// Used in implementations of `read_at` by files where random access is not
 /// supported.
 pub async fn read_at_by_seek<T: Io>(
@@ -328,3 +737,29 @@ pub async fn write_at_by_seek<T: Io>(
     let _ = io.seek(SeekFrom::Start(old)).await;
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slice_cursor() {
+        let (mut a, mut b) = ([0; 3], [0; 4]);
+        let mut bufs: [IoSliceMut; 3] = [&mut a, &mut [], &mut b];
+        let mut cursor = SliceCursor::new(&mut bufs);
+        assert_eq!(cursor.copy_from(&[1, 2]), 2);
+        assert_eq!(cursor.copy_from(&[3, 4, 5]), 3);
+        assert_eq!(cursor.len(), 2);
+        assert_eq!(cursor.copy_from(&[6, 7, 8]), 2);
+        assert!(cursor.is_empty());
+        assert_eq!((a, b), ([1, 2, 3], [4, 5, 6, 7]));
+
+        let mut bufs: [IoSlice; 2] = [&[1, 2, 3], &[4, 5]];
+        let mut cursor = SliceCursor::new(&mut bufs);
+        let mut dst = [0; 4];
+        assert_eq!(cursor.copy_into(&mut dst), 4);
+        assert_eq!(dst, [1, 2, 3, 4]);
+        assert_eq!(cursor.copy_into(&mut dst), 1);
+        assert!(cursor.is_empty());
+    }
+}